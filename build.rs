@@ -0,0 +1,24 @@
+use std::path::Path;
+
+/// Expose the resolved cdk version to the crate as `CDK_VERSION`
+///
+/// cdk is a git dependency, so its version is read from the lockfile.
+fn main() {
+    let lockfile = Path::new(&std::env::var("CARGO_MANIFEST_DIR").unwrap()).join("Cargo.lock");
+    println!("cargo:rerun-if-changed={}", lockfile.display());
+
+    let version = std::fs::read_to_string(&lockfile)
+        .ok()
+        .and_then(|lock| {
+            let mut lines = lock.lines();
+            lines.find(|line| *line == "name = \"cdk\"")?;
+            lines
+                .next()?
+                .strip_prefix("version = \"")?
+                .strip_suffix('"')
+                .map(str::to_string)
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=CDK_VERSION={}", version);
+}
@@ -0,0 +1,425 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use axum::extract::{Path, State};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use cdk::amount::Amount;
+use cdk::mint_url::MintUrl;
+use cdk::nuts::nut18::{PaymentRequest, PaymentRequestPayload, TransportType};
+use cdk::nuts::{CurrencyUnit, Nut10Secret, PublicKey, SpendingConditions, Token};
+use cdk::wallet::SendOptions;
+use cdk::wallet::types::WalletKey;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::gateway_server::{ErrorKind, ErrorResponse, GatwayState};
+use crate::payment_store::{OwedChange, PendingPayment, UnclaimedPayment};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddMintRequest {
+    pub mint_url: MintUrl,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MintEarnings {
+    pub mint_url: MintUrl,
+    pub amount: Amount,
+}
+
+/// How long delivering a withdrawn token to a payment request may take
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WithdrawRequest {
+    pub mint_url: MintUrl,
+    pub amount: Amount,
+    /// Where the token goes, it is only returned when not set
+    #[serde(default)]
+    pub destination: Option<WithdrawDestination>,
+}
+
+/// Where withdrawn earnings are sent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WithdrawDestination {
+    /// Lock the token to a public key, so only its holder can redeem it
+    Pubkey(PublicKey),
+    /// Deliver the token to a NUT-18 payment request over its HTTP transport
+    PaymentRequest(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WithdrawResponse {
+    pub token: String,
+    /// Why the token could not be delivered to the payment request, it has to
+    /// be passed on by hand
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delivery_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReconcileResponse {
+    /// Requests whose tokens were claimed
+    pub claimed: Vec<Uuid>,
+    /// Pending batch payments whose outcome is now known
+    pub settled: Vec<Uuid>,
+}
+
+/// Routes for managing the gateway at runtime
+///
+/// These must only be mounted behind API key authentication.
+pub fn admin_routes() -> Router<GatwayState> {
+    Router::new()
+        .route("/admin/mints", post(add_mint))
+        .route("/admin/mints/{url}", delete(remove_mint))
+        .route("/admin/earnings", get(get_earnings))
+        .route("/admin/withdraw", post(withdraw))
+        .route("/admin/owed-change", get(get_owed_change))
+        .route("/admin/unclaimed-payments", get(get_unclaimed_payments))
+        .route("/admin/pending-payments", get(get_pending_payments))
+        .route("/admin/reconcile", post(reconcile))
+}
+
+/// Start supporting a mint, creating a wallet for it
+///
+/// Mints added at runtime are not written back to the config file.
+pub async fn add_mint(
+    State(state): State<GatwayState>,
+    Json(request): Json<AddMintRequest>,
+) -> Result<Json<Vec<MintUrl>>, ErrorResponse> {
+    let mint_url = request.mint_url;
+
+    if state.supported_mints().contains(&mint_url) {
+        tracing::debug!("Mint {} is already supported", mint_url);
+        return Ok(Json(state.supported_mints()));
+    }
+
+    tracing::info!("Adding supported mint {}", mint_url);
+    state
+        .inner
+        .wallets()
+        .create_and_add_wallet(&mint_url.to_string(), CurrencyUnit::Sat, None)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to create wallet for mint {}: {}", mint_url, e);
+            ErrorResponse::internal("Failed to create wallet for mint").with_details(e.to_string())
+        })?;
+
+    let mut mints = state.mints.write().expect("mints lock poisoned");
+    if !mints.contains(&mint_url) {
+        mints.push(mint_url);
+    }
+
+    Ok(Json(mints.clone()))
+}
+
+/// Stop supporting a mint
+///
+/// The mint's wallet is removed from the gateway, but its proofs stay in the
+/// database so adding the mint again restores its balance.
+pub async fn remove_mint(
+    State(state): State<GatwayState>,
+    Path(url): Path<String>,
+) -> Result<Json<Vec<MintUrl>>, ErrorResponse> {
+    let mint_url = MintUrl::from_str(&url)
+        .map_err(|e| ErrorResponse::bad_request("Invalid mint URL").with_details(e.to_string()))?;
+
+    let mints = {
+        let mut mints = state.mints.write().expect("mints lock poisoned");
+
+        let Some(position) = mints.iter().position(|mint| mint == &mint_url) else {
+            return Err(
+                ErrorResponse::new(ErrorKind::NotFound, "Mint not supported")
+                    .with_details(format!("{} is not a supported mint", mint_url)),
+            );
+        };
+
+        if mints.len() == 1 {
+            return Err(ErrorResponse::bad_request(
+                "Cannot remove the last supported mint",
+            ));
+        }
+
+        mints.remove(position);
+        mints.clone()
+    };
+
+    tracing::info!("Removing supported mint {}", mint_url);
+    state
+        .inner
+        .wallets()
+        .remove_wallet(&WalletKey::new(mint_url, CurrencyUnit::Sat))
+        .await;
+
+    Ok(Json(mints))
+}
+
+/// Ecash the gateway has kept from payments and not yet withdrawn, by mint
+pub async fn get_earnings(
+    State(state): State<GatwayState>,
+) -> Result<Json<Vec<MintEarnings>>, ErrorResponse> {
+    let earnings = state.inner.payment_store().earnings().map_err(|e| {
+        tracing::error!("Failed to read earnings: {}", e);
+        ErrorResponse::internal("Failed to read earnings")
+    })?;
+
+    Ok(Json(
+        earnings
+            .into_iter()
+            .map(|(mint_url, amount)| MintEarnings { mint_url, amount })
+            .collect(),
+    ))
+}
+
+/// Withdraw earnings from a mint as a token
+///
+/// The token is locked to the destination's key, or to the payment request's
+/// NUT-10 condition, and delivered to the payment request when one is given.
+pub async fn withdraw(
+    State(state): State<GatwayState>,
+    Json(request): Json<WithdrawRequest>,
+) -> Result<Json<WithdrawResponse>, ErrorResponse> {
+    let store = state.inner.payment_store();
+
+    let payment_request = match &request.destination {
+        Some(WithdrawDestination::PaymentRequest(payment_request)) => Some(
+            withdraw_payment_request(payment_request, &request.mint_url, request.amount)?,
+        ),
+        _ => None,
+    };
+    let conditions = match (&request.destination, &payment_request) {
+        (Some(WithdrawDestination::Pubkey(pubkey)), _) => {
+            Some(SpendingConditions::new_p2pk(*pubkey, None))
+        }
+        (_, Some((payment_request, _))) => payment_request
+            .nut10
+            .clone()
+            .map(|nut10| SpendingConditions::try_from(Nut10Secret::from(nut10)))
+            .transpose()
+            .map_err(|e| {
+                ErrorResponse::bad_request("Invalid payment request")
+                    .with_details(format!("Unsupported spending condition: {}", e))
+            })?,
+        _ => None,
+    };
+
+    // Deduct first so concurrent withdrawals can't take the same earnings twice
+    let withdrawn = store
+        .withdraw_earnings(&request.mint_url, request.amount)
+        .map_err(|e| {
+            tracing::error!("Failed to update earnings: {}", e);
+            ErrorResponse::internal("Failed to update earnings")
+        })?;
+    if !withdrawn {
+        return Err(
+            ErrorResponse::bad_request("Insufficient earnings").with_details(format!(
+                "Cannot withdraw {} from {}",
+                request.amount, request.mint_url
+            )),
+        );
+    }
+
+    let token = async {
+        let wallet = state
+            .inner
+            .wallets()
+            .get_wallet(&WalletKey::new(request.mint_url.clone(), CurrencyUnit::Sat))
+            .await
+            .ok_or_else(|| anyhow::anyhow!("No wallet for mint {}", request.mint_url))?;
+        let prepared = wallet
+            .prepare_send(
+                request.amount,
+                SendOptions {
+                    conditions,
+                    ..Default::default()
+                },
+            )
+            .await?;
+        anyhow::Ok(wallet.send(prepared, None).await?)
+    }
+    .await;
+
+    match token {
+        Ok(token) => {
+            tracing::info!("Withdrew {} from {}", request.amount, request.mint_url);
+
+            // The earnings have left the wallet, so a failed delivery still returns the token
+            let delivery_error = match &payment_request {
+                Some((payment_request, target)) => {
+                    deliver_token(payment_request, target, &request.mint_url, &token)
+                        .await
+                        .err()
+                        .map(|e| {
+                            tracing::error!(
+                                "Failed to deliver withdrawn token to {}: {}",
+                                target,
+                                e
+                            );
+                            e.to_string()
+                        })
+                }
+                None => None,
+            };
+
+            Ok(Json(WithdrawResponse {
+                token: token.to_string(),
+                delivery_error,
+            }))
+        }
+        Err(e) => {
+            tracing::error!("Failed to withdraw earnings: {}", e);
+            if let Err(e) = store.add_earnings(&[(request.mint_url.clone(), request.amount)]) {
+                tracing::error!("Failed to restore earnings after failed withdrawal: {}", e);
+            }
+            Err(ErrorResponse::internal("Failed to withdraw earnings").with_details(e.to_string()))
+        }
+    }
+}
+
+/// Parse a payment request to withdraw to, and find where to deliver the token
+///
+/// Checked before any earnings are taken, so a request that can't be paid
+/// leaves them untouched.
+fn withdraw_payment_request(
+    payment_request: &str,
+    mint_url: &MintUrl,
+    amount: Amount,
+) -> Result<(PaymentRequest, String), ErrorResponse> {
+    let invalid = |details: String| {
+        ErrorResponse::bad_request("Invalid payment request").with_details(details)
+    };
+
+    let payment_request =
+        PaymentRequest::from_str(payment_request).map_err(|e| invalid(e.to_string()))?;
+    if payment_request
+        .unit
+        .as_ref()
+        .is_some_and(|unit| *unit != CurrencyUnit::Sat)
+    {
+        return Err(invalid(
+            "Only sat payment requests are supported".to_string(),
+        ));
+    }
+    if payment_request
+        .amount
+        .is_some_and(|requested| requested != amount)
+    {
+        return Err(invalid(format!(
+            "Payment request is for {:?}, not {}",
+            payment_request.amount, amount
+        )));
+    }
+    if payment_request
+        .mints
+        .as_ref()
+        .is_some_and(|mints| !mints.contains(mint_url))
+    {
+        return Err(invalid(format!(
+            "Payment request does not accept {}",
+            mint_url
+        )));
+    }
+
+    let target = payment_request
+        .transports
+        .iter()
+        .find(|transport| transport._type == TransportType::HttpPost)
+        .map(|transport| transport.target.clone())
+        .ok_or_else(|| invalid("Payment request has no HTTP transport".to_string()))?;
+
+    Ok((payment_request, target))
+}
+
+/// Post a token's proofs to a payment request's HTTP transport
+async fn deliver_token(
+    payment_request: &PaymentRequest,
+    target: &str,
+    mint_url: &MintUrl,
+    token: &Token,
+) -> anyhow::Result<()> {
+    let payload = PaymentRequestPayload {
+        id: payment_request.payment_id.clone(),
+        memo: None,
+        mint: mint_url.clone(),
+        unit: CurrencyUnit::Sat,
+        proofs: token.proofs(),
+    };
+
+    reqwest::Client::new()
+        .post(target)
+        .timeout(DELIVERY_TIMEOUT)
+        .json(&payload)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+/// Change that could not be returned to clients after their payment
+pub async fn get_owed_change(
+    State(state): State<GatwayState>,
+) -> Result<Json<Vec<OwedChange>>, ErrorResponse> {
+    let owed = state.inner.payment_store().owed_change().map_err(|e| {
+        tracing::error!("Failed to read owed change: {}", e);
+        ErrorResponse::internal("Failed to read owed change")
+    })?;
+
+    Ok(Json(owed))
+}
+
+/// Payments sent without the node returning a preimage, whose tokens are not claimed yet
+pub async fn get_unclaimed_payments(
+    State(state): State<GatwayState>,
+) -> Result<Json<Vec<UnclaimedPayment>>, ErrorResponse> {
+    let unclaimed = state
+        .inner
+        .payment_store()
+        .unclaimed_payments()
+        .map_err(|e| {
+            tracing::error!("Failed to read unclaimed payments: {}", e);
+            ErrorResponse::internal("Failed to read unclaimed payments")
+        })?;
+
+    Ok(Json(unclaimed))
+}
+
+/// Batch payments whose outcome was not known when the batch finished
+pub async fn get_pending_payments(
+    State(state): State<GatwayState>,
+) -> Result<Json<Vec<PendingPayment>>, ErrorResponse> {
+    let pending = state
+        .inner
+        .payment_store()
+        .pending_payments()
+        .map_err(|e| {
+            tracing::error!("Failed to read pending payments: {}", e);
+            ErrorResponse::internal("Failed to read pending payments")
+        })?;
+
+    Ok(Json(pending))
+}
+
+/// Ask the node again for the preimages of unclaimed payments and claim their
+/// tokens, and settle pending batch payments it now reports an outcome for
+pub async fn reconcile(
+    State(state): State<GatwayState>,
+) -> Result<Json<ReconcileResponse>, ErrorResponse> {
+    let claimed = state
+        .inner
+        .reconcile_unclaimed_payments()
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to reconcile unclaimed payments: {}", e);
+            ErrorResponse::internal("Failed to reconcile unclaimed payments")
+                .with_details(e.to_string())
+        })?;
+
+    let settled = state.inner.settle_pending_payments().await.map_err(|e| {
+        tracing::error!("Failed to settle pending payments: {}", e);
+        ErrorResponse::internal("Failed to settle pending payments").with_details(e.to_string())
+    })?;
+
+    Ok(Json(ReconcileResponse { claimed, settled }))
+}
@@ -0,0 +1,76 @@
+use axum::extract::{Request, State};
+use axum::http::{HeaderMap, header};
+use axum::middleware::Next;
+use axum::response::Response;
+use bitcoin::hashes::Hash as _;
+use bitcoin::hashes::sha256::Hash as Sha256Hash;
+
+use crate::gateway_server::{ErrorKind, ErrorResponse, GatwayState};
+
+/// Header accepted as an alternative to `Authorization: Bearer <key>`
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// Middleware rejecting requests without a configured API key
+///
+/// When no API keys are configured every request is let through.
+pub async fn require_api_key(
+    State(state): State<GatwayState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ErrorResponse> {
+    let settings = state.inner.settings();
+    let api_keys = &settings.auth.api_keys;
+
+    if api_keys.is_empty() {
+        return Ok(next.run(request).await);
+    }
+
+    if valid_api_key(api_keys, request.headers()).is_none() {
+        tracing::debug!(
+            "Rejecting request to {} with missing or invalid API key",
+            request.uri()
+        );
+        return Err(ErrorResponse::new(ErrorKind::Unauthorized, "Unauthorized")
+            .with_details("A valid API key is required"));
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// The API key sent with a request, if it is one of `api_keys`
+///
+/// Every key is compared in constant time, so how long the check takes
+/// doesn't tell a client how close its guess was or which key it matched.
+pub(crate) fn valid_api_key<'a>(api_keys: &[String], headers: &'a HeaderMap) -> Option<&'a str> {
+    api_key_from_headers(headers).filter(|provided| {
+        api_keys
+            .iter()
+            .fold(false, |matched, key| matched | keys_match(key, provided))
+    })
+}
+
+/// Compare two keys in time independent of where they differ
+///
+/// Both are hashed first, so keys of different lengths are compared the same way.
+fn keys_match(key: &str, provided: &str) -> bool {
+    let key = Sha256Hash::hash(key.as_bytes()).to_byte_array();
+    let provided = Sha256Hash::hash(provided.as_bytes()).to_byte_array();
+    let difference = key
+        .iter()
+        .zip(provided.iter())
+        .fold(0u8, |difference, (a, b)| difference | (a ^ b));
+    std::hint::black_box(difference) == 0
+}
+
+/// API key from either the `Authorization: Bearer` or `X-Api-Key` header
+fn api_key_from_headers(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| {
+            headers
+                .get(API_KEY_HEADER)
+                .and_then(|value| value.to_str().ok())
+        })
+}
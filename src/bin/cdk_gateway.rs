@@ -1,33 +1,169 @@
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+use bitcoin::NetworkKind;
+use bitcoin::bip32::{ChildNumber, Xpriv};
+use bitcoin::secp256k1::Secp256k1;
+
+use cdk::cdk_database::{self, WalletDatabase};
+use cdk::cdk_payment::{self, MintPayment};
 use cdk::mint_url::MintUrl;
 use cdk::wallet::{MultiMintWallet, WalletBuilder};
-use cdk_gateway::config::Settings;
-use cdk_gateway::gateway_server::CdkGateway;
+use cdk_gateway::config::{DatabaseBackend, DatabaseConfig, LogFormat, LoggingConfig, Settings};
+use cdk_gateway::gateway_server::{CdkGateway, ServerHandle};
+use cdk_gateway::node::{FailoverBackend, FailoverNode, NodeHealth, ReconnectingNode};
+use cdk_gateway::payment_store::PaymentStore;
 use cdk_redb::WalletRedbDatabase;
-use std::sync::Arc;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use cdk_sqlite::WalletSqliteDatabase;
+use clap::Parser;
+use std::sync::{Arc, Mutex};
+use tokio_util::sync::CancellationToken;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{
+    EnvFilter, Layer, Registry, layer::SubscriberExt, reload, util::SubscriberInitExt,
+};
 
 const DEFAULT_WORK_DIR: &str = ".cdk-gateway";
 
+/// Command line arguments, overriding the loaded configuration
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Directory holding the config file and databases [default: ~/.cdk-gateway]
+    #[arg(long, env = "CDK_GATEWAY_WORK_DIR")]
+    work_dir: Option<PathBuf>,
+    /// Config file to load instead of the one in the work directory
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Address for the HTTP server to listen on
+    #[arg(long)]
+    listen_addr: Option<String>,
+    /// Port for the HTTP server to listen on
+    #[arg(long)]
+    port: Option<u16>,
+    /// Log level for the gateway, e.g. "debug", overriding logging.level. RUST_LOG takes precedence when set
+    #[arg(long)]
+    log_level: Option<String>,
+    /// Check the configuration and that the payment processors and mints can be reached, then exit
+    #[arg(long)]
+    check: bool,
+}
+
+impl Cli {
+    /// Override the loaded settings with the values given on the command line
+    fn apply(&self, settings: &mut Settings) {
+        if let Some(listen_addr) = &self.listen_addr {
+            settings.server.listen_addr = listen_addr.clone();
+        }
+        if let Some(port) = self.port {
+            settings.server.port = port;
+        }
+        if let Some(log_level) = &self.log_level {
+            settings.logging.level = log_level.clone();
+        }
+    }
+}
+
+/// Build the log output layer for the configured format, writing to `writer`
+fn log_layer<S, W>(config: &LoggingConfig, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    match config.format {
+        LogFormat::Pretty => tracing_subscriber::fmt::layer()
+            .with_writer(writer)
+            .with_target(true)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_writer(writer)
+            .with_target(true)
+            .boxed(),
+    }
+}
+
+/// Log filter for the configured level, unless RUST_LOG is set
+fn log_filter(config: &LoggingConfig) -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        // Default to the configured level if RUST_LOG environment variable is not set
+        format!(
+            "cdk_gateway={},tower_http=debug,axum::rejection=trace",
+            config.level
+        )
+        .into()
+    })
+}
+
+/// Load the config again and apply the settings that can change without a restart
+fn reload_settings(
+    gateway: &CdkGateway,
+    cli: &Cli,
+    work_dir: &Path,
+    filter: &reload::Handle<EnvFilter, Registry>,
+) {
+    tracing::info!("Received SIGHUP, reloading configuration");
+    let mut settings = match Settings::load(work_dir.to_str(), cli.config.as_deref()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            tracing::error!(
+                "Failed to reload configuration, keeping the current settings: {}",
+                e
+            );
+            return;
+        }
+    };
+    cli.apply(&mut settings);
+    if let Err(e) = settings.validate() {
+        tracing::error!(
+            "Reloaded configuration is invalid, keeping the current settings: {}",
+            e
+        );
+        return;
+    }
+
+    for name in gateway.reload_settings(&settings) {
+        tracing::warn!(
+            "Ignoring changes to {}, they only take effect after a restart",
+            name
+        );
+    }
+    if let Err(e) = filter.reload(log_filter(&settings.logging)) {
+        tracing::error!("Failed to apply the reloaded log level: {}", e);
+    }
+    tracing::info!("Configuration reloaded");
+}
+
 fn main() -> anyhow::Result<()> {
-    // Initialize tracing
+    let cli = Cli::parse();
+
+    let work_dir = match &cli.work_dir {
+        Some(work_dir) => work_dir.clone(),
+        None => home::home_dir().unwrap().join(DEFAULT_WORK_DIR),
+    };
+
+    // Load configuration from the work directory, then apply command line overrides
+    let mut settings = Settings::load(Some(work_dir.to_str().unwrap()), cli.config.as_deref())?;
+    cli.apply(&mut settings);
+
+    // Initialize tracing once the logging config is known, the filter can be
+    // swapped when the log level is reloaded
+    let (filter, filter_handle) = reload::Layer::new(log_filter(&settings.logging));
     tracing_subscriber::registry()
-        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-            // Default to INFO level if RUST_LOG environment variable is not set
-            "cdk_gateway=info,tower_http=debug,axum::rejection=trace".into()
-        }))
-        .with(tracing_subscriber::fmt::layer().with_target(true))
+        .with(filter)
+        .with(log_layer(&settings.logging, std::io::stdout))
         .init();
 
+    if cli.check {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        return runtime.block_on(run_check(&settings));
+    }
+
     tracing::info!("Starting CDK Gateway");
-    // Get home directory
-    let home_dir = home::home_dir().unwrap();
-    let work_dir = home_dir.join(DEFAULT_WORK_DIR);
-    
-    
-    // Load configuration from the work directory
-    let settings = Settings::with_work_dir(Some(work_dir.to_str().unwrap()))?;
+    settings.validate()?;
     tracing::info!("Loaded configuration");
 
     let runtime = tokio::runtime::Builder::new_multi_thread()
@@ -37,29 +173,69 @@ fn main() -> anyhow::Result<()> {
     let runtime = Arc::new(runtime);
 
     // Pass settings to your application components here
-    let gateway_result: anyhow::Result<CdkGateway> = runtime.block_on(async {
+    let gateway_result: anyhow::Result<(ServerHandle, CdkGateway)> = runtime.block_on(async {
         tracing::info!("Initializing application components");
-        // Extract settings for each component
+        // The gateway keeps the full settings, extract settings for each other component
+        let gateway_settings = settings.clone();
         let grpc_settings = settings.grpc_processor;
+        let fallback_settings = settings.fallback_processors;
         let wallet_settings = settings.wallet;
         let server_settings = settings.server;
-        
-        // Verify that a mnemonic seed is provided
-        if wallet_settings.mnemonic_seed.is_empty() {
-            return Err(anyhow::anyhow!(
-                "Error: No mnemonic seed provided in configuration. Please add a mnemonic_seed to your config.toml file."
-            ));
+        let database_settings = settings.database;
+
+        // Verify that a mnemonic seed is provided, either inline or from a file
+        let mnemonic_seed = wallet_settings.mnemonic()?;
+
+        // Initialize the payment processors, the fallbacks are used in order while the primary is down
+        let wait_for_processor = grpc_settings.wait_for_processor;
+        let mut backends = vec![];
+        let mut probe_error = None;
+        let mut any_reachable = false;
+        // Cancelled by the gateway when its server stops, ending the processor pings
+        let server_cancel = CancellationToken::new();
+        for processor in std::iter::once(grpc_settings).chain(fallback_settings) {
+            let name = format!("{}:{}", processor.addr, processor.port);
+            tracing::info!("Connecting to payment processor at {}", name);
+            let health = Arc::new(NodeHealth::new());
+            let node =
+                ReconnectingNode::connect(processor, health.clone(), server_cancel.child_token())
+                    .await;
+            match node.probe().await {
+                Ok(()) => {
+                    tracing::info!("Payment processor connection established to {}", name);
+                    any_reachable = true;
+                }
+                Err(e) => {
+                    tracing::warn!("{}", e);
+                    probe_error.get_or_insert(e);
+                }
+            }
+            backends.push(FailoverBackend { name, node, health });
+        }
+        match probe_error {
+            Some(e) if !any_reachable && wait_for_processor => {
+                return Err(e.context(
+                    "Could not reach the payment processor, check grpc_processor.addr and port or set grpc_processor.wait_for_processor = false",
+                ));
+            }
+            Some(_) if !any_reachable => {
+                tracing::warn!("Continuing without a payment processor, payments will fail until one is reachable");
+            }
+            _ => {}
         }
 
-        // Initialize the payment processor
-        tracing::info!("Connecting to payment processor at {}:{}", grpc_settings.addr, grpc_settings.port);
-        let payment_processor = cdk_payment_processor::PaymentProcessorClient::new(
-            &grpc_settings.addr,
-            grpc_settings.port,
-            grpc_settings.tls_dir,
-        )
-        .await?;
-        tracing::info!("Payment processor connection established");
+        let (payment_processor, node_health): (
+            Arc<dyn MintPayment<Err = cdk_payment::Error> + Send + Sync>,
+            Arc<NodeHealth>,
+        ) = if backends.len() == 1 {
+            let backend = backends.remove(0);
+            (backend.node, backend.health)
+        } else {
+            tracing::info!("Failing over between {} payment processors", backends.len());
+            let node = FailoverNode::new(backends)?;
+            let health = Arc::new(node.health());
+            (Arc::new(node), health)
+        };
 
         // Make sure the work directory exists
         if !work_dir.exists() {
@@ -69,49 +245,43 @@ fn main() -> anyhow::Result<()> {
 
         // Parse the mnemonic
         tracing::debug!("Initializing wallet from mnemonic seed");
-        let mnemonic = bip39::Mnemonic::from_str(&wallet_settings.mnemonic_seed)?;
+        let mnemonic = bip39::Mnemonic::from_str(&mnemonic_seed)?;
 
         // Set up the database in the work directory
-        let redb_path = work_dir.join("cdk-gateway.redb");
-        tracing::info!("Opening database at {:?}", redb_path);
-        let localstore = Arc::new(WalletRedbDatabase::new(&redb_path)?);
+        let localstore = open_localstore(&database_settings, &work_dir).await?;
 
         let mut wallets = vec![];
 
         let seed = mnemonic.to_seed_normalized("");
+        let seed = match wallet_settings.derivation_account {
+            Some(account) => {
+                tracing::info!("Deriving wallet seed for account {}", account);
+                derive_account_seed(&seed, account)?
+            }
+            None => seed,
+        };
         tracing::info!("Initializing wallets for {} mint URLs", wallet_settings.mint_urls.len());
 
-        for mint_url in wallet_settings.mint_urls.iter() {
-            tracing::info!("Setting up wallet for mint: {}", mint_url);
+        for entry in wallet_settings.mint_urls.iter() {
+            tracing::info!("Setting up wallet for mint: {}", entry.url());
             let builder = WalletBuilder::new()
-                .mint_url(MintUrl::from_str(mint_url)?)
+                .mint_url(MintUrl::from_str(entry.url())?)
                 .unit(cdk::nuts::CurrencyUnit::Sat)
                 .localstore(localstore.clone())
                 .seed(&seed);
 
             let wallet = builder.build()?;
 
-            let wallet_clone = wallet.clone();
-
-            tokio::spawn(async move {
-                tracing::debug!("Fetching mint info for {}", wallet_clone.mint_url);
-                if let Err(err) = wallet_clone.get_mint_info().await {
-                    tracing::error!(
-                        "Could not get mint quote for {}, {}",
-                        wallet_clone.mint_url,
-                        err
-                    );
-                } else {
-                    tracing::debug!("Successfully retrieved mint info for {}", wallet_clone.mint_url);
-                }
-            });
-
             wallets.push(wallet);
         }
 
         let multi_mint_wallet = MultiMintWallet::new(localstore, Arc::new(seed), wallets);
         tracing::info!("Multi-mint wallet initialized");
 
+        for mint_url in gateway_settings.payment.dleq_trusted_mints.iter() {
+            tracing::info!("DLEQ verification disabled for trusted mint: {}", mint_url);
+        }
+
         // Log the wallet balances at startup
         tracing::info!("Logging wallet balances at startup");
         for wallet_key in multi_mint_wallet.get_wallets().await.iter().map(|w| cdk::wallet::types::WalletKey::new(w.mint_url.clone(), w.unit.clone())) {
@@ -133,7 +303,23 @@ fn main() -> anyhow::Result<()> {
         }
 
         // Start the gateway server with all components
-        let gateway = CdkGateway::new(Arc::new(payment_processor), multi_mint_wallet);
+        let payment_store = match database_settings.backend {
+            DatabaseBackend::Memory => Arc::new(PaymentStore::in_memory()?),
+            DatabaseBackend::Redb | DatabaseBackend::Sqlite => {
+                let payments_path = payments_path(&database_settings, &work_dir);
+                tracing::info!("Opening payment history at {:?}", payments_path);
+                Arc::new(PaymentStore::new(&payments_path)?)
+            }
+        };
+
+        let gateway = CdkGateway::new(
+            payment_processor,
+            node_health,
+            multi_mint_wallet,
+            payment_store,
+            gateway_settings,
+        )
+        .with_server_cancel(server_cancel);
 
         // Create socket address from server settings
         let socket_addr = std::net::SocketAddr::new(
@@ -143,22 +329,20 @@ fn main() -> anyhow::Result<()> {
 
         tracing::info!("Starting server on {}", socket_addr);
 
-        // Create a new gateway instance to return
-        let gateway_clone = gateway.clone();
-
-        // Start the server in a separate task
-        tokio::spawn(async move {
-            if let Err(e) = gateway.start_server(socket_addr, wallet_settings.mint_urls.clone().iter().flat_map(|s|MintUrl::from_str(s)).collect()).await {
-                tracing::error!("Server error: {}", e);
-            }
-        });
+        // Start the server in a background task
+        let mints = wallet_settings
+            .mint_urls
+            .iter()
+            .flat_map(|entry| MintUrl::from_str(entry.url()))
+            .collect();
+        let server = gateway.start_server(socket_addr, mints).await?;
 
-        Ok(gateway_clone)
+        Ok((server, gateway))
     });
 
     // Handle the result of gateway initialization
-    let gateway = match gateway_result {
-        Ok(gateway) => gateway,
+    let (server, gateway) = match gateway_result {
+        Ok(result) => result,
         Err(e) => {
             tracing::error!("Failed to initialize gateway: {}", e);
             return Err(e);
@@ -166,44 +350,445 @@ fn main() -> anyhow::Result<()> {
     };
 
     // Set up signal handling for graceful shutdown
-    let gateway_for_shutdown = gateway.clone();
-    let runtime_for_shutdown = runtime.clone();
-    
+    // The handle is shared by every signal handler, whichever fires first takes it
+    let server = Arc::new(Mutex::new(Some(server)));
+
     // Create a channel to signal when shutdown is complete
     let (shutdown_tx, shutdown_rx) = std::sync::mpsc::channel();
 
-    // Common shutdown function
-    let create_shutdown_handler = |tx: std::sync::mpsc::Sender<()>, gw: CdkGateway, rt: Arc<tokio::runtime::Runtime>| {
-        move || {
-            tracing::info!("Received shutdown signal, shutting down...");
-            let gateway = gw.clone();
-            let runtime = rt.clone();
-            let shutdown_tx = tx.clone();
-            
-            // Shutdown the gateway
-            runtime.block_on(async {
-                if let Err(e) = gateway.stop_server().await {
-                    tracing::error!("Error during shutdown: {}", e);
-                }
-                // Signal that shutdown is complete
-                let _ = shutdown_tx.send(());
-            });
-        }
-    };
-
     // Set up SIGINT (Ctrl+C) handler
-    let sigint_handler = create_shutdown_handler(
-        shutdown_tx.clone(),
-        gateway_for_shutdown.clone(),
-        runtime_for_shutdown.clone()
-    );
-    ctrlc::set_handler(sigint_handler).expect("Error setting Ctrl-C handler");
+    let sigint_server = server.clone();
+    let sigint_tx = shutdown_tx.clone();
+    let sigint_runtime = runtime.clone();
+    ctrlc::set_handler(move || {
+        sigint_runtime.block_on(shutdown_gateway(&sigint_server, &sigint_tx));
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    // Set up SIGTERM handler, which is what systemd and Docker send on stop
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let sigterm_server = server.clone();
+        let sigterm_tx = shutdown_tx.clone();
+        let mut sigterm = runtime.block_on(async { signal(SignalKind::terminate()) })?;
+        runtime.spawn(async move {
+            sigterm.recv().await;
+            shutdown_gateway(&sigterm_server, &sigterm_tx).await;
+        });
+
+        // Reload the settings that can change at runtime on SIGHUP
+        let mut sighup = runtime.block_on(async { signal(SignalKind::hangup()) })?;
+        runtime.spawn(async move {
+            while sighup.recv().await.is_some() {
+                reload_settings(&gateway, &cli, &work_dir, &filter_handle);
+            }
+        });
+    }
 
     tracing::info!("CDK Gateway running. Press Ctrl+C to stop.");
 
     // Wait for shutdown signal
     let _ = shutdown_rx.recv();
     tracing::info!("CDK Gateway shutdown complete");
-    
+
+    Ok(())
+}
+
+/// Passed and failed steps of `--check`
+#[derive(Debug, Default)]
+struct CheckReport {
+    checks: usize,
+    failures: usize,
+}
+
+impl CheckReport {
+    /// Print the outcome of a step
+    fn record<E: std::fmt::Display>(&mut self, name: &str, result: Result<(), E>) {
+        self.checks += 1;
+        match result {
+            Ok(()) => println!("[PASS] {}", name),
+            Err(e) => {
+                self.failures += 1;
+                println!("[FAIL] {}: {}", name, e);
+            }
+        }
+    }
+}
+
+/// Validate the settings and check every payment processor and mint can be
+/// reached, without starting the server or touching the work directory
+async fn run_check(settings: &Settings) -> anyhow::Result<()> {
+    let mut report = CheckReport::default();
+    report.record("configuration", settings.validate());
+
+    let processors = std::iter::once(settings.grpc_processor.clone())
+        .chain(settings.fallback_processors.iter().cloned());
+    for processor in processors {
+        let name = format!("payment processor {}:{}", processor.addr, processor.port);
+        let probed = async {
+            let cancel = CancellationToken::new();
+            let node =
+                ReconnectingNode::connect(processor, Arc::new(NodeHealth::new()), cancel.clone())
+                    .await;
+            let _stop_pinging = cancel.drop_guard();
+            node.probe().await
+        }
+        .await;
+        report.record(&name, probed);
+    }
+
+    // Only the mints' info is fetched, so the wallets need no real seed or storage
+    let localstore: Arc<dyn WalletDatabase<Err = cdk_database::Error> + Send + Sync> =
+        Arc::new(cdk_sqlite::wallet::memory::empty().await?);
+    for entry in settings.wallet.mint_urls.iter() {
+        let name = format!("mint {}", entry.url());
+        let fetched = async {
+            let wallet = WalletBuilder::new()
+                .mint_url(MintUrl::from_str(entry.url())?)
+                .unit(cdk::nuts::CurrencyUnit::Sat)
+                .localstore(localstore.clone())
+                .seed(&[0u8; 64])
+                .build()?;
+            match wallet.get_mint_info().await? {
+                Some(_) => Ok(()),
+                None => Err(anyhow::anyhow!("the mint returned no info")),
+            }
+        }
+        .await;
+        report.record(&name, fetched);
+    }
+
+    println!(
+        "{} of {} checks passed",
+        report.checks - report.failures,
+        report.checks
+    );
+    if report.failures > 0 {
+        anyhow::bail!("{} of {} checks failed", report.failures, report.checks);
+    }
+
     Ok(())
 }
+
+/// Purpose used when deriving account seeds, taken from the NUT-13 derivation path
+const ACCOUNT_SEED_PURPOSE: u32 = 129372;
+
+/// Derive a wallet seed for `account` from the mnemonic seed
+///
+/// The seed is the private key and chain code at `m/129372'/<account>'`, so each
+/// account gets its own independent set of deterministic secrets.
+fn derive_account_seed(seed: &[u8; 64], account: u32) -> anyhow::Result<[u8; 64]> {
+    let secp = Secp256k1::new();
+    let path = [
+        ChildNumber::from_hardened_idx(ACCOUNT_SEED_PURPOSE)?,
+        ChildNumber::from_hardened_idx(account)?,
+    ];
+    let xpriv = Xpriv::new_master(NetworkKind::Main, seed)?.derive_priv(&secp, &path)?;
+
+    let mut account_seed = [0u8; 64];
+    account_seed[..32].copy_from_slice(&xpriv.private_key.secret_bytes());
+    account_seed[32..].copy_from_slice(xpriv.chain_code.as_bytes());
+
+    Ok(account_seed)
+}
+
+/// Payment history file, kept beside the wallet database
+///
+/// With a `db_path` the history is named after the database file, so
+/// instances sharing a data directory each keep their own.
+fn payments_path(config: &DatabaseConfig, work_dir: &Path) -> PathBuf {
+    match &config.db_path {
+        Some(db_path) => db_path.with_extension("payments.redb"),
+        None => work_dir.join("payments.redb"),
+    }
+}
+
+/// Open the wallet database for the configured backend
+///
+/// The database is opened at `db_path` when configured, otherwise in the work directory.
+async fn open_localstore(
+    config: &DatabaseConfig,
+    work_dir: &Path,
+) -> anyhow::Result<Arc<dyn WalletDatabase<Err = cdk_database::Error> + Send + Sync>> {
+    let localstore: Arc<dyn WalletDatabase<Err = cdk_database::Error> + Send + Sync> =
+        match config.backend {
+            DatabaseBackend::Redb => {
+                let redb_path = config
+                    .db_path
+                    .clone()
+                    .unwrap_or_else(|| work_dir.join("cdk-gateway.redb"));
+                tracing::info!("Opening redb database at {:?}", redb_path);
+                Arc::new(WalletRedbDatabase::new(&redb_path)?)
+            }
+            DatabaseBackend::Sqlite => {
+                let sqlite_path = config
+                    .db_path
+                    .clone()
+                    .unwrap_or_else(|| work_dir.join("cdk-gateway.sqlite"));
+                tracing::info!("Opening sqlite database at {:?}", sqlite_path);
+                let sqlite = WalletSqliteDatabase::new(&sqlite_path).await?;
+                sqlite.migrate().await;
+                Arc::new(sqlite)
+            }
+            DatabaseBackend::Memory => {
+                tracing::warn!("Using in-memory database, wallet proofs will be lost on restart");
+                Arc::new(cdk_sqlite::wallet::memory::empty().await?)
+            }
+        };
+
+    Ok(localstore)
+}
+
+/// Stop the gateway server and signal that shutdown is complete
+///
+/// Only the first call stops the server, later signals are ignored.
+async fn shutdown_gateway(
+    server: &Mutex<Option<ServerHandle>>,
+    shutdown_tx: &std::sync::mpsc::Sender<()>,
+) {
+    let server = server.lock().expect("shutdown lock poisoned").take();
+    let Some(server) = server else {
+        return;
+    };
+
+    tracing::info!("Received shutdown signal, shutting down...");
+    if let Err(e) = server.stop().await {
+        tracing::error!("Error during shutdown: {}", e);
+    }
+
+    // Signal that shutdown is complete
+    let _ = shutdown_tx.send(());
+}
+
+#[cfg(test)]
+mod tests {
+    use cdk_gateway::config::GrpcProcessor;
+    use cdk_gateway::testing::{
+        LogBuffer, MockMint, MockNode, TestGateway, TestInvoice, melt_request,
+    };
+    use uuid::Uuid;
+
+    use super::*;
+
+    /// Held by tests that parse the command line, which also reads the environment
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// A new empty directory for a test to work in
+    fn temp_work_dir() -> PathBuf {
+        let work_dir = std::env::temp_dir().join(format!("cdk-gateway-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&work_dir).unwrap();
+        work_dir
+    }
+
+    #[tokio::test]
+    async fn pays_with_the_in_memory_backend() {
+        let work_dir = temp_work_dir();
+        let config = DatabaseConfig {
+            backend: DatabaseBackend::Memory,
+            db_path: None,
+        };
+        let localstore = open_localstore(&config, &work_dir).await.unwrap();
+
+        let mint = MockMint::start().await.unwrap();
+        let invoice = TestInvoice::new(Some(100));
+        let gateway = TestGateway::start_with_localstore(
+            MockNode::succeeding(&invoice.preimage),
+            TestGateway::settings(&[&mint]),
+            localstore,
+        )
+        .await
+        .unwrap();
+
+        let token = mint.htlc_token(invoice.payment_hash(), 110);
+        let response = gateway
+            .post("/payment", &melt_request(&invoice, &[token]))
+            .await;
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        gateway.stop().await.unwrap();
+
+        // Nothing is written to the work directory
+        assert_eq!(std::fs::read_dir(&work_dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(work_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn opens_the_database_at_the_configured_path() {
+        let work_dir = temp_work_dir();
+        let db_dir = temp_work_dir();
+
+        for (backend, file_name, default_name) in [
+            (DatabaseBackend::Redb, "wallet.redb", "cdk-gateway.redb"),
+            (
+                DatabaseBackend::Sqlite,
+                "wallet.sqlite",
+                "cdk-gateway.sqlite",
+            ),
+        ] {
+            let config = DatabaseConfig {
+                backend,
+                db_path: Some(db_dir.join(file_name)),
+            };
+            open_localstore(&config, &work_dir).await.unwrap();
+
+            assert!(db_dir.join(file_name).exists());
+            assert!(!work_dir.join(default_name).exists());
+        }
+
+        std::fs::remove_dir_all(work_dir).unwrap();
+        std::fs::remove_dir_all(db_dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_payment_history_beside_the_database() {
+        let work_dir = PathBuf::from("/srv/gateway");
+        let mut config = DatabaseConfig::default();
+        assert_eq!(
+            payments_path(&config, &work_dir),
+            work_dir.join("payments.redb")
+        );
+
+        config.db_path = Some(PathBuf::from("/var/lib/cdk-gateway/wallet.sqlite"));
+        assert_eq!(
+            payments_path(&config, &work_dir),
+            PathBuf::from("/var/lib/cdk-gateway/wallet.payments.redb")
+        );
+    }
+
+    #[test]
+    fn accounts_derive_distinct_seeds() {
+        let seed = bip39::Mnemonic::from_str(cdk_gateway::testing::TEST_MNEMONIC)
+            .unwrap()
+            .to_seed_normalized("");
+
+        let first = derive_account_seed(&seed, 0).unwrap();
+        let second = derive_account_seed(&seed, 1).unwrap();
+        assert_ne!(first, second);
+        assert_ne!(first, seed);
+        assert_eq!(derive_account_seed(&seed, 0).unwrap(), first);
+
+        // Wallets on each seed derive different keys for their secrets
+        let key = |seed: &[u8; 64]| {
+            Xpriv::new_master(NetworkKind::Main, seed)
+                .unwrap()
+                .private_key
+        };
+        assert_ne!(key(&first), key(&second));
+    }
+
+    #[test]
+    fn command_line_flags_override_settings() {
+        let _env = ENV_LOCK.lock().unwrap();
+        let cli = Cli::try_parse_from([
+            "cdk-gateway",
+            "--work-dir",
+            "/srv/gateway",
+            "--config",
+            "/etc/cdk-gateway.toml",
+            "--listen-addr",
+            "0.0.0.0",
+            "--port",
+            "8080",
+            "--log-level",
+            "debug",
+        ])
+        .unwrap();
+        assert_eq!(cli.work_dir, Some(PathBuf::from("/srv/gateway")));
+        assert_eq!(cli.config, Some(PathBuf::from("/etc/cdk-gateway.toml")));
+
+        let mut settings = Settings::default();
+        cli.apply(&mut settings);
+        assert_eq!(settings.server.listen_addr, "0.0.0.0");
+        assert_eq!(settings.server.port, 8080);
+        assert_eq!(settings.logging.level, "debug");
+
+        // Settings without a flag are left as loaded
+        let defaults = Settings::default();
+        let mut settings = Settings::default();
+        Cli::try_parse_from(["cdk-gateway", "--port", "8080"])
+            .unwrap()
+            .apply(&mut settings);
+        assert_eq!(settings.server.listen_addr, defaults.server.listen_addr);
+        assert_eq!(settings.logging.level, defaults.logging.level);
+    }
+
+    #[test]
+    fn reads_the_work_dir_from_the_environment() {
+        let work_dir = temp_work_dir();
+        std::fs::write(work_dir.join("config.toml"), "[server]\nport = 4242\n").unwrap();
+
+        let _env = ENV_LOCK.lock().unwrap();
+        // SAFETY: the other tests reading the variable hold ENV_LOCK too
+        unsafe { std::env::set_var("CDK_GATEWAY_WORK_DIR", &work_dir) };
+        let from_env = Cli::try_parse_from(["cdk-gateway"]).unwrap();
+        let from_flag = Cli::try_parse_from(["cdk-gateway", "--work-dir", "/srv/gateway"]).unwrap();
+        unsafe { std::env::remove_var("CDK_GATEWAY_WORK_DIR") };
+
+        assert_eq!(from_env.work_dir.as_ref(), Some(&work_dir));
+        assert_eq!(from_flag.work_dir, Some(PathBuf::from("/srv/gateway")));
+
+        let settings = Settings::with_work_dir(work_dir.to_str()).unwrap();
+        assert_eq!(settings.server.port, 4242);
+
+        std::fs::remove_dir_all(work_dir).unwrap();
+    }
+
+    #[test]
+    fn logs_in_the_configured_format() {
+        let log = |format| {
+            let output = LogBuffer::default();
+            let config = LoggingConfig {
+                format,
+                ..Default::default()
+            };
+            let subscriber = Registry::default().with(log_layer(&config, output.clone()));
+            tracing::subscriber::with_default(subscriber, || {
+                tracing::info!(amount = 100, "Payment complete")
+            });
+            output.contents()
+        };
+
+        let json = log(LogFormat::Json);
+        let line: serde_json::Value = serde_json::from_str(json.lines().next().unwrap()).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["fields"]["message"], "Payment complete");
+        assert_eq!(line["fields"]["amount"], 100);
+
+        let pretty = log(LogFormat::Pretty);
+        assert!(pretty.contains("Payment complete"));
+        assert!(serde_json::from_str::<serde_json::Value>(pretty.trim()).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn checks_the_processor_and_mints_without_starting_the_server() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let node = Arc::new(MockNode::failing("unused"));
+        let mut processor =
+            cdk_payment_processor::PaymentProcessorServer::new(node.clone(), "127.0.0.1", port)
+                .unwrap();
+        processor.start(None).await.unwrap();
+
+        let mint = MockMint::start().await.unwrap();
+        let mut settings = TestGateway::settings(&[&mint]);
+        settings.grpc_processor = GrpcProcessor {
+            addr: "http://127.0.0.1".to_string(),
+            port,
+            ..Default::default()
+        };
+        run_check(&settings).await.unwrap();
+        assert!(node.settings_requests() > 0);
+        assert!(mint.info_requests() > 0);
+        assert!(node.payments().is_empty());
+
+        // An unreachable mint fails the check
+        mint.stop();
+        assert!(run_check(&settings).await.is_err());
+
+        processor.stop().await.unwrap();
+    }
+}
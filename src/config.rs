@@ -1,13 +1,63 @@
+use cdk::mint_url::MintUrl;
+use cdk::nuts::SecretKey;
 use config::{Config, ConfigError, Environment, File};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use tracing;
 
+use crate::gateway_server::PaymentMethod;
+
+/// Environment variable the mnemonic seed can be given in instead of a file
+pub const MNEMONIC_ENV_VAR: &str = "CDK_GATEWAY__WALLET__MNEMONIC_SEED";
+
+/// Warn when `path` can be read by any user, since it holds the wallet's secrets
+#[cfg(unix)]
+fn warn_if_world_readable(path: &Path, description: &str) {
+    use std::os::unix::fs::PermissionsExt;
+
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.permissions().mode() & 0o004 != 0 => {
+            tracing::warn!(
+                "The {} {:?} is readable by every user, restrict it with `chmod 600` or set {} instead",
+                description,
+                path,
+                MNEMONIC_ENV_VAR
+            );
+        }
+        Ok(_) => {}
+        Err(e) => tracing::debug!("Could not check permissions of {:?}: {}", path, e),
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_if_world_readable(_path: &Path, _description: &str) {}
+
+/// Override file read right after the config file at `path`, so secrets and
+/// per-environment values can be kept apart from the shared config
+///
+/// `config.toml` is overridden by `config.local.toml` next to it.
+pub fn local_override_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("toml");
+    path.with_file_name(format!("{}.local.{}", stem, extension))
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
 pub struct GrpcProcessor {
     pub addr: String,
     pub port: u16,
     pub tls_dir: Option<PathBuf>,
+    /// Abort startup when the processor can't be reached, instead of only warning
+    pub wait_for_processor: bool,
+    /// How often an idle connection is pinged to keep it open, 0 to disable
+    pub ping_interval_secs: u64,
 }
 
 impl Default for GrpcProcessor {
@@ -16,29 +66,146 @@ impl Default for GrpcProcessor {
             addr: "127.0.0.1".to_string(),
             port: 50051,
             tls_dir: None,
+            wait_for_processor: true,
+            ping_interval_secs: 30,
+        }
+    }
+}
+
+/// A supported mint, given either as a plain URL or with payment limits
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum MintEntry {
+    Url(String),
+    WithLimits {
+        url: String,
+        /// Smallest amount of ecash accepted from this mint in one payment
+        #[serde(default)]
+        min_sat: Option<u64>,
+        /// Largest amount of ecash accepted from this mint in one payment
+        #[serde(default)]
+        max_sat: Option<u64>,
+    },
+}
+
+impl MintEntry {
+    /// URL of the mint
+    pub fn url(&self) -> &str {
+        match self {
+            Self::Url(url) | Self::WithLimits { url, .. } => url,
+        }
+    }
+
+    /// Smallest amount accepted from this mint in one payment, if limited
+    pub fn min_sat(&self) -> Option<u64> {
+        match self {
+            Self::Url(_) => None,
+            Self::WithLimits { min_sat, .. } => *min_sat,
         }
     }
+
+    /// Largest amount accepted from this mint in one payment, if limited
+    pub fn max_sat(&self) -> Option<u64> {
+        match self {
+            Self::Url(_) => None,
+            Self::WithLimits { max_sat, .. } => *max_sat,
+        }
+    }
+}
+
+impl From<&str> for MintEntry {
+    fn from(url: &str) -> Self {
+        Self::Url(url.to_string())
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct WalletConfig {
     pub mnemonic_seed: String,
-    pub mint_urls: Vec<String>,
+    /// File containing the mnemonic seed, used instead of `mnemonic_seed`
+    #[serde(default)]
+    pub mnemonic_file: Option<PathBuf>,
+    pub mint_urls: Vec<MintEntry>,
+    /// Account to derive the wallet seed from, isolating funds from other wallets on the same mnemonic
+    #[serde(default)]
+    pub derivation_account: Option<u32>,
+}
+
+impl WalletConfig {
+    /// Configuration of `mint_url`, if it is one of the configured mints
+    pub fn mint_entry(&self, mint_url: &MintUrl) -> Option<&MintEntry> {
+        self.mint_urls
+            .iter()
+            .find(|entry| MintUrl::from_str(entry.url()).is_ok_and(|url| &url == mint_url))
+    }
+
+    /// Mnemonic seed phrase from either the inline value or `mnemonic_file`
+    ///
+    /// Exactly one of the two must be set.
+    pub fn mnemonic(&self) -> Result<String, ConfigError> {
+        match (&self.mnemonic_file, self.mnemonic_seed.is_empty()) {
+            (Some(_), false) => Err(ConfigError::Message(
+                "Both mnemonic_seed and mnemonic_file are set, please configure only one"
+                    .to_string(),
+            )),
+            (Some(path), true) => {
+                let mnemonic = std::fs::read_to_string(path).map_err(|e| {
+                    ConfigError::Message(format!(
+                        "Could not read mnemonic file {:?}: {}",
+                        path, e
+                    ))
+                })?;
+                let mnemonic = mnemonic.trim();
+                if mnemonic.is_empty() {
+                    return Err(ConfigError::Message(format!(
+                        "Mnemonic file {:?} is empty",
+                        path
+                    )));
+                }
+                Ok(mnemonic.to_string())
+            }
+            (None, false) => Ok(self.mnemonic_seed.clone()),
+            (None, true) => Err(ConfigError::Message(
+                "No mnemonic seed provided in configuration. Please add a mnemonic_seed or mnemonic_file to your config.toml file.".to_string(),
+            )),
+        }
+    }
 }
 
 impl Default for WalletConfig {
     fn default() -> Self {
         Self {
             mnemonic_seed: String::new(),
-            mint_urls: vec!["https://mint.example.com".to_string()],
+            mnemonic_file: None,
+            mint_urls: vec![MintEntry::from("https://mint.example.com")],
+            derivation_account: None,
         }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
 pub struct ServerConfig {
     pub listen_addr: String,
     pub port: u16,
+    pub shutdown_drain_timeout_secs: u64,
+    /// How long to wait for the server to stop once payments are drained
+    pub shutdown_timeout_secs: u64,
+    pub request_timeout_secs: u64,
+    pub max_body_bytes: usize,
+    /// Include internal error details in responses, they are always logged
+    pub expose_error_details: bool,
+    /// Log each request's method, path, status and latency, with redacted bodies at trace level
+    pub log_requests: bool,
+    /// Path all routes are served under, for path-based reverse proxies
+    pub base_path: Option<String>,
+    pub tls: Option<TlsConfig>,
 }
 
 impl Default for ServerConfig {
@@ -46,6 +213,335 @@ impl Default for ServerConfig {
         Self {
             listen_addr: "127.0.0.1".to_string(),
             port: 3000,
+            shutdown_drain_timeout_secs: 30,
+            shutdown_timeout_secs: 10,
+            request_timeout_secs: 120,
+            max_body_bytes: 1024 * 1024,
+            expose_error_details: false,
+            log_requests: false,
+            base_path: None,
+            tls: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct PaymentConfig {
+    pub include_breakdown: bool,
+    pub dleq_trusted_mints: Vec<String>,
+    /// Only accept tokens from these mints when set, whatever wallets exist
+    pub mint_allowlist: Vec<String>,
+    /// Never accept tokens from these mints, even when a wallet exists
+    pub mint_denylist: Vec<String>,
+    /// Check with the mint that the tokens are unspent before paying
+    pub check_proofs_before_payment: bool,
+    /// How long proofs the mint reports as pending are waited on
+    pub proof_check_timeout_secs: u64,
+    pub max_retries: u32,
+    pub backoff_ms: u64,
+    pub max_tokens_per_request: usize,
+    pub max_proofs_per_request: usize,
+    /// Most payments accepted in one `/payment/batch` request
+    pub max_batch_payments: usize,
+    pub minimize_change_proofs: bool,
+    pub accepted_token_versions: Vec<TokenVersion>,
+    pub accepted_methods: Vec<PaymentMethod>,
+    /// How long the node may spend attempting a payment, left to the node when unset
+    pub payment_timeout_secs: Option<u64>,
+    /// Retry-After sent when the processor rate limits a payment without saying for how long
+    pub processor_retry_after_secs: u64,
+    pub max_concurrent_payments: usize,
+    /// Keep change in the gateway wallet instead of returning it, for deposit models
+    pub retain_change: bool,
+    /// Hex secret key that tokens may be P2PK locked to instead of the payment hash
+    pub p2pk_secret_key: Option<String>,
+    /// Routing fee quoted, in parts per million, when the node can't estimate it
+    pub fee_reserve_ppm: u64,
+    /// Smallest routing fee quoted when the node can't estimate it
+    pub fee_reserve_min_sat: u64,
+    /// How long the terms of a quote are reserved for payments referencing it
+    pub quote_ttl_secs: u64,
+    /// How long the response to an idempotency key is replayed for
+    pub idempotency_ttl_secs: u64,
+    /// Highest `max_fee_sat` a payment request can set, higher ones are clamped to it
+    pub max_fee_ceiling_sat: Option<u64>,
+    /// Hard cap on the amount paid by a single request, whatever else allows
+    pub max_single_payment_sat: Option<u64>,
+    /// Only accept HTLC tokens with a locktime, so senders can always reclaim unspent tokens
+    pub require_locktime: bool,
+    /// How far away a token's locktime must be when the request is verified
+    pub min_locktime_secs: u64,
+    /// How far away a token's locktime may be at most, unlimited when unset
+    pub max_locktime_secs: Option<u64>,
+    /// How far away a token's locktime must still be when the tokens are claimed
+    pub claim_locktime_margin_secs: u64,
+}
+
+impl PaymentConfig {
+    /// Key the gateway accepts P2PK-locked tokens for, if one is configured
+    pub fn p2pk_key(&self) -> Result<Option<SecretKey>, ConfigError> {
+        self.p2pk_secret_key
+            .as_deref()
+            .map(|key| {
+                SecretKey::from_hex(key.trim()).map_err(|e| {
+                    ConfigError::Message(format!("invalid payment.p2pk_secret_key: {}", e))
+                })
+            })
+            .transpose()
+    }
+
+    /// Routing fee reserved for paying `amount_sat` when the node can't estimate it
+    pub fn fee_reserve(&self, amount_sat: u64) -> u64 {
+        let proportional = (amount_sat as u128 * self.fee_reserve_ppm as u128).div_ceil(1_000_000);
+        (proportional as u64).max(self.fee_reserve_min_sat)
+    }
+
+    /// Whether DLEQ verification is skipped for tokens from `mint_url`
+    pub fn is_dleq_trusted(&self, mint_url: &MintUrl) -> bool {
+        self.dleq_trusted_mints
+            .iter()
+            .any(|trusted| MintUrl::from_str(trusted).is_ok_and(|trusted| &trusted == mint_url))
+    }
+
+    /// Whether tokens from `mint_url` are accepted under the mint allow and deny lists
+    pub fn is_mint_accepted(&self, mint_url: &MintUrl) -> bool {
+        let listed = |list: &[String]| {
+            list.iter()
+                .any(|listed| MintUrl::from_str(listed).is_ok_and(|listed| &listed == mint_url))
+        };
+
+        (self.mint_allowlist.is_empty() || listed(&self.mint_allowlist))
+            && !listed(&self.mint_denylist)
+    }
+}
+
+impl Default for PaymentConfig {
+    fn default() -> Self {
+        Self {
+            include_breakdown: false,
+            dleq_trusted_mints: Vec::new(),
+            mint_allowlist: Vec::new(),
+            mint_denylist: Vec::new(),
+            check_proofs_before_payment: false,
+            proof_check_timeout_secs: 30,
+            max_retries: 0,
+            backoff_ms: 500,
+            max_tokens_per_request: 50,
+            max_proofs_per_request: 1000,
+            max_batch_payments: 20,
+            minimize_change_proofs: false,
+            accepted_token_versions: vec![TokenVersion::V3, TokenVersion::V4],
+            accepted_methods: vec![
+                PaymentMethod::Bolt11,
+                PaymentMethod::LnAddress,
+                PaymentMethod::Lnurl,
+            ],
+            payment_timeout_secs: None,
+            processor_retry_after_secs: 5,
+            max_concurrent_payments: 100,
+            retain_change: false,
+            p2pk_secret_key: None,
+            fee_reserve_ppm: 10_000,
+            fee_reserve_min_sat: 2,
+            quote_ttl_secs: 60,
+            idempotency_ttl_secs: 24 * 60 * 60,
+            max_fee_ceiling_sat: None,
+            max_single_payment_sat: None,
+            require_locktime: false,
+            min_locktime_secs: 900,
+            max_locktime_secs: None,
+            claim_locktime_margin_secs: 60,
+        }
+    }
+}
+
+/// Encoding of a cashu token
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenVersion {
+    /// JSON encoded `cashuA` tokens
+    V3,
+    /// CBOR encoded `cashuB` tokens
+    V4,
+}
+
+impl TokenVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenVersion::V3 => "v3",
+            TokenVersion::V4 => "v4",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub mint_labels: bool,
+    pub destination_labels: bool,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            mint_labels: true,
+            destination_labels: false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub api_keys: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RateLimitConfig {
+    pub requests_per_minute: u32,
+    pub burst: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            allowed_headers: vec![
+                "content-type".to_string(),
+                "authorization".to_string(),
+                "x-api-key".to_string(),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseBackend {
+    #[default]
+    Redb,
+    Sqlite,
+    /// Nothing is persisted, wallet proofs are lost on restart
+    Memory,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    pub backend: DatabaseBackend,
+    /// Wallet database file, defaults to a file in the work directory
+    pub db_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct LnurlConfig {
+    /// Allow the gateway to contact recipients' servers to resolve payment destinations
+    pub enabled: bool,
+    pub request_timeout_secs: u64,
+}
+
+impl Default for LnurlConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            request_timeout_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct WebhookConfig {
+    /// URL payment events are posted to
+    pub url: String,
+    /// Shared secret events are signed with using HMAC-SHA256
+    pub secret: Option<String>,
+    pub max_retries: u32,
+    pub timeout_secs: u64,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            secret: None,
+            max_retries: 5,
+            timeout_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct RatesConfig {
+    /// Fixed sats per unit of each fiat unit, e.g. `usd` for cents
+    pub sat_per_unit: HashMap<String, f64>,
+    /// URL returning a JSON object of sats per unit, overriding the fixed rates
+    pub provider_url: Option<String>,
+    pub refresh_secs: u64,
+    pub request_timeout_secs: u64,
+}
+
+impl Default for RatesConfig {
+    fn default() -> Self {
+        Self {
+            sat_per_unit: HashMap::new(),
+            provider_url: None,
+            refresh_secs: 300,
+            request_timeout_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct MintInfoConfig {
+    /// How long fetched mint info is served before it is fetched again
+    pub ttl_secs: u64,
+}
+
+impl Default for MintInfoConfig {
+    fn default() -> Self {
+        Self { ttl_secs: 60 * 60 }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Pretty,
+    /// One JSON object per line, for log aggregators
+    Json,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    /// Log level for the gateway, used when `RUST_LOG` is not set
+    pub level: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Pretty,
+            level: "info".to_string(),
         }
     }
 }
@@ -53,8 +549,33 @@ impl Default for ServerConfig {
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Settings {
     pub grpc_processor: GrpcProcessor,
+    /// Processors used in order while `grpc_processor` is down
+    #[serde(default)]
+    pub fallback_processors: Vec<GrpcProcessor>,
     pub wallet: WalletConfig,
     pub server: ServerConfig,
+    #[serde(default)]
+    pub payment: PaymentConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+    #[serde(default)]
+    pub cors: CorsConfig,
+    #[serde(default)]
+    pub database: DatabaseConfig,
+    #[serde(default)]
+    pub lnurl: LnurlConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub mint_info: MintInfoConfig,
+    #[serde(default)]
+    pub webhooks: Option<WebhookConfig>,
+    #[serde(default)]
+    pub rates: RatesConfig,
 }
 
 impl Settings {
@@ -63,13 +584,18 @@ impl Settings {
     }
 
     pub fn with_work_dir(work_dir: Option<&str>) -> Result<Self, ConfigError> {
+        Self::load(work_dir, None)
+    }
+
+    /// Load settings, reading `config_file` instead of looking for a config file
+    /// via `CDK_GATEWAY_CONFIG` when it is given
+    pub fn load(work_dir: Option<&str>, config_file: Option<&Path>) -> Result<Self, ConfigError> {
         // Start with default settings
         let mut s = Config::builder()
             // Start with default values
-            .add_source(Config::try_from(&Self::default())?)
-            // Add in the current environment
-            // Prefix can be empty, or set to something like "CDK_GATEWAY"
-            .add_source(Environment::with_prefix("CDK_GATEWAY").separator("__"));
+            .add_source(Config::try_from(&Self::default())?);
+        // Config files that may have been read, to check their permissions
+        let mut config_paths = Vec::new();
 
         // If work_dir is provided, look for config file there
         if let Some(dir) = work_dir {
@@ -78,28 +604,86 @@ impl Settings {
             if config_path.exists() {
                 tracing::info!("Found config file at: {:?}", config_path);
             }
-            s = s.add_source(File::from(config_path).required(false));
+            config_paths.push(config_path.clone());
+            s = s.add_source(File::from(config_path.clone()).required(false));
+
+            let override_path = local_override_path(&config_path);
+            if override_path.exists() {
+                tracing::info!("Found config override file at: {:?}", override_path);
+            }
+            config_paths.push(override_path.clone());
+            s = s.add_source(File::from(override_path).required(false));
         } else {
             // Otherwise look in the current directory
             tracing::debug!("Looking for config.toml in current directory");
+            config_paths.push(PathBuf::from("config.toml"));
             s = s.add_source(File::with_name("config").required(false));
+            config_paths.push(PathBuf::from("config.local.toml"));
+            s = s.add_source(File::with_name("config.local").required(false));
         }
 
-        // You can also specify a different config file path with an environment variable
-        if let Ok(config_path) = std::env::var("CDK_GATEWAY_CONFIG") {
-            tracing::info!("Using config file specified by CDK_GATEWAY_CONFIG: {}", config_path);
-            s = s.add_source(File::with_name(&config_path).required(true));
+        // You can also specify a different config file path as an argument or with an environment variable
+        let config_file = match config_file {
+            Some(config_path) => {
+                tracing::info!("Using config file: {:?}", config_path);
+                Some(config_path.to_path_buf())
+            }
+            None => std::env::var("CDK_GATEWAY_CONFIG").ok().map(|config_path| {
+                tracing::info!(
+                    "Using config file specified by CDK_GATEWAY_CONFIG: {}",
+                    config_path
+                );
+                PathBuf::from(config_path)
+            }),
+        };
+        if let Some(config_path) = config_file {
+            config_paths.push(config_path.clone());
+            s = s.add_source(File::from(config_path.clone()).required(true));
+
+            // An explicit config file has its own optional override next to it
+            let override_path = local_override_path(&config_path);
+            if override_path.exists() {
+                tracing::info!("Found config override file at: {:?}", override_path);
+            }
+            config_paths.push(override_path.clone());
+            s = s.add_source(File::from(override_path).required(false));
         }
 
+        // The environment overrides the config files, so secrets such as
+        // CDK_GATEWAY__WALLET__MNEMONIC_SEED can be kept out of them entirely
+        s = s.add_source(Environment::with_prefix("CDK_GATEWAY").separator("__"));
+
         // Build and deserialize the config
         tracing::debug!("Building configuration");
         let result = s.build()?.try_deserialize::<Self>();
         match &result {
             Ok(settings) => {
+                if std::env::var_os(MNEMONIC_ENV_VAR).is_some() {
+                    tracing::info!("Using the mnemonic seed from {}", MNEMONIC_ENV_VAR);
+                } else if !settings.wallet.mnemonic_seed.is_empty() {
+                    for path in config_paths.iter().filter(|path| path.exists()) {
+                        warn_if_world_readable(path, "config file holding the mnemonic seed");
+                    }
+                }
+                if let Some(path) = &settings.wallet.mnemonic_file {
+                    warn_if_world_readable(path, "mnemonic file");
+                }
+
                 tracing::info!("Configuration successfully loaded");
-                tracing::debug!("Server configured to listen on {}:{}", settings.server.listen_addr, settings.server.port);
-                tracing::debug!("Payment processor configured at {}:{}", settings.grpc_processor.addr, settings.grpc_processor.port);
-                tracing::debug!("Configured with {} mint URLs", settings.wallet.mint_urls.len());
+                tracing::debug!(
+                    "Server configured to listen on {}:{}",
+                    settings.server.listen_addr,
+                    settings.server.port
+                );
+                tracing::debug!(
+                    "Payment processor configured at {}:{}",
+                    settings.grpc_processor.addr,
+                    settings.grpc_processor.port
+                );
+                tracing::debug!(
+                    "Configured with {} mint URLs",
+                    settings.wallet.mint_urls.len()
+                );
             }
             Err(e) => {
                 tracing::error!("Failed to load configuration: {}", e);
@@ -107,14 +691,242 @@ impl Settings {
         }
         result
     }
+
+    /// These settings with the parts of `new` that can change at runtime applied
+    ///
+    /// Payment settings, mint limits, the rate limit and the log level are taken
+    /// from `new`. Everything else needs a restart, so it is kept as is and the
+    /// settings that differ are returned so they can be reported.
+    pub fn reloaded(&self, new: &Settings) -> (Settings, Vec<&'static str>) {
+        let mut reloaded = self.clone();
+        let mut ignored = Vec::new();
+
+        // The P2PK key and the payment permits are only set up at startup
+        reloaded.payment = new.payment.clone();
+        if new.payment.p2pk_secret_key != self.payment.p2pk_secret_key {
+            ignored.push("payment.p2pk_secret_key");
+            reloaded.payment.p2pk_secret_key = self.payment.p2pk_secret_key.clone();
+        }
+        if new.payment.max_concurrent_payments != self.payment.max_concurrent_payments {
+            ignored.push("payment.max_concurrent_payments");
+            reloaded.payment.max_concurrent_payments = self.payment.max_concurrent_payments;
+        }
+
+        // Limits of the configured mints can change, but not which mints have wallets
+        let mint_urls = |wallet: &WalletConfig| {
+            wallet
+                .mint_urls
+                .iter()
+                .map(|entry| entry.url().to_string())
+                .collect::<Vec<_>>()
+        };
+        if mint_urls(&new.wallet) == mint_urls(&self.wallet) {
+            reloaded.wallet.mint_urls = new.wallet.mint_urls.clone();
+        } else {
+            ignored.push("wallet.mint_urls");
+        }
+        let mut wallet = new.wallet.clone();
+        wallet.mint_urls = self.wallet.mint_urls.clone();
+        if wallet != self.wallet {
+            ignored.push("wallet");
+        }
+
+        // Enabling or disabling the rate limit changes the routes
+        match (&self.rate_limit, &new.rate_limit) {
+            (Some(_), Some(rate_limit)) => reloaded.rate_limit = Some(rate_limit.clone()),
+            (None, None) => {}
+            _ => ignored.push("rate_limit"),
+        }
+
+        reloaded.logging.level = new.logging.level.clone();
+        if new.logging.format != self.logging.format {
+            ignored.push("logging.format");
+        }
+
+        for (name, changed) in [
+            ("grpc_processor", new.grpc_processor != self.grpc_processor),
+            (
+                "fallback_processors",
+                new.fallback_processors != self.fallback_processors,
+            ),
+            ("server", new.server != self.server),
+            ("metrics", new.metrics != self.metrics),
+            ("auth", new.auth != self.auth),
+            ("cors", new.cors != self.cors),
+            ("database", new.database != self.database),
+            ("lnurl", new.lnurl != self.lnurl),
+            ("mint_info", new.mint_info != self.mint_info),
+            ("webhooks", new.webhooks != self.webhooks),
+            ("rates", new.rates != self.rates),
+        ] {
+            if changed {
+                ignored.push(name);
+            }
+        }
+
+        (reloaded, ignored)
+    }
+
+    /// Check the settings are usable before starting the gateway
+    ///
+    /// Every problem found is listed in the returned error, not just the first.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        match self.wallet.mnemonic() {
+            Ok(mnemonic) => {
+                if let Err(e) = bip39::Mnemonic::from_str(&mnemonic) {
+                    problems.push(format!(
+                        "wallet mnemonic is not a valid BIP39 phrase: {}",
+                        e
+                    ));
+                }
+            }
+            Err(e) => problems.push(e.to_string()),
+        }
+
+        if self.payment.claim_locktime_margin_secs > self.payment.min_locktime_secs {
+            problems.push(
+                "payment.claim_locktime_margin_secs must not exceed payment.min_locktime_secs"
+                    .to_string(),
+            );
+        }
+        if let Some(max_locktime_secs) = self.payment.max_locktime_secs {
+            if max_locktime_secs < self.payment.min_locktime_secs {
+                problems.push(
+                    "payment.max_locktime_secs must not be below payment.min_locktime_secs"
+                        .to_string(),
+                );
+            }
+        }
+
+        for (name, list) in [
+            ("mint_allowlist", &self.payment.mint_allowlist),
+            ("mint_denylist", &self.payment.mint_denylist),
+        ] {
+            for url in list.iter() {
+                if let Err(e) = MintUrl::from_str(url) {
+                    problems.push(format!(
+                        "invalid mint url {:?} in payment.{}: {}",
+                        url, name, e
+                    ));
+                }
+            }
+        }
+
+        if self.wallet.mint_urls.is_empty() {
+            problems.push("wallet.mint_urls is empty, at least one mint is required".to_string());
+        }
+        for entry in self.wallet.mint_urls.iter() {
+            if let Err(e) = MintUrl::from_str(entry.url()) {
+                problems.push(format!("invalid mint url {:?}: {}", entry.url(), e));
+            }
+            if let (Some(min_sat), Some(max_sat)) = (entry.min_sat(), entry.max_sat()) {
+                if min_sat > max_sat {
+                    problems.push(format!(
+                        "min_sat {} is above max_sat {} for mint {}",
+                        min_sat,
+                        max_sat,
+                        entry.url()
+                    ));
+                }
+            }
+        }
+
+        if self.payment.accepted_token_versions.is_empty() {
+            problems.push(
+                "payment.accepted_token_versions is empty, no tokens would be accepted".to_string(),
+            );
+        }
+
+        if self.mint_info.ttl_secs == 0 {
+            problems.push("mint_info.ttl_secs must not be 0".to_string());
+        }
+
+        if let Err(e) = self.payment.p2pk_key() {
+            problems.push(e.to_string());
+        }
+
+        if self.payment.max_concurrent_payments == 0 {
+            problems.push("payment.max_concurrent_payments must not be 0".to_string());
+        }
+        if self.payment.idempotency_ttl_secs == 0 {
+            problems.push("payment.idempotency_ttl_secs must not be 0".to_string());
+        }
+        if self.payment.max_batch_payments == 0 {
+            problems.push("payment.max_batch_payments must not be 0".to_string());
+        }
+        if self.payment.accepted_methods.is_empty() {
+            problems.push(
+                "payment.accepted_methods is empty, no payments would be accepted".to_string(),
+            );
+        }
+
+        for (unit, rate) in self.rates.sat_per_unit.iter() {
+            if !rate.is_finite() || *rate <= 0.0 {
+                problems.push(format!("rates.sat_per_unit.{} must be positive", unit));
+            }
+        }
+        if self.rates.provider_url.is_some() && self.rates.refresh_secs == 0 {
+            problems.push("rates.refresh_secs must not be 0".to_string());
+        }
+
+        if let Some(webhooks) = &self.webhooks {
+            if !webhooks.url.starts_with("https://") && !webhooks.url.starts_with("http://") {
+                problems.push(format!("invalid webhooks.url {:?}", webhooks.url));
+            }
+        }
+
+        if let Err(e) = IpAddr::from_str(&self.server.listen_addr) {
+            problems.push(format!(
+                "invalid server.listen_addr {:?}: {}",
+                self.server.listen_addr, e
+            ));
+        }
+        if self.server.port == 0 {
+            problems.push("server.port must not be 0".to_string());
+        }
+        if let Some(base_path) = &self.server.base_path {
+            let base_path = base_path.trim_end_matches('/');
+            if !base_path.is_empty()
+                && (!base_path.starts_with('/') || base_path.contains(['{', '}']))
+            {
+                problems.push(format!(
+                    "invalid server.base_path {:?}, it must start with / and not contain path parameters",
+                    base_path
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Message(format!(
+                "Invalid configuration:\n  - {}",
+                problems.join("\n  - ")
+            )))
+        }
+    }
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Self {
             grpc_processor: GrpcProcessor::default(),
+            fallback_processors: Vec::new(),
             wallet: WalletConfig::default(),
             server: ServerConfig::default(),
+            payment: PaymentConfig::default(),
+            metrics: MetricsConfig::default(),
+            auth: AuthConfig::default(),
+            rate_limit: None,
+            cors: CorsConfig::default(),
+            database: DatabaseConfig::default(),
+            lnurl: LnurlConfig::default(),
+            logging: LoggingConfig::default(),
+            mint_info: MintInfoConfig::default(),
+            webhooks: None,
+            rates: RatesConfig::default(),
         }
     }
 }
@@ -0,0 +1,65 @@
+use axum::http::{HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::config::CorsConfig;
+
+/// Build the CORS layer for the gateway API
+///
+/// With no origins configured only localhost origins are allowed, which keeps
+/// local wallet development working without opening the gateway to every site.
+/// An origin of `*` allows any origin.
+pub fn cors_layer(config: &CorsConfig) -> anyhow::Result<CorsLayer> {
+    let origins = if config.allowed_origins.is_empty() {
+        AllowOrigin::predicate(|origin: &HeaderValue, _| is_localhost_origin(origin))
+    } else if config.allowed_origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            config
+                .allowed_origins
+                .iter()
+                .map(|origin| origin.parse::<HeaderValue>())
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+    };
+
+    let methods = config
+        .allowed_methods
+        .iter()
+        .map(|method| method.parse::<Method>())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let headers = config
+        .allowed_headers
+        .iter()
+        .map(|header| header.parse::<HeaderName>())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods(methods)
+        .allow_headers(headers)
+        // Browsers only let scripts read the payment request header if it is exposed
+        .expose_headers([HeaderName::from_static("x-cashu")]))
+}
+
+/// Whether `origin` is a localhost origin on any port
+fn is_localhost_origin(origin: &HeaderValue) -> bool {
+    let Ok(origin) = origin.to_str() else {
+        return false;
+    };
+
+    let Some(authority) = origin
+        .strip_prefix("http://")
+        .or_else(|| origin.strip_prefix("https://"))
+    else {
+        return false;
+    };
+
+    let host = match authority.strip_prefix("[::1]") {
+        Some(rest) if rest.is_empty() || rest.starts_with(':') => return true,
+        _ => authority.split(':').next().unwrap_or_default(),
+    };
+
+    host == "localhost" || host == "127.0.0.1"
+}
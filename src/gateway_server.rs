@@ -1,30 +1,92 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
+use anyhow::Context;
 use axum::Router;
-use axum::http::{StatusCode, header};
+use axum::extract::{DefaultBodyLimit, Path, Query, Request};
+use axum::http::{Extensions, HeaderMap, HeaderName, HeaderValue, StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::{Json, extract::State};
+use axum_server::tls_rustls::RustlsConfig;
+use bitcoin::hashes::Hash as _;
+use bitcoin::hashes::sha256::Hash as Sha256Hash;
+use bitcoin::hex::FromHex;
 use cdk::Bolt11Invoice;
-use cdk::amount::Amount;
-use cdk::cdk_payment::{self, Bolt11OutgoingPaymentOptions, MintPayment, OutgoingPaymentOptions};
+use cdk::amount::{Amount, SplitTarget};
+use cdk::cdk_payment::{
+    self, Bolt11OutgoingPaymentOptions, MakePaymentResponse, MintPayment, OutgoingPaymentOptions,
+    PaymentIdentifier,
+};
 use cdk::mint_url::MintUrl;
-use cdk::nuts::nut18::PaymentRequestBuilder;
-use cdk::nuts::{CurrencyUnit, Nut10Secret, SpendingConditions, Token};
+use cdk::nuts::nut18::{PaymentRequest, PaymentRequestBuilder};
+use cdk::nuts::{
+    CurrencyUnit, MeltOptions, MeltQuoteState, Nut10Secret, Proof, Proofs, PublicKey, SecretKey,
+    SpendingConditions, Token,
+};
 use cdk::util::unix_time;
 use cdk::wallet::types::WalletKey;
-use cdk::wallet::{MultiMintWallet, ReceiveOptions, SendOptions};
+use cdk::wallet::{MultiMintWallet, ReceiveOptions, SendOptions, Wallet};
+use futures::Stream;
+use futures::future::join_all;
 
 use serde::{Deserialize, Serialize};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore, mpsc};
+use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
+use tower_http::limit::RequestBodyLimitLayer;
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::admin::admin_routes;
+use crate::auth::require_api_key;
+use crate::config::{PaymentConfig, Settings, TokenVersion};
+use crate::cors::cors_layer;
+use crate::idempotency::{
+    IDEMPOTENCY_KEY_HEADER, IdempotencyGuard, IdempotencyStatus, begin_idempotent_request,
+};
+use crate::lnurl::{LnurlError, LnurlResolver};
+use crate::metrics::Metrics;
+use crate::mint_info::{MintInfoCache, refresh_mint_info, unknown_mint};
+use crate::node::NodeHealth;
+use crate::openapi::get_openapi;
+use crate::payment_store::{
+    OwedChange, PaymentRecord, PaymentStore, PendingPayment, UnclaimedPayment,
+};
+use crate::quote::{ClaimError, QuoteClaim, QuoteReservations, ReservedQuote};
+use crate::rate_limit::{RateLimiter, client_key, rate_limit};
+use crate::rates::{ExchangeRates, FiatAmount, FiatRate, refresh_rates};
+use crate::request_log::log_requests;
+use crate::webhook::{PaymentEvent, WebhookNotifier};
 
 /// Cashu Lsp State
 #[derive(Clone)]
 pub struct CdkGateway {
     node: Arc<dyn MintPayment<Err = cdk_payment::Error> + Send + Sync>,
+    node_health: Arc<NodeHealth>,
     wallets: MultiMintWallet,
+    /// Swapped as a whole when the settings are reloaded
+    settings: Arc<RwLock<Arc<Settings>>>,
+    payment_config: Arc<RwLock<Arc<PaymentConfig>>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    metrics: Arc<Metrics>,
+    payments: Arc<PaymentTracker>,
+    payment_store: Arc<PaymentStore>,
+    idempotency_keys: Arc<Mutex<HashSet<String>>>,
+    lnurl: Arc<LnurlResolver>,
+    mint_info: Arc<MintInfoCache>,
+    p2pk_key: Option<SecretKey>,
+    webhooks: Option<Arc<WebhookNotifier>>,
+    quotes: Arc<QuoteReservations>,
+    rates: Arc<ExchangeRates>,
+    payment_permits: Arc<Semaphore>,
     server_cancel: CancellationToken,
 }
 
@@ -32,11 +94,49 @@ impl CdkGateway {
     /// Create a new CdkGateway instance
     pub fn new(
         node: Arc<dyn MintPayment<Err = cdk_payment::Error> + Send + Sync>,
+        node_health: Arc<NodeHealth>,
         wallets: MultiMintWallet,
+        payment_store: Arc<PaymentStore>,
+        settings: Settings,
     ) -> Self {
+        let metrics =
+            Metrics::new(settings.metrics.clone()).expect("metrics are registered exactly once");
+        let lnurl = Arc::new(LnurlResolver::new(&settings.lnurl));
+        let mint_info = Arc::new(MintInfoCache::new(&settings.mint_info));
+        let p2pk_key = settings.payment.p2pk_key().unwrap_or_else(|e| {
+            tracing::error!("{}, P2PK-locked tokens will not be accepted", e);
+            None
+        });
+        let webhooks = settings
+            .webhooks
+            .as_ref()
+            .map(|config| Arc::new(WebhookNotifier::new(config)));
+        let payment_permits = Arc::new(Semaphore::new(settings.payment.max_concurrent_payments));
+        let rates = Arc::new(ExchangeRates::new(&settings.rates));
+        let rate_limiter = settings
+            .rate_limit
+            .as_ref()
+            .map(|config| Arc::new(RateLimiter::new(config)));
+        let payment_config = Arc::new(RwLock::new(Arc::new(settings.payment.clone())));
+
         Self {
             node,
+            node_health,
             wallets,
+            settings: Arc::new(RwLock::new(Arc::new(settings))),
+            payment_config,
+            rate_limiter,
+            metrics: Arc::new(metrics),
+            payments: Arc::new(PaymentTracker::default()),
+            payment_store,
+            idempotency_keys: Arc::default(),
+            lnurl,
+            mint_info,
+            p2pk_key,
+            webhooks,
+            quotes: Arc::default(),
+            rates,
+            payment_permits,
             server_cancel: CancellationToken::new(),
         }
     }
@@ -46,11 +146,345 @@ impl CdkGateway {
         &self.node
     }
 
+    /// Ask the node for the preimage of a payment it has sent
+    pub async fn query_preimage(&self, hash: &Sha256Hash) -> Option<String> {
+        let payment_identifier = PaymentIdentifier::PaymentHash(hash.to_byte_array());
+        match self.node.check_outgoing_payment(&payment_identifier).await {
+            Ok(response) if response.status == MeltQuoteState::Paid => {
+                response.payment_proof.filter(|preimage| {
+                    let matches = preimage_matches(preimage, hash);
+                    if !matches {
+                        tracing::error!("Node returned a wrong preimage for payment {}", hash);
+                    }
+                    matches
+                })
+            }
+            Ok(response) => {
+                tracing::debug!("Payment {} is {:?}", hash, response.status);
+                None
+            }
+            Err(e) => {
+                tracing::warn!("Failed to look up payment {}: {}", hash, e);
+                None
+            }
+        }
+    }
+
+    /// Claim the tokens of payments sent without a preimage, once the node reports it
+    ///
+    /// Change due from a claimed payment is recorded as owed, since the client
+    /// has already received an error. A payment that fails to reconcile is
+    /// logged and counted, and the others are still reconciled. Returns the
+    /// requests that were claimed.
+    pub async fn reconcile_unclaimed_payments(&self) -> anyhow::Result<Vec<Uuid>> {
+        let mut claimed = Vec::new();
+
+        for payment in self.payment_store.unclaimed_payments()? {
+            let request_id = payment.request_id;
+            match self.reconcile_unclaimed_payment(payment).await {
+                Ok(true) => claimed.push(request_id),
+                Ok(false) => {}
+                Err(e) => {
+                    tracing::error!(
+                        "Failed to reconcile unclaimed payment {}: {}",
+                        request_id,
+                        e
+                    );
+                    self.metrics.record_reconcile_failure();
+                }
+            }
+        }
+
+        Ok(claimed)
+    }
+
+    /// Claim the tokens of one unclaimed payment, returning whether it was claimed
+    ///
+    /// The stored payment is updated after every token received, so when
+    /// claiming fails partway a later attempt only claims the tokens left.
+    async fn reconcile_unclaimed_payment(
+        &self,
+        mut payment: UnclaimedPayment,
+    ) -> anyhow::Result<bool> {
+        let hash = Sha256Hash::from_str(&payment.payment_hash)?;
+        let Some(preimage) = self.query_preimage(&hash).await else {
+            return Ok(false);
+        };
+
+        tracing::info!("Found preimage for payment {}, claiming tokens", hash);
+        let change_mint = match payment.tokens.first() {
+            Some(token) => Some(Token::from_str(token)?.mint_url()?),
+            None => None,
+        };
+        while let Some(token) = payment.tokens.first() {
+            let mint_url = Token::from_str(token)?.mint_url()?;
+            let wallet = self
+                .wallets
+                .get_wallet(&WalletKey::new(mint_url.clone(), CurrencyUnit::Sat))
+                .await
+                .with_context(|| format!("No wallet for mint {}", mint_url))?;
+            let amount = wallet
+                .receive(
+                    token,
+                    ReceiveOptions {
+                        preimages: vec![preimage.clone()],
+                        p2pk_signing_keys: self.p2pk_signing_keys(),
+                        ..Default::default()
+                    },
+                )
+                .await?;
+            self.metrics.record_receive(&mint_url, amount);
+
+            payment.tokens.remove(0);
+            if !payment.tokens.is_empty() {
+                self.payment_store.add_unclaimed_payment(&payment)?;
+            }
+        }
+
+        let change = change_due(
+            &self.metrics,
+            payment.request_id,
+            payment.provided,
+            payment.total_spent,
+        );
+        if let (Some(mint_url), true) = (change_mint, change > Amount::ZERO) {
+            self.payment_store.add_owed_change(&OwedChange {
+                request_id: payment.request_id,
+                mint_url,
+                amount: change,
+                timestamp: unix_time(),
+            })?;
+        }
+
+        self.payment_store
+            .remove_unclaimed_payment(&payment.request_id)?;
+
+        Ok(true)
+    }
+
+    /// Settle batch payments left pending, once the node reports their outcome
+    ///
+    /// What was held for a payment and not spent is recorded as owed change.
+    /// A payment that fails to settle is logged and counted like an unclaimed
+    /// payment. Returns the payments that were settled.
+    pub async fn settle_pending_payments(&self) -> anyhow::Result<Vec<Uuid>> {
+        let mut settled = Vec::new();
+
+        for payment in self.payment_store.pending_payments()? {
+            match self.settle_pending_payment(&payment).await {
+                Ok(true) => settled.push(payment.payment_id),
+                Ok(false) => {}
+                Err(e) => {
+                    tracing::error!(
+                        "Failed to settle pending payment {}: {}",
+                        payment.payment_id,
+                        e
+                    );
+                    self.metrics.record_reconcile_failure();
+                }
+            }
+        }
+
+        Ok(settled)
+    }
+
+    /// Settle one pending payment, returning whether its outcome is known
+    async fn settle_pending_payment(&self, payment: &PendingPayment) -> anyhow::Result<bool> {
+        let hash = Sha256Hash::from_str(&payment.payment_hash)?;
+        let response = self
+            .node
+            .check_outgoing_payment(&PaymentIdentifier::PaymentHash(hash.to_byte_array()))
+            .await?;
+        let spent = match response.status {
+            MeltQuoteState::Paid => response.total_spent,
+            MeltQuoteState::Unpaid | MeltQuoteState::Failed => Amount::ZERO,
+            MeltQuoteState::Pending | MeltQuoteState::Unknown => return Ok(false),
+        };
+        tracing::info!(
+            "Pending payment {} is {:?}, spent {}",
+            payment.payment_id,
+            response.status,
+            spent
+        );
+
+        if spent > Amount::ZERO {
+            let record = PaymentRecord {
+                request_id: payment.payment_id,
+                amount: payment.amount,
+                fee: spent.checked_sub(payment.amount).unwrap_or_default(),
+                payment_hash: payment.payment_hash.clone(),
+                timestamp: unix_time(),
+                change: Amount::ZERO,
+            };
+            self.payment_store.add(&record)?;
+        }
+
+        let owed = payment.held.checked_sub(spent).unwrap_or_default();
+        if owed > Amount::ZERO {
+            self.payment_store.add_owed_change(&OwedChange {
+                request_id: payment.request_id,
+                mint_url: payment.mint_url.clone(),
+                amount: owed,
+                timestamp: unix_time(),
+            })?;
+        }
+
+        self.payment_store
+            .remove_pending_payment(&payment.payment_id)?;
+
+        Ok(true)
+    }
+
+    /// Get the connection state of the payment node
+    pub fn node_health(&self) -> &NodeHealth {
+        &self.node_health
+    }
+
+    /// Get the resolver for lightning address and LNURL destinations
+    pub fn lnurl(&self) -> &LnurlResolver {
+        &self.lnurl
+    }
+
+    /// Trigger `cancel` when the server stops, so tasks started before the
+    /// gateway, like pinging the payment processor, stop with it
+    pub fn with_server_cancel(mut self, cancel: CancellationToken) -> Self {
+        self.server_cancel = cancel;
+        self
+    }
+
+    /// Resolve lightning addresses and LNURLs with `lnurl` instead of the
+    /// resolver built from the settings
+    #[cfg(feature = "testing")]
+    pub fn with_lnurl_resolver(mut self, lnurl: LnurlResolver) -> Self {
+        self.lnurl = Arc::new(lnurl);
+        self
+    }
+
+    /// Public key tokens can be P2PK locked to, if the gateway has a key configured
+    pub fn p2pk_pubkey(&self) -> Option<PublicKey> {
+        self.p2pk_key.as_ref().map(|key| key.public_key())
+    }
+
+    /// Keys to sign P2PK-locked proofs with when receiving them
+    fn p2pk_signing_keys(&self) -> Vec<SecretKey> {
+        self.p2pk_key.iter().cloned().collect()
+    }
+
+    /// Post a payment event to the configured webhook, if any
+    pub fn notify_payment(&self, event: PaymentEvent) {
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.notify(event);
+        }
+    }
+
+    /// Get the exchange rates of fiat units, for display
+    pub fn rates(&self) -> &Arc<ExchangeRates> {
+        &self.rates
+    }
+
+    /// Get the quotes reserved for payments
+    pub fn quotes(&self) -> &Arc<QuoteReservations> {
+        &self.quotes
+    }
+
+    /// Get the cache of supported mints' metadata
+    pub fn mint_info(&self) -> &MintInfoCache {
+        &self.mint_info
+    }
+
+    /// Metadata of a mint, fetched from the mint only when the cached copy has expired
+    pub async fn mint_metadata(&self, url: MintUrl) -> MintMetadata {
+        if let Some(metadata) = self.mint_info.get(&url) {
+            return metadata;
+        }
+
+        let wallet = self
+            .wallets
+            .get_wallet(&WalletKey::new(url.clone(), CurrencyUnit::Sat))
+            .await;
+        match wallet {
+            Some(wallet) => self.mint_info.refresh(&wallet).await,
+            None => unknown_mint(url),
+        }
+    }
+
     /// Get a reference to the wallet collection
     pub fn wallets(&self) -> &MultiMintWallet {
         &self.wallets
     }
 
+    /// The current gateway settings
+    pub fn settings(&self) -> Arc<Settings> {
+        self.settings
+            .read()
+            .expect("settings lock poisoned")
+            .clone()
+    }
+
+    /// The current payment configuration
+    pub fn payment_config(&self) -> Arc<PaymentConfig> {
+        self.payment_config
+            .read()
+            .expect("settings lock poisoned")
+            .clone()
+    }
+
+    /// Rate limiter for payment requests, when rate limiting is enabled
+    pub fn rate_limiter(&self) -> Option<&Arc<RateLimiter>> {
+        self.rate_limiter.as_ref()
+    }
+
+    /// Apply the settings that can change at runtime from `settings`
+    ///
+    /// Handlers read the settings as they need them, so requests in progress
+    /// may see the new settings. Returns the settings that differ but need a
+    /// restart to take effect.
+    pub fn reload_settings(&self, settings: &Settings) -> Vec<&'static str> {
+        let (reloaded, ignored) = self.settings().reloaded(settings);
+
+        if let (Some(limiter), Some(config)) = (&self.rate_limiter, &reloaded.rate_limit) {
+            limiter.update(config);
+        }
+        *self.payment_config.write().expect("settings lock poisoned") =
+            Arc::new(reloaded.payment.clone());
+        *self.settings.write().expect("settings lock poisoned") = Arc::new(reloaded);
+
+        ignored
+    }
+
+    /// Get a reference to the gateway metrics
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Get a reference to the in-flight payment tracker
+    pub fn payments(&self) -> &Arc<PaymentTracker> {
+        &self.payments
+    }
+
+    /// Get a reference to the payment history store
+    pub fn payment_store(&self) -> &PaymentStore {
+        &self.payment_store
+    }
+
+    /// Get the idempotency keys of requests currently in progress
+    pub fn idempotency_keys(&self) -> &Arc<Mutex<HashSet<String>>> {
+        &self.idempotency_keys
+    }
+
+    /// Whether DLEQ verification is skipped for tokens from `mint_url`
+    pub fn is_dleq_trusted(&self, mint_url: &MintUrl) -> bool {
+        self.payment_config().is_dleq_trusted(mint_url)
+    }
+
+    /// Whether tokens from `mint_url` are accepted under the mint allow and deny lists
+    ///
+    /// This is independent of which mints have wallets, the lists only narrow
+    /// the supported mints down.
+    pub fn is_mint_accepted(&self, mint_url: &MintUrl) -> bool {
+        self.payment_config().is_mint_accepted(mint_url)
+    }
+
     /// Start the Axum HTTP server for the gateway API in a background task
     ///
     /// # Arguments
@@ -64,42 +498,238 @@ impl CdkGateway {
         &self,
         bind_address: SocketAddr,
         mints: Vec<MintUrl>,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<ServerHandle> {
         let gateway = Arc::new(self.clone());
 
-        let cancel = self.server_cancel.clone();
+        // Each server gets its own token so `stop_server` still cancels all of them
+        let cancel = self.server_cancel.child_token();
+
+        let app = create_cashu_lsp_router(gateway, mints).await?;
 
-        // Spawn the server task
-        let app = create_cashu_lsp_router(gateway, mints).await.unwrap();
+        let listener = tokio::net::TcpListener::bind(bind_address)
+            .await
+            .with_context(|| format!("failed to bind {bind_address}"))?;
+        let local_addr = listener.local_addr()?;
+
+        let app = app.into_make_service_with_connect_info::<SocketAddr>();
+
+        // Spawn the server task, configured to gracefully shut down
+        let shutdown = cancel.clone();
+        let settings = self.settings();
+        let shutdown_timeout = Duration::from_secs(settings.server.shutdown_timeout_secs);
+        let task = match &settings.server.tls {
+            Some(tls) => {
+                // reqwest already links ring, so the server uses it too rather
+                // than leaving rustls to pick between providers. An error only
+                // means a provider has been installed already.
+                let _ = rustls::crypto::ring::default_provider().install_default();
+                let rustls_config = RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path)
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "failed to load TLS certificate {:?} and key {:?}",
+                            tls.cert_path, tls.key_path
+                        )
+                    })?;
+
+                let handle = axum_server::Handle::new();
+                let shutdown_handle = handle.clone();
+                tokio::spawn(async move {
+                    shutdown.cancelled().await;
+                    shutdown_handle.graceful_shutdown(Some(shutdown_timeout));
+                });
+
+                tracing::info!("Starting CDK Gateway server on {} with TLS", bind_address);
+                let server = axum_server::from_tcp_rustls(listener.into_std()?, rustls_config)
+                    .handle(handle);
+                tokio::spawn(async move {
+                    server.serve(app).await?;
+                    Ok::<_, anyhow::Error>(())
+                })
+            }
+            None => {
+                tracing::info!("Starting CDK Gateway server on {}", bind_address);
+                tokio::spawn(async move {
+                    axum::serve(listener, app)
+                        .with_graceful_shutdown(async move { shutdown.cancelled().await })
+                        .await?;
+                    Ok::<_, anyhow::Error>(())
+                })
+            }
+        };
 
-        tracing::info!("Starting CDK Gateway server on {}", bind_address);
-        let listener = tokio::net::TcpListener::bind(bind_address).await.unwrap();
-        // Configure the server to gracefully shut down
-        Ok(axum::serve(listener, app)
-            .with_graceful_shutdown(async move { cancel.cancelled().await })
-            .await?)
+        Ok(ServerHandle {
+            cancel,
+            task,
+            local_addr,
+            payments: self.payments.clone(),
+            drain_timeout: Duration::from_secs(settings.server.shutdown_drain_timeout_secs),
+            shutdown_timeout,
+        })
     }
 
     /// Stop the server and cancel all tasks
+    ///
+    /// In-flight payments are given up to the configured drain timeout to finish first.
     pub async fn stop_server(&self) -> anyhow::Result<()> {
         tracing::info!("Shutting down CDK Gateway server");
+        self.payments
+            .drain(Duration::from_secs(
+                self.settings().server.shutdown_drain_timeout_secs,
+            ))
+            .await;
         self.server_cancel.cancel();
         Ok(())
     }
 }
 
+/// Handle to a running gateway server
+pub struct ServerHandle {
+    cancel: CancellationToken,
+    task: JoinHandle<anyhow::Result<()>>,
+    local_addr: SocketAddr,
+    payments: Arc<PaymentTracker>,
+    drain_timeout: Duration,
+    shutdown_timeout: Duration,
+}
+
+impl ServerHandle {
+    /// Address the server is listening on, with the port it was given when bound to port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Signal the server to shut down and wait for the serve loop to exit
+    ///
+    /// In-flight payments are given up to the drain timeout to finish before
+    /// the server is cancelled, so a payment is not aborted between paying the
+    /// invoice and claiming the tokens. The serve loop is then given up to the
+    /// shutdown timeout to close its connections, after which it is aborted
+    /// so a stuck handler can't hang shutdown.
+    pub async fn stop(mut self) -> anyhow::Result<()> {
+        tracing::info!("Shutting down CDK Gateway server");
+        self.payments.drain(self.drain_timeout).await;
+        self.cancel.cancel();
+
+        match tokio::time::timeout(self.shutdown_timeout, &mut self.task).await {
+            Ok(result) => result?,
+            Err(_) => {
+                tracing::warn!(
+                    "Server did not stop within {:?}, abandoning in-flight requests",
+                    self.shutdown_timeout
+                );
+                self.task.abort();
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Tracks payment handlers that are currently running
+#[derive(Debug, Default)]
+pub struct PaymentTracker {
+    active: AtomicUsize,
+    draining: AtomicBool,
+    idle: Notify,
+}
+
+impl PaymentTracker {
+    /// Register a running payment handler
+    ///
+    /// Returns `None` once draining has started, the payment is tracked until
+    /// the returned guard is dropped.
+    pub fn start(self: &Arc<Self>) -> Option<PaymentGuard> {
+        // Increment before checking so a concurrent drain always sees this payment
+        self.active.fetch_add(1, Ordering::SeqCst);
+        let guard = PaymentGuard(self.clone());
+
+        if self.draining.load(Ordering::SeqCst) {
+            return None;
+        }
+
+        Some(guard)
+    }
+
+    /// Whether payments are no longer accepted because the gateway is shutting down
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Number of payment handlers currently running
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Stop accepting payments and wait up to `timeout` for running ones to finish
+    ///
+    /// Returns `false` if payments were still running when the timeout elapsed.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.draining.store(true, Ordering::SeqCst);
+
+        let active = self.active();
+        if active > 0 {
+            tracing::info!("Waiting for {} in-flight payments to complete", active);
+        }
+
+        let drained = tokio::time::timeout(timeout, async {
+            loop {
+                let idle = self.idle.notified();
+                if self.active() == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        })
+        .await
+        .is_ok();
+
+        if !drained {
+            tracing::warn!(
+                "Shutdown drain timed out with {} payments still in flight",
+                self.active()
+            );
+        }
+
+        drained
+    }
+}
+
+/// Marks a payment as in flight until dropped
+pub struct PaymentGuard(Arc<PaymentTracker>);
+
+impl Drop for PaymentGuard {
+    fn drop(&mut self) {
+        if self.0.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// Response header echoing the ID assigned to a payment request
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Seconds clients are asked to wait when the gateway has too many payments in progress
+const BUSY_RETRY_AFTER_SECS: u64 = 1;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GatwayInfo {
     pub mints: Vec<String>,
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PaymentMethod {
     #[default]
     #[serde(rename = "bolt11")]
     Bolt11,
     #[serde(rename = "bolt12")]
     Bolt12,
+    /// A lightning address such as `name@domain`, resolved to a BOLT11 invoice
+    /// for `amount`
+    #[serde(rename = "ln_address")]
+    LnAddress,
+    /// An LNURL-pay string, resolved to a BOLT11 invoice for `amount`
+    #[serde(rename = "lnurl")]
+    Lnurl,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -108,347 +738,3196 @@ pub struct MeltRequest {
     pub request: String,
     pub amount: Option<Amount>,
     pub tokens: Vec<String>,
+    /// Pay only part of the invoice, for multi-path payments split across gateways
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub melt_options: Option<MeltOptions>,
+    /// Pay only what the tokens cover when they are worth less than `amount`,
+    /// for invoices without an amount
+    #[serde(default)]
+    pub allow_partial: bool,
+    /// Quote from `/quote` whose terms the payment is made on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quote_id: Option<Uuid>,
+    /// Most the routing fee may be, clamped to the configured ceiling
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_fee_sat: Option<Amount>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MeltResponse {
     pub payment_proof: String,
+    /// Hash of the invoice that was paid
+    #[serde(default)]
+    pub payment_hash: String,
     pub change: Vec<String>,
+    /// Amount paid over lightning, before routing fees
+    #[serde(default)]
+    pub amount_paid_sat: u64,
+    /// Routing fee paid on top of the amount
+    #[serde(default)]
+    pub fee_paid_sat: u64,
+    /// Total value of the change tokens
+    #[serde(default)]
+    pub change_sat: u64,
+    /// Set when the payment succeeded but some change could not be created
+    ///
+    /// The change owed is recorded by the gateway so it can be returned later.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change_error: Option<String>,
+    /// Change kept in the gateway wallet, when the gateway is configured to retain change
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retained_change_sat: Option<u64>,
 }
 
+/// What the gateway would do with a payment request
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ErrorResponse {
-    pub code: u16,
-    pub message: String,
-    pub details: Option<String>,
-    #[serde(skip)]
-    pub payment_request: Option<String>,
+pub struct WouldPay {
+    /// Amount that would be paid over lightning, before routing fees
+    pub amount: Amount,
+    /// Total value of the tokens provided
+    pub provided: Amount,
+    /// Change that would be returned if there were no routing fees
+    pub change: Amount,
+    pub mints: Vec<MintUrl>,
 }
 
-impl IntoResponse for ErrorResponse {
-    fn into_response(self) -> Response {
-        // If the error is about insufficient funds or related to payment, use 402 Payment Required
-        let status = if self.code == 400
-            && (self.message.contains("Insufficient funds")
-                || self.message.contains("Missing amount")
-                || self.message.contains("Token verification failed"))
-        {
-            StatusCode::PAYMENT_REQUIRED
-        } else {
-            StatusCode::from_u16(self.code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
-        };
-
-        // Copy relevant data for serialization
-        let serializable_error = ErrorResponse {
-            code: self.code,
-            message: self.message.clone(),
-            details: self.details.clone(),
-            payment_request: None, // Skip this in serialization
-        };
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DryRunResponse {
+    pub would_pay: WouldPay,
+}
 
-        // Create a basic response with the status and JSON body
-        let mut response = (status, Json(serializable_error)).into_response();
+/// One of the payments of a batch, paid from the batch's tokens
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchPayment {
+    pub method: PaymentMethod,
+    pub request: String,
+    pub amount: Option<Amount>,
+}
 
-        // If we're returning a 402 Payment Required, add the X-cashu header
-        if status == StatusCode::PAYMENT_REQUIRED {
-            if let Some(payment_request) = self.payment_request {
-                if let Ok(header_value) = header::HeaderValue::from_str(&payment_request) {
-                    response
-                        .headers_mut()
-                        .insert(header::HeaderName::from_static("x-cashu"), header_value);
-                }
-            }
-        }
+/// Several payments made from one set of tokens
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchMeltRequest {
+    pub payments: Vec<BatchPayment>,
+    /// Tokens P2PK locked to the gateway's key, covering every payment and its fee reserve
+    pub tokens: Vec<String>,
+}
 
-        response
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchPaymentStatus {
+    Paid,
+    /// The payment was not made, its share of the tokens is returned in change
+    Failed,
+    /// The node could not tell whether the payment was made, its share of the
+    /// tokens is held by the gateway until the operator resolves it
+    Pending,
 }
 
-#[derive(Clone)]
-pub struct GatwayState {
-    pub inner: Arc<CdkGateway>,
-    pub mints: Vec<MintUrl>,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchPaymentResult {
+    /// ID the payment is recorded under in the payment history and webhooks
+    pub request_id: Uuid,
+    pub request: String,
+    pub status: BatchPaymentStatus,
+    /// Hash of the invoice paid for the request
+    pub payment_hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payment_proof: Option<String>,
+    #[serde(default)]
+    pub amount_paid_sat: u64,
+    #[serde(default)]
+    pub fee_paid_sat: u64,
+    /// Why the payment failed or may not have been made
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorResponse>,
 }
 
-pub async fn create_cashu_lsp_router(
-    gateway: Arc<CdkGateway>,
-    mints: Vec<MintUrl>,
-) -> anyhow::Result<Router> {
-    tracing::debug!(
-        "Creating CDK Gateway router with {} supported mints",
-        mints.len()
-    );
-    let gateway_state = GatwayState {
-        inner: gateway,
-        mints,
-    };
-    let router = Router::new()
-        .route("/payment", post(post_melt_request))
-        .route("/mints", get(get_mints))
-        .with_state(gateway_state);
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchMeltResponse {
+    /// Outcome of each payment, in the order they were requested
+    pub payments: Vec<BatchPaymentResult>,
+    /// Change for everything not spent, including the share of failed payments
+    pub change: Vec<String>,
+    #[serde(default)]
+    pub change_sat: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change_error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retained_change_sat: Option<u64>,
+}
 
-    Ok(router)
+/// A payment to estimate the cost of, without any ecash
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuoteRequest {
+    pub method: PaymentMethod,
+    pub request: String,
+    pub amount: Option<Amount>,
 }
 
-pub async fn get_mints(
-    State(state): State<GatwayState>,
-) -> Result<Json<Vec<MintUrl>>, ErrorResponse> {
-    tracing::debug!("Request received for /mints endpoint");
-    Ok(Json(state.mints))
+/// Where a quoted routing fee came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeSource {
+    /// Estimated by the payment node
+    Node,
+    /// The configured reserve, used when the node can't estimate
+    Reserve,
 }
 
-pub async fn post_melt_request(
-    State(state): State<GatwayState>,
-    Json(payload): Json<MeltRequest>,
-) -> Result<Json<MeltResponse>, ErrorResponse> {
-    tracing::info!("Payment request received with method: {:?}", payload.method);
-    let hash;
-    let (amount_to_pay_sat, outgoing_options) = match payload.method {
-        PaymentMethod::Bolt11 => {
-            let bolt11: Bolt11Invoice = payload.request.parse().map_err(|_| ErrorResponse {
-                code: 400,
-                message: "Invalid BOLT11 invoice".to_string(),
-                details: None,
-                payment_request: None,
-            })?;
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuoteResponse {
+    /// Amount that would be paid over lightning, before routing fees
+    pub amount: Amount,
+    /// Estimated routing fee
+    pub fee: Amount,
+    pub total: Amount,
+    pub fee_source: FeeSource,
+    /// Reference a payment can give to be made on these terms
+    pub quote_id: Uuid,
+    /// Unix timestamp the terms are reserved until
+    pub expires_at: u64,
+    /// `total` in the fiat units with a configured rate, for display
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fiat_totals: Vec<FiatAmount>,
+    /// Summary of the amounts for display, when `include_breakdown` is enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub breakdown: Option<String>,
+}
 
-            let amount = if let Some(amount) = bolt11.amount_milli_satoshis() {
-                (amount / 1_000).into()
-            } else {
-                payload.amount.ok_or(ErrorResponse {
-                    code: 400,
-                    message: "Missing amount".to_string(),
-                    details: Some(
-                        "Invoice has no amount specified. Please provide an amount in the request."
-                            .to_string(),
-                    ),
-                    payment_request: None,
-                })?
-            };
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletBalance {
+    pub mint_url: MintUrl,
+    pub unit: CurrencyUnit,
+    pub balance: Amount,
+}
 
-            hash = bolt11.payment_hash().to_owned();
+/// Stage of a payment reported to clients streaming its progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentStage {
+    VerifyingTokens,
+    PayingInvoice,
+    ReceivingTokens,
+    ReturningChange,
+}
 
-            let outgoing = OutgoingPaymentOptions::Bolt11(Box::new(Bolt11OutgoingPaymentOptions {
-                bolt11,
-                max_fee_amount: None,
-                timeout_secs: None,
-                melt_options: None,
-            }));
+/// Reports the stages of a payment to a streaming client, if there is one
+#[derive(Debug, Clone, Default)]
+pub struct PaymentProgress {
+    tx: Option<mpsc::UnboundedSender<PaymentStage>>,
+}
 
-            (amount, outgoing)
+impl PaymentProgress {
+    fn report(&self, stage: PaymentStage) {
+        if let Some(tx) = &self.tx {
+            // The client may have disconnected, the payment carries on regardless
+            let _ = tx.send(stage);
         }
-        PaymentMethod::Bolt12 => {
-            return Err(ErrorResponse {
-                code: 400,
-                message: "Payment method not supported".to_string(),
-                details: Some("BOLT12 payment method is not supported".to_string()),
-                payment_request: None,
-            });
-        }
-    };
+    }
+}
 
-    let nut10 = SpendingConditions::HTLCConditions {
-        data: hash,
-        conditions: None,
+/// Number of payments returned by `/payments` when no limit is given
+const DEFAULT_PAYMENTS_LIMIT: usize = 50;
+
+/// Upper bound on the number of payments returned by `/payments`
+const MAX_PAYMENTS_LIMIT: usize = 500;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PaymentsQuery {
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MintsQuery {
+    pub plain: Option<bool>,
+}
+
+/// A supported mint and what the gateway knows about it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MintMetadata {
+    pub url: MintUrl,
+    pub name: Option<String>,
+    /// Units of the mint's active keysets
+    pub units: Vec<CurrencyUnit>,
+    /// Whether the mint answered the gateway's last fetch of its info
+    pub reachable: bool,
+    /// Input fee of the mint's active sat keyset, in parts per thousand
+    pub fee_ppk: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthResponse {
+    pub status: String,
+    pub node_connected: bool,
+}
+
+/// Whether the gateway is ready to take payments, and what it is waiting for
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadyResponse {
+    pub ready: bool,
+    pub wallets_initialized: bool,
+    /// Whether info has been fetched from at least one supported mint
+    pub mint_info_loaded: bool,
+    pub node_connected: bool,
+    /// Whether the gateway is shutting down and no longer accepts payments
+    pub draining: bool,
+}
+
+/// What the gateway accepts, for clients to check before building a payment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InfoResponse {
+    pub mints: Vec<MintUrl>,
+    pub accepted_methods: Vec<PaymentMethod>,
+    pub accepted_token_versions: Vec<TokenVersion>,
+    /// Key tokens can be P2PK locked to instead of the payment hash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p2pk_pubkey: Option<PublicKey>,
+    /// Approximate sat value of one unit of each fiat unit with a configured rate
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rates: Vec<FiatRate>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionResponse {
+    pub gateway_version: String,
+    pub cdk_version: String,
+}
+
+/// Machine-readable category of an error, for clients to branch on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    InvalidRequest,
+    InvalidInvoice,
+    UnsupportedMethod,
+    UnsupportedMint,
+    InsufficientFunds,
+    /// The request had no tokens, the payment request says what to send
+    NoTokens,
+    TokenVerificationFailed,
+    /// A mint needed to verify the tokens could not be reached, the request can be retried
+    MintUnreachable,
+    DestinationUnresolved,
+    PaymentFailed,
+    TooLarge,
+    Unauthorized,
+    RateLimited,
+    RequestInProgress,
+    /// An idempotency key was sent again with a different request
+    IdempotencyKeyReused,
+    Timeout,
+    Unavailable,
+    NotFound,
+    Internal,
+}
+
+impl ErrorKind {
+    /// HTTP status errors of this kind are returned with
+    pub fn status(&self) -> u16 {
+        match self {
+            Self::InvalidRequest
+            | Self::InvalidInvoice
+            | Self::UnsupportedMethod
+            | Self::UnsupportedMint
+            | Self::NoTokens
+            | Self::TokenVerificationFailed => 400,
+            Self::Unauthorized => 401,
+            Self::InsufficientFunds => 402,
+            Self::NotFound => 404,
+            Self::RequestInProgress => 409,
+            Self::TooLarge => 413,
+            Self::IdempotencyKeyReused => 422,
+            Self::RateLimited => 429,
+            Self::PaymentFailed | Self::Internal => 500,
+            Self::DestinationUnresolved => 502,
+            Self::MintUnreachable | Self::Unavailable => 503,
+            Self::Timeout => 504,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    /// HTTP status code the error is returned with, set from its kind
+    pub code: u16,
+    pub error_kind: ErrorKind,
+    pub message: String,
+    pub details: Option<String>,
+    /// NUT-18 payment request, sent in the body of 402 and `no_tokens` responses as well as
+    /// the `X-Cashu` header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payment_request: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub breakdown: Option<String>,
+    /// Seconds sent in the `Retry-After` header
+    #[serde(skip)]
+    pub retry_after: Option<u64>,
+}
+
+impl ErrorResponse {
+    /// An error returned with the HTTP status of its kind
+    pub fn new(error_kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            code: error_kind.status(),
+            error_kind,
+            message: message.into(),
+            details: None,
+            payment_request: None,
+            breakdown: None,
+            retry_after: None,
+        }
+    }
+
+    /// A 400 for a request that is malformed or asks for something invalid
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::InvalidRequest, message)
+    }
+
+    /// A 500 for a failure on the gateway's side
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Internal, message)
+    }
+
+    /// Explain the error further with `details`
+    pub fn with_details(mut self, details: impl Into<String>) -> Self {
+        self.details = Some(details.into());
+        self
+    }
+
+    /// Ask the client to pay `payment_request`, it is only sent with 402 and
+    /// `no_tokens` responses
+    pub fn with_payment_request(mut self, payment_request: impl Into<String>) -> Self {
+        self.payment_request = Some(payment_request.into());
+        self
+    }
+
+    /// Attach the human-readable `breakdown` of the payment's cost
+    pub fn with_breakdown(mut self, breakdown: Option<String>) -> Self {
+        self.breakdown = breakdown;
+        self
+    }
+
+    /// Ask the client to wait `secs` seconds before retrying
+    pub fn with_retry_after(mut self, secs: u64) -> Self {
+        self.retry_after = Some(secs);
+        self
+    }
+
+    /// Whether the same request can succeed when it is sent again
+    ///
+    /// Covers requests asking for ecash, which the client retries once it has
+    /// it, and those rejected for the gateway, a mint or the processor being
+    /// busy or unreachable, where nothing was paid.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.error_kind,
+            ErrorKind::InsufficientFunds
+                | ErrorKind::NoTokens
+                | ErrorKind::RateLimited
+                | ErrorKind::MintUnreachable
+                | ErrorKind::Unavailable
+        )
+    }
+
+    /// The error as sent to clients, without details unless `expose_details`
+    fn sanitized(mut self, expose_details: bool) -> Self {
+        if !expose_details {
+            if let Some(details) = self.details.take() {
+                tracing::debug!("{} ({}): {}", self.message, self.code, details);
+            }
+        }
+        self
+    }
+}
+
+impl IntoResponse for ErrorResponse {
+    fn into_response(self) -> Response {
+        // The code set where the error is created is the HTTP status, so
+        // rewording a message can never change the status clients see
+        let status = StatusCode::from_u16(self.code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+        // Copy relevant data for serialization, the payment request only
+        // belongs in the response when the client is asked to pay
+        let asks_for_payment =
+            status == StatusCode::PAYMENT_REQUIRED || self.error_kind == ErrorKind::NoTokens;
+        let serializable_error = ErrorResponse {
+            code: self.code,
+            error_kind: self.error_kind,
+            message: self.message.clone(),
+            details: self.details.clone(),
+            payment_request: self.payment_request.clone().filter(|_| asks_for_payment),
+            breakdown: self.breakdown.clone(),
+            retry_after: self.retry_after,
+        };
+
+        // Details are left out here and added back by `expose_error_details`,
+        // which knows whether the gateway is configured to send them
+        let body = ErrorResponse {
+            details: None,
+            ..serializable_error.clone()
+        };
+        let mut response = (status, Json(body)).into_response();
+        response.extensions_mut().insert(serializable_error);
+
+        // An unavailable gateway or mint is usually back shortly, so those
+        // errors ask for a retry even without a hint of their own
+        let retry_after = self
+            .retry_after
+            .or((status == StatusCode::SERVICE_UNAVAILABLE).then_some(BUSY_RETRY_AFTER_SECS));
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+
+        // If we're asking for payment, add the X-cashu header
+        if asks_for_payment {
+            if let Some(payment_request) = self.payment_request {
+                if let Ok(header_value) = header::HeaderValue::from_str(&payment_request) {
+                    response
+                        .headers_mut()
+                        .insert(header::HeaderName::from_static("x-cashu"), header_value);
+                }
+            }
+        }
+
+        response
+    }
+}
+
+/// Human-readable summary of the amount a client must provide
+///
+/// Built from the same values used for the payment request so the text
+/// never disagrees with the structured amount, `required` is the amount
+/// requested, the invoice amount plus the fees the tokens must cover. The
+/// gateway fee is whatever `required` asks for beyond the invoice and the
+/// routing reserve, so the parts always add up to the total.
+pub fn payment_breakdown(
+    invoice_amount: Amount,
+    routing_reserve: Amount,
+    required: Amount,
+) -> String {
+    let gateway_fee = required
+        .checked_sub(invoice_amount)
+        .and_then(|fees| fees.checked_sub(routing_reserve))
+        .unwrap_or_default();
+    format!(
+        "Invoice: {} sat, routing reserve: {} sat, gateway fee: {} sat, total: {} sat",
+        invoice_amount, routing_reserve, gateway_fee, required
+    )
+}
+
+/// NUT-10 secret tag carrying an overall token validity, independent of the HTLC locktime
+const EXPIRY_TAG: &str = "expiry";
+
+/// Unix timestamp after which the token is no longer valid, if the secret declares one
+fn secret_expiry(secret: &Nut10Secret) -> Option<u64> {
+    secret
+        .secret_data
+        .tags
+        .as_ref()?
+        .iter()
+        .find_map(|tag| match tag.as_slice() {
+            [kind, value, ..] if kind == EXPIRY_TAG => value.parse().ok(),
+            _ => None,
+        })
+}
+
+/// Poll the mint until it reports every proof in `proofs` as unspent
+///
+/// Used before paying, so the invoice is never paid with tokens the gateway
+/// can't claim. Fails as soon as the mint reports any proof as spent, or when
+/// `timeout` elapses while proofs are still pending.
+async fn wait_for_proofs_unspent(
+    wallet: &Wallet,
+    proofs: Proofs,
+    timeout: Duration,
+) -> Result<(), ErrorResponse> {
+    let checked = tokio::time::timeout(timeout, async {
+        loop {
+            let states = wallet
+                .check_proofs_spent(proofs.clone())
+                .await
+                .map_err(|e| {
+                    if is_mint_unreachable(&e) {
+                        ErrorResponse::new(ErrorKind::MintUnreachable, "Mint unreachable")
+                            .with_details(format!(
+                                "Could not check the proofs with the mint: {}",
+                                e
+                            ))
+                    } else {
+                        ErrorResponse::internal("Failed to check token proofs")
+                            .with_details(e.to_string())
+                    }
+                })?;
+
+            if states.iter().any(|p| p.state == cdk::nuts::State::Spent) {
+                return Err(ErrorResponse::new(
+                    ErrorKind::TokenVerificationFailed,
+                    "Token already spent",
+                )
+                .with_details(format!("Tokens from {} are already spent", wallet.mint_url)));
+            }
+
+            if states.iter().all(|p| p.state == cdk::nuts::State::Unspent) {
+                return Ok(());
+            }
+
+            tracing::debug!("Token proofs still pending at mint, waiting");
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    })
+    .await;
+
+    checked.unwrap_or_else(|_| {
+        Err(
+            ErrorResponse::new(ErrorKind::Unavailable, "Token proofs are pending").with_details(
+                format!(
+                    "The mint still reports proofs as pending after {:?}, retry after {} seconds",
+                    timeout, BUSY_RETRY_AFTER_SECS
+                ),
+            ),
+        )
+    })
+}
+
+/// Wallet errors meaning the mint could not be reached
+///
+/// Matched against the lowercased error message, since the wallet reports
+/// HTTP and transport failures as text.
+const MINT_UNREACHABLE_ERRORS: &[&str] = &[
+    "error sending request",
+    "connection refused",
+    "connection reset",
+    "dns error",
+    "timed out",
+    "service unavailable",
+    "bad gateway",
+    "gateway timeout",
+];
+
+/// Whether a wallet call failed because the mint could not be reached
+fn is_mint_unreachable(err: &cdk::Error) -> bool {
+    let message = err.to_string().to_lowercase();
+    MINT_UNREACHABLE_ERRORS
+        .iter()
+        .any(|pattern| message.contains(pattern))
+}
+
+/// Node errors after which the payment is known not to have been sent
+///
+/// Matched against the lowercased error message, since the payment processor
+/// reports lightning failures as text.
+const TRANSIENT_PAYMENT_ERRORS: &[&str] = &[
+    "temporary channel failure",
+    "temporary_channel_failure",
+    "temporarychannelfailure",
+    "no route",
+    "route not found",
+];
+
+/// Whether a failed payment can safely be retried
+///
+/// Only failures where the node reports that no HTLC is outstanding are
+/// retried, anything else may mean the payment was sent.
+fn is_transient_payment_error(err: &cdk_payment::Error) -> bool {
+    match err {
+        cdk_payment::Error::InvoiceAlreadyPaid | cdk_payment::Error::InvoicePaymentPending => false,
+        err => {
+            let message = err.to_string().to_lowercase();
+            TRANSIENT_PAYMENT_ERRORS
+                .iter()
+                .any(|pattern| message.contains(pattern))
+        }
+    }
+}
+
+/// Processor errors meaning it is rate limiting the gateway
+///
+/// Matched like `TRANSIENT_PAYMENT_ERRORS`. A rate limited request is rejected
+/// before the processor acts on it, so the payment was not sent.
+const RATE_LIMIT_ERRORS: &[&str] = &[
+    "resource exhausted",
+    "resourceexhausted",
+    "resource_exhausted",
+    "rate limit",
+    "too many requests",
+];
+
+fn is_rate_limited(err: &cdk_payment::Error) -> bool {
+    let message = err.to_string().to_lowercase();
+    RATE_LIMIT_ERRORS
+        .iter()
+        .any(|pattern| message.contains(pattern))
+}
+
+/// Seconds of a "retry after N" hint in the error message of a rate limiting processor
+fn parse_retry_after(message: &str) -> Option<u64> {
+    let message = message.to_lowercase();
+    let hint = message
+        .find("retry after")
+        .or_else(|| message.find("retry-after"))?;
+    // Both spellings are the same length
+    let digits: String = message[hint + "retry after".len()..]
+        .trim_start_matches([':', ' '])
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect();
+    digits.parse().ok()
+}
+
+/// The error returned for a payment the node failed to make
+///
+/// A processor rate limiting the gateway gets a 429 with its retry hint, or
+/// the configured delay when it gives none, since the client can simply retry.
+fn payment_error(payment_config: &PaymentConfig, err: &cdk_payment::Error) -> ErrorResponse {
+    if is_rate_limited(err) {
+        let retry_after = parse_retry_after(&err.to_string())
+            .unwrap_or(payment_config.processor_retry_after_secs);
+        tracing::warn!(
+            "Payment processor is rate limiting, asking to retry in {}s: {}",
+            retry_after,
+            err
+        );
+        return ErrorResponse::new(ErrorKind::RateLimited, "Payment processor is busy")
+            .with_details(format!("Retry after {} seconds: {}", retry_after, err))
+            .with_retry_after(retry_after);
+    }
+
+    tracing::error!("Payment failed: {}", err);
+    ErrorResponse::new(ErrorKind::PaymentFailed, "Payment failed").with_details(err.to_string())
+}
+
+/// Make a payment, retrying transient failures with exponential backoff
+async fn make_payment_with_retry(
+    gateway: &CdkGateway,
+    config: &PaymentConfig,
+    outgoing_options: OutgoingPaymentOptions,
+) -> Result<MakePaymentResponse, cdk_payment::Error> {
+    let mut attempt = 0;
+
+    loop {
+        let result = gateway
+            .node()
+            .make_payment(&CurrencyUnit::Sat, outgoing_options.clone())
+            .await;
+
+        match result {
+            Err(err) if attempt < config.max_retries && is_transient_payment_error(&err) => {
+                let backoff =
+                    Duration::from_millis(config.backoff_ms.saturating_mul(1 << attempt.min(16)));
+                attempt += 1;
+                tracing::warn!(
+                    "Payment attempt {} failed with transient error, retrying in {:?}: {}",
+                    attempt,
+                    backoff,
+                    err
+                );
+                tokio::time::sleep(backoff).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct GatwayState {
+    pub inner: Arc<CdkGateway>,
+    /// Mints accepted by the gateway, which can change at runtime
+    pub mints: Arc<RwLock<Vec<MintUrl>>>,
+}
+
+impl GatwayState {
+    /// Mints currently accepted by the gateway
+    pub fn supported_mints(&self) -> Vec<MintUrl> {
+        self.mints.read().expect("mints lock poisoned").clone()
+    }
+
+    /// Supported mints whose tokens are currently accepted
+    pub fn accepted_mints(&self) -> Vec<MintUrl> {
+        self.mints_accepted_by(&self.inner.payment_config())
+    }
+
+    /// Supported mints whose tokens are accepted under `payment_config`
+    fn mints_accepted_by(&self, payment_config: &PaymentConfig) -> Vec<MintUrl> {
+        self.supported_mints()
+            .into_iter()
+            .filter(|mint_url| payment_config.is_mint_accepted(mint_url))
+            .collect()
+    }
+}
+
+/// Prefix of the current version of the API
+pub const API_VERSION_PREFIX: &str = "/v1";
+
+pub async fn create_cashu_lsp_router(
+    gateway: Arc<CdkGateway>,
+    mints: Vec<MintUrl>,
+) -> anyhow::Result<Router> {
+    if mints.is_empty() {
+        anyhow::bail!("Cannot create gateway router without any supported mints");
+    }
+
+    // A mint whose wallet failed to build could be advertised but never paid
+    // from, so it is left out rather than failing requests later
+    let mut available = Vec::with_capacity(mints.len());
+    for mint_url in mints {
+        let wallet = gateway
+            .wallets()
+            .get_wallet(&WalletKey::new(mint_url.clone(), CurrencyUnit::Sat))
+            .await;
+        match wallet {
+            Some(_) => available.push(mint_url),
+            None => tracing::error!(
+                "No wallet for supported mint {}, it is unavailable until added again",
+                mint_url
+            ),
+        }
+    }
+    let mints = available;
+    if mints.is_empty() {
+        anyhow::bail!("None of the supported mints has a wallet");
+    }
+
+    tracing::debug!(
+        "Creating CDK Gateway router with {} supported mints",
+        mints.len()
+    );
+    let gateway_state = GatwayState {
+        inner: gateway,
+        mints: Arc::new(RwLock::new(mints)),
     };
 
-    // Build the payment request with the correct amount for any error responses
-    let payment_request = PaymentRequestBuilder::default()
-        .unit(CurrencyUnit::Sat)
-        .amount(u64::from(amount_to_pay_sat))
-        .mints(state.mints.clone())
-        .nut10(nut10.into())
-        .build();
+    tokio::spawn(refresh_mint_info(
+        gateway_state.clone(),
+        gateway_state.inner.server_cancel.child_token(),
+    ));
+    tokio::spawn(refresh_rates(
+        gateway_state.inner.rates().clone(),
+        gateway_state.inner.server_cancel.child_token(),
+    ));
+
+    let max_body_bytes = gateway_state.inner.settings().server.max_body_bytes;
+    let mut protected = Router::new()
+        .route(
+            "/payment",
+            post(post_melt_request)
+                // Replace axum's default limit so the configured one applies
+                .layer(DefaultBodyLimit::disable())
+                .layer(RequestBodyLimitLayer::new(max_body_bytes)),
+        )
+        .route(
+            "/payment/stream",
+            post(post_melt_request_stream)
+                .layer(DefaultBodyLimit::disable())
+                .layer(RequestBodyLimitLayer::new(max_body_bytes)),
+        )
+        .route(
+            "/payment/batch",
+            post(post_melt_batch)
+                .layer(DefaultBodyLimit::disable())
+                .layer(RequestBodyLimitLayer::new(max_body_bytes)),
+        )
+        .route(
+            "/payment/dry-run",
+            post(post_melt_dry_run)
+                .layer(DefaultBodyLimit::disable())
+                .layer(RequestBodyLimitLayer::new(max_body_bytes)),
+        )
+        .route("/quote", post(post_quote))
+        .route("/quote/{id}", get(get_quote))
+        .route("/balances", get(get_balances))
+        .route("/payments", get(get_payments));
+
+    // Admin routes are never exposed without authentication
+    if gateway_state.inner.settings().auth.api_keys.is_empty() {
+        tracing::info!("No API keys configured, admin endpoints are disabled");
+    } else {
+        protected = protected.merge(admin_routes());
+    }
+
+    protected = protected.route_layer(middleware::from_fn_with_state(
+        gateway_state.clone(),
+        require_api_key,
+    ));
+
+    // Added last so it runs before authentication and also limits key guessing
+    if let Some(rate_limit_config) = &gateway_state.inner.settings().rate_limit {
+        tracing::info!(
+            "Rate limiting payments to {} requests per minute with a burst of {}",
+            rate_limit_config.requests_per_minute,
+            rate_limit_config.burst
+        );
+        protected = protected.route_layer(middleware::from_fn_with_state(
+            gateway_state.clone(),
+            rate_limit,
+        ));
+    }
+
+    let cors = cors_layer(&gateway_state.inner.settings().cors)?;
+
+    let api = Router::new()
+        .merge(protected)
+        .route("/mints", get(get_mints))
+        .route("/health", get(get_health))
+        .route("/ready", get(get_ready))
+        .route("/info", get(get_info))
+        .route("/version", get(get_version))
+        .route("/openapi.json", get(get_openapi));
+
+    // The unprefixed routes are kept for existing clients until a breaking /v2
+    let mut router = Router::new()
+        .nest(API_VERSION_PREFIX, api.clone())
+        .merge(api);
+
+    if gateway_state.inner.settings().metrics.enabled {
+        router = router.route("/metrics", get(get_metrics));
+    }
+
+    // Nesting at the root isn't allowed, an empty base path serves the routes as they are
+    let base_path = gateway_state
+        .inner
+        .settings()
+        .server
+        .base_path
+        .as_deref()
+        .map(|base_path| base_path.trim_end_matches('/'))
+        .filter(|base_path| !base_path.is_empty());
+    if let Some(base_path) = base_path {
+        tracing::info!("Serving routes under {}", base_path);
+        router = Router::new().nest(base_path, router);
+    }
+
+    if gateway_state.inner.settings().server.log_requests {
+        router = router.layer(middleware::from_fn_with_state(max_body_bytes, log_requests));
+    }
+
+    let router = router
+        .layer(middleware::from_fn_with_state(
+            gateway_state.clone(),
+            expose_error_details,
+        ))
+        .layer(cors)
+        .with_state(gateway_state);
+
+    Ok(router)
+}
+
+/// Send the details of error responses when the gateway is configured to,
+/// otherwise they are only logged
+async fn expose_error_details(
+    State(state): State<GatwayState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    let Some(error) = response.extensions_mut().remove::<ErrorResponse>() else {
+        return response;
+    };
+
+    let error = error.sanitized(state.inner.settings().server.expose_error_details);
+    if error.details.is_none() {
+        return response;
+    }
+    let (parts, _) = response.into_parts();
+    Response::from_parts(parts, Json(error).into_response().into_body())
+}
+
+/// Supported mints with the metadata the gateway has cached for them
+///
+/// `?plain=true` returns only the mint URLs.
+pub async fn get_mints(
+    State(state): State<GatwayState>,
+    Query(query): Query<MintsQuery>,
+) -> Result<Response, ErrorResponse> {
+    tracing::debug!("Request received for /mints endpoint");
+    if query.plain.unwrap_or_default() {
+        return Ok(Json(state.accepted_mints()).into_response());
+    }
+
+    let mut mints = Vec::new();
+    for url in state.accepted_mints() {
+        mints.push(state.inner.mint_metadata(url).await);
+    }
+
+    Ok(Json(mints).into_response())
+}
+
+/// Report that the gateway is alive
+///
+/// Always returns a 200 while the server responds, so a disconnected payment
+/// node doesn't get the gateway restarted. Use `/ready` to route traffic.
+pub async fn get_health(State(state): State<GatwayState>) -> impl IntoResponse {
+    let node_connected = state.inner.node_health().is_connected();
+    let health = if node_connected { "ok" } else { "degraded" };
+
+    Json(HealthResponse {
+        status: health.to_string(),
+        node_connected,
+    })
+}
+
+/// Report whether the gateway can take payments
+///
+/// Returns a 503 until the wallets are initialized, info has been fetched
+/// from a mint and the payment node is connected, and again while the node
+/// is disconnected or the gateway is shutting down.
+pub async fn get_ready(State(state): State<GatwayState>) -> impl IntoResponse {
+    let wallets_initialized = !state.inner.wallets().get_wallets().await.is_empty();
+    let mint_info_loaded = state.inner.mint_info().has_reachable_mint();
+    let node_connected = state.inner.node_health().is_connected();
+    let draining = state.inner.payments().is_draining();
+
+    let ready = wallets_initialized && mint_info_loaded && node_connected && !draining;
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(ReadyResponse {
+            ready,
+            wallets_initialized,
+            mint_info_loaded,
+            node_connected,
+            draining,
+        }),
+    )
+}
+
+pub async fn get_info(State(state): State<GatwayState>) -> Json<InfoResponse> {
+    let payment_config = state.inner.payment_config();
+    Json(InfoResponse {
+        mints: state.mints_accepted_by(&payment_config),
+        accepted_methods: payment_config.accepted_methods.clone(),
+        accepted_token_versions: payment_config.accepted_token_versions.clone(),
+        p2pk_pubkey: state.inner.p2pk_pubkey(),
+        rates: state.inner.rates().rates(),
+    })
+}
+
+/// Versions of the gateway and the cdk it was built with
+pub async fn get_version() -> Json<VersionResponse> {
+    Json(VersionResponse {
+        gateway_version: env!("CARGO_PKG_VERSION").to_string(),
+        cdk_version: env!("CDK_VERSION").to_string(),
+    })
+}
+
+pub async fn get_balances(
+    State(state): State<GatwayState>,
+) -> Result<Json<Vec<WalletBalance>>, ErrorResponse> {
+    tracing::debug!("Request received for /balances endpoint");
+    let mut balances = vec![];
+
+    for wallet in state.inner.wallets().get_wallets().await {
+        let balance = wallet.total_balance().await.map_err(|e| {
+            tracing::error!(
+                "Failed to get balance for wallet [{}]: {}",
+                wallet.mint_url,
+                e
+            );
+            ErrorResponse::internal("Failed to get wallet balance").with_details(e.to_string())
+        })?;
+
+        balances.push(WalletBalance {
+            mint_url: wallet.mint_url.clone(),
+            unit: wallet.unit.clone(),
+            balance,
+        });
+    }
+
+    Ok(Json(balances))
+}
+
+pub async fn get_payments(
+    State(state): State<GatwayState>,
+    Query(query): Query<PaymentsQuery>,
+) -> Result<Json<Vec<PaymentRecord>>, ErrorResponse> {
+    tracing::debug!("Request received for /payments endpoint");
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAYMENTS_LIMIT)
+        .min(MAX_PAYMENTS_LIMIT);
+    let offset = query.offset.unwrap_or_default();
+
+    let payments = state
+        .inner
+        .payment_store()
+        .list(limit, offset)
+        .map_err(|e| {
+            tracing::error!("Failed to read payment history: {}", e);
+            ErrorResponse::internal("Failed to read payment history")
+        })?;
+
+    Ok(Json(payments))
+}
+
+pub async fn get_metrics(
+    State(state): State<GatwayState>,
+) -> Result<impl IntoResponse, ErrorResponse> {
+    tracing::debug!("Request received for /metrics endpoint");
+    let metrics = state.inner.metrics().encode().map_err(|e| {
+        ErrorResponse::internal("Failed to encode metrics").with_details(e.to_string())
+    })?;
+
+    Ok(([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], metrics))
+}
+
+pub async fn post_melt_request(
+    State(state): State<GatwayState>,
+    headers: HeaderMap,
+    extensions: Extensions,
+    Json(payload): Json<MeltRequest>,
+) -> impl IntoResponse {
+    // Every log line for this request is emitted inside a span carrying its ID
+    let request_id = Uuid::new_v4();
+    let span = tracing::info_span!("payment", %request_id);
+
+    let result = handle_melt_request(
+        state,
+        &headers,
+        &extensions,
+        payload,
+        request_id,
+        PaymentProgress::default(),
+    )
+    .instrument(span)
+    .await;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        REQUEST_ID_HEADER,
+        HeaderValue::from_str(&request_id.to_string()).expect("a UUID is a valid header value"),
+    );
+
+    (headers, result)
+}
+
+/// Pay several requests from one set of tokens
+///
+/// Every payment is checked and the tokens claimed before anything is paid,
+/// then the payments are made in order. A failed payment doesn't stop the
+/// rest, the response reports the outcome of each one.
+pub async fn post_melt_batch(
+    State(state): State<GatwayState>,
+    Json(payload): Json<BatchMeltRequest>,
+) -> impl IntoResponse {
+    let request_id = Uuid::new_v4();
+    let span = tracing::info_span!("batch", %request_id);
+
+    // The tokens are claimed before paying, so the batch runs in its own task
+    // and a client disconnecting can't cancel it between payments
+    let result = tokio::spawn(process_melt_batch(state, payload, request_id).instrument(span))
+        .await
+        .unwrap_or_else(|e| {
+            tracing::error!("Batch payment task failed: {}", e);
+            Err(ErrorResponse::internal("Internal error processing payment"))
+        });
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        REQUEST_ID_HEADER,
+        HeaderValue::from_str(&request_id.to_string()).expect("a UUID is a valid header value"),
+    );
+
+    (headers, result)
+}
+
+/// Validate a payment request exactly like `/payment` without paying it
+///
+/// Nothing is sent to the payment node or the mints beyond DLEQ verification,
+/// so integrators can test how they build tokens without moving funds.
+pub async fn post_melt_dry_run(
+    State(state): State<GatwayState>,
+    Json(payload): Json<MeltRequest>,
+) -> Result<Json<DryRunResponse>, ErrorResponse> {
+    tracing::debug!("Dry run payment request received");
+    let payment_config = state.inner.payment_config();
+    let verified = verify_melt_request(
+        &state,
+        &payment_config,
+        payload,
+        &PaymentProgress::default(),
+    )
+    .await?;
+
+    let mut mints: Vec<MintUrl> = Vec::new();
+    for mint_url in verified.used_mints {
+        if !mints.contains(&mint_url) {
+            mints.push(mint_url);
+        }
+    }
+
+    Ok(Json(DryRunResponse {
+        would_pay: WouldPay {
+            amount: verified.amount_to_pay_sat,
+            provided: verified.total_amount,
+            change: verified
+                .total_amount
+                .checked_sub(verified.amount_to_pay_sat)
+                .unwrap_or_default(),
+            mints,
+        },
+    }))
+}
+
+/// Estimate the cost of paying a request
+///
+/// The routing fee is estimated by the node, falling back to the configured
+/// reserve when the node can't estimate it.
+pub async fn post_quote(
+    State(state): State<GatwayState>,
+    Json(payload): Json<QuoteRequest>,
+) -> Result<Json<QuoteResponse>, ErrorResponse> {
+    tracing::debug!("Quote request received");
+    let payment_config = state.inner.payment_config();
+    if payload.method == PaymentMethod::Bolt12
+        || !payment_config.accepted_methods.contains(&payload.method)
+    {
+        return Err(ErrorResponse::new(
+            ErrorKind::UnsupportedMethod,
+            "Payment method not supported",
+        )
+        .with_details("This gateway does not accept the payment method"));
+    }
+
+    let bolt11 = bolt11_for_request(
+        &state,
+        &payment_config,
+        payload.method,
+        &payload.request,
+        payload.amount,
+    )
+    .await?;
+    let amount = request_amount(&bolt11, payload.amount)?;
+    check_payment_cap(&payment_config, amount)?;
+
+    let payment_hash = bolt11.payment_hash().to_string();
+    let melt_options = bolt11
+        .amount_milli_satoshis()
+        .is_none()
+        .then(|| amountless_melt_options(amount));
+    let options = OutgoingPaymentOptions::Bolt11(Box::new(Bolt11OutgoingPaymentOptions {
+        bolt11,
+        max_fee_amount: None,
+        timeout_secs: None,
+        melt_options,
+    }));
+
+    let (fee, fee_source) = match state
+        .inner
+        .node()
+        .get_payment_quote(&CurrencyUnit::Sat, options)
+        .await
+    {
+        Ok(quote) => (quote.fee, FeeSource::Node),
+        Err(e) => {
+            tracing::debug!("Node could not estimate the fee, using the reserve: {}", e);
+            let reserve = payment_config.fee_reserve(u64::from(amount));
+            (Amount::from(reserve), FeeSource::Reserve)
+        }
+    };
+
+    let quote =
+        state
+            .inner
+            .quotes()
+            .reserve(payment_hash, amount, fee, payment_config.quote_ttl_secs);
+
+    Ok(Json(QuoteResponse {
+        amount,
+        fee,
+        total: quote.total,
+        fee_source,
+        quote_id: quote.quote_id,
+        expires_at: quote.expires_at,
+        fiat_totals: state.inner.rates().convert(quote.total),
+        breakdown: payment_config
+            .include_breakdown
+            .then(|| payment_breakdown(amount, fee, quote.total)),
+    }))
+}
+
+/// Status of a quote and the terms it reserved
+pub async fn get_quote(
+    State(state): State<GatwayState>,
+    Path(quote_id): Path<Uuid>,
+) -> Result<Json<ReservedQuote>, ErrorResponse> {
+    state
+        .inner
+        .quotes()
+        .get(&quote_id)
+        .map(Json)
+        .ok_or(ErrorResponse::new(ErrorKind::NotFound, "Unknown quote"))
+}
+
+/// Handle a payment request, streaming its progress as server-sent events
+///
+/// Emits a `stage` event as each stage of the payment starts, followed by a
+/// final `complete` event with the payment response or an `error` event.
+pub async fn post_melt_request_stream(
+    State(state): State<GatwayState>,
+    headers: HeaderMap,
+    extensions: Extensions,
+    Json(payload): Json<MeltRequest>,
+) -> impl IntoResponse {
+    let request_id = Uuid::new_v4();
+    let span = tracing::info_span!("payment", %request_id);
+
+    let expose_details = state.inner.settings().server.expose_error_details;
+    let (tx, rx) = mpsc::unbounded_channel();
+    let progress = PaymentProgress { tx: Some(tx) };
+    let task = tokio::spawn(
+        async move {
+            handle_melt_request(state, &headers, &extensions, payload, request_id, progress).await
+        }
+        .instrument(span),
+    );
+
+    let request_id =
+        HeaderValue::from_str(&request_id.to_string()).expect("a UUID is a valid header value");
+
+    (
+        [(REQUEST_ID_HEADER, request_id)],
+        Sse::new(payment_events(rx, task, expose_details)).keep_alive(KeepAlive::default()),
+    )
+}
+
+/// Stream stage events until the payment task finishes, then its result
+fn payment_events(
+    rx: mpsc::UnboundedReceiver<PaymentStage>,
+    task: JoinHandle<Result<Json<MeltResponse>, ErrorResponse>>,
+    expose_details: bool,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    futures::stream::unfold(Some((rx, task)), |state| async move {
+        let (mut rx, mut task) = state?;
+
+        tokio::select! {
+            // Stages already reported are sent before the result
+            biased;
+            Some(stage) = rx.recv() => {
+                let event = Event::default()
+                    .event("stage")
+                    .json_data(stage)
+                    .expect("stages serialize to JSON");
+                Some((Ok(event), Some((rx, task))))
+            }
+            result = &mut task => {
+                let result = result.unwrap_or_else(|e| {
+                    tracing::error!("Payment task failed: {}", e);
+                    Err(ErrorResponse::internal("Internal error processing payment"))
+                });
+                let event = match result {
+                    Ok(Json(response)) => Event::default().event("complete").json_data(response),
+                    Err(error) => Event::default().event("error").json_data(error.sanitized(expose_details)),
+                }
+                .expect("payment responses serialize to JSON");
+                Some((Ok(event), None))
+            }
+        }
+    })
+}
+
+/// Check the idempotency key of a payment request and run it if it is new
+async fn handle_melt_request(
+    state: GatwayState,
+    headers: &HeaderMap,
+    extensions: &Extensions,
+    payload: MeltRequest,
+    request_id: Uuid,
+    progress: PaymentProgress,
+) -> Result<Json<MeltResponse>, ErrorResponse> {
+    let idempotency_key = headers
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|value| value.to_str().ok());
+
+    let idempotency = match idempotency_key {
+        Some(key) => {
+            let client = client_key(&state.inner.settings().auth.api_keys, headers, extensions);
+            match begin_idempotent_request(&state.inner, &client, key, &payload)? {
+                IdempotencyStatus::Cached(cached) => return cached.into_result(),
+                IdempotencyStatus::New(guard) => Some(guard),
+            }
+        }
+        None => None,
+    };
+
+    run_melt_request(state, payload, request_id, idempotency, progress).await
+}
+
+/// Run a payment request, bounded by the configured request timeout
+///
+/// When an idempotency guard is given the outcome is stored against its key,
+/// including for payments that complete after the timeout has elapsed.
+async fn run_melt_request(
+    state: GatwayState,
+    payload: MeltRequest,
+    request_id: Uuid,
+    idempotency: Option<IdempotencyGuard>,
+    progress: PaymentProgress,
+) -> Result<Json<MeltResponse>, ErrorResponse> {
+    let started = Instant::now();
+    let gateway = state.inner.clone();
+    let timeout = Duration::from_secs(gateway.settings().server.request_timeout_secs);
+
+    // The payment runs in its own task so that hitting the timeout only abandons
+    // the response, a payment that may already be in flight still completes and
+    // its tokens are claimed rather than being cancelled midway
+    let task_gateway = gateway.clone();
+    let invoice = match payload.method {
+        PaymentMethod::Bolt11 => payload.request.parse::<Bolt11Invoice>().ok(),
+        _ => None,
+    };
+    let mut payment = tokio::spawn(
+        async move {
+            let result = process_melt_request(state, payload, request_id, progress).await;
+            if let Some(idempotency) = idempotency {
+                idempotency.complete(task_gateway.payment_store(), &result);
+            }
+
+            // A 402 asks for more ecash and is part of building a payment, not a failure
+            if let Err(e) = &result {
+                if e.code != 402 {
+                    task_gateway.notify_payment(PaymentEvent::failed(
+                        request_id,
+                        invoice
+                            .as_ref()
+                            .and_then(|invoice| invoice.amount_milli_satoshis())
+                            .map(|msat| Amount::from(msat / 1_000)),
+                        invoice.map(|invoice| invoice.payment_hash().to_string()),
+                        e,
+                    ));
+                }
+            }
+            result
+        }
+        .instrument(tracing::Span::current()),
+    );
+
+    let result = match tokio::time::timeout(timeout, &mut payment).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => {
+            tracing::error!("Payment task failed: {}", e);
+            Err(ErrorResponse::internal("Internal error processing payment"))
+        }
+        Err(_) => {
+            tracing::error!(
+                "Payment request timed out after {:?}, continuing in the background",
+                timeout
+            );
+
+            tokio::spawn(
+                async move {
+                    match payment.await {
+                        Ok(Ok(_)) => tracing::warn!("Timed out payment completed successfully"),
+                        Ok(Err(e)) => tracing::error!("Timed out payment failed: {}", e.message),
+                        Err(e) => tracing::error!("Timed out payment task failed: {}", e),
+                    }
+                }
+                .instrument(tracing::Span::current()),
+            );
+
+            Err(
+                ErrorResponse::new(ErrorKind::Timeout, "Payment request timed out")
+                    .with_details("The payment state is unknown, it may still complete."),
+            )
+        }
+    };
+
+    gateway
+        .metrics()
+        .record_request(result.is_ok(), started.elapsed());
+
+    result
+}
+
+/// Split `change` across mints by their balance, preferring them in order
+///
+/// Change is returned from the first mint when it can cover all of it, and
+/// only spills over to the next ones when it can't. Returns the amount to send
+/// from each mint and what none of them could cover.
+fn allocate_change(
+    change: Amount,
+    balances: &[(MintUrl, Amount)],
+) -> (Vec<(MintUrl, Amount)>, Amount) {
+    let mut remaining = change;
+    let mut allocation = Vec::new();
+
+    for (mint_url, balance) in balances {
+        if remaining == Amount::ZERO {
+            break;
+        }
+        let amount = remaining.min(*balance);
+        if amount > Amount::ZERO {
+            allocation.push((mint_url.clone(), amount));
+            remaining = remaining.checked_sub(amount).unwrap_or_default();
+        }
+    }
+
+    (allocation, remaining)
+}
+
+/// Record change that could not be returned, so it can be claimed later
+fn record_owed_change(state: &GatwayState, request_id: Uuid, mint_url: &MintUrl, amount: Amount) {
+    let owed = OwedChange {
+        request_id,
+        mint_url: mint_url.clone(),
+        amount,
+        timestamp: unix_time(),
+    };
+    if let Err(e) = state.inner.payment_store().add_owed_change(&owed) {
+        tracing::error!("Failed to record owed change: {}", e);
+    }
+}
+
+/// Create a change token of `amount` from `wallet`
+///
+/// With `minimize_proofs` the token holds the fewest proofs possible, one per
+/// power of two in `amount`, swapping at the mint if the wallet's proofs don't
+/// already add up that way.
+async fn send_change(
+    wallet: &Wallet,
+    amount: Amount,
+    minimize_proofs: bool,
+) -> anyhow::Result<Token> {
+    let options = if minimize_proofs {
+        SendOptions {
+            amount_split_target: SplitTarget::None,
+            max_proofs: Some(amount.split().len()),
+            ..Default::default()
+        }
+    } else {
+        SendOptions::default()
+    };
+
+    let prepared = wallet.prepare_send(amount, options).await?;
+    Ok(wallet.send(prepared, None).await?)
+}
+
+/// Change returned to the requester, and what could not be
+#[derive(Debug, Default)]
+struct ReturnedChange {
+    /// Encoded change tokens
+    tokens: Vec<String>,
+    /// Change the tokens were created for
+    returned: Amount,
+    /// Total value of the tokens
+    sent: Amount,
+    /// Change recorded as owed because it could not be created
+    owed: Amount,
+    by_mint: HashMap<MintUrl, Amount>,
+    /// Why some of the change is owed
+    error: Option<String>,
+}
+
+/// Return `change_amount` from the mints the tokens came from
+///
+/// The invoice is already paid, so failing to create change must not fail the
+/// request. Change that can't be returned is recorded as owed instead.
+async fn return_change(
+    state: &GatwayState,
+    payment_config: &PaymentConfig,
+    request_id: Uuid,
+    wallets: &HashMap<MintUrl, Wallet>,
+    used_mints: &[MintUrl],
+    change_amount: Amount,
+) -> ReturnedChange {
+    let mut returned = ReturnedChange::default();
+    if change_amount == Amount::ZERO {
+        return returned;
+    }
+
+    let mut balances: Vec<(MintUrl, Amount)> = Vec::new();
+    for mint_url in used_mints {
+        if balances.iter().any(|(mint, _)| mint == mint_url) {
+            continue;
+        }
+        let balance = wallets[mint_url].total_balance().await.unwrap_or_else(|e| {
+            tracing::warn!("Could not get the balance at {}: {}", mint_url, e);
+            Amount::ZERO
+        });
+        balances.push((mint_url.clone(), balance));
+    }
+
+    let (allocation, shortfall) = allocate_change(change_amount, &balances);
+    if shortfall > Amount::ZERO {
+        // Nothing left to send it from, so the rest is owed at the preferred mint
+        let mint_url = balances[0].0.clone();
+        tracing::error!(
+            "Not enough balance to return change of {}, {} is owed at {}",
+            change_amount,
+            shortfall,
+            mint_url
+        );
+        record_owed_change(state, request_id, &mint_url, shortfall);
+        returned.owed += shortfall;
+        returned.error = Some(format!(
+            "Not enough balance to return {} of the change, the gateway has recorded the amount owed",
+            shortfall
+        ));
+    }
+
+    for (mint_url, amount) in allocation {
+        let wallet = &wallets[&mint_url];
+
+        let token = match send_change(wallet, amount, payment_config.minimize_change_proofs).await {
+            Ok(token) => token,
+            Err(e) => {
+                tracing::error!(
+                    "Failed to create change of {} at {}: {}",
+                    amount,
+                    mint_url,
+                    e
+                );
+                record_owed_change(state, request_id, &mint_url, amount);
+                returned.owed += amount;
+                returned.error = Some(format!(
+                    "Failed to create change of {} at {}, the gateway has recorded the amount owed",
+                    amount, mint_url
+                ));
+                continue;
+            }
+        };
+
+        state.inner.metrics().record_change(&mint_url, amount);
+
+        returned.returned += amount;
+        returned.sent += token.value().unwrap_or_default();
+        *returned.by_mint.entry(mint_url).or_default() += amount;
+        returned.tokens.push(token.to_string());
+    }
+
+    returned
+}
+
+/// Options paying `amount` sat of an invoice without an amount
+fn amountless_melt_options(amount: Amount) -> MeltOptions {
+    MeltOptions::new_amountless(u64::from(amount) * 1_000)
+}
+
+/// Whether the hex encoded `preimage` hashes to `hash`
+fn preimage_matches(preimage: &str, hash: &Sha256Hash) -> bool {
+    Vec::<u8>::from_hex(preimage).is_ok_and(|preimage| Sha256Hash::hash(&preimage) == *hash)
+}
+
+/// Total value of `tokens` from each mint, in the order the mints first appear
+fn value_by_mint(tokens: &[Token]) -> Vec<(MintUrl, Amount)> {
+    let mut values: Vec<(MintUrl, Amount)> = Vec::new();
+    for token in tokens {
+        let mint_url = token.mint_url().unwrap();
+        let value = token.value().unwrap();
+        match values.iter_mut().find(|(mint, _)| mint == &mint_url) {
+            Some((_, amount)) => *amount += value,
+            None => values.push((mint_url, value)),
+        }
+    }
+    values
+}
+
+/// Change due to the requester after `spent` was paid with `received` in ecash
+///
+/// Spending more than was received means the gateway covered the difference.
+/// No change is due then, and the deficit is logged and counted as a loss.
+fn change_due(metrics: &Metrics, request_id: Uuid, received: Amount, spent: Amount) -> Amount {
+    match received.checked_sub(spent) {
+        Some(change) => change,
+        None => {
+            let deficit = spent.checked_sub(received).unwrap_or_default();
+            tracing::error!(
+                "Payment for request {} spent {} but only {} was received, the gateway lost {}",
+                request_id,
+                spent,
+                received,
+                deficit
+            );
+            metrics.record_overpayment(deficit);
+            Amount::ZERO
+        }
+    }
+}
+
+/// Safety net against change calculation bugs: change can never exceed what
+/// was received, and what was sent or recorded as owed must add up to what
+/// was expected to be returned
+///
+/// Returns whether the change was as expected.
+pub(crate) fn check_change(
+    metrics: &Metrics,
+    request_id: Uuid,
+    received: Amount,
+    change_due: Amount,
+    expected: Amount,
+    accounted: Amount,
+) -> bool {
+    if change_due > received || accounted != expected {
+        tracing::warn!(
+            "Unexpected change for request {}: received {}, change due {}, expected {}, sent or owed {}",
+            request_id,
+            received,
+            change_due,
+            expected,
+            accounted
+        );
+        metrics.record_change_anomaly();
+        return false;
+    }
+    true
+}
+
+/// Ecash kept by the gateway from a payment, by mint
+///
+/// The amount spent on lightning is attributed to the mints in the order their
+/// tokens were given, whatever each mint received beyond its share of the
+/// spend and the change it returned was kept by the gateway.
+fn fee_earnings(
+    tokens: &[Token],
+    total_spent: Amount,
+    change_by_mint: &HashMap<MintUrl, Amount>,
+) -> Vec<(MintUrl, Amount)> {
+    let mut unallocated_spend = total_spent;
+    value_by_mint(tokens)
+        .into_iter()
+        .filter_map(|(mint_url, received)| {
+            let spent = received.min(unallocated_spend);
+            unallocated_spend = unallocated_spend.checked_sub(spent).unwrap_or_default();
+
+            let change = change_by_mint.get(&mint_url).copied().unwrap_or_default();
+            let kept = received
+                .checked_sub(spent)
+                .and_then(|remaining| remaining.checked_sub(change))
+                .unwrap_or_default();
+
+            (kept > Amount::ZERO).then_some((mint_url, kept))
+        })
+        .collect()
+}
+
+/// Verify the DLEQ proofs and spending conditions of a single token
+///
+/// Returns the mint the token is from.
+async fn verify_token(
+    state: &GatwayState,
+    payment_config: &PaymentConfig,
+    wallet: &Wallet,
+    token: &Token,
+    hash: &Sha256Hash,
+    payment_request: &PaymentRequest,
+    breakdown: &Option<String>,
+) -> Result<MintUrl, ErrorResponse> {
+    let mint_url = wallet.mint_url.clone();
+
+    if payment_config.is_dleq_trusted(&mint_url) {
+        tracing::debug!("Skipping DLEQ verification for trusted mint {}", mint_url);
+    } else {
+        // Tokens may be encoded without DLEQ proofs, report that separately
+        // from proofs that are present but invalid
+        if token.proofs().iter().any(|proof| proof.dleq.is_none()) {
+            tracing::debug!("Token from {} is missing DLEQ proofs", mint_url);
+            return Err(ErrorResponse::new(
+                ErrorKind::TokenVerificationFailed,
+                "Token missing DLEQ proof; a DLEQ-carrying token is required",
+            )
+            .with_payment_request(payment_request.to_string())
+            .with_breakdown(breakdown.clone()));
+        }
+
+        let verified = wallet.verify_token_dleq(token).await;
+        state
+            .inner
+            .metrics()
+            .record_dleq_verification(&mint_url, verified.is_ok());
+        verified.map_err(|e| {
+            // The mint's keys may have to be fetched to check the proofs, a
+            // mint that can't be reached says nothing about the token
+            if is_mint_unreachable(&e) {
+                tracing::warn!("Could not reach {} to verify DLEQ proofs: {}", mint_url, e);
+                return ErrorResponse::new(
+                    ErrorKind::MintUnreachable,
+                    "Mint could not be reached to verify the token, try again later",
+                )
+                .with_details(format!("DLEQ verification error: {}", e))
+                .with_payment_request(payment_request.to_string())
+                .with_breakdown(breakdown.clone());
+            }
+
+            tracing::error!("Invalid dleq: {}", e);
+            ErrorResponse::new(
+                ErrorKind::TokenVerificationFailed,
+                "Token verification failed",
+            )
+            .with_details(format!("DLEQ verification error: {}", e))
+            .with_payment_request(payment_request.to_string())
+            .with_breakdown(breakdown.clone())
+        })?;
+    }
+
+    for proof in token.proofs() {
+        let secret: Nut10Secret = proof.secret.try_into().map_err(|err| {
+            tracing::error!("Invalid secret: {}", err);
+            ErrorResponse::new(
+                ErrorKind::TokenVerificationFailed,
+                "Token verification failed",
+            )
+            .with_details(format!("Secret validation failed: {}", err))
+            .with_payment_request(payment_request.to_string())
+            .with_breakdown(breakdown.clone())
+        })?;
+
+        if let Some(expiry) = secret_expiry(&secret) {
+            if expiry <= unix_time() {
+                tracing::debug!("Token validity expired at {}", expiry);
+                return Err(ErrorResponse::new(
+                    ErrorKind::TokenVerificationFailed,
+                    "Token has expired",
+                )
+                .with_details(format!("Token was valid until {}", expiry))
+                .with_payment_request(payment_request.to_string())
+                .with_breakdown(breakdown.clone()));
+            }
+        }
+
+        let secret_spending_conditions: SpendingConditions = secret.try_into().map_err(|err| {
+            tracing::debug!("Invalid spending conditions: {}", err);
+            ErrorResponse::new(
+                ErrorKind::TokenVerificationFailed,
+                "Invalid spending conditions",
+            )
+            .with_details(err.to_string())
+            .with_payment_request(payment_request.to_string())
+            .with_breakdown(breakdown.clone())
+        })?;
+
+        match secret_spending_conditions {
+            SpendingConditions::HTLCConditions { data, conditions } => {
+                if data != *hash {
+                    tracing::debug!("Payment hash does not equal token hash");
+                    return Err(ErrorResponse::new(
+                        ErrorKind::TokenVerificationFailed,
+                        "Token hash does not match payment hash",
+                    )
+                    .with_payment_request(payment_request.to_string())
+                    .with_breakdown(breakdown.clone()));
+                }
+
+                let locktime = conditions.and_then(|conditions| conditions.locktime);
+                if locktime.is_none() && payment_config.require_locktime {
+                    tracing::debug!("Rejecting HTLC token without a locktime");
+                    return Err(ErrorResponse::new(
+                        ErrorKind::TokenVerificationFailed,
+                        "Token has no lock time",
+                    )
+                    .with_details("This gateway only accepts HTLC tokens with a locktime")
+                    .with_payment_request(payment_request.to_string())
+                    .with_breakdown(breakdown.clone()));
+                }
+                check_locktime(locktime, payment_config, payment_request, breakdown)?;
+            }
+            SpendingConditions::P2PKConditions { data, conditions } => {
+                // Tokens locked to the gateway's key are claimed with it
+                // instead of the preimage
+                if state.inner.p2pk_pubkey() != Some(data) {
+                    tracing::debug!("Token is P2PK locked to a key other than the gateway's");
+                    return Err(ErrorResponse::new(
+                        ErrorKind::TokenVerificationFailed,
+                        "Token verification failed",
+                    )
+                    .with_details("Token is locked to a public key other than the gateway's")
+                    .with_payment_request(payment_request.to_string())
+                    .with_breakdown(breakdown.clone()));
+                }
+
+                if let Some(conditions) = conditions {
+                    if conditions.num_sigs.unwrap_or(1) > 1 {
+                        tracing::debug!("Token requires {:?} signatures", conditions.num_sigs);
+                        return Err(ErrorResponse::new(
+                            ErrorKind::TokenVerificationFailed,
+                            "Token requires more signatures than the gateway can provide",
+                        )
+                        .with_payment_request(payment_request.to_string())
+                        .with_breakdown(breakdown.clone()));
+                    }
+                    check_locktime(
+                        conditions.locktime,
+                        payment_config,
+                        payment_request,
+                        breakdown,
+                    )?;
+                }
+            }
+        }
+    }
+
+    Ok(mint_url)
+}
+
+/// Reject a locktime that would let the sender reclaim a token before the
+/// gateway has had time to claim it
+///
+/// A locktime beyond `max_locktime_secs` is rejected too, a token locked that
+/// far out is likely malformed or held back to be replayed.
+fn check_locktime(
+    locktime: Option<u64>,
+    payment_config: &PaymentConfig,
+    payment_request: &PaymentRequest,
+    breakdown: &Option<String>,
+) -> Result<(), ErrorResponse> {
+    let Some(locktime) = locktime else {
+        return Ok(());
+    };
+
+    let now = unix_time();
+    if locktime < now + payment_config.min_locktime_secs {
+        tracing::debug!("Token locktime is not long enough");
+        return Err(ErrorResponse::new(
+            ErrorKind::TokenVerificationFailed,
+            "Token lock time is not long enough",
+        )
+        .with_payment_request(payment_request.to_string())
+        .with_breakdown(breakdown.clone()));
+    }
+
+    if let Some(max_secs) = payment_config.max_locktime_secs {
+        if locktime > now.saturating_add(max_secs) {
+            tracing::debug!("Token locktime {} is too far in the future", locktime);
+            return Err(ErrorResponse::new(
+                ErrorKind::TokenVerificationFailed,
+                "Token lock time is too far in the future",
+            )
+            .with_details(format!(
+                "Lock times may be at most {} seconds away",
+                max_secs
+            ))
+            .with_payment_request(payment_request.to_string())
+            .with_breakdown(breakdown.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Earliest locktime of the proofs in `tokens`, after which their senders can reclaim them
+fn earliest_locktime(tokens: &[Token]) -> Option<u64> {
+    tokens
+        .iter()
+        .flat_map(|token| token.proofs())
+        .filter_map(|proof| {
+            let secret: Nut10Secret = proof.secret.try_into().ok()?;
+            let conditions: SpendingConditions = secret.try_into().ok()?;
+            match conditions {
+                SpendingConditions::HTLCConditions { conditions, .. }
+                | SpendingConditions::P2PKConditions { conditions, .. } => conditions?.locktime,
+            }
+        })
+        .min()
+}
+
+/// The BOLT11 invoice to pay for a request, resolving it first if needed
+async fn bolt11_for_request(
+    state: &GatwayState,
+    payment_config: &PaymentConfig,
+    method: PaymentMethod,
+    request: &str,
+    amount: Option<Amount>,
+) -> Result<Bolt11Invoice, ErrorResponse> {
+    if let PaymentMethod::Bolt11 = method {
+        return request
+            .parse()
+            .map_err(|_| ErrorResponse::new(ErrorKind::InvalidInvoice, "Invalid BOLT11 invoice"));
+    }
+
+    if !state.inner.settings().lnurl.enabled {
+        return Err(ErrorResponse::new(
+            ErrorKind::UnsupportedMethod,
+            "Payment method not supported",
+        )
+        .with_details("Lightning address and LNURL resolution is disabled"));
+    }
+
+    let amount = amount.ok_or(
+        ErrorResponse::bad_request("Missing amount")
+            .with_details("An amount is required to pay a lightning address or LNURL"),
+    )?;
+
+    // Checked before resolving, so a request that can't be paid never makes
+    // the recipient's server issue an invoice
+    if amount == Amount::ZERO {
+        return Err(ErrorResponse::bad_request("Invalid amount")
+            .with_details("The amount must be greater than zero"));
+    }
+    check_payment_cap(payment_config, amount)?;
+
+    let lnurl = state.inner.lnurl();
+    let resolved = match method {
+        PaymentMethod::Lnurl => lnurl.resolve_lnurl(request, amount).await,
+        _ => lnurl.resolve_lightning_address(request, amount).await,
+    };
+
+    resolved.map_err(lnurl_error_response)
+}
+
+fn lnurl_error_response(err: LnurlError) -> ErrorResponse {
+    tracing::debug!("Failed to resolve payment destination: {}", err);
+    let error_kind = match err {
+        LnurlError::InvalidDestination(_) | LnurlError::AmountOutOfRange { .. } => {
+            ErrorKind::InvalidRequest
+        }
+        LnurlError::Remote(_) => ErrorKind::DestinationUnresolved,
+    };
+
+    ErrorResponse::new(error_kind, "Failed to resolve payment destination")
+        .with_details(err.to_string())
+}
+
+/// A payment request that passed validation and is ready to be paid
+struct VerifiedMelt {
+    /// Claim on the quote the payment is made on, released if it isn't paid
+    quote: Option<QuoteClaim>,
+    amount_to_pay_sat: Amount,
+    outgoing_options: OutgoingPaymentOptions,
+    hash: Sha256Hash,
+    destination: String,
+    tokens: Vec<Token>,
+    total_amount: Amount,
+    wallets: HashMap<MintUrl, Wallet>,
+    used_mints: Vec<MintUrl>,
+}
+
+/// Amount of an invoice that specifies one
+///
+/// A request amount is only needed for invoices without one, but one that
+/// disagrees with the invoice is rejected rather than ignored.
+fn invoice_amount(invoice_msat: u64, requested: Option<Amount>) -> Result<Amount, ErrorResponse> {
+    let amount = Amount::from(invoice_msat / 1_000);
+    match requested {
+        Some(requested) if requested != amount => Err(ErrorResponse::bad_request(
+            "Amount does not match the invoice",
+        )
+        .with_details(format!(
+            "Request amount is {} sat but the invoice is for {} sat",
+            requested, amount
+        ))),
+        _ => Ok(amount),
+    }
+}
+
+/// Amount to pay an invoice, taken from the request for invoices without one
+fn request_amount(
+    bolt11: &Bolt11Invoice,
+    requested: Option<Amount>,
+) -> Result<Amount, ErrorResponse> {
+    match bolt11.amount_milli_satoshis() {
+        Some(invoice_msat) => invoice_amount(invoice_msat, requested),
+        None => requested.ok_or(ErrorResponse::bad_request("Missing amount").with_details(
+            "Invoice has no amount specified. Please provide an amount in the request.",
+        )),
+    }
+}
+
+/// Reject paying `amount` in one request when it is above `max_single_payment_sat`
+fn check_payment_cap(payment_config: &PaymentConfig, amount: Amount) -> Result<(), ErrorResponse> {
+    match payment_config.max_single_payment_sat {
+        Some(max_sat) if u64::from(amount) > max_sat => {
+            tracing::warn!(
+                "Rejecting payment of {} above the cap of {} sat",
+                amount,
+                max_sat
+            );
+            Err(
+                ErrorResponse::bad_request("Amount above the maximum payment").with_details(
+                    format!(
+                        "At most {} sat can be paid in a single request, got {} sat",
+                        max_sat, amount
+                    ),
+                ),
+            )
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Claim the quote a payment references, if it can still be paid against for `hash` and `amount`
+fn reserved_quote(
+    state: &GatwayState,
+    quote_id: &Uuid,
+    hash: &Sha256Hash,
+    amount: Amount,
+) -> Result<QuoteClaim, ErrorResponse> {
+    let quote_error = |error_kind, message: &str| {
+        ErrorResponse::new(error_kind, message).with_details(format!("Quote {}", quote_id))
+    };
+
+    let claim = state.inner.quotes().claim(quote_id).map_err(|e| match e {
+        ClaimError::Unknown => quote_error(ErrorKind::NotFound, "Unknown quote"),
+        ClaimError::Paying => {
+            quote_error(ErrorKind::RequestInProgress, "Quote is already being paid")
+        }
+        ClaimError::Paid => quote_error(ErrorKind::InvalidRequest, "Quote has already been paid"),
+        ClaimError::Expired => quote_error(ErrorKind::InvalidRequest, "Quote has expired"),
+    })?;
+
+    // Dropping the claim reserves the quote again for the right payment
+    let quote = claim.quote();
+    if quote.payment_hash != hash.to_string() || quote.amount != amount {
+        return Err(quote_error(
+            ErrorKind::InvalidRequest,
+            "Quote is for a different payment",
+        ));
+    }
+
+    Ok(claim)
+}
+
+/// Parse the tokens of a request and check them against the request limits
+/// and accepted mints and token versions
+///
+/// Every token returned is from exactly one mint, so its `mint_url` can be
+/// unwrapped from then on.
+fn parse_tokens(
+    payment_config: &PaymentConfig,
+    raw_tokens: &[String],
+    payment_request: &PaymentRequest,
+    breakdown: &Option<String>,
+) -> Result<(Vec<Token>, Amount), ErrorResponse> {
+    if raw_tokens.len() > payment_config.max_tokens_per_request {
+        return Err(
+            ErrorResponse::new(ErrorKind::TooLarge, "Too many tokens").with_details(format!(
+                "At most {} tokens are accepted per request, got {}",
+                payment_config.max_tokens_per_request,
+                raw_tokens.len()
+            )),
+        );
+    }
 
-    let tokens: Vec<Token> = payload
-        .tokens
+    if raw_tokens.is_empty() {
+        tracing::debug!("Payment request has no tokens");
+        return Err(
+            ErrorResponse::new(ErrorKind::NoTokens, "No tokens provided")
+                .with_payment_request(payment_request.to_string())
+                .with_breakdown(breakdown.clone()),
+        );
+    }
+
+    // A token that doesn't parse is rejected rather than skipped, the client
+    // would otherwise be paying with fewer tokens than it sent
+    let tokens = raw_tokens
         .iter()
-        .flat_map(|t| Token::from_str(t))
-        .collect();
+        .enumerate()
+        .map(|(index, token)| {
+            Token::from_str(token).map_err(|e| {
+                tracing::debug!("Rejecting token {} that could not be parsed: {}", index, e);
+                ErrorResponse::bad_request("Invalid token")
+                    .with_details(format!("Token {} could not be parsed: {}", index, e))
+            })
+        })
+        .collect::<Result<Vec<Token>, _>>()?;
 
-    let token_amount: Vec<Amount> = tokens.iter().map(|a| a.value().unwrap()).collect();
-    let total_amount = Amount::try_sum(token_amount).unwrap();
+    for token in tokens.iter() {
+        let version = match token {
+            Token::TokenV3(_) => TokenVersion::V3,
+            Token::TokenV4(_) => TokenVersion::V4,
+        };
+        if !payment_config.accepted_token_versions.contains(&version) {
+            tracing::debug!("Rejecting {} token", version.as_str());
+            return Err(
+                ErrorResponse::bad_request("Unsupported token version").with_details(format!(
+                    "{} tokens are not accepted, accepted versions: {}",
+                    version.as_str(),
+                    payment_config
+                        .accepted_token_versions
+                        .iter()
+                        .map(|version| version.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )),
+            );
+        }
+    }
 
-    if total_amount < amount_to_pay_sat {
-        tracing::error!("Not enough proofs provided");
-        return Err(ErrorResponse {
-            code: 402,
-            message: "Insufficient funds".to_string(),
-            details: Some(format!(
-                "Required: {}, provided: {}",
-                amount_to_pay_sat, total_amount
+    for token in tokens.iter() {
+        // A V3 token can name several mints or none, its proofs can't be
+        // claimed from a single wallet then
+        let mint_url = token.mint_url().map_err(|e| {
+            tracing::debug!("Rejecting token without a single mint URL: {}", e);
+            ErrorResponse::bad_request("Token has no mint URL")
+                .with_details(format!("Each token must be from exactly one mint: {}", e))
+        })?;
+        if !payment_config.is_mint_accepted(&mint_url) {
+            tracing::debug!(
+                "Rejecting token from mint {} that is not accepted",
+                mint_url
+            );
+            return Err(
+                ErrorResponse::new(ErrorKind::UnsupportedMint, "Mint not accepted")
+                    .with_details(format!("Tokens from {} are not accepted", mint_url)),
+            );
+        }
+    }
+
+    let proof_count: usize = tokens.iter().map(|token| token.proofs().len()).sum();
+    if proof_count > payment_config.max_proofs_per_request {
+        return Err(
+            ErrorResponse::new(ErrorKind::TooLarge, "Too many proofs").with_details(format!(
+                "At most {} proofs are accepted per request, got {}",
+                payment_config.max_proofs_per_request, proof_count
             )),
-            payment_request: Some(payment_request.to_string()),
-        });
+        );
+    }
+
+    // Proofs are client supplied, so their sum can overflow an amount
+    let total_amount = tokens
+        .iter()
+        .try_fold(Amount::ZERO, |total, token| {
+            token
+                .value()
+                .ok()
+                .and_then(|value| total.checked_add(value))
+        })
+        .ok_or_else(|| {
+            tracing::debug!("Rejecting tokens whose value overflows an amount");
+            ErrorResponse::bad_request("Invalid token amount")
+                .with_details("The value of the tokens is too large to be an amount")
+        })?;
+
+    Ok((tokens, total_amount))
+}
+
+/// `amount` plus `fee`, rejecting a total too large to be an amount
+fn amount_with_fee(amount: Amount, fee: Amount) -> Result<Amount, ErrorResponse> {
+    amount.checked_add(fee).ok_or_else(|| {
+        ErrorResponse::bad_request("Amount too large").with_details(format!(
+            "{} sat plus a fee of {} sat is too large to be an amount",
+            amount, fee
+        ))
+    })
+}
+
+/// Enforce the limits of each mint on the ecash it provides to a payment
+fn check_mint_limits(state: &GatwayState, tokens: &[Token]) -> Result<(), ErrorResponse> {
+    let settings = state.inner.settings();
+    for (mint_url, provided) in value_by_mint(tokens).iter() {
+        let Some(entry) = settings.wallet.mint_entry(mint_url) else {
+            continue;
+        };
+        let provided = u64::from(*provided);
+        let below_min = entry.min_sat().is_some_and(|min_sat| provided < min_sat);
+        let above_max = entry.max_sat().is_some_and(|max_sat| provided > max_sat);
+        if below_min || above_max {
+            tracing::debug!(
+                "Rejecting {} sat from {} outside of its limits",
+                provided,
+                mint_url
+            );
+            return Err(
+                ErrorResponse::bad_request("Amount outside mint limits").with_details(format!(
+                    "{} accepts between {} and {} sat per payment, got {} sat",
+                    mint_url,
+                    entry.min_sat().unwrap_or(0),
+                    entry
+                        .max_sat()
+                        .map_or("unlimited".to_string(), |max_sat| max_sat.to_string()),
+                    provided
+                )),
+            );
+        }
     }
 
-    let mut used_mints = vec![];
+    Ok(())
+}
 
+/// Wallet of each mint the tokens are from
+///
+/// Each wallet is looked up once and reused for every token from its mint.
+/// Tokens from a mint the gateway has no wallet for are rejected.
+async fn token_wallets(
+    state: &GatwayState,
+    tokens: &[Token],
+) -> Result<HashMap<MintUrl, Wallet>, ErrorResponse> {
+    let mut wallets: HashMap<MintUrl, Wallet> = HashMap::new();
     for token in tokens.iter() {
         let mint_url = token.mint_url().unwrap();
-        let wallet = state
-            .inner
-            .wallets()
-            .get_wallet(&WalletKey::new(mint_url.clone(), CurrencyUnit::Sat))
-            .await
-            .expect("wallet");
-
-        used_mints.push(mint_url);
+        if !wallets.contains_key(&mint_url) {
+            let wallet = state
+                .inner
+                .wallets()
+                .get_wallet(&WalletKey::new(mint_url.clone(), CurrencyUnit::Sat))
+                .await
+                .ok_or_else(|| {
+                    tracing::debug!("Rejecting token from {}, it has no wallet", mint_url);
+                    ErrorResponse::new(ErrorKind::UnsupportedMint, "Mint not supported")
+                        .with_details(format!("The gateway has no wallet for {}", mint_url))
+                })?;
+            wallets.insert(mint_url, wallet);
+        }
+    }
 
-        wallet.verify_token_dleq(token).await.map_err(|e| {
-            tracing::error!("Invalid dleq: {}", e);
-            ErrorResponse {
-                code: 400,
-                message: "Token verification failed".to_string(),
-                details: Some(format!("DLEQ verification error: {}", e)),
-                payment_request: Some(payment_request.to_string()),
-            }
-        })?;
+    Ok(wallets)
+}
 
-        for proof in token.proofs() {
-            let secret: Nut10Secret = proof.secret.try_into().map_err(|err| {
-                tracing::error!("Invalid secret: {}", err);
-                ErrorResponse {
-                    code: 400,
-                    message: "Token verification failed".to_string(),
-                    details: Some(format!("Secret validation failed: {}", err)),
-                    payment_request: Some(payment_request.to_string()),
-                }
-            })?;
+/// Parse and validate a payment request without paying anything
+///
+/// Checks the invoice, request limits, that enough ecash was provided, and the
+/// DLEQ proofs and spending conditions of every token.
+async fn verify_melt_request(
+    state: &GatwayState,
+    payment_config: &PaymentConfig,
+    payload: MeltRequest,
+    progress: &PaymentProgress,
+) -> Result<VerifiedMelt, ErrorResponse> {
+    let accepted_methods = &payment_config.accepted_methods;
+    if !accepted_methods.contains(&payload.method) {
+        tracing::debug!(
+            "Rejecting {:?} payment, method not accepted",
+            payload.method
+        );
+        return Err(ErrorResponse::new(
+            ErrorKind::UnsupportedMethod,
+            "Payment method not supported",
+        )
+        .with_details("This gateway does not accept the payment method"));
+    }
 
-            let secret_spending_conditions: SpendingConditions = secret.try_into().unwrap();
-
-            match secret_spending_conditions {
-                SpendingConditions::HTLCConditions { data, conditions } => {
-                    if data != hash {
-                        tracing::debug!("Payment hash does not equal token hash");
-                        return Err(ErrorResponse {
-                            code: 400,
-                            message: "Token hash does not match payment hash".to_string(),
-                            details: None,
-                            payment_request: Some(payment_request.to_string()),
-                        });
-                    }
+    let hash;
+    let destination;
+    let mut amountless = false;
+    let (mut amount_to_pay_sat, mut outgoing_options) = match payload.method {
+        PaymentMethod::Bolt11 | PaymentMethod::LnAddress | PaymentMethod::Lnurl => {
+            let bolt11 = bolt11_for_request(
+                state,
+                payment_config,
+                payload.method,
+                &payload.request,
+                payload.amount,
+            )
+            .await?;
 
-                    if let Some(conditions) = conditions {
-                        if let Some(locktime) = conditions.locktime {
-                            if locktime < unix_time() + 900 {
-                                tracing::debug!("Token locktime is not long enough");
-                                return Err(ErrorResponse {
-                                    code: 400,
-                                    message: "Token lock time is not long enough".to_string(),
-                                    details: None,
-                                    payment_request: Some(payment_request.to_string()),
-                                });
-                            }
-                        }
+            let amount = match (&payload.melt_options, bolt11.amount_milli_satoshis()) {
+                (Some(melt_options), invoice_msat) => {
+                    let partial_msat = u64::from(melt_options.amount_msat());
+                    if partial_msat == 0
+                        || partial_msat % 1_000 != 0
+                        || invoice_msat.is_some_and(|invoice_msat| partial_msat > invoice_msat)
+                    {
+                        return Err(ErrorResponse::bad_request("Invalid partial payment amount").with_details("Partial amount must be a whole number of sats, greater than zero and at most the invoice amount"));
                     }
+                    Amount::from(partial_msat / 1_000)
                 }
-                SpendingConditions::P2PKConditions {
-                    data: _,
-                    conditions: _,
-                } => {
-                    return Err(ErrorResponse {
-                        code: 400,
-                        message: "Token verification failed".to_string(),
-                        details: None,
-                        payment_request: Some(payment_request.to_string()),
-                    });
+                (None, Some(invoice_msat)) => invoice_amount(invoice_msat, payload.amount)?,
+                (None, None) => {
+                    amountless = true;
+                    payload
+                        .amount
+                        .ok_or(ErrorResponse::bad_request("Missing amount").with_details(
+                        "Invoice has no amount specified. Please provide an amount in the request.",
+                    ))?
                 }
+            };
+
+            hash = bolt11.payment_hash().to_owned();
+            destination = bolt11.get_payee_pub_key().to_string();
+
+            // The node needs to be told the amount of an invoice without one
+            let melt_options = match payload.melt_options {
+                None if amountless => Some(amountless_melt_options(amount)),
+                melt_options => melt_options,
+            };
+
+            let outgoing = OutgoingPaymentOptions::Bolt11(Box::new(Bolt11OutgoingPaymentOptions {
+                bolt11,
+                max_fee_amount: None,
+                timeout_secs: payment_config.payment_timeout_secs,
+                melt_options,
+            }));
+
+            (amount, outgoing)
+        }
+        PaymentMethod::Bolt12 => {
+            return Err(ErrorResponse::new(
+                ErrorKind::UnsupportedMethod,
+                "Payment method not supported",
+            )
+            .with_details("BOLT12 payment method is not supported"));
+        }
+    };
+
+    check_payment_cap(payment_config, amount_to_pay_sat)?;
+
+    // A quote locks in its terms: the fee is capped at the quoted one, which
+    // the tokens must cover on top of the amount
+    let quote = match payload.quote_id {
+        Some(quote_id) => Some(reserved_quote(state, &quote_id, &hash, amount_to_pay_sat)?),
+        None => None,
+    };
+    let quoted_fee_cap = quote.as_ref().map(|quote| quote.quote().fee);
+    // A client's own fee limit is kept within the operator's ceiling, and can
+    // only lower the fee a quote allows. Without either the fee reserve is
+    // the limit, so a payment never has an unbounded routing fee
+    let fee_ceiling = payment_config.max_fee_ceiling_sat.map(Amount::from);
+    let within_ceiling = |fee: Amount| match fee_ceiling {
+        Some(ceiling) => fee.min(ceiling),
+        None => fee,
+    };
+    let requested_fee_cap = payload.max_fee_sat.map(within_ceiling);
+    let max_fee = match (quoted_fee_cap, requested_fee_cap) {
+        (Some(quoted), Some(requested)) => quoted.min(requested),
+        (Some(quoted), None) => quoted,
+        (None, Some(requested)) => requested,
+        (None, None) => within_ceiling(Amount::from(
+            payment_config.fee_reserve(u64::from(amount_to_pay_sat)),
+        )),
+    };
+    if let OutgoingPaymentOptions::Bolt11(options) = &mut outgoing_options {
+        options.max_fee_amount = Some(max_fee);
+    }
+    // The tokens cover the quoted fee when paying on a quote, and otherwise
+    // the most the routing fee is allowed to be
+    let mut fee_due = quoted_fee_cap.unwrap_or(max_fee);
+    let required = amount_with_fee(amount_to_pay_sat, fee_due)?;
+
+    let nut10 = SpendingConditions::HTLCConditions {
+        data: hash,
+        conditions: None,
+    };
+
+    // Build the payment request with the correct amount for any error responses
+    let payment_request = PaymentRequestBuilder::default()
+        .unit(CurrencyUnit::Sat)
+        .amount(u64::from(required))
+        .mints(state.mints_accepted_by(payment_config))
+        .nut10(nut10.into())
+        .build();
+
+    let breakdown = payment_config
+        .include_breakdown
+        .then(|| payment_breakdown(amount_to_pay_sat, fee_due, required));
+
+    let (tokens, total_amount) = parse_tokens(
+        payment_config,
+        &payload.tokens,
+        &payment_request,
+        &breakdown,
+    )?;
+
+    // Only invoices without an amount can be paid partially, the amount is ours to choose
+    // A quote fixes the amount, so it can't be paid partially
+    let partial = payload.allow_partial && amountless && quote.is_none();
+    if total_amount < required && partial && total_amount > Amount::ZERO {
+        // The routing fee is paid from the tokens as well, so the amount paid
+        // leaves room for the most the fee is allowed to be
+        if let OutgoingPaymentOptions::Bolt11(options) = &mut outgoing_options {
+            let reserve = within_ceiling(Amount::from(
+                payment_config.fee_reserve(u64::from(total_amount)),
+            ));
+            let max_fee = requested_fee_cap.map_or(reserve, |max_fee| max_fee.min(reserve));
+            let partial_amount = total_amount.checked_sub(max_fee).unwrap_or_default();
+            if partial_amount > Amount::ZERO {
+                tracing::info!(
+                    "Paying {} of the requested {} covered by the tokens, with a fee of at most {}",
+                    partial_amount,
+                    amount_to_pay_sat,
+                    max_fee
+                );
+                amount_to_pay_sat = partial_amount;
+                fee_due = max_fee;
+                options.melt_options = Some(amountless_melt_options(amount_to_pay_sat));
+                options.max_fee_amount = Some(max_fee);
             }
         }
     }
 
-    let payment_response = state
+    let required = amount_with_fee(amount_to_pay_sat, fee_due)?;
+    if total_amount < required {
+        tracing::error!("Not enough proofs provided");
+        return Err(
+            ErrorResponse::new(ErrorKind::InsufficientFunds, "Insufficient funds")
+                .with_details(format!(
+                    "Required: {}, provided: {}",
+                    required, total_amount
+                ))
+                .with_payment_request(payment_request.to_string())
+                .with_breakdown(breakdown.clone()),
+        );
+    }
+
+    check_mint_limits(state, &tokens)?;
+    let wallets = token_wallets(state, &tokens).await?;
+
+    progress.report(PaymentStage::VerifyingTokens);
+
+    // Tokens are verified concurrently, but the first failure by position is
+    // reported so the error doesn't depend on which mint responds first
+    let verified = join_all(tokens.iter().map(|token| {
+        let wallet = &wallets[&token.mint_url().unwrap()];
+        verify_token(
+            state,
+            payment_config,
+            wallet,
+            token,
+            &hash,
+            &payment_request,
+            &breakdown,
+        )
+    }))
+    .await;
+    let used_mints = verified.into_iter().collect::<Result<Vec<MintUrl>, _>>()?;
+
+    // Proofs that are spent or pending at the mint may never be claimable,
+    // so they are checked before anything is paid
+    if payment_config.check_proofs_before_payment {
+        let timeout = Duration::from_secs(payment_config.proof_check_timeout_secs);
+        let checked = join_all(tokens.iter().map(|token| {
+            let wallet = &wallets[&token.mint_url().unwrap()];
+            wait_for_proofs_unspent(wallet, token.proofs(), timeout)
+        }))
+        .await;
+        checked.into_iter().collect::<Result<Vec<()>, _>>()?;
+    }
+
+    Ok(VerifiedMelt {
+        quote,
+        amount_to_pay_sat,
+        outgoing_options,
+        hash,
+        destination,
+        tokens,
+        total_amount,
+        wallets,
+        used_mints,
+    })
+}
+
+/// Register a payment handler, unless the gateway is shutting down or busy
+fn start_payment(
+    state: &GatwayState,
+) -> Result<(PaymentGuard, OwnedSemaphorePermit), ErrorResponse> {
+    let Some(payment_guard) = state.inner.payments().start() else {
+        return Err(ErrorResponse::new(
+            ErrorKind::Unavailable,
+            "Gateway is shutting down",
+        ));
+    };
+
+    let Ok(payment_permit) = state.inner.payment_permits.clone().try_acquire_owned() else {
+        tracing::warn!("Too many payments in progress, rejecting payment");
+        return Err(
+            ErrorResponse::new(ErrorKind::Unavailable, "Gateway is busy").with_details(format!(
+                "Too many payments in progress, retry after {} seconds",
+                BUSY_RETRY_AFTER_SECS
+            )),
+        );
+    };
+
+    Ok((payment_guard, payment_permit))
+}
+
+async fn process_melt_request(
+    state: GatwayState,
+    payload: MeltRequest,
+    request_id: Uuid,
+    progress: PaymentProgress,
+) -> Result<Json<MeltResponse>, ErrorResponse> {
+    tracing::info!("Payment request received with method: {:?}", payload.method);
+    let payment_config = state.inner.payment_config();
+
+    // Held for the whole handler, including past the request timeout, so
+    // shutdown waits for this payment to finish
+    let (_payment_guard, _payment_permit) = start_payment(&state)?;
+
+    let VerifiedMelt {
+        quote,
+        amount_to_pay_sat,
+        outgoing_options,
+        hash,
+        destination,
+        tokens,
+        total_amount,
+        wallets,
+        used_mints,
+    } = verify_melt_request(&state, &payment_config, payload, &progress).await?;
+
+    progress.report(PaymentStage::PayingInvoice);
+    let payment_started = Instant::now();
+    let payment_result =
+        make_payment_with_retry(&state.inner, &payment_config, outgoing_options).await;
+    state.inner.metrics().record_payment(
+        &destination,
+        payment_result.is_ok(),
+        payment_started.elapsed(),
+    );
+
+    let payment_response = payment_result.map_err(|e| payment_error(&payment_config, &e))?;
+
+    tracing::info!("Payment successfully processed");
+    state
         .inner
-        .node()
-        .make_payment(&CurrencyUnit::Sat, outgoing_options)
-        .await
-        .map_err(|e| {
-            tracing::error!("Payment failed: {}", e);
-            ErrorResponse {
-                code: 500,
-                message: "Payment failed".to_string(),
-                details: Some(e.to_string()),
-                payment_request: None,
+        .metrics()
+        .record_paid(payment_response.total_spent);
+    if let Some(quote) = quote {
+        quote.mark_paid();
+    }
+    state.inner.lnurl().forget(&hash);
+
+    // The tokens can only be claimed with the preimage, ask the node for it
+    // again before giving up
+    let preimage = match payment_response.payment_proof.clone() {
+        Some(preimage) => Some(preimage),
+        None => state.inner.query_preimage(&hash).await,
+    };
+    let invalid_preimage = preimage
+        .as_ref()
+        .is_some_and(|preimage| !preimage_matches(preimage, &hash));
+
+    let preimage = match preimage {
+        Some(preimage) if !invalid_preimage => preimage,
+        _ => {
+            if invalid_preimage {
+                tracing::error!(
+                    "Node returned a preimage that does not match payment {}, tokens cannot be claimed",
+                    hash
+                );
+            } else {
+                tracing::error!(
+                    "Payment {} was sent but the node returned no preimage, tokens cannot be claimed",
+                    hash
+                );
             }
-        })?;
+            state.inner.metrics().record_missing_preimage();
+            let unclaimed = UnclaimedPayment {
+                request_id,
+                payment_hash: hash.to_string(),
+                tokens: tokens.iter().map(|token| token.to_string()).collect(),
+                provided: total_amount,
+                total_spent: payment_response.total_spent,
+                timestamp: unix_time(),
+            };
+            if let Err(e) = state
+                .inner
+                .payment_store()
+                .add_unclaimed_payment(&unclaimed)
+            {
+                tracing::error!("Failed to record unclaimed payment: {}", e);
+            }
+            let message = if invalid_preimage {
+                "Node returned a preimage that does not match the payment hash"
+            } else {
+                "Payment sent but no preimage returned"
+            };
+            return Err(ErrorResponse::internal(message.to_string()).with_details("The gateway has recorded the payment and will claim the tokens once the node reports the preimage"));
+        }
+    };
 
-    tracing::info!("Payment successfully processed");
+    // A slow payment can leave a locktime checked before paying too close to
+    // claim safely. The tokens are left for the operator rather than racing
+    // the senders reclaiming them.
+    if let Some(locktime) = earliest_locktime(&tokens) {
+        if locktime < unix_time() + payment_config.claim_locktime_margin_secs {
+            tracing::error!(
+                "Payment {} was sent but a token locktime at {} is too close to claim the tokens",
+                hash,
+                locktime
+            );
+            let unclaimed = UnclaimedPayment {
+                request_id,
+                payment_hash: hash.to_string(),
+                tokens: tokens.iter().map(|token| token.to_string()).collect(),
+                provided: total_amount,
+                total_spent: payment_response.total_spent,
+                timestamp: unix_time(),
+            };
+            if let Err(e) = state
+                .inner
+                .payment_store()
+                .add_unclaimed_payment(&unclaimed)
+            {
+                tracing::error!("Failed to record unclaimed payment: {}", e);
+            }
+            return Err(ErrorResponse::internal("Token lock time too close to claim the tokens").with_details(format!(
+                    "The payment was sent but a token can be reclaimed from {}, the gateway has recorded the payment",
+                    locktime
+                )));
+        }
+    }
 
-    for token in tokens.iter() {
-        let wallet = state
-            .inner
-            .wallets()
-            .get_wallet(&WalletKey::new(
-                token.mint_url().unwrap(),
-                CurrencyUnit::Sat,
-            ))
-            .await
-            .expect("wallet");
+    progress.report(PaymentStage::ReceivingTokens);
+    for (index, token) in tokens.iter().enumerate() {
+        let wallet = &wallets[&token.mint_url().unwrap()];
 
-        wallet
+        let received = wallet
             .receive(
                 &token.to_string(),
                 ReceiveOptions {
-                    preimages: vec![payment_response.payment_proof.clone().ok_or(
-                        ErrorResponse {
-                            code: 500,
-                            message: "Missing payment proof".to_string(),
-                            details: None,
-                            payment_request: None,
-                        },
-                    )?],
+                    preimages: vec![preimage.clone()],
+                    p2pk_signing_keys: state.inner.p2pk_signing_keys(),
                     ..Default::default()
                 },
             )
-            .await
-            .map_err(|e| ErrorResponse {
-                code: 500,
-                message: "Failed to process token receive".to_string(),
-                details: Some(e.to_string()),
-                payment_request: None,
-            })?;
-    }
+            .await;
 
-    let proof = payment_response.payment_proof.ok_or(ErrorResponse {
-        code: 500,
-        message: "Missing payment proof in response".to_string(),
-        details: None,
-        payment_request: None,
-    })?;
+        match &received {
+            Ok(amount) => state
+                .inner
+                .metrics()
+                .record_receive(&wallet.mint_url, *amount),
+            Err(_) => state
+                .inner
+                .metrics()
+                .record_receive_failure(&wallet.mint_url),
+        }
 
-    let change_amount = total_amount
-        .checked_sub(payment_response.total_spent)
-        .unwrap_or_default();
+        // The invoice is already paid, so the tokens not claimed yet are kept
+        // for reconciliation rather than lost with the error
+        if let Err(e) = received {
+            tracing::error!(
+                "Payment {} was sent but receiving token {} failed: {}",
+                hash,
+                index,
+                e
+            );
+            let unclaimed = UnclaimedPayment {
+                request_id,
+                payment_hash: hash.to_string(),
+                tokens: tokens[index..]
+                    .iter()
+                    .map(|token| token.to_string())
+                    .collect(),
+                provided: total_amount,
+                total_spent: payment_response.total_spent,
+                timestamp: unix_time(),
+            };
+            if let Err(e) = state
+                .inner
+                .payment_store()
+                .add_unclaimed_payment(&unclaimed)
+            {
+                tracing::error!("Failed to record unclaimed payment: {}", e);
+            }
+            return Err(
+                ErrorResponse::internal("Failed to process token receive").with_details(format!(
+                    "The payment was sent but the tokens could not be claimed, the gateway has recorded the payment: {}",
+                    e
+                )),
+            );
+        }
+    }
+
+    let change_amount = change_due(
+        state.inner.metrics(),
+        request_id,
+        total_amount,
+        payment_response.total_spent,
+    );
 
+    progress.report(PaymentStage::ReturningChange);
     tracing::info!("Preparing change payment of {}", change_amount);
-    let mut change = vec![];
 
-    for mint_url in used_mints {
-        let wallet = state
-            .inner
-            .wallets()
-            .get_wallet(&WalletKey::new(mint_url.clone(), CurrencyUnit::Sat))
-            .await
-            .expect("wallet");
+    let retain_change = payment_config.retain_change;
+    let returned = if retain_change && change_amount > Amount::ZERO {
+        tracing::info!(
+            "Retaining change of {} in the gateway wallet",
+            change_amount
+        );
+        ReturnedChange::default()
+    } else {
+        return_change(
+            &state,
+            &payment_config,
+            request_id,
+            &wallets,
+            &used_mints,
+            change_amount,
+        )
+        .await
+    };
 
-        let change_prepared_send = wallet
-            .prepare_send(change_amount, SendOptions::default())
-            .await
-            .unwrap();
+    let expected_change = if retain_change || change_amount == Amount::ZERO {
+        Amount::ZERO
+    } else {
+        change_amount
+    };
+    check_change(
+        state.inner.metrics(),
+        request_id,
+        total_amount,
+        change_amount,
+        expected_change,
+        returned.sent + returned.owed,
+    );
 
-        let token = wallet.send(change_prepared_send, None).await.unwrap();
+    // Retained change belongs to the requester's deposit, not to the gateway's earnings
+    if !retain_change {
+        let earnings = fee_earnings(&tokens, payment_response.total_spent, &returned.by_mint);
+        if let Err(e) = state.inner.payment_store().add_earnings(&earnings) {
+            tracing::error!("Failed to record fee earnings: {}", e);
+        }
+    }
 
-        change.push(token.to_string());
+    let record = PaymentRecord {
+        request_id,
+        amount: amount_to_pay_sat,
+        fee: payment_response
+            .total_spent
+            .checked_sub(amount_to_pay_sat)
+            .unwrap_or_default(),
+        payment_hash: hash.to_string(),
+        timestamp: unix_time(),
+        change: returned.returned,
+    };
+    // The payment has already been made, so a failure here must not fail the request
+    if let Err(e) = state.inner.payment_store().add(&record) {
+        tracing::error!("Failed to record payment in history: {}", e);
     }
+    state.inner.notify_payment(PaymentEvent::succeeded(&record));
 
     tracing::info!(
         "Payment request completed successfully with {} tokens in change",
-        change.len()
+        returned.tokens.len()
     );
     Ok(Json(MeltResponse {
-        payment_proof: proof,
-        change,
+        payment_proof: preimage,
+        payment_hash: record.payment_hash.clone(),
+        change: returned.tokens,
+        amount_paid_sat: u64::from(record.amount),
+        fee_paid_sat: u64::from(record.fee),
+        change_sat: u64::from(returned.returned),
+        change_error: returned.error,
+        retained_change_sat: retain_change.then(|| u64::from(change_amount)),
+    }))
+}
+
+/// An invoice of a batch, checked and ready to be paid
+struct BatchInvoice {
+    request: String,
+    amount: Amount,
+    /// Most the routing fee may be, reserved from the tokens
+    fee_reserve: Amount,
+    hash: Sha256Hash,
+    destination: String,
+    outgoing_options: OutgoingPaymentOptions,
+}
+
+/// Resolve and check one payment of a batch
+async fn batch_invoice(
+    state: &GatwayState,
+    payment_config: &PaymentConfig,
+    payment: BatchPayment,
+) -> Result<BatchInvoice, ErrorResponse> {
+    if payment.method == PaymentMethod::Bolt12
+        || !payment_config.accepted_methods.contains(&payment.method)
+    {
+        return Err(ErrorResponse::new(
+            ErrorKind::UnsupportedMethod,
+            "Payment method not supported",
+        )
+        .with_details("This gateway does not accept the payment method"));
+    }
+
+    let bolt11 = bolt11_for_request(
+        state,
+        payment_config,
+        payment.method,
+        &payment.request,
+        payment.amount,
+    )
+    .await?;
+    let amount = request_amount(&bolt11, payment.amount)?;
+    let fee_reserve = Amount::from(payment_config.fee_reserve(u64::from(amount)));
+
+    let hash = bolt11.payment_hash().to_owned();
+    let destination = bolt11.get_payee_pub_key().to_string();
+    let melt_options = bolt11
+        .amount_milli_satoshis()
+        .is_none()
+        .then(|| amountless_melt_options(amount));
+    // Each payment is held to the fee reserved for it, so one expensive route
+    // can't spend the share of the payments after it
+    let outgoing_options = OutgoingPaymentOptions::Bolt11(Box::new(Bolt11OutgoingPaymentOptions {
+        bolt11,
+        max_fee_amount: Some(fee_reserve),
+        timeout_secs: payment_config.payment_timeout_secs,
+        melt_options,
+    }));
+
+    Ok(BatchInvoice {
+        request: payment.request,
+        amount,
+        fee_reserve,
+        hash,
+        destination,
+        outgoing_options,
+    })
+}
+
+/// Whether a proof is P2PK locked, rather than to a payment hash or not at all
+fn is_p2pk_locked(proof: Proof) -> bool {
+    let Ok(secret) = Nut10Secret::try_from(proof.secret) else {
+        return false;
+    };
+    matches!(
+        SpendingConditions::try_from(secret),
+        Ok(SpendingConditions::P2PKConditions { .. })
+    )
+}
+
+async fn process_melt_batch(
+    state: GatwayState,
+    payload: BatchMeltRequest,
+    request_id: Uuid,
+) -> Result<Json<BatchMeltResponse>, ErrorResponse> {
+    tracing::info!(
+        "Batch payment request received with {} payments",
+        payload.payments.len()
+    );
+
+    let (_payment_guard, _payment_permit) = start_payment(&state)?;
+
+    // Tokens locked to a payment hash can only be claimed after paying that
+    // one invoice, so a batch is paid with tokens locked to the gateway's key
+    let Some(pubkey) = state.inner.p2pk_pubkey() else {
+        return Err(ErrorResponse::new(
+            ErrorKind::UnsupportedMethod,
+            "Batch payments not supported",
+        )
+        .with_details(
+            "Batch payments need tokens locked to the gateway's key, and it has none configured",
+        ));
+    };
+
+    let payment_config = state.inner.payment_config();
+    if payload.payments.is_empty() {
+        return Err(ErrorResponse::bad_request("No payments provided"));
+    }
+    if payload.payments.len() > payment_config.max_batch_payments {
+        return Err(
+            ErrorResponse::new(ErrorKind::TooLarge, "Too many payments").with_details(format!(
+                "At most {} payments are accepted per batch, got {}",
+                payment_config.max_batch_payments,
+                payload.payments.len()
+            )),
+        );
+    }
+
+    let mut invoices = Vec::with_capacity(payload.payments.len());
+    for payment in payload.payments {
+        invoices.push(batch_invoice(&state, &payment_config, payment).await?);
+    }
+
+    // A second payment of the same invoice could only fail
+    let mut hashes = HashSet::new();
+    if let Some(invoice) = invoices.iter().find(|invoice| !hashes.insert(invoice.hash)) {
+        return Err(
+            ErrorResponse::bad_request("Duplicate payment in batch").with_details(format!(
+                "Invoice {} is included more than once",
+                invoice.hash
+            )),
+        );
+    }
+
+    let amount = invoices
+        .iter()
+        .fold(Amount::ZERO, |total, invoice| total + invoice.amount);
+    let fee_reserve = invoices
+        .iter()
+        .fold(Amount::ZERO, |total, invoice| total + invoice.fee_reserve);
+    check_payment_cap(&payment_config, amount)?;
+    let required = amount_with_fee(amount, fee_reserve)?;
+
+    let nut10 = SpendingConditions::P2PKConditions {
+        data: pubkey,
+        conditions: None,
+    };
+    let payment_request = PaymentRequestBuilder::default()
+        .unit(CurrencyUnit::Sat)
+        .amount(u64::from(required))
+        .mints(state.mints_accepted_by(&payment_config))
+        .nut10(nut10.into())
+        .build();
+    let breakdown = payment_config
+        .include_breakdown
+        .then(|| payment_breakdown(amount, fee_reserve, required));
+
+    let (tokens, total_amount) = parse_tokens(
+        &payment_config,
+        &payload.tokens,
+        &payment_request,
+        &breakdown,
+    )?;
+    if tokens
+        .iter()
+        .flat_map(|token| token.proofs())
+        .any(|proof| !is_p2pk_locked(proof))
+    {
+        tracing::debug!("Rejecting batch with tokens not P2PK locked");
+        return Err(ErrorResponse::new(
+            ErrorKind::TokenVerificationFailed,
+            "Batch payments need tokens locked to the gateway's key",
+        )
+        .with_payment_request(payment_request.to_string())
+        .with_breakdown(breakdown.clone()));
+    }
+
+    if total_amount < required {
+        tracing::error!("Not enough proofs provided");
+        return Err(
+            ErrorResponse::new(ErrorKind::InsufficientFunds, "Insufficient funds")
+                .with_details(format!(
+                    "Required: {}, provided: {}",
+                    required, total_amount
+                ))
+                .with_payment_request(payment_request.to_string())
+                .with_breakdown(breakdown),
+        );
+    }
+
+    check_mint_limits(&state, &tokens)?;
+    let wallets = token_wallets(&state, &tokens).await?;
+
+    // Only P2PK tokens are left, so the payment hash given is never compared
+    let verified = join_all(tokens.iter().map(|token| {
+        let wallet = &wallets[&token.mint_url().unwrap()];
+        verify_token(
+            &state,
+            wallet,
+            token,
+            &invoices[0].hash,
+            &payment_request,
+            &breakdown,
+        )
+    }))
+    .await;
+    let used_mints = verified.into_iter().collect::<Result<Vec<MintUrl>, _>>()?;
+
+    // Nothing is paid until every token is claimed, so the gateway never pays
+    // for ecash it can't redeem
+    for (claimed, token) in tokens.iter().enumerate() {
+        let wallet = &wallets[&token.mint_url().unwrap()];
+
+        let received = wallet
+            .receive(
+                &token.to_string(),
+                ReceiveOptions {
+                    p2pk_signing_keys: state.inner.p2pk_signing_keys(),
+                    ..Default::default()
+                },
+            )
+            .await;
+
+        match &received {
+            Ok(amount) => state
+                .inner
+                .metrics()
+                .record_receive(&wallet.mint_url, *amount),
+            Err(_) => state
+                .inner
+                .metrics()
+                .record_receive_failure(&wallet.mint_url),
+        }
+
+        if let Err(e) = received {
+            tracing::error!("Failed to claim batch tokens: {}", e);
+            // Nothing was paid, so the tokens already claimed are owed back in full
+            for (mint_url, amount) in value_by_mint(&tokens[..claimed]) {
+                record_owed_change(&state, request_id, &mint_url, amount);
+            }
+            return Err(
+                ErrorResponse::internal("Failed to process token receive").with_details(format!(
+                    "{}, nothing was paid and tokens already claimed have been recorded as owed",
+                    e
+                )),
+            );
+        }
+    }
+
+    let mut payments = Vec::with_capacity(invoices.len());
+    let mut spent = Amount::ZERO;
+    let mut held = Amount::ZERO;
+    for invoice in invoices {
+        let amount = invoice.amount;
+        let (result, taken) = pay_batch_invoice(&state, &payment_config, invoice).await;
+        match result.status {
+            BatchPaymentStatus::Paid => spent += taken,
+            BatchPaymentStatus::Pending => {
+                held += taken;
+                // Kept until the node reports the outcome, so what the client
+                // is owed if it fails survives a restart
+                let pending = PendingPayment {
+                    request_id,
+                    payment_id: result.request_id,
+                    payment_hash: result.payment_hash.clone(),
+                    amount,
+                    held: taken,
+                    mint_url: used_mints[0].clone(),
+                    timestamp: unix_time(),
+                };
+                if let Err(e) = state.inner.payment_store().add_pending_payment(&pending) {
+                    tracing::error!("Failed to record pending payment: {}", e);
+                }
+            }
+            BatchPaymentStatus::Failed => {}
+        }
+        payments.push(result);
+    }
+
+    let change_amount = change_due(
+        state.inner.metrics(),
+        request_id,
+        total_amount.checked_sub(held).unwrap_or_default(),
+        spent,
+    );
+
+    let retain_change = payment_config.retain_change;
+    let returned = if retain_change && change_amount > Amount::ZERO {
+        tracing::info!(
+            "Retaining change of {} in the gateway wallet",
+            change_amount
+        );
+        ReturnedChange::default()
+    } else {
+        return_change(
+            &state,
+            &payment_config,
+            request_id,
+            &wallets,
+            &used_mints,
+            change_amount,
+        )
+        .await
+    };
+
+    // What is held for pending payments isn't earned until they are resolved
+    if !retain_change {
+        let earnings = fee_earnings(&tokens, spent + held, &returned.by_mint);
+        if let Err(e) = state.inner.payment_store().add_earnings(&earnings) {
+            tracing::error!("Failed to record fee earnings: {}", e);
+        }
+    }
+
+    let paid = payments
+        .iter()
+        .filter(|payment| payment.status == BatchPaymentStatus::Paid)
+        .count();
+    tracing::info!(
+        "Batch payment completed with {} of {} payments made",
+        paid,
+        payments.len()
+    );
+    Ok(Json(BatchMeltResponse {
+        payments,
+        change: returned.tokens,
+        change_sat: u64::from(returned.returned),
+        change_error: returned.error,
+        retained_change_sat: retain_change.then(|| u64::from(change_amount)),
     }))
 }
+
+/// Pay one invoice of a batch whose tokens are already claimed
+///
+/// Returns the outcome and how much of the tokens it took: what was spent if
+/// it was paid, its amount and fee reserve while it is pending, and nothing
+/// if it failed.
+async fn pay_batch_invoice(
+    state: &GatwayState,
+    payment_config: &PaymentConfig,
+    invoice: BatchInvoice,
+) -> (BatchPaymentResult, Amount) {
+    // Each payment gets its own ID, so it is recorded like any other payment
+    let payment_id = Uuid::new_v4();
+    tracing::info!("Paying {} as request {}", invoice.hash, payment_id);
+
+    let payment_started = Instant::now();
+    let payment_result =
+        make_payment_with_retry(&state.inner, payment_config, invoice.outgoing_options).await;
+    state.inner.metrics().record_payment(
+        &invoice.destination,
+        payment_result.is_ok(),
+        payment_started.elapsed(),
+    );
+
+    let mut result = BatchPaymentResult {
+        request_id: payment_id,
+        request: invoice.request,
+        status: BatchPaymentStatus::Failed,
+        payment_hash: invoice.hash.to_string(),
+        payment_proof: None,
+        amount_paid_sat: 0,
+        fee_paid_sat: 0,
+        error: None,
+    };
+
+    let payment_response = match payment_result {
+        Ok(payment_response) => payment_response,
+        Err(e) => {
+            let error = payment_error(payment_config, &e);
+            state.inner.notify_payment(PaymentEvent::failed(
+                payment_id,
+                Some(invoice.amount),
+                Some(invoice.hash.to_string()),
+                &error,
+            ));
+            let rate_limited = error.error_kind == ErrorKind::RateLimited;
+            result.error =
+                Some(error.sanitized(state.inner.settings().server.expose_error_details));
+            if rate_limited {
+                return (result, Amount::ZERO);
+            }
+
+            // The tokens are already claimed, so the payment's share is only
+            // returned once the node confirms it wasn't sent
+            let payment_identifier = PaymentIdentifier::PaymentHash(invoice.hash.to_byte_array());
+            let status = state
+                .inner
+                .node()
+                .check_outgoing_payment(&payment_identifier)
+                .await
+                .map(|response| response.status);
+            if matches!(status, Ok(MeltQuoteState::Unpaid | MeltQuoteState::Failed)) {
+                return (result, Amount::ZERO);
+            }
+
+            let taken = invoice.amount + invoice.fee_reserve;
+            tracing::error!(
+                "Payment {} may have been sent, holding {} until it is resolved",
+                invoice.hash,
+                taken
+            );
+            result.status = BatchPaymentStatus::Pending;
+            return (result, taken);
+        }
+    };
+
+    state
+        .inner
+        .metrics()
+        .record_paid(payment_response.total_spent);
+    state.inner.lnurl().forget(&invoice.hash);
+
+    // The tokens don't need the preimage, it is only returned as proof of payment
+    let preimage = match payment_response.payment_proof {
+        Some(preimage) => Some(preimage),
+        None => state.inner.query_preimage(&invoice.hash).await,
+    };
+
+    let record = PaymentRecord {
+        request_id: payment_id,
+        amount: invoice.amount,
+        fee: payment_response
+            .total_spent
+            .checked_sub(invoice.amount)
+            .unwrap_or_default(),
+        payment_hash: invoice.hash.to_string(),
+        timestamp: unix_time(),
+        change: Amount::ZERO,
+    };
+    if let Err(e) = state.inner.payment_store().add(&record) {
+        tracing::error!("Failed to record payment in history: {}", e);
+    }
+    state.inner.notify_payment(PaymentEvent::succeeded(&record));
+
+    result.status = BatchPaymentStatus::Paid;
+    result.payment_proof = preimage;
+    result.amount_paid_sat = u64::from(record.amount);
+    result.fee_paid_sat = u64::from(record.fee);
+    (result, payment_response.total_spent)
+}
@@ -0,0 +1,169 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use axum::Json;
+use bitcoin::hashes::Hash as _;
+use bitcoin::hashes::sha256::Hash as Sha256Hash;
+use cdk::util::unix_time;
+use serde::{Deserialize, Serialize};
+
+use crate::gateway_server::{CdkGateway, ErrorKind, ErrorResponse, MeltRequest, MeltResponse};
+use crate::payment_store::PaymentStore;
+
+/// Request header carrying a client chosen idempotency key
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Outcome of a payment request stored against its idempotency key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "outcome", content = "response", rename_all = "snake_case")]
+pub enum CachedResponse {
+    Success(MeltResponse),
+    Error(ErrorResponse),
+}
+
+impl CachedResponse {
+    /// Convert back into the handler result it was stored from
+    pub fn into_result(self) -> Result<Json<MeltResponse>, ErrorResponse> {
+        match self {
+            Self::Success(response) => Ok(Json(response)),
+            Self::Error(error) => Err(error),
+        }
+    }
+}
+
+/// Outcome stored against an idempotency key, with the request it answered
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredResponse {
+    /// SHA-256 of the request, a key sent again with another request is rejected
+    pub request_hash: String,
+    /// Unix time the response was stored at
+    pub timestamp: u64,
+    pub response: CachedResponse,
+}
+
+/// Result of starting a request with an idempotency key
+pub enum IdempotencyStatus {
+    /// The key was used before, replay this response
+    Cached(CachedResponse),
+    /// The key is new, the request should run and complete the guard
+    New(IdempotencyGuard),
+}
+
+/// Marks an idempotency key as in progress until dropped
+pub struct IdempotencyGuard {
+    keys: Arc<Mutex<HashSet<String>>>,
+    key: String,
+    request_hash: String,
+    ttl_secs: u64,
+}
+
+impl IdempotencyGuard {
+    /// Store the outcome of the request so retries with the same key replay it
+    ///
+    /// Errors the request can recover from, such as a 402 asking for more
+    /// ecash or the gateway being busy or rate limited, are not stored, so a
+    /// retry with the same key executes normally.
+    pub fn complete(
+        self,
+        store: &PaymentStore,
+        result: &Result<Json<MeltResponse>, ErrorResponse>,
+    ) {
+        let cached = match result {
+            Ok(Json(response)) => CachedResponse::Success(response.clone()),
+            Err(error) if error.is_retryable() => return,
+            Err(error) => CachedResponse::Error(error.clone()),
+        };
+
+        let now = unix_time();
+        let stored = StoredResponse {
+            request_hash: self.request_hash.clone(),
+            timestamp: now,
+            response: cached,
+        };
+        let expired_before = now.saturating_sub(self.ttl_secs);
+        if let Err(e) = store.add_cached_response(&self.key, &stored, expired_before) {
+            tracing::error!("Failed to store response for idempotency key: {}", e);
+        }
+    }
+}
+
+impl Drop for IdempotencyGuard {
+    fn drop(&mut self) {
+        self.keys
+            .lock()
+            .expect("idempotency lock poisoned")
+            .remove(&self.key);
+    }
+}
+
+/// Key `key` is stored under for `client`
+///
+/// Keys are scoped to the client so one client can't replay another's
+/// responses, and hashed so API keys never end up in the store.
+fn scoped_key(client: &str, key: &str) -> String {
+    Sha256Hash::hash(format!("{}\n{}", client, key).as_bytes()).to_string()
+}
+
+/// Claim `key` for a new request or find the response of an earlier one
+///
+/// Concurrent requests with the same key are rejected with a 409 rather than
+/// waiting, since the first one may take as long as a lightning payment. A key
+/// reused with a different request is rejected with a 422, and responses older
+/// than the configured TTL are forgotten.
+pub fn begin_idempotent_request(
+    gateway: &CdkGateway,
+    client: &str,
+    key: &str,
+    request: &MeltRequest,
+) -> Result<IdempotencyStatus, ErrorResponse> {
+    let keys = gateway.idempotency_keys().clone();
+    let key = scoped_key(client, key);
+    let request_hash =
+        Sha256Hash::hash(&serde_json::to_vec(request).expect("payment requests serialize to JSON"))
+            .to_string();
+    let ttl_secs = gateway.payment_config().idempotency_ttl_secs;
+
+    if !keys
+        .lock()
+        .expect("idempotency lock poisoned")
+        .insert(key.clone())
+    {
+        return Err(ErrorResponse::new(
+            ErrorKind::RequestInProgress,
+            "Request already in progress",
+        )
+        .with_details("A request with this idempotency key is still being processed"));
+    }
+
+    // Look up the cache only after claiming the key, so a request completing
+    // between the lookup and the claim can't be executed a second time
+    let guard = IdempotencyGuard {
+        keys,
+        key,
+        request_hash,
+        ttl_secs,
+    };
+
+    match gateway.payment_store().cached_response(&guard.key) {
+        Ok(Some(stored)) if stored.timestamp.saturating_add(ttl_secs) <= unix_time() => {
+            tracing::debug!("Stored response for idempotency key has expired");
+            Ok(IdempotencyStatus::New(guard))
+        }
+        Ok(Some(stored)) if stored.request_hash != guard.request_hash => {
+            tracing::debug!("Rejecting idempotency key reused with a different request");
+            Err(
+                ErrorResponse::new(ErrorKind::IdempotencyKeyReused, "Idempotency key reused")
+                    .with_details("The idempotency key was already used with a different request"),
+            )
+        }
+        Ok(Some(stored)) => {
+            tracing::info!("Replaying stored response for idempotency key");
+            Ok(IdempotencyStatus::Cached(stored.response))
+        }
+        Ok(None) => Ok(IdempotencyStatus::New(guard)),
+        Err(e) => {
+            tracing::error!("Failed to look up idempotency key: {}", e);
+            Err(ErrorResponse::internal("Failed to look up idempotency key"))
+        }
+    }
+}
@@ -1,2 +1,19 @@
+pub mod admin;
+pub mod auth;
 pub mod config;
+pub mod cors;
 pub mod gateway_server;
+pub mod idempotency;
+pub mod lnurl;
+pub mod metrics;
+pub mod mint_info;
+pub mod node;
+pub mod openapi;
+pub mod payment_store;
+pub mod quote;
+pub mod rate_limit;
+pub mod rates;
+pub mod request_log;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod webhook;
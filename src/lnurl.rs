@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bitcoin::bech32;
+use bitcoin::hashes::sha256::Hash as Sha256Hash;
+use cdk::Bolt11Invoice;
+use cdk::amount::Amount;
+use reqwest::Url;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect;
+use serde::Deserialize;
+
+use crate::config::LnurlConfig;
+
+/// Most resolved invoices kept for reuse, so destinations can't grow the cache without bound
+const MAX_RESOLVED_INVOICES: usize = 1_000;
+
+/// Most redirects followed for one request
+const MAX_REDIRECTS: usize = 5;
+
+/// Why a payment destination could not be resolved to an invoice
+#[derive(Debug)]
+pub enum LnurlError {
+    /// The destination is not a valid lightning address or LNURL
+    InvalidDestination(String),
+    /// The amount is outside what the recipient accepts
+    AmountOutOfRange { min_sat: u64, max_sat: u64 },
+    /// The recipient's server could not be reached or returned something unusable
+    Remote(String),
+}
+
+impl fmt::Display for LnurlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LnurlError::InvalidDestination(reason) => write!(f, "{}", reason),
+            LnurlError::AmountOutOfRange { min_sat, max_sat } => write!(
+                f,
+                "Recipient accepts between {} and {} sat",
+                min_sat, max_sat
+            ),
+            LnurlError::Remote(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// LUD-06 pay parameters
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PayParams {
+    callback: String,
+    min_sendable: u64,
+    max_sendable: u64,
+    tag: String,
+}
+
+/// LUD-06 callback response
+#[derive(Debug, Deserialize)]
+struct CallbackResponse {
+    pr: Option<String>,
+    reason: Option<String>,
+}
+
+/// Resolves LNURL-pay destinations to invoices
+///
+/// Resolved invoices are reused until they expire or are paid, so a client
+/// that repeats a request after being asked to pay gets the same payment hash
+/// and can lock its tokens to it.
+///
+/// Destinations come from clients, so only public https servers (or http onion
+/// services) are contacted: hosts that are, or resolve to, loopback or private
+/// addresses are refused, including after a redirect.
+pub struct LnurlResolver {
+    client: reqwest::Client,
+    resolved: Mutex<HashMap<(String, Amount), Bolt11Invoice>>,
+    allow_private: bool,
+}
+
+impl LnurlResolver {
+    pub fn new(config: &LnurlConfig) -> Self {
+        Self::build(config, false, None)
+    }
+
+    /// A resolver that also contacts loopback and private hosts, trusting
+    /// `root_certificate`, for tests against local LNURL servers
+    #[cfg(feature = "testing")]
+    pub fn for_local_servers(config: &LnurlConfig, root_certificate: reqwest::Certificate) -> Self {
+        Self::build(config, true, Some(root_certificate))
+    }
+
+    fn build(
+        config: &LnurlConfig,
+        allow_private: bool,
+        root_certificate: Option<reqwest::Certificate>,
+    ) -> Self {
+        let mut builder = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.request_timeout_secs))
+            .redirect(redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("Too many redirects")
+                } else if let Err(e) = check_url(attempt.url(), allow_private) {
+                    attempt.error(e.to_string())
+                } else {
+                    attempt.follow()
+                }
+            }));
+        if !allow_private {
+            builder = builder.dns_resolver(Arc::new(PublicResolver));
+        }
+        if let Some(root_certificate) = root_certificate {
+            builder = builder.add_root_certificate(root_certificate);
+        }
+        let client = builder.build().expect("http client config is valid");
+
+        Self {
+            client,
+            resolved: Mutex::new(HashMap::new()),
+            allow_private,
+        }
+    }
+
+    /// Resolve a lightning address such as `name@domain` to an invoice for `amount`
+    pub async fn resolve_lightning_address(
+        &self,
+        address: &str,
+        amount: Amount,
+    ) -> Result<Bolt11Invoice, LnurlError> {
+        let url = lightning_address_url(address)?;
+        self.resolve(address, &url, amount).await
+    }
+
+    /// Resolve a bech32 LNURL-pay string, or a LUD-17 `lnurlp://` URL, to an invoice
+    /// for `amount`
+    pub async fn resolve_lnurl(
+        &self,
+        lnurl: &str,
+        amount: Amount,
+    ) -> Result<Bolt11Invoice, LnurlError> {
+        let url = lnurl_url(lnurl)?;
+        self.resolve(lnurl, &url, amount).await
+    }
+
+    async fn resolve(
+        &self,
+        destination: &str,
+        url: &Url,
+        amount: Amount,
+    ) -> Result<Bolt11Invoice, LnurlError> {
+        let key = (destination.to_string(), amount);
+        if let Some(invoice) = self.cached(&key) {
+            tracing::debug!("Reusing invoice resolved for {}", destination);
+            return Ok(invoice);
+        }
+
+        tracing::debug!("Resolving {} for {} sat", destination, amount);
+        check_url(url, self.allow_private)?;
+        let invoice = self.fetch_invoice(url, amount).await?;
+
+        let mut resolved = self.resolved.lock().expect("lnurl cache lock poisoned");
+        if resolved.len() >= MAX_RESOLVED_INVOICES {
+            // Make room by dropping the invoice that expires first
+            if let Some(oldest) = resolved
+                .iter()
+                .min_by_key(|(_, invoice)| invoice.expires_at())
+                .map(|(key, _)| key.clone())
+            {
+                resolved.remove(&oldest);
+            }
+        }
+        resolved.insert(key, invoice.clone());
+
+        Ok(invoice)
+    }
+
+    /// Stop reusing the invoice for `payment_hash` once it has been paid
+    ///
+    /// The gateway is shared between clients, so a later payment to the same
+    /// destination and amount must get an invoice of its own.
+    pub fn forget(&self, payment_hash: &Sha256Hash) {
+        self.resolved
+            .lock()
+            .expect("lnurl cache lock poisoned")
+            .retain(|_, invoice| invoice.payment_hash() != payment_hash);
+    }
+
+    fn cached(&self, key: &(String, Amount)) -> Option<Bolt11Invoice> {
+        let mut resolved = self.resolved.lock().expect("lnurl cache lock poisoned");
+        resolved.retain(|_, invoice| !invoice.is_expired());
+        resolved.get(key).cloned()
+    }
+
+    async fn fetch_invoice(&self, url: &Url, amount: Amount) -> Result<Bolt11Invoice, LnurlError> {
+        let params: PayParams = self.get_json(url.clone()).await?;
+        if params.tag != "payRequest" {
+            return Err(LnurlError::Remote(format!(
+                "Expected a payRequest, got {}",
+                params.tag
+            )));
+        }
+
+        // An amount too large to express in msat is above any maximum
+        let amount_msat = u64::from(amount)
+            .checked_mul(1_000)
+            .filter(|msat| (params.min_sendable..=params.max_sendable).contains(msat))
+            .ok_or(LnurlError::AmountOutOfRange {
+                min_sat: params.min_sendable.div_ceil(1_000),
+                max_sat: params.max_sendable / 1_000,
+            })?;
+
+        let mut callback = Url::parse(&params.callback)
+            .map_err(|e| LnurlError::Remote(format!("Invalid callback URL: {}", e)))?;
+        check_url(&callback, self.allow_private)
+            .map_err(|e| LnurlError::Remote(format!("Invalid callback URL: {}", e)))?;
+        callback
+            .query_pairs_mut()
+            .append_pair("amount", &amount_msat.to_string());
+        let response: CallbackResponse = self.get_json(callback).await?;
+        let pr = response.pr.ok_or_else(|| {
+            LnurlError::Remote(
+                response
+                    .reason
+                    .unwrap_or_else(|| "Callback returned no invoice".to_string()),
+            )
+        })?;
+
+        let invoice: Bolt11Invoice = pr
+            .parse()
+            .map_err(|_| LnurlError::Remote("Callback returned an invalid invoice".to_string()))?;
+
+        // Never pay an invoice for a different amount than was requested
+        if invoice.amount_milli_satoshis() != Some(amount_msat) {
+            return Err(LnurlError::Remote(format!(
+                "Callback returned an invoice for {:?} msat instead of {} msat",
+                invoice.amount_milli_satoshis(),
+                amount_msat
+            )));
+        }
+
+        Ok(invoice)
+    }
+
+    async fn get_json<T: for<'de> Deserialize<'de>>(&self, url: Url) -> Result<T, LnurlError> {
+        self.client
+            .get(url.clone())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| LnurlError::Remote(format!("Request to {} failed: {}", url, e)))?
+            .json()
+            .await
+            .map_err(|e| LnurlError::Remote(format!("Invalid response from {}: {}", url, e)))
+    }
+}
+
+/// Resolves host names to their public addresses only
+///
+/// Checking the URL alone isn't enough, as a public name can point at a
+/// private address.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                let error: Box<dyn std::error::Error + Send + Sync> =
+                    format!("{} does not resolve to a public address", name.as_str()).into();
+                return Err(error);
+            }
+
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+/// Check a URL may be requested
+///
+/// Only https is allowed, or http for onion services, and hosts given as an
+/// address must be public.
+fn check_url(url: &Url, allow_private: bool) -> Result<(), LnurlError> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| LnurlError::InvalidDestination(format!("{} has no host", url)))?;
+
+    match url.scheme() {
+        "https" => {}
+        "http" if is_onion(host) => {}
+        _ => {
+            return Err(LnurlError::InvalidDestination(
+                "Only https URLs are supported".to_string(),
+            ));
+        }
+    }
+
+    if allow_private {
+        return Ok(());
+    }
+
+    // IPv6 hosts are bracketed in URLs
+    let private = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => !is_public(ip),
+        Err(_) => {
+            let domain = host.trim_end_matches('.').to_lowercase();
+            domain == "localhost" || domain.ends_with(".localhost")
+        }
+    };
+    if private {
+        return Err(LnurlError::InvalidDestination(format!(
+            "{} is not a public host",
+            host
+        )));
+    }
+
+    Ok(())
+}
+
+fn is_onion(domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    domain
+        .strip_suffix(".onion")
+        .is_some_and(|name| !name.is_empty())
+}
+
+/// Whether an address is reachable on the public internet
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // This network, shared address space, benchmarking and reserved
+                || a == 0
+                || (a == 100 && (64..128).contains(&b))
+                || (a == 198 && (18..20).contains(&b))
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                !(ip.is_unspecified()
+                    || ip.is_loopback()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+                    // Documentation
+                    || (ip.segments()[0] == 0x2001 && ip.segments()[1] == 0x0db8))
+            }
+        },
+    }
+}
+
+/// LUD-16 well-known URL for a lightning address
+fn lightning_address_url(address: &str) -> Result<Url, LnurlError> {
+    let (name, domain) = address.split_once('@').ok_or_else(|| {
+        LnurlError::InvalidDestination(format!("{} is not a lightning address", address))
+    })?;
+
+    if name.is_empty() || domain.is_empty() || domain.contains(['/', '@']) {
+        return Err(LnurlError::InvalidDestination(format!(
+            "{} is not a lightning address",
+            address
+        )));
+    }
+
+    let scheme = if is_onion(domain) { "http" } else { "https" };
+    let url = format!("{}://{}/.well-known/lnurlp/{}", scheme, domain, name);
+    Url::parse(&url).map_err(|_| {
+        LnurlError::InvalidDestination(format!("{} is not a lightning address", address))
+    })
+}
+
+/// URL encoded by an LNURL
+fn lnurl_url(lnurl: &str) -> Result<Url, LnurlError> {
+    let lnurl = lnurl.trim();
+    let lnurl = lnurl
+        .strip_prefix("lightning:")
+        .or_else(|| lnurl.strip_prefix("LIGHTNING:"))
+        .unwrap_or(lnurl);
+
+    if let Some(rest) = lnurl.strip_prefix("lnurlp://") {
+        // LUD-17: onion services are reached over http, everything else over https
+        let url = Url::parse(&format!("https://{}", rest))
+            .map_err(|e| LnurlError::InvalidDestination(format!("Invalid LNURL: {}", e)))?;
+        if url.host_str().is_some_and(is_onion) {
+            return Url::parse(&format!("http://{}", rest))
+                .map_err(|e| LnurlError::InvalidDestination(format!("Invalid LNURL: {}", e)));
+        }
+        return Ok(url);
+    }
+
+    let (hrp, data) = bech32::decode(lnurl)
+        .map_err(|e| LnurlError::InvalidDestination(format!("Invalid LNURL: {}", e)))?;
+    if hrp.to_lowercase() != "lnurl" {
+        return Err(LnurlError::InvalidDestination(format!(
+            "Invalid LNURL: unexpected prefix {}",
+            hrp
+        )));
+    }
+
+    String::from_utf8(data)
+        .ok()
+        .and_then(|url| Url::parse(&url).ok())
+        .ok_or_else(|| LnurlError::InvalidDestination("Invalid LNURL: not a URL".to_string()))
+}
@@ -0,0 +1,261 @@
+use std::time::Duration;
+
+use cdk::amount::Amount;
+use cdk::mint_url::MintUrl;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry,
+    TextEncoder,
+};
+
+use crate::config::MetricsConfig;
+
+/// Label value used in place of a high-cardinality label that has been disabled
+const AGGREGATED_LABEL: &str = "all";
+
+/// Prometheus metrics collected by the gateway
+pub struct Metrics {
+    registry: Registry,
+    config: MetricsConfig,
+    payments: IntCounter,
+    failed_payments: IntCounter,
+    paid_sats: IntCounter,
+    payment_duration: Histogram,
+    receives: IntCounterVec,
+    dleq_verifications: IntCounterVec,
+    received_sats: IntCounterVec,
+    change_sats: IntCounterVec,
+    destination_payments: IntCounterVec,
+    destination_payment_duration: HistogramVec,
+    missing_preimages: IntCounter,
+    reconcile_failures: IntCounter,
+    change_anomalies: IntCounter,
+    overpaid_sats: IntCounter,
+}
+
+impl Metrics {
+    /// Create a new set of metrics registered in their own registry
+    pub fn new(config: MetricsConfig) -> Result<Self, prometheus::Error> {
+        let registry = Registry::new();
+
+        let payments = IntCounter::new("gateway_payments_total", "Payment requests handled")?;
+        let failed_payments = IntCounter::new(
+            "gateway_payments_failed_total",
+            "Payment requests that failed",
+        )?;
+        let paid_sats = IntCounter::new(
+            "gateway_paid_sats_total",
+            "Sats spent paying lightning invoices, including routing fees",
+        )?;
+        let payment_duration = Histogram::with_opts(HistogramOpts::new(
+            "gateway_payment_duration_seconds",
+            "Time taken to handle payment requests end to end",
+        ))?;
+        let receives = IntCounterVec::new(
+            Opts::new(
+                "gateway_receives_total",
+                "Token receives by mint and outcome",
+            ),
+            &["mint_url", "outcome"],
+        )?;
+        let dleq_verifications = IntCounterVec::new(
+            Opts::new(
+                "gateway_dleq_verifications_total",
+                "Token DLEQ verifications by mint and outcome",
+            ),
+            &["mint_url", "outcome"],
+        )?;
+        let received_sats = IntCounterVec::new(
+            Opts::new(
+                "gateway_received_sats_total",
+                "Sats received from tokens by mint",
+            ),
+            &["mint_url"],
+        )?;
+        let change_sats = IntCounterVec::new(
+            Opts::new(
+                "gateway_change_sats_total",
+                "Sats returned as change by mint",
+            ),
+            &["mint_url"],
+        )?;
+        let destination_payments = IntCounterVec::new(
+            Opts::new(
+                "gateway_destination_payments_total",
+                "Lightning payments by destination node and outcome",
+            ),
+            &["destination", "outcome"],
+        )?;
+        let destination_payment_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "gateway_destination_payment_duration_seconds",
+                "Time spent making lightning payments by destination node",
+            ),
+            &["destination"],
+        )?;
+
+        let missing_preimages = IntCounter::new(
+            "gateway_missing_preimages_total",
+            "Payments sent without the node returning a preimage",
+        )?;
+
+        let reconcile_failures = IntCounter::new(
+            "gateway_reconcile_failures_total",
+            "Attempts to claim the tokens of an unclaimed payment that failed",
+        )?;
+
+        let change_anomalies = IntCounter::new(
+            "gateway_change_anomalies_total",
+            "Payments whose change did not add up to the amount due",
+        )?;
+
+        let overpaid_sats = IntCounter::new(
+            "gateway_overpaid_sats_total",
+            "Sats spent on lightning beyond the ecash received, lost by the gateway",
+        )?;
+
+        registry.register(Box::new(payments.clone()))?;
+        registry.register(Box::new(failed_payments.clone()))?;
+        registry.register(Box::new(paid_sats.clone()))?;
+        registry.register(Box::new(payment_duration.clone()))?;
+        registry.register(Box::new(receives.clone()))?;
+        registry.register(Box::new(dleq_verifications.clone()))?;
+        registry.register(Box::new(received_sats.clone()))?;
+        registry.register(Box::new(change_sats.clone()))?;
+        registry.register(Box::new(destination_payments.clone()))?;
+        registry.register(Box::new(destination_payment_duration.clone()))?;
+        registry.register(Box::new(missing_preimages.clone()))?;
+        registry.register(Box::new(reconcile_failures.clone()))?;
+        registry.register(Box::new(change_anomalies.clone()))?;
+        registry.register(Box::new(overpaid_sats.clone()))?;
+
+        Ok(Self {
+            registry,
+            config,
+            payments,
+            failed_payments,
+            paid_sats,
+            payment_duration,
+            receives,
+            dleq_verifications,
+            received_sats,
+            change_sats,
+            destination_payments,
+            destination_payment_duration,
+            missing_preimages,
+            reconcile_failures,
+            change_anomalies,
+            overpaid_sats,
+        })
+    }
+
+    /// Registry holding all gateway metrics
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Encode all metrics in the Prometheus text exposition format
+    pub fn encode(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        String::from_utf8(buffer).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+
+    /// Record a handled payment request and how long it took
+    pub fn record_request(&self, success: bool, duration: Duration) {
+        self.payments.inc();
+        if !success {
+            self.failed_payments.inc();
+        }
+        self.payment_duration.observe(duration.as_secs_f64());
+    }
+
+    /// Record sats spent on a successful lightning payment
+    pub fn record_paid(&self, amount: Amount) {
+        self.paid_sats.inc_by(u64::from(amount));
+    }
+
+    /// Record a successful token receive from `mint_url`
+    pub fn record_receive(&self, mint_url: &MintUrl, amount: Amount) {
+        let mint_url = self.mint_label(mint_url);
+        self.receives
+            .with_label_values(&[mint_url.as_str(), "success"])
+            .inc();
+        self.received_sats
+            .with_label_values(&[mint_url.as_str()])
+            .inc_by(u64::from(amount));
+    }
+
+    /// Record a failed token receive from `mint_url`
+    pub fn record_receive_failure(&self, mint_url: &MintUrl) {
+        let mint_url = self.mint_label(mint_url);
+        self.receives
+            .with_label_values(&[mint_url.as_str(), "failure"])
+            .inc();
+    }
+
+    /// Record the outcome of verifying the DLEQ proofs of a token from `mint_url`
+    pub fn record_dleq_verification(&self, mint_url: &MintUrl, success: bool) {
+        let mint_url = self.mint_label(mint_url);
+        let outcome = if success { "success" } else { "failure" };
+        self.dleq_verifications
+            .with_label_values(&[mint_url.as_str(), outcome])
+            .inc();
+    }
+
+    /// Record change returned from the wallet for `mint_url`
+    pub fn record_change(&self, mint_url: &MintUrl, amount: Amount) {
+        let mint_url = self.mint_label(mint_url);
+        self.change_sats
+            .with_label_values(&[mint_url.as_str()])
+            .inc_by(u64::from(amount));
+    }
+
+    /// Record the outcome and duration of a payment to `destination`
+    pub fn record_payment(&self, destination: &str, success: bool, duration: Duration) {
+        let destination = self.destination_label(destination);
+        let outcome = if success { "success" } else { "failure" };
+        self.destination_payments
+            .with_label_values(&[destination, outcome])
+            .inc();
+        self.destination_payment_duration
+            .with_label_values(&[destination])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Record a payment sent without the node returning its preimage
+    pub fn record_missing_preimage(&self) {
+        self.missing_preimages.inc();
+    }
+
+    /// Record a failed attempt to claim the tokens of an unclaimed payment
+    pub fn record_reconcile_failure(&self) {
+        self.reconcile_failures.inc();
+    }
+
+    /// Record a payment whose change exceeded the amount received or did not
+    /// add up to the change due
+    pub fn record_change_anomaly(&self) {
+        self.change_anomalies.inc();
+    }
+
+    /// Record a payment that spent more than the ecash it was paid with
+    pub fn record_overpayment(&self, deficit: Amount) {
+        self.overpaid_sats.inc_by(u64::from(deficit));
+    }
+
+    fn mint_label(&self, mint_url: &MintUrl) -> String {
+        if self.config.mint_labels {
+            mint_url.to_string()
+        } else {
+            AGGREGATED_LABEL.to_string()
+        }
+    }
+
+    fn destination_label<'a>(&self, destination: &'a str) -> &'a str {
+        if self.config.destination_labels {
+            destination
+        } else {
+            AGGREGATED_LABEL
+        }
+    }
+}
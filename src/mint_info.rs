@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use cdk::mint_url::MintUrl;
+use cdk::nuts::CurrencyUnit;
+use cdk::wallet::Wallet;
+use cdk::wallet::types::WalletKey;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::MintInfoConfig;
+use crate::gateway_server::{GatwayState, MintMetadata};
+
+/// Delay before retrying mints that could not be reached, doubled on each failure
+const RETRY_BACKOFF: Duration = Duration::from_secs(2);
+
+struct CachedMintInfo {
+    metadata: MintMetadata,
+    fetched_at: Instant,
+}
+
+/// Metadata of the supported mints, so endpoints don't contact the mints on
+/// every request
+pub struct MintInfoCache {
+    ttl: Duration,
+    entries: RwLock<HashMap<MintUrl, CachedMintInfo>>,
+}
+
+impl MintInfoCache {
+    pub fn new(config: &MintInfoConfig) -> Self {
+        Self {
+            ttl: Duration::from_secs(config.ttl_secs),
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// How long fetched metadata is served before it is fetched again
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Metadata of `url`, if it was fetched within the TTL
+    pub fn get(&self, url: &MintUrl) -> Option<MintMetadata> {
+        let entries = self.entries.read().expect("mint info lock poisoned");
+        entries
+            .get(url)
+            .filter(|entry| entry.fetched_at.elapsed() < self.ttl)
+            .map(|entry| entry.metadata.clone())
+    }
+
+    /// Whether info has been fetched from any mint, however long ago
+    pub fn has_reachable_mint(&self) -> bool {
+        self.entries
+            .read()
+            .expect("mint info lock poisoned")
+            .values()
+            .any(|entry| entry.metadata.reachable)
+    }
+
+    /// Fetch the info and keysets of the wallet's mint and cache them
+    ///
+    /// When the mint can't be reached the last known metadata is kept and
+    /// marked unreachable.
+    pub async fn refresh(&self, wallet: &Wallet) -> MintMetadata {
+        let url = wallet.mint_url.clone();
+        tracing::debug!("Fetching mint info for {}", url);
+
+        let fetched = async {
+            let info = wallet.get_mint_info().await?;
+            let keysets = wallet.get_mint_keysets().await?;
+            Ok::<_, cdk::Error>((info, keysets))
+        }
+        .await;
+
+        let metadata = match fetched {
+            Ok((info, keysets)) => {
+                let mut units: Vec<CurrencyUnit> = Vec::new();
+                for keyset in keysets.iter().filter(|keyset| keyset.active) {
+                    if !units.contains(&keyset.unit) {
+                        units.push(keyset.unit.clone());
+                    }
+                }
+                let fee_ppk = keysets
+                    .iter()
+                    .find(|keyset| keyset.active && keyset.unit == CurrencyUnit::Sat)
+                    .map(|keyset| keyset.input_fee_ppk);
+
+                MintMetadata {
+                    url: url.clone(),
+                    reachable: info.is_some(),
+                    name: info.and_then(|info| info.name),
+                    units,
+                    fee_ppk,
+                }
+            }
+            Err(e) => {
+                tracing::warn!("Could not get mint info for {}: {}", url, e);
+                let previous = self
+                    .entries
+                    .read()
+                    .expect("mint info lock poisoned")
+                    .get(&url)
+                    .map(|entry| entry.metadata.clone());
+
+                MintMetadata {
+                    reachable: false,
+                    ..previous.unwrap_or_else(|| unknown_mint(url.clone()))
+                }
+            }
+        };
+
+        self.entries
+            .write()
+            .expect("mint info lock poisoned")
+            .insert(
+                url,
+                CachedMintInfo {
+                    metadata: metadata.clone(),
+                    fetched_at: Instant::now(),
+                },
+            );
+
+        metadata
+    }
+}
+
+/// Metadata of a mint the gateway knows nothing about
+pub fn unknown_mint(url: MintUrl) -> MintMetadata {
+    MintMetadata {
+        url,
+        name: None,
+        units: Vec::new(),
+        reachable: false,
+        fee_ppk: None,
+    }
+}
+
+/// Keep the metadata of every supported mint cached until `cancel` is triggered
+///
+/// Mints are fetched again once per TTL. While any of them is unreachable
+/// they are retried sooner, with backoff.
+pub async fn refresh_mint_info(state: GatwayState, cancel: CancellationToken) {
+    let cache = state.inner.mint_info();
+    let mut backoff = RETRY_BACKOFF;
+
+    loop {
+        let mut all_reachable = true;
+        for url in state.supported_mints() {
+            let wallet = state
+                .inner
+                .wallets()
+                .get_wallet(&WalletKey::new(url, CurrencyUnit::Sat))
+                .await;
+            if let Some(wallet) = wallet {
+                all_reachable &= cache.refresh(&wallet).await.reachable;
+            }
+        }
+
+        let delay = if all_reachable {
+            backoff = RETRY_BACKOFF;
+            cache.ttl()
+        } else {
+            let delay = backoff.min(cache.ttl());
+            tracing::debug!("Retrying unreachable mints in {:?}", delay);
+            // Held to the TTL, so it settles there instead of growing until it overflows
+            backoff = (backoff * 2).min(cache.ttl());
+            delay
+        };
+
+        tokio::select! {
+            _ = cancel.cancelled() => return,
+            _ = tokio::time::sleep(delay) => {}
+        }
+    }
+}
@@ -0,0 +1,623 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use cdk::cdk_payment::{
+    self, CreateIncomingPaymentResponse, IncomingPaymentOptions, MakePaymentResponse, MintPayment,
+    OutgoingPaymentOptions, PaymentIdentifier, PaymentQuoteResponse, WaitPaymentResponse,
+};
+use cdk::nuts::{CurrencyUnit, MeltQuoteState};
+use cdk_payment_processor::PaymentProcessorClient;
+use futures::Stream;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::GrpcProcessor;
+
+/// Delay before the first reconnect attempt
+const INITIAL_RECONNECT_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Upper bound on the delay between reconnect attempts
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Number of times the processor is probed at startup before giving up
+const PROBE_ATTEMPTS: u32 = 5;
+
+/// Error messages meaning the connection to the payment processor was lost
+///
+/// Matched against the lowercased error message, since the client reports
+/// gRPC and transport failures as text.
+const CONNECTION_ERRORS: &[&str] = &[
+    "transport error",
+    "broken pipe",
+    "connection refused",
+    "connection reset",
+    "unavailable",
+];
+
+/// Whether the gateway is currently connected to its payment node
+#[derive(Debug)]
+pub struct NodeHealth {
+    connected: AtomicBool,
+    /// Health of the backends of a failover node, any of which being connected is enough
+    members: Vec<Arc<NodeHealth>>,
+}
+
+impl NodeHealth {
+    /// Create a new health state, assuming the node is connected
+    pub fn new() -> Self {
+        Self {
+            connected: AtomicBool::new(true),
+            members: Vec::new(),
+        }
+    }
+
+    /// Health of a node that is connected while any of `members` is
+    pub fn any_of(members: Vec<Arc<NodeHealth>>) -> Self {
+        Self {
+            connected: AtomicBool::new(true),
+            members,
+        }
+    }
+
+    /// Whether the node is connected
+    pub fn is_connected(&self) -> bool {
+        if self.members.is_empty() {
+            self.connected.load(Ordering::SeqCst)
+        } else {
+            self.members.iter().any(|member| member.is_connected())
+        }
+    }
+
+    /// Update the connection state
+    pub fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::SeqCst);
+    }
+}
+
+impl Default for NodeHealth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Payment processor client that reconnects when the connection is lost
+///
+/// Calls failing with a connection error are returned to the caller as is,
+/// since a payment may already have reached the processor. The connection is
+/// re-established in the background with exponential backoff.
+pub struct ReconnectingNode {
+    config: GrpcProcessor,
+    /// Unset until a client could first be built
+    client: RwLock<Option<Arc<PaymentProcessorClient>>>,
+    health: Arc<NodeHealth>,
+    disconnected: Notify,
+}
+
+impl ReconnectingNode {
+    /// Connect to the payment processor and start watching the connection
+    ///
+    /// A processor that can't be connected to doesn't fail startup: the node
+    /// starts out disconnected and keeps reconnecting in the background. The
+    /// processor is pinged until `cancel` is triggered.
+    pub async fn connect(
+        config: GrpcProcessor,
+        health: Arc<NodeHealth>,
+        cancel: CancellationToken,
+    ) -> Arc<Self> {
+        let client = match Self::new_client(&config).await {
+            Ok(client) => {
+                health.set_connected(true);
+                Some(Arc::new(client))
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Could not connect to payment processor at {}:{}, retrying in the background: {}",
+                    config.addr,
+                    config.port,
+                    e
+                );
+                health.set_connected(false);
+                None
+            }
+        };
+
+        let node = Arc::new(Self {
+            config,
+            client: RwLock::new(client),
+            health,
+            disconnected: Notify::new(),
+        });
+        if !node.health.is_connected() {
+            node.disconnected.notify_one();
+        }
+
+        tokio::spawn(node.clone().reconnect_loop());
+        if node.config.ping_interval_secs > 0 {
+            tokio::spawn(node.clone().ping_loop(cancel));
+        }
+
+        node
+    }
+
+    /// Check the processor responds, retrying with backoff
+    ///
+    /// The client may connect lazily, so this is the first point a processor
+    /// that is down or misconfigured is noticed. When it never responds the
+    /// node is marked disconnected and reconnected in the background.
+    pub async fn probe(&self) -> anyhow::Result<()> {
+        let mut backoff = INITIAL_RECONNECT_BACKOFF;
+        let mut attempt = 1;
+
+        loop {
+            match self.get_settings().await {
+                Ok(_) => return Ok(()),
+                Err(e) if attempt < PROBE_ATTEMPTS => {
+                    tracing::warn!(
+                        "Payment processor not reachable (attempt {}/{}), retrying in {:?}: {}",
+                        attempt,
+                        PROBE_ATTEMPTS,
+                        backoff,
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+                    attempt += 1;
+                }
+                Err(e) => {
+                    self.health.set_connected(false);
+                    self.disconnected.notify_one();
+                    anyhow::bail!(
+                        "Payment processor at {}:{} is not reachable after {} attempts: {}",
+                        self.config.addr,
+                        self.config.port,
+                        PROBE_ATTEMPTS,
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    async fn new_client(config: &GrpcProcessor) -> anyhow::Result<PaymentProcessorClient> {
+        PaymentProcessorClient::new(&config.addr, config.port, config.tls_dir.clone()).await
+    }
+
+    fn client(&self) -> Result<Arc<PaymentProcessorClient>, cdk_payment::Error> {
+        self.client
+            .read()
+            .expect("payment processor client lock poisoned")
+            .clone()
+            .ok_or_else(|| {
+                cdk_payment::Error::Custom(format!(
+                    "Not connected to payment processor at {}:{}",
+                    self.config.addr, self.config.port
+                ))
+            })
+    }
+
+    /// Wait for the connection to drop, then reconnect with backoff
+    ///
+    /// A new client only counts as connected once the processor responds.
+    async fn reconnect_loop(self: Arc<Self>) {
+        loop {
+            self.disconnected.notified().await;
+
+            let mut backoff = INITIAL_RECONNECT_BACKOFF;
+            while !self.health.is_connected() {
+                tracing::info!(
+                    "Reconnecting to payment processor at {}:{}",
+                    self.config.addr,
+                    self.config.port
+                );
+
+                let connected = match Self::new_client(&self.config).await {
+                    Ok(client) => client
+                        .get_settings()
+                        .await
+                        .map(|_| client)
+                        .map_err(Into::into),
+                    Err(e) => Err(e),
+                };
+                match connected {
+                    Ok(client) => {
+                        *self
+                            .client
+                            .write()
+                            .expect("payment processor client lock poisoned") =
+                            Some(Arc::new(client));
+                        self.health.set_connected(true);
+                        tracing::info!("Payment processor connection re-established");
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            "Failed to reconnect to payment processor, retrying in {:?}: {}",
+                            backoff,
+                            e
+                        );
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Ping the processor periodically so idle connections aren't silently dropped
+    ///
+    /// `PaymentProcessorClient::new` builds its tonic channel itself and takes
+    /// no endpoint or channel, so HTTP/2 keepalive can't be configured on it.
+    /// Regular requests keep the connection in use instead, and a ping that
+    /// fails or hangs is treated as a lost connection, so it is re-established
+    /// before the next payment rather than failing it.
+    async fn ping_loop(self: Arc<Self>, cancel: CancellationToken) {
+        let interval = Duration::from_secs(self.config.ping_interval_secs);
+
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                _ = tokio::time::sleep(interval) => {}
+            }
+            if !self.health.is_connected() {
+                continue;
+            }
+
+            match tokio::time::timeout(interval, self.get_settings()).await {
+                Ok(Ok(_)) => tracing::trace!("Payment processor ping succeeded"),
+                Ok(Err(e)) => tracing::debug!("Payment processor ping failed: {}", e),
+                Err(_) => {
+                    tracing::error!(
+                        "Payment processor ping timed out after {:?}, reconnecting",
+                        interval
+                    );
+                    self.health.set_connected(false);
+                    self.disconnected.notify_one();
+                }
+            }
+        }
+    }
+
+    /// Mark the node disconnected if `result` failed because the connection was lost
+    fn check<T>(&self, result: Result<T, cdk_payment::Error>) -> Result<T, cdk_payment::Error> {
+        if let Err(err) = &result {
+            let message = err.to_string().to_lowercase();
+            if CONNECTION_ERRORS
+                .iter()
+                .any(|pattern| message.contains(pattern))
+            {
+                tracing::error!("Lost connection to payment processor: {}", err);
+                self.health.set_connected(false);
+                self.disconnected.notify_one();
+            }
+        }
+
+        result
+    }
+}
+
+#[async_trait]
+impl MintPayment for ReconnectingNode {
+    type Err = cdk_payment::Error;
+
+    async fn get_settings(&self) -> Result<serde_json::Value, Self::Err> {
+        self.check(self.client()?.get_settings().await)
+    }
+
+    async fn create_incoming_payment_request(
+        &self,
+        unit: &CurrencyUnit,
+        options: IncomingPaymentOptions,
+    ) -> Result<CreateIncomingPaymentResponse, Self::Err> {
+        self.check(
+            self.client()?
+                .create_incoming_payment_request(unit, options)
+                .await,
+        )
+    }
+
+    async fn get_payment_quote(
+        &self,
+        unit: &CurrencyUnit,
+        options: OutgoingPaymentOptions,
+    ) -> Result<PaymentQuoteResponse, Self::Err> {
+        self.check(self.client()?.get_payment_quote(unit, options).await)
+    }
+
+    async fn make_payment(
+        &self,
+        unit: &CurrencyUnit,
+        options: OutgoingPaymentOptions,
+    ) -> Result<MakePaymentResponse, Self::Err> {
+        self.check(self.client()?.make_payment(unit, options).await)
+    }
+
+    async fn wait_any_incoming_payment(
+        &self,
+    ) -> Result<Pin<Box<dyn Stream<Item = WaitPaymentResponse> + Send>>, Self::Err> {
+        self.check(self.client()?.wait_any_incoming_payment().await)
+    }
+
+    fn is_wait_invoice_active(&self) -> bool {
+        self.client()
+            .is_ok_and(|client| client.is_wait_invoice_active())
+    }
+
+    fn cancel_wait_invoice(&self) {
+        if let Ok(client) = self.client() {
+            client.cancel_wait_invoice()
+        }
+    }
+
+    async fn check_incoming_payment_status(
+        &self,
+        payment_identifier: &PaymentIdentifier,
+    ) -> Result<Vec<WaitPaymentResponse>, Self::Err> {
+        self.check(
+            self.client()?
+                .check_incoming_payment_status(payment_identifier)
+                .await,
+        )
+    }
+
+    async fn check_outgoing_payment(
+        &self,
+        payment_identifier: &PaymentIdentifier,
+    ) -> Result<MakePaymentResponse, Self::Err> {
+        self.check(
+            self.client()?
+                .check_outgoing_payment(payment_identifier)
+                .await,
+        )
+    }
+}
+
+/// A payment processor backend of a [`FailoverNode`]
+pub struct FailoverBackend {
+    /// Name used in logs, e.g. the processor address
+    pub name: String,
+    pub node: Arc<dyn MintPayment<Err = cdk_payment::Error> + Send + Sync>,
+    pub health: Arc<NodeHealth>,
+}
+
+/// Several payment processors used in order, skipping those that are down
+///
+/// A call goes to the first backend that is connected. Calls that fail are
+/// only retried on the next backend when they can't have moved funds, so a
+/// payment that failed or was lost mid-flight is never sent again through
+/// another processor. Payments whose outcome isn't final yet are checked on
+/// the backend that made them.
+pub struct FailoverNode {
+    backends: Vec<FailoverBackend>,
+    /// Backend that made each outgoing payment not yet known to be final, by
+    /// payment lookup id
+    payments: Mutex<HashMap<String, usize>>,
+}
+
+impl FailoverNode {
+    pub fn new(backends: Vec<FailoverBackend>) -> anyhow::Result<Self> {
+        if backends.is_empty() {
+            anyhow::bail!("A failover node needs at least one payment processor");
+        }
+
+        Ok(Self {
+            backends,
+            payments: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Combined health of the backends, connected while any of them is
+    pub fn health(&self) -> NodeHealth {
+        NodeHealth::any_of(
+            self.backends
+                .iter()
+                .map(|backend| backend.health.clone())
+                .collect(),
+        )
+    }
+
+    /// Backends that are currently connected, in order of preference
+    ///
+    /// Falls back to the first backend when none are, so callers still get
+    /// its error.
+    fn available(&self) -> Vec<usize> {
+        let connected: Vec<usize> = (0..self.backends.len())
+            .filter(|&index| self.backends[index].health.is_connected())
+            .collect();
+        if connected.is_empty() {
+            vec![0]
+        } else {
+            connected
+        }
+    }
+
+    fn primary(&self) -> &FailoverBackend {
+        &self.backends[self.available()[0]]
+    }
+
+    /// Number of outgoing payments whose backend is remembered
+    #[cfg(feature = "testing")]
+    pub fn tracked_payments(&self) -> usize {
+        self.payments
+            .lock()
+            .expect("failover payments lock poisoned")
+            .len()
+    }
+}
+
+/// Whether nothing more can be learned about a payment by checking it again
+///
+/// A payment the processor reports as paid without its preimage is still
+/// looked up later, on the backend that made it.
+fn is_final(response: &MakePaymentResponse) -> bool {
+    match response.status {
+        MeltQuoteState::Failed => true,
+        MeltQuoteState::Paid => response.payment_proof.is_some(),
+        _ => false,
+    }
+}
+
+#[async_trait]
+impl MintPayment for FailoverNode {
+    type Err = cdk_payment::Error;
+
+    async fn get_settings(&self) -> Result<serde_json::Value, Self::Err> {
+        let mut last_error = None;
+        for index in self.available() {
+            let backend = &self.backends[index];
+            match backend.node.get_settings().await {
+                Ok(settings) => return Ok(settings),
+                Err(e) => {
+                    tracing::warn!("Payment processor {} failed: {}", backend.name, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.expect("at least one backend is tried"))
+    }
+
+    async fn create_incoming_payment_request(
+        &self,
+        unit: &CurrencyUnit,
+        options: IncomingPaymentOptions,
+    ) -> Result<CreateIncomingPaymentResponse, Self::Err> {
+        self.primary()
+            .node
+            .create_incoming_payment_request(unit, options)
+            .await
+    }
+
+    async fn get_payment_quote(
+        &self,
+        unit: &CurrencyUnit,
+        options: OutgoingPaymentOptions,
+    ) -> Result<PaymentQuoteResponse, Self::Err> {
+        // Quotes don't move funds, so any failure can be retried elsewhere
+        let mut last_error = None;
+        for index in self.available() {
+            let backend = &self.backends[index];
+            match backend.node.get_payment_quote(unit, options.clone()).await {
+                Ok(quote) => return Ok(quote),
+                Err(e) => {
+                    tracing::warn!(
+                        "Payment processor {} could not quote, trying the next: {}",
+                        backend.name,
+                        e
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.expect("at least one backend is tried"))
+    }
+
+    async fn make_payment(
+        &self,
+        unit: &CurrencyUnit,
+        options: OutgoingPaymentOptions,
+    ) -> Result<MakePaymentResponse, Self::Err> {
+        // Backends found disconnected beforehand are skipped, but once one is
+        // called its result is final: the payment may have left the node even
+        // when the call failed.
+        let index = self.available()[0];
+        let backend = &self.backends[index];
+        if index > 0 {
+            tracing::info!(
+                "Paying through payment processor {}, preferred processors are down",
+                backend.name
+            );
+        }
+
+        let response = backend.node.make_payment(unit, options).await?;
+        // Only payments that will be checked again are remembered, so the map
+        // doesn't grow with every payment made
+        if !is_final(&response) {
+            self.payments
+                .lock()
+                .expect("failover payments lock poisoned")
+                .insert(response.payment_lookup_id.to_string(), index);
+        }
+
+        Ok(response)
+    }
+
+    async fn wait_any_incoming_payment(
+        &self,
+    ) -> Result<Pin<Box<dyn Stream<Item = WaitPaymentResponse> + Send>>, Self::Err> {
+        self.primary().node.wait_any_incoming_payment().await
+    }
+
+    fn is_wait_invoice_active(&self) -> bool {
+        self.backends
+            .iter()
+            .any(|backend| backend.node.is_wait_invoice_active())
+    }
+
+    fn cancel_wait_invoice(&self) {
+        for backend in &self.backends {
+            backend.node.cancel_wait_invoice();
+        }
+    }
+
+    async fn check_incoming_payment_status(
+        &self,
+        payment_identifier: &PaymentIdentifier,
+    ) -> Result<Vec<WaitPaymentResponse>, Self::Err> {
+        self.primary()
+            .node
+            .check_incoming_payment_status(payment_identifier)
+            .await
+    }
+
+    async fn check_outgoing_payment(
+        &self,
+        payment_identifier: &PaymentIdentifier,
+    ) -> Result<MakePaymentResponse, Self::Err> {
+        let known = self
+            .payments
+            .lock()
+            .expect("failover payments lock poisoned")
+            .get(&payment_identifier.to_string())
+            .copied();
+
+        match known {
+            Some(index) => {
+                let response = self.backends[index]
+                    .node
+                    .check_outgoing_payment(payment_identifier)
+                    .await?;
+                if is_final(&response) {
+                    self.payments
+                        .lock()
+                        .expect("failover payments lock poisoned")
+                        .remove(&payment_identifier.to_string());
+                }
+                Ok(response)
+            }
+            // Made before a restart, ask every backend until one knows it
+            None => {
+                let mut last_response = None;
+                for backend in &self.backends {
+                    match backend
+                        .node
+                        .check_outgoing_payment(payment_identifier)
+                        .await
+                    {
+                        Ok(response) if response.status != MeltQuoteState::Unknown => {
+                            return Ok(response);
+                        }
+                        Ok(response) => last_response = Some(Ok(response)),
+                        Err(e) => {
+                            if last_response.is_none() {
+                                last_response = Some(Err(e));
+                            }
+                        }
+                    }
+                }
+                last_response.expect("at least one backend is tried")
+            }
+        }
+    }
+}
@@ -0,0 +1,988 @@
+use axum::Json;
+use serde_json::{Value, json};
+
+/// OpenAPI 3 description of the gateway API, for wallet authors
+///
+/// Kept by hand next to the request and response types in `gateway_server`,
+/// update it whenever they change.
+pub fn openapi_document() -> Value {
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "CDK Gateway",
+            "description": "Pay lightning invoices with Cashu ecash",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": {
+            "/payment": {
+                "post": {
+                    "summary": "Pay a lightning invoice with ecash locked to its payment hash",
+                    "parameters": [{
+                        "name": "Idempotency-Key",
+                        "in": "header",
+                        "required": false,
+                        "schema": { "type": "string" },
+                    }],
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": { "$ref": "#/components/schemas/MeltRequest" },
+                            },
+                        },
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The invoice was paid",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "$ref": "#/components/schemas/MeltResponse" },
+                                },
+                            },
+                        },
+                        "402": {
+                            "description": "More ecash is needed, the NUT-18 payment request is also sent in the X-Cashu header",
+                            "headers": {
+                                "X-Cashu": { "schema": { "type": "string" } },
+                            },
+                            "content": {
+                                "application/json": {
+                                    "schema": { "$ref": "#/components/schemas/ErrorResponse" },
+                                },
+                            },
+                        },
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+            "/payment/batch": {
+                "post": {
+                    "summary": "Pay several requests with one set of ecash locked to the gateway's key",
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": { "$ref": "#/components/schemas/BatchMeltRequest" },
+                            },
+                        },
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The tokens were claimed and every payment attempted, each result says whether it was paid",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "$ref": "#/components/schemas/BatchMeltResponse" },
+                                },
+                            },
+                        },
+                        "402": {
+                            "description": "More ecash is needed, the NUT-18 payment request is also sent in the X-Cashu header",
+                            "headers": {
+                                "X-Cashu": { "schema": { "type": "string" } },
+                            },
+                            "content": {
+                                "application/json": {
+                                    "schema": { "$ref": "#/components/schemas/ErrorResponse" },
+                                },
+                            },
+                        },
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+            "/payment/dry-run": {
+                "post": {
+                    "summary": "Check a payment request exactly like /payment, without paying it",
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": { "$ref": "#/components/schemas/MeltRequest" },
+                            },
+                        },
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The request would be paid",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "$ref": "#/components/schemas/DryRunResponse" },
+                                },
+                            },
+                        },
+                        "402": {
+                            "description": "More ecash is needed, the NUT-18 payment request is also sent in the X-Cashu header",
+                            "headers": {
+                                "X-Cashu": { "schema": { "type": "string" } },
+                            },
+                            "content": {
+                                "application/json": {
+                                    "schema": { "$ref": "#/components/schemas/ErrorResponse" },
+                                },
+                            },
+                        },
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+            "/payment/stream": {
+                "post": {
+                    "summary": "Pay like /payment, streaming the progress of the payment as server-sent events",
+                    "parameters": [{
+                        "name": "Idempotency-Key",
+                        "in": "header",
+                        "required": false,
+                        "schema": { "type": "string" },
+                    }],
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": { "$ref": "#/components/schemas/MeltRequest" },
+                            },
+                        },
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "A stage event as each stage starts, then a complete event with a MeltResponse or an error event with an ErrorResponse",
+                            "content": {
+                                "text/event-stream": {
+                                    "schema": { "type": "string" },
+                                },
+                            },
+                        },
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+            "/quote": {
+                "post": {
+                    "summary": "Estimate the cost of paying a request, without any ecash",
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": { "$ref": "#/components/schemas/QuoteRequest" },
+                            },
+                        },
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The estimated cost",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "$ref": "#/components/schemas/QuoteResponse" },
+                                },
+                            },
+                        },
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+            "/quote/{id}": {
+                "get": {
+                    "summary": "Status of a quote and the terms it reserved",
+                    "parameters": [{
+                        "name": "id",
+                        "in": "path",
+                        "required": true,
+                        "schema": { "type": "string", "format": "uuid" },
+                    }],
+                    "responses": {
+                        "200": {
+                            "description": "The quote",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "$ref": "#/components/schemas/ReservedQuote" },
+                                },
+                            },
+                        },
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+            "/balances": {
+                "get": {
+                    "summary": "Balance of the gateway's wallet at each mint",
+                    "responses": {
+                        "200": {
+                            "description": "Balances",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "array",
+                                        "items": { "$ref": "#/components/schemas/WalletBalance" },
+                                    },
+                                },
+                            },
+                        },
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+            "/payments": {
+                "get": {
+                    "summary": "Completed payments, most recent first",
+                    "parameters": [
+                        {
+                            "name": "limit",
+                            "in": "query",
+                            "required": false,
+                            "description": "Number of payments to return, at most 500",
+                            "schema": { "type": "integer", "default": 50 },
+                        },
+                        {
+                            "name": "offset",
+                            "in": "query",
+                            "required": false,
+                            "schema": { "type": "integer", "default": 0 },
+                        },
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "Payment history",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "array",
+                                        "items": { "$ref": "#/components/schemas/PaymentRecord" },
+                                    },
+                                },
+                            },
+                        },
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+            "/admin/mints": {
+                "post": {
+                    "summary": "Start accepting ecash from a mint, only served when API keys are configured",
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": { "$ref": "#/components/schemas/AddMintRequest" },
+                            },
+                        },
+                    },
+                    "responses": {
+                        "200": { "$ref": "#/components/responses/MintUrls" },
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+            "/admin/mints/{url}": {
+                "delete": {
+                    "summary": "Stop accepting ecash from a mint, only served when API keys are configured",
+                    "parameters": [{
+                        "name": "url",
+                        "in": "path",
+                        "required": true,
+                        "description": "URL of the mint, percent-encoded",
+                        "schema": { "type": "string" },
+                    }],
+                    "responses": {
+                        "200": { "$ref": "#/components/responses/MintUrls" },
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+            "/admin/earnings": {
+                "get": {
+                    "summary": "Ecash kept from payments at each mint and not yet withdrawn, only served when API keys are configured",
+                    "responses": {
+                        "200": {
+                            "description": "Earnings",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "array",
+                                        "items": { "$ref": "#/components/schemas/MintEarnings" },
+                                    },
+                                },
+                            },
+                        },
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+            "/admin/withdraw": {
+                "post": {
+                    "summary": "Withdraw earnings from a mint as a token, only served when API keys are configured",
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": { "$ref": "#/components/schemas/WithdrawRequest" },
+                            },
+                        },
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "The withdrawn token",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "$ref": "#/components/schemas/WithdrawResponse" },
+                                },
+                            },
+                        },
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+            "/admin/owed-change": {
+                "get": {
+                    "summary": "Change owed to clients because it could not be created, only served when API keys are configured",
+                    "responses": {
+                        "200": {
+                            "description": "Owed change",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "array",
+                                        "items": { "$ref": "#/components/schemas/OwedChange" },
+                                    },
+                                },
+                            },
+                        },
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+            "/admin/unclaimed-payments": {
+                "get": {
+                    "summary": "Payments sent without a preimage, whose tokens are still to be claimed, only served when API keys are configured",
+                    "responses": {
+                        "200": {
+                            "description": "Unclaimed payments",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "array",
+                                        "items": { "$ref": "#/components/schemas/UnclaimedPayment" },
+                                    },
+                                },
+                            },
+                        },
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+            "/admin/pending-payments": {
+                "get": {
+                    "summary": "Batch payments with an unknown outcome, only served when API keys are configured",
+                    "responses": {
+                        "200": {
+                            "description": "Pending payments",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "array",
+                                        "items": { "$ref": "#/components/schemas/PendingPayment" },
+                                    },
+                                },
+                            },
+                        },
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+            "/admin/reconcile": {
+                "post": {
+                    "summary": "Claim unclaimed payments and settle pending ones the node now has an outcome for, only served when API keys are configured",
+                    "responses": {
+                        "200": {
+                            "description": "Requests claimed and payments settled",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "$ref": "#/components/schemas/ReconcileResponse" },
+                                },
+                            },
+                        },
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+            "/mints": {
+                "get": {
+                    "summary": "Mints the gateway accepts ecash from",
+                    "parameters": [{
+                        "name": "plain",
+                        "in": "query",
+                        "required": false,
+                        "description": "Return only the mint URLs",
+                        "schema": { "type": "boolean" },
+                    }],
+                    "responses": {
+                        "200": {
+                            "description": "Supported mints, or their URLs when plain is set",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "oneOf": [
+                                            {
+                                                "type": "array",
+                                                "items": { "$ref": "#/components/schemas/MintMetadata" },
+                                            },
+                                            {
+                                                "type": "array",
+                                                "items": { "type": "string", "format": "uri" },
+                                            },
+                                        ],
+                                    },
+                                },
+                            },
+                        },
+                    },
+                },
+            },
+            "/info": {
+                "get": {
+                    "summary": "What the gateway accepts",
+                    "responses": {
+                        "200": {
+                            "description": "Accepted mints, payment methods and token versions",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "$ref": "#/components/schemas/InfoResponse" },
+                                },
+                            },
+                        },
+                    },
+                },
+            },
+            "/version": {
+                "get": {
+                    "summary": "Versions of the gateway and the cdk it was built with",
+                    "responses": {
+                        "200": {
+                            "description": "Versions",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "$ref": "#/components/schemas/VersionResponse" },
+                                },
+                            },
+                        },
+                    },
+                },
+            },
+            "/health": {
+                "get": {
+                    "summary": "Whether the gateway is alive",
+                    "responses": {
+                        "200": { "$ref": "#/components/responses/Health" },
+                    },
+                },
+            },
+            "/ready": {
+                "get": {
+                    "summary": "Whether the gateway can take payments",
+                    "responses": {
+                        "200": { "$ref": "#/components/responses/Ready" },
+                        "503": { "$ref": "#/components/responses/Ready" },
+                    },
+                },
+            },
+            "/openapi.json": {
+                "get": {
+                    "summary": "This document",
+                    "responses": {
+                        "200": {
+                            "description": "OpenAPI 3 description of the gateway API",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "type": "object" },
+                                },
+                            },
+                        },
+                    },
+                },
+            },
+            "/metrics": {
+                "get": {
+                    "summary": "Prometheus metrics, only served when metrics are enabled",
+                    "responses": {
+                        "200": {
+                            "description": "Metrics in the Prometheus text format",
+                            "content": {
+                                "text/plain": {
+                                    "schema": { "type": "string" },
+                                },
+                            },
+                        },
+                        "default": { "$ref": "#/components/responses/Error" },
+                    },
+                },
+            },
+        },
+        "components": {
+            "schemas": {
+                "MeltRequest": {
+                    "type": "object",
+                    "required": ["method", "request", "tokens"],
+                    "properties": {
+                        "method": { "type": "string", "enum": ["bolt11", "bolt12", "ln_address", "lnurl"] },
+                        "request": {
+                            "type": "string",
+                            "description": "BOLT11 invoice, lightning address or LNURL",
+                        },
+                        "amount": {
+                            "type": "integer",
+                            "nullable": true,
+                            "description": "Amount in sat, for invoices without one and for lightning addresses and LNURLs. Must match the invoice amount when given for an invoice with one",
+                        },
+                        "tokens": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Cashu tokens locked to the invoice's payment hash",
+                        },
+                        "quote_id": {
+                            "type": "string",
+                            "format": "uuid",
+                            "description": "Quote whose reserved terms the payment is made on",
+                        },
+                        "allow_partial": {
+                            "type": "boolean",
+                            "description": "For invoices without an amount, pay only what the tokens cover",
+                        },
+                        "max_fee_sat": {
+                            "type": "integer",
+                            "nullable": true,
+                            "description": "Most the routing fee may be, lowered to the gateway's ceiling and to the quoted fee. Defaults to the fee reserve",
+                        },
+                        "melt_options": {
+                            "type": "object",
+                            "nullable": true,
+                            "description": "Pay part of the invoice as one path of a multi-path payment",
+                            "properties": {
+                                "mpp": {
+                                    "type": "object",
+                                    "properties": {
+                                        "amount": { "type": "integer", "description": "Amount in msat" },
+                                    },
+                                },
+                            },
+                        },
+                    },
+                },
+                "MeltResponse": {
+                    "type": "object",
+                    "required": ["payment_proof", "payment_hash", "change", "amount_paid_sat", "fee_paid_sat", "change_sat"],
+                    "properties": {
+                        "payment_proof": { "type": "string", "description": "Payment preimage" },
+                        "payment_hash": { "type": "string", "description": "Hash of the invoice that was paid" },
+                        "change": { "type": "array", "items": { "type": "string" } },
+                        "amount_paid_sat": {
+                            "type": "integer",
+                            "description": "Amount paid over lightning, before routing fees",
+                        },
+                        "fee_paid_sat": { "type": "integer", "description": "Routing fee paid" },
+                        "change_sat": { "type": "integer", "description": "Total value of the change tokens" },
+                        "change_error": { "type": "string" },
+                        "retained_change_sat": {
+                            "type": "integer",
+                            "description": "Change kept in the gateway wallet instead of being returned",
+                        },
+                    },
+                },
+                "DryRunResponse": {
+                    "type": "object",
+                    "required": ["would_pay"],
+                    "properties": {
+                        "would_pay": { "$ref": "#/components/schemas/WouldPay" },
+                    },
+                },
+                "WouldPay": {
+                    "type": "object",
+                    "required": ["amount", "provided", "change", "mints"],
+                    "properties": {
+                        "amount": {
+                            "type": "integer",
+                            "description": "Amount that would be paid over lightning, before routing fees",
+                        },
+                        "provided": { "type": "integer", "description": "Total value of the tokens" },
+                        "change": {
+                            "type": "integer",
+                            "description": "Change that would be returned if there were no routing fees",
+                        },
+                        "mints": {
+                            "type": "array",
+                            "items": { "type": "string", "format": "uri" },
+                        },
+                    },
+                },
+                "BatchMeltRequest": {
+                    "type": "object",
+                    "required": ["payments", "tokens"],
+                    "properties": {
+                        "payments": {
+                            "type": "array",
+                            "items": { "$ref": "#/components/schemas/QuoteRequest" },
+                        },
+                        "tokens": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Cashu tokens P2PK locked to the gateway's key, covering every payment and its fee reserve",
+                        },
+                    },
+                },
+                "BatchMeltResponse": {
+                    "type": "object",
+                    "required": ["payments", "change", "change_sat"],
+                    "properties": {
+                        "payments": {
+                            "type": "array",
+                            "items": { "$ref": "#/components/schemas/BatchPaymentResult" },
+                        },
+                        "change": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Change for everything not spent, including the share of failed payments",
+                        },
+                        "change_sat": { "type": "integer", "description": "Total value of the change tokens" },
+                        "change_error": { "type": "string" },
+                        "retained_change_sat": {
+                            "type": "integer",
+                            "description": "Change kept in the gateway wallet instead of being returned",
+                        },
+                    },
+                },
+                "BatchPaymentResult": {
+                    "type": "object",
+                    "required": ["request_id", "request", "status", "payment_hash", "amount_paid_sat", "fee_paid_sat"],
+                    "properties": {
+                        "request_id": {
+                            "type": "string",
+                            "format": "uuid",
+                            "description": "ID the payment is recorded under in the payment history and webhooks",
+                        },
+                        "request": { "type": "string" },
+                        "status": {
+                            "type": "string",
+                            "enum": ["paid", "failed", "pending"],
+                            "description": "A pending payment may have been sent, its share is held by the gateway until it is resolved",
+                        },
+                        "payment_hash": { "type": "string", "description": "Hash of the invoice paid for the request" },
+                        "payment_proof": { "type": "string", "description": "Payment preimage" },
+                        "amount_paid_sat": { "type": "integer" },
+                        "fee_paid_sat": { "type": "integer" },
+                        "error": { "$ref": "#/components/schemas/ErrorResponse" },
+                    },
+                },
+                "QuoteRequest": {
+                    "type": "object",
+                    "required": ["method", "request"],
+                    "properties": {
+                        "method": { "type": "string", "enum": ["bolt11", "ln_address", "lnurl"] },
+                        "request": { "type": "string" },
+                        "amount": { "type": "integer", "nullable": true },
+                    },
+                },
+                "QuoteResponse": {
+                    "type": "object",
+                    "required": ["amount", "fee", "total", "fee_source", "quote_id", "expires_at"],
+                    "properties": {
+                        "amount": { "type": "integer" },
+                        "fee": { "type": "integer", "description": "Estimated routing fee" },
+                        "total": { "type": "integer" },
+                        "fee_source": { "type": "string", "enum": ["node", "reserve"] },
+                        "quote_id": { "type": "string", "format": "uuid" },
+                        "fiat_totals": {
+                            "type": "array",
+                            "items": { "$ref": "#/components/schemas/FiatAmount" },
+                        },
+                        "expires_at": {
+                            "type": "integer",
+                            "description": "Unix timestamp the terms are reserved until",
+                        },
+                        "breakdown": {
+                            "type": "string",
+                            "description": "Summary of the amounts, when include_breakdown is enabled",
+                        },
+                    },
+                },
+                "FiatRate": {
+                    "type": "object",
+                    "required": ["unit", "approx_sat"],
+                    "properties": {
+                        "unit": { "type": "string" },
+                        "approx_sat": { "type": "number", "description": "Approximate sat value of one unit" },
+                    },
+                },
+                "FiatAmount": {
+                    "type": "object",
+                    "required": ["unit", "amount", "approx_sat"],
+                    "properties": {
+                        "unit": { "type": "string" },
+                        "amount": { "type": "integer" },
+                        "approx_sat": { "type": "integer" },
+                    },
+                },
+                "ReservedQuote": {
+                    "type": "object",
+                    "required": ["quote_id", "payment_hash", "amount", "fee", "total", "expires_at", "state"],
+                    "properties": {
+                        "quote_id": { "type": "string", "format": "uuid" },
+                        "payment_hash": { "type": "string" },
+                        "amount": { "type": "integer" },
+                        "fee": { "type": "integer" },
+                        "total": { "type": "integer" },
+                        "expires_at": { "type": "integer" },
+                        "state": { "type": "string", "enum": ["reserved", "paying", "paid", "expired"] },
+                    },
+                },
+                "WalletBalance": {
+                    "type": "object",
+                    "required": ["mint_url", "unit", "balance"],
+                    "properties": {
+                        "mint_url": { "type": "string", "format": "uri" },
+                        "unit": { "type": "string" },
+                        "balance": { "type": "integer" },
+                    },
+                },
+                "PaymentRecord": {
+                    "type": "object",
+                    "required": ["request_id", "amount", "fee", "payment_hash", "timestamp", "change"],
+                    "properties": {
+                        "request_id": { "type": "string", "format": "uuid" },
+                        "amount": { "type": "integer" },
+                        "fee": { "type": "integer", "description": "Routing fee paid" },
+                        "payment_hash": { "type": "string" },
+                        "timestamp": { "type": "integer" },
+                        "change": { "type": "integer" },
+                    },
+                },
+                "AddMintRequest": {
+                    "type": "object",
+                    "required": ["mint_url"],
+                    "properties": {
+                        "mint_url": { "type": "string", "format": "uri" },
+                    },
+                },
+                "MintEarnings": {
+                    "type": "object",
+                    "required": ["mint_url", "amount"],
+                    "properties": {
+                        "mint_url": { "type": "string", "format": "uri" },
+                        "amount": { "type": "integer" },
+                    },
+                },
+                "WithdrawRequest": {
+                    "type": "object",
+                    "required": ["mint_url", "amount"],
+                    "properties": {
+                        "mint_url": { "type": "string", "format": "uri" },
+                        "amount": { "type": "integer" },
+                        "destination": {
+                            "type": "object",
+                            "nullable": true,
+                            "description": "Lock the token to a public key, or deliver it to a NUT-18 payment request. It is only returned when not set",
+                            "properties": {
+                                "pubkey": { "type": "string" },
+                                "payment_request": { "type": "string" },
+                            },
+                        },
+                    },
+                },
+                "WithdrawResponse": {
+                    "type": "object",
+                    "required": ["token"],
+                    "properties": {
+                        "token": { "type": "string" },
+                        "delivery_error": {
+                            "type": "string",
+                            "description": "Why the token could not be delivered to the payment request",
+                        },
+                    },
+                },
+                "OwedChange": {
+                    "type": "object",
+                    "required": ["request_id", "mint_url", "amount", "timestamp"],
+                    "properties": {
+                        "request_id": { "type": "string", "format": "uuid" },
+                        "mint_url": { "type": "string", "format": "uri" },
+                        "amount": { "type": "integer" },
+                        "timestamp": { "type": "integer" },
+                    },
+                },
+                "UnclaimedPayment": {
+                    "type": "object",
+                    "required": ["request_id", "payment_hash", "tokens", "provided", "total_spent", "timestamp"],
+                    "properties": {
+                        "request_id": { "type": "string", "format": "uuid" },
+                        "payment_hash": { "type": "string" },
+                        "tokens": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Tokens still to be claimed",
+                        },
+                        "provided": { "type": "integer" },
+                        "total_spent": { "type": "integer" },
+                        "timestamp": { "type": "integer" },
+                    },
+                },
+                "PendingPayment": {
+                    "type": "object",
+                    "required": ["request_id", "payment_id", "payment_hash", "amount", "held", "mint_url", "timestamp"],
+                    "properties": {
+                        "request_id": { "type": "string", "format": "uuid", "description": "The batch request" },
+                        "payment_id": { "type": "string", "format": "uuid" },
+                        "payment_hash": { "type": "string" },
+                        "amount": { "type": "integer" },
+                        "held": { "type": "integer", "description": "Amount and fee reserve held for the payment" },
+                        "mint_url": { "type": "string", "format": "uri" },
+                        "timestamp": { "type": "integer" },
+                    },
+                },
+                "ReconcileResponse": {
+                    "type": "object",
+                    "required": ["claimed", "settled"],
+                    "properties": {
+                        "claimed": { "type": "array", "items": { "type": "string", "format": "uuid" } },
+                        "settled": { "type": "array", "items": { "type": "string", "format": "uuid" } },
+                    },
+                },
+                "MintMetadata": {
+                    "type": "object",
+                    "required": ["url", "units", "reachable"],
+                    "properties": {
+                        "url": { "type": "string", "format": "uri" },
+                        "name": { "type": "string", "nullable": true },
+                        "units": { "type": "array", "items": { "type": "string" } },
+                        "reachable": { "type": "boolean" },
+                        "fee_ppk": { "type": "integer", "nullable": true },
+                    },
+                },
+                "InfoResponse": {
+                    "type": "object",
+                    "required": ["mints", "accepted_methods", "accepted_token_versions"],
+                    "properties": {
+                        "mints": {
+                            "type": "array",
+                            "items": { "type": "string", "format": "uri" },
+                        },
+                        "accepted_methods": {
+                            "type": "array",
+                            "items": { "type": "string", "enum": ["bolt11", "bolt12", "ln_address", "lnurl"] },
+                        },
+                        "accepted_token_versions": {
+                            "type": "array",
+                            "items": { "type": "string", "enum": ["v3", "v4"] },
+                        },
+                        "rates": {
+                            "type": "array",
+                            "items": { "$ref": "#/components/schemas/FiatRate" },
+                        },
+                        "p2pk_pubkey": {
+                            "type": "string",
+                            "description": "Key tokens can be P2PK locked to, when the gateway has one configured",
+                        },
+                    },
+                },
+                "VersionResponse": {
+                    "type": "object",
+                    "required": ["gateway_version", "cdk_version"],
+                    "properties": {
+                        "gateway_version": { "type": "string" },
+                        "cdk_version": { "type": "string" },
+                    },
+                },
+                "HealthResponse": {
+                    "type": "object",
+                    "required": ["status", "node_connected"],
+                    "properties": {
+                        "status": { "type": "string", "enum": ["ok", "degraded"] },
+                        "node_connected": { "type": "boolean" },
+                    },
+                },
+                "ReadyResponse": {
+                    "type": "object",
+                    "required": ["ready", "wallets_initialized", "mint_info_loaded", "node_connected", "draining"],
+                    "properties": {
+                        "ready": { "type": "boolean" },
+                        "wallets_initialized": { "type": "boolean" },
+                        "mint_info_loaded": { "type": "boolean" },
+                        "node_connected": { "type": "boolean" },
+                        "draining": { "type": "boolean" },
+                    },
+                },
+                "ErrorResponse": {
+                    "type": "object",
+                    "required": ["code", "error_kind", "message"],
+                    "properties": {
+                        "code": { "type": "integer", "description": "HTTP status code" },
+                        "error_kind": {
+                            "type": "string",
+                            "enum": [
+                                "invalid_request",
+                                "invalid_invoice",
+                                "unsupported_method",
+                                "unsupported_mint",
+                                "insufficient_funds",
+                                "no_tokens",
+                                "token_verification_failed",
+                                "mint_unreachable",
+                                "destination_unresolved",
+                                "payment_failed",
+                                "too_large",
+                                "unauthorized",
+                                "rate_limited",
+                                "request_in_progress",
+                                "idempotency_key_reused",
+                                "timeout",
+                                "unavailable",
+                                "not_found",
+                                "internal",
+                            ],
+                        },
+                        "message": { "type": "string" },
+                        "details": { "type": "string", "nullable": true },
+                        "payment_request": {
+                            "type": "string",
+                            "description": "NUT-18 payment request, only on 402 and no_tokens responses",
+                        },
+                        "breakdown": { "type": "string" },
+                    },
+                },
+            },
+            "responses": {
+                "Error": {
+                    "description": "The request failed",
+                    "content": {
+                        "application/json": {
+                            "schema": { "$ref": "#/components/schemas/ErrorResponse" },
+                        },
+                    },
+                },
+                "MintUrls": {
+                    "description": "Mints the gateway now accepts ecash from",
+                    "content": {
+                        "application/json": {
+                            "schema": { "type": "array", "items": { "type": "string", "format": "uri" } },
+                        },
+                    },
+                },
+                "Health": {
+                    "description": "Health of the gateway",
+                    "content": {
+                        "application/json": {
+                            "schema": { "$ref": "#/components/schemas/HealthResponse" },
+                        },
+                    },
+                },
+                "Ready": {
+                    "description": "Readiness of the gateway",
+                    "content": {
+                        "application/json": {
+                            "schema": { "$ref": "#/components/schemas/ReadyResponse" },
+                        },
+                    },
+                },
+            },
+        },
+    })
+}
+
+pub async fn get_openapi() -> Json<Value> {
+    Json(openapi_document())
+}
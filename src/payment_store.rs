@@ -0,0 +1,369 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use cdk::amount::Amount;
+use cdk::mint_url::MintUrl;
+use redb::backends::InMemoryBackend;
+use redb::{Database, ReadableTable, TableDefinition};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::idempotency::StoredResponse;
+
+/// Completed payments keyed by `(timestamp, request_id)` so iteration is chronological
+const PAYMENTS_TABLE: TableDefinition<(u64, &str), &str> = TableDefinition::new("payments");
+
+/// Responses to payment requests keyed by their idempotency key
+const IDEMPOTENCY_TABLE: TableDefinition<&str, &str> = TableDefinition::new("idempotency");
+
+/// Ecash kept by the gateway that has not been withdrawn, keyed by mint URL
+const EARNINGS_TABLE: TableDefinition<&str, u64> = TableDefinition::new("earnings");
+
+/// Change that could not be returned to a client, keyed by request ID
+const OWED_CHANGE_TABLE: TableDefinition<&str, &str> = TableDefinition::new("owed_change");
+
+/// Payments sent without the node returning a preimage, keyed by request ID
+const UNCLAIMED_PAYMENTS_TABLE: TableDefinition<&str, &str> =
+    TableDefinition::new("unclaimed_payments");
+
+/// Batch payments whose outcome was not known when the batch finished, keyed by payment ID
+const PENDING_PAYMENTS_TABLE: TableDefinition<&str, &str> =
+    TableDefinition::new("pending_payments");
+
+/// A payment completed by the gateway
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PaymentRecord {
+    pub request_id: Uuid,
+    pub amount: Amount,
+    pub fee: Amount,
+    pub payment_hash: String,
+    pub timestamp: u64,
+    pub change: Amount,
+}
+
+/// Change owed to a client because it could not be created after their payment
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OwedChange {
+    pub request_id: Uuid,
+    pub mint_url: MintUrl,
+    pub amount: Amount,
+    pub timestamp: u64,
+}
+
+/// A payment the node sent without returning its preimage
+///
+/// The client's tokens are locked to the payment hash, so they can only be
+/// claimed once the preimage is known.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UnclaimedPayment {
+    pub request_id: Uuid,
+    pub payment_hash: String,
+    /// Tokens still to be claimed
+    pub tokens: Vec<String>,
+    /// Total value of the tokens sent with the request, including any already claimed
+    pub provided: Amount,
+    pub total_spent: Amount,
+    pub timestamp: u64,
+}
+
+/// A batch payment that may have been sent, whose share of the tokens is held
+///
+/// The tokens were already claimed, so once the node reports the outcome what
+/// wasn't spent is owed to the client.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingPayment {
+    /// The batch request, owed change is recorded against it
+    pub request_id: Uuid,
+    pub payment_id: Uuid,
+    pub payment_hash: String,
+    pub amount: Amount,
+    /// Amount and fee reserve held for the payment
+    pub held: Amount,
+    /// Mint any owed change is recorded for
+    pub mint_url: MintUrl,
+    pub timestamp: u64,
+}
+
+/// Persistent history of completed payments
+pub struct PaymentStore {
+    db: Database,
+}
+
+impl PaymentStore {
+    /// Open or create the payment history database at `path`
+    pub fn new(path: &Path) -> anyhow::Result<Self> {
+        Self::init(Database::create(path)?)
+    }
+
+    /// Create a payment history that is only kept in memory
+    pub fn in_memory() -> anyhow::Result<Self> {
+        Self::init(Database::builder().create_with_backend(InMemoryBackend::new())?)
+    }
+
+    fn init(db: Database) -> anyhow::Result<Self> {
+        // Make sure the tables exist so reads before the first payment succeed
+        let write_txn = db.begin_write()?;
+        write_txn.open_table(PAYMENTS_TABLE)?;
+        write_txn.open_table(IDEMPOTENCY_TABLE)?;
+        write_txn.open_table(EARNINGS_TABLE)?;
+        write_txn.open_table(OWED_CHANGE_TABLE)?;
+        write_txn.open_table(UNCLAIMED_PAYMENTS_TABLE)?;
+        write_txn.open_table(PENDING_PAYMENTS_TABLE)?;
+        write_txn.commit()?;
+
+        Ok(Self { db })
+    }
+
+    /// Record a completed payment
+    pub fn add(&self, record: &PaymentRecord) -> anyhow::Result<()> {
+        let request_id = record.request_id.to_string();
+        let value = serde_json::to_string(record)?;
+
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(PAYMENTS_TABLE)?;
+            table.insert((record.timestamp, request_id.as_str()), value.as_str())?;
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    /// List payments, newest first
+    pub fn list(&self, limit: usize, offset: usize) -> anyhow::Result<Vec<PaymentRecord>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(PAYMENTS_TABLE)?;
+
+        let mut records = Vec::new();
+        for entry in table.iter()?.rev().skip(offset).take(limit) {
+            let (_, value) = entry?;
+            records.push(serde_json::from_str(value.value())?);
+        }
+
+        Ok(records)
+    }
+
+    /// Response stored for an idempotency key, if the key has been used
+    pub fn cached_response(&self, key: &str) -> anyhow::Result<Option<StoredResponse>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(IDEMPOTENCY_TABLE)?;
+
+        match table.get(key)? {
+            Some(value) => Ok(Some(serde_json::from_str(value.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Store the response for an idempotency key
+    ///
+    /// Responses stored before `expired_before` are removed at the same time,
+    /// so the table only holds keys that can still be replayed.
+    pub fn add_cached_response(
+        &self,
+        key: &str,
+        response: &StoredResponse,
+        expired_before: u64,
+    ) -> anyhow::Result<()> {
+        let value = serde_json::to_string(response)?;
+
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(IDEMPOTENCY_TABLE)?;
+            table.retain(|_, value| {
+                serde_json::from_str::<StoredResponse>(value)
+                    .is_ok_and(|stored| stored.timestamp >= expired_before)
+            })?;
+            table.insert(key, value.as_str())?;
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    /// Add ecash kept by the gateway to the earnings of each mint
+    pub fn add_earnings(&self, earnings: &[(MintUrl, Amount)]) -> anyhow::Result<()> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(EARNINGS_TABLE)?;
+            for (mint_url, amount) in earnings {
+                let mint_url = mint_url.to_string();
+                let current = table
+                    .get(mint_url.as_str())?
+                    .map(|value| value.value())
+                    .unwrap_or_default();
+                table.insert(mint_url.as_str(), current + u64::from(*amount))?;
+            }
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    /// Earnings that have not been withdrawn, by mint
+    pub fn earnings(&self) -> anyhow::Result<Vec<(MintUrl, Amount)>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(EARNINGS_TABLE)?;
+
+        let mut earnings = Vec::new();
+        for entry in table.iter()? {
+            let (mint_url, amount) = entry?;
+            earnings.push((
+                MintUrl::from_str(mint_url.value())?,
+                Amount::from(amount.value()),
+            ));
+        }
+
+        Ok(earnings)
+    }
+
+    /// Deduct `amount` from the earnings of `mint_url`
+    ///
+    /// Returns false without changing anything if the earnings are too low.
+    pub fn withdraw_earnings(&self, mint_url: &MintUrl, amount: Amount) -> anyhow::Result<bool> {
+        let mint_url = mint_url.to_string();
+
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(EARNINGS_TABLE)?;
+            let current = table
+                .get(mint_url.as_str())?
+                .map(|value| value.value())
+                .unwrap_or_default();
+            let Some(remaining) = current.checked_sub(u64::from(amount)) else {
+                return Ok(false);
+            };
+            table.insert(mint_url.as_str(), remaining)?;
+        }
+        write_txn.commit()?;
+
+        Ok(true)
+    }
+
+    /// Record change that is owed to a client
+    ///
+    /// Several entries for the same request, one per mint, are combined.
+    pub fn add_owed_change(&self, owed: &OwedChange) -> anyhow::Result<()> {
+        let key = format!("{}/{}", owed.request_id, owed.mint_url);
+        let value = serde_json::to_string(owed)?;
+
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(OWED_CHANGE_TABLE)?;
+            let existing: Option<OwedChange> = table
+                .get(key.as_str())?
+                .map(|value| serde_json::from_str(value.value()))
+                .transpose()?;
+            let value = match existing {
+                Some(mut existing) => {
+                    existing.amount += owed.amount;
+                    serde_json::to_string(&existing)?
+                }
+                None => value,
+            };
+            table.insert(key.as_str(), value.as_str())?;
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    /// Change owed to clients that has not been returned
+    pub fn owed_change(&self) -> anyhow::Result<Vec<OwedChange>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(OWED_CHANGE_TABLE)?;
+
+        let mut owed = Vec::new();
+        for entry in table.iter()? {
+            let (_, value) = entry?;
+            owed.push(serde_json::from_str(value.value())?);
+        }
+
+        Ok(owed)
+    }
+
+    /// Record a payment whose tokens could not be claimed for lack of a preimage
+    pub fn add_unclaimed_payment(&self, payment: &UnclaimedPayment) -> anyhow::Result<()> {
+        let request_id = payment.request_id.to_string();
+        let value = serde_json::to_string(payment)?;
+
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(UNCLAIMED_PAYMENTS_TABLE)?;
+            table.insert(request_id.as_str(), value.as_str())?;
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    /// Payments whose tokens have not been claimed yet
+    pub fn unclaimed_payments(&self) -> anyhow::Result<Vec<UnclaimedPayment>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(UNCLAIMED_PAYMENTS_TABLE)?;
+
+        let mut payments = Vec::new();
+        for entry in table.iter()? {
+            let (_, value) = entry?;
+            payments.push(serde_json::from_str(value.value())?);
+        }
+
+        Ok(payments)
+    }
+
+    /// Forget an unclaimed payment once its tokens have been claimed
+    pub fn remove_unclaimed_payment(&self, request_id: &Uuid) -> anyhow::Result<()> {
+        let request_id = request_id.to_string();
+
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(UNCLAIMED_PAYMENTS_TABLE)?;
+            table.remove(request_id.as_str())?;
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    /// Record a batch payment whose outcome is not known yet
+    pub fn add_pending_payment(&self, payment: &PendingPayment) -> anyhow::Result<()> {
+        let payment_id = payment.payment_id.to_string();
+        let value = serde_json::to_string(payment)?;
+
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(PENDING_PAYMENTS_TABLE)?;
+            table.insert(payment_id.as_str(), value.as_str())?;
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    /// Batch payments that have not been settled yet
+    pub fn pending_payments(&self) -> anyhow::Result<Vec<PendingPayment>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(PENDING_PAYMENTS_TABLE)?;
+
+        let mut payments = Vec::new();
+        for entry in table.iter()? {
+            let (_, value) = entry?;
+            payments.push(serde_json::from_str(value.value())?);
+        }
+
+        Ok(payments)
+    }
+
+    /// Forget a pending payment once its outcome is settled
+    pub fn remove_pending_payment(&self, payment_id: &Uuid) -> anyhow::Result<()> {
+        let payment_id = payment_id.to_string();
+
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(PENDING_PAYMENTS_TABLE)?;
+            table.remove(payment_id.as_str())?;
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use cdk::amount::Amount;
+use cdk::util::unix_time;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuoteState {
+    /// The terms can still be paid against
+    Reserved,
+    /// A payment against the quote is in progress
+    Paying,
+    /// A payment was made against the quote
+    Paid,
+    /// The reservation ran out before it was paid
+    Expired,
+}
+
+/// Terms reserved for a payment by `/quote`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReservedQuote {
+    pub quote_id: Uuid,
+    pub payment_hash: String,
+    /// Amount paid over lightning, before routing fees
+    pub amount: Amount,
+    /// Most the routing fee may be when paying against the quote
+    pub fee: Amount,
+    pub total: Amount,
+    /// Unix timestamp the reservation lasts until
+    pub expires_at: u64,
+    pub state: QuoteState,
+}
+
+/// Why a quote could not be claimed by a payment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClaimError {
+    Unknown,
+    Paying,
+    Paid,
+    Expired,
+}
+
+/// Quotes reserved for a time, so a payment can lock in quoted terms
+#[derive(Default)]
+pub struct QuoteReservations {
+    quotes: Mutex<HashMap<Uuid, ReservedQuote>>,
+}
+
+impl QuoteReservations {
+    /// Reserve `amount` and `fee` for paying `payment_hash` for `ttl_secs`
+    pub fn reserve(
+        &self,
+        payment_hash: String,
+        amount: Amount,
+        fee: Amount,
+        ttl_secs: u64,
+    ) -> ReservedQuote {
+        let now = unix_time();
+        let quote = ReservedQuote {
+            quote_id: Uuid::new_v4(),
+            payment_hash,
+            amount,
+            fee,
+            total: amount + fee,
+            expires_at: now + ttl_secs,
+            state: QuoteState::Reserved,
+        };
+
+        let mut quotes = self.quotes.lock().expect("quotes lock poisoned");
+        // Quotes are kept for a while after they expire so their status can still be checked
+        quotes.retain(|_, quote| quote.expires_at + ttl_secs > now);
+        quotes.insert(quote.quote_id, quote.clone());
+
+        quote
+    }
+
+    /// The quote with `quote_id`, marked expired if its reservation ran out
+    pub fn get(&self, quote_id: &Uuid) -> Option<ReservedQuote> {
+        let quotes = self.quotes.lock().expect("quotes lock poisoned");
+        let mut quote = quotes.get(quote_id)?.clone();
+        if quote.state == QuoteState::Reserved && quote.expires_at <= unix_time() {
+            quote.state = QuoteState::Expired;
+        }
+        Some(quote)
+    }
+
+    /// Claim the quote for a payment
+    ///
+    /// The quote is moved to paying under the lock, so of two payments
+    /// referencing it only one can be made on its terms. It is reserved again
+    /// if the claim is dropped without being marked paid.
+    pub fn claim(self: &Arc<Self>, quote_id: &Uuid) -> Result<QuoteClaim, ClaimError> {
+        let mut quotes = self.quotes.lock().expect("quotes lock poisoned");
+        let quote = quotes.get_mut(quote_id).ok_or(ClaimError::Unknown)?;
+        match quote.state {
+            QuoteState::Reserved if quote.expires_at <= unix_time() => {
+                return Err(ClaimError::Expired);
+            }
+            QuoteState::Reserved => {}
+            QuoteState::Paying => return Err(ClaimError::Paying),
+            QuoteState::Paid => return Err(ClaimError::Paid),
+            QuoteState::Expired => return Err(ClaimError::Expired),
+        }
+        quote.state = QuoteState::Paying;
+
+        Ok(QuoteClaim {
+            quotes: self.clone(),
+            quote: quote.clone(),
+            paid: false,
+        })
+    }
+
+    fn set_state(&self, quote_id: &Uuid, state: QuoteState) {
+        if let Some(quote) = self
+            .quotes
+            .lock()
+            .expect("quotes lock poisoned")
+            .get_mut(quote_id)
+        {
+            quote.state = state;
+        }
+    }
+}
+
+/// A quote claimed by a payment, reserved again when dropped before being paid
+pub struct QuoteClaim {
+    quotes: Arc<QuoteReservations>,
+    quote: ReservedQuote,
+    paid: bool,
+}
+
+impl QuoteClaim {
+    /// The terms the payment is made on
+    pub fn quote(&self) -> &ReservedQuote {
+        &self.quote
+    }
+
+    /// Record that the payment was made, the quote can't be paid against again
+    pub fn mark_paid(mut self) {
+        self.paid = true;
+        self.quotes
+            .set_state(&self.quote.quote_id, QuoteState::Paid);
+    }
+}
+
+impl Drop for QuoteClaim {
+    fn drop(&mut self) {
+        if !self.paid {
+            self.quotes
+                .set_state(&self.quote.quote_id, QuoteState::Reserved);
+        }
+    }
+}
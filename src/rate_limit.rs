@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{Extensions, HeaderMap};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use crate::auth::valid_api_key;
+use crate::config::RateLimitConfig;
+use crate::gateway_server::{ErrorKind, ErrorResponse, GatwayState};
+
+/// Number of tracked clients above which idle buckets are pruned
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    capacity: f64,
+    refill_per_sec: f64,
+}
+
+impl Limits {
+    fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            capacity: f64::from(config.burst.max(1)),
+            refill_per_sec: f64::from(config.requests_per_minute.max(1)) / 60.0,
+        }
+    }
+}
+
+/// Token bucket rate limiter keyed by client
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RwLock<Limits>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Create a new rate limiter from config
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            limits: RwLock::new(Limits::new(config)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Apply a changed rate limit, clients keep the tokens they have up to the new burst
+    pub fn update(&self, config: &RateLimitConfig) {
+        *self.limits.write().expect("rate limiter lock poisoned") = Limits::new(config);
+    }
+
+    /// Take a token for `key`
+    ///
+    /// Returns how long the client should wait before retrying when its bucket is empty.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        let Limits {
+            capacity,
+            refill_per_sec,
+        } = *self.limits.read().expect("rate limiter lock poisoned");
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("rate limiter lock poisoned");
+
+        if buckets.len() > PRUNE_THRESHOLD {
+            // Buckets that have refilled completely are equivalent to new ones
+            let full_after = capacity / refill_per_sec;
+            buckets
+                .retain(|_, bucket| now.duration_since(bucket.updated).as_secs_f64() < full_after);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill_per_sec).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / refill_per_sec,
+            ))
+        }
+    }
+}
+
+/// Middleware rejecting clients that exceed the configured request rate
+///
+/// Clients are identified by their API key when it is one of the configured
+/// keys, otherwise by IP address. Runs before authentication, so made up keys
+/// count against the client's address and can't be used to get a fresh limit.
+pub async fn rate_limit(
+    State(state): State<GatwayState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(limiter) = state.inner.rate_limiter() else {
+        return next.run(request).await;
+    };
+    let key = client_key(
+        &state.inner.settings().auth.api_keys,
+        request.headers(),
+        request.extensions(),
+    );
+
+    if let Err(retry_after) = limiter.check(&key) {
+        let retry_after_secs = retry_after.as_secs_f64().ceil() as u64;
+        tracing::debug!("Rate limit exceeded, retry in {}s", retry_after_secs);
+
+        return ErrorResponse::new(ErrorKind::RateLimited, "Too many requests")
+            .with_details(format!("Retry after {} seconds", retry_after_secs))
+            .with_retry_after(retry_after_secs)
+            .into_response();
+    }
+
+    next.run(request).await
+}
+
+/// Identify the client by a valid API key if it sent one, otherwise by remote IP address
+pub(crate) fn client_key(
+    api_keys: &[String],
+    headers: &HeaderMap,
+    extensions: &Extensions,
+) -> String {
+    if let Some(api_key) = valid_api_key(api_keys, headers) {
+        return format!("key:{}", api_key);
+    }
+
+    match extensions.get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => format!("ip:{}", addr.ip()),
+        None => "unknown".to_string(),
+    }
+}
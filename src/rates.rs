@@ -0,0 +1,120 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use cdk::amount::Amount;
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::RatesConfig;
+
+/// Approximate value of one unit of a fiat currency
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FiatRate {
+    pub unit: String,
+    pub approx_sat: f64,
+}
+
+/// A sat amount expressed in a fiat unit
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FiatAmount {
+    pub unit: String,
+    /// Amount in the unit, rounded up
+    pub amount: u64,
+    pub approx_sat: u64,
+}
+
+/// Exchange rates of fiat units to sat, for display only
+///
+/// Rates fetched from the configured provider take precedence over the fixed
+/// rates, which are used until the first fetch succeeds.
+pub struct ExchangeRates {
+    client: reqwest::Client,
+    config: RatesConfig,
+    fetched: RwLock<HashMap<String, f64>>,
+}
+
+impl ExchangeRates {
+    pub fn new(config: &RatesConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.request_timeout_secs))
+            .build()
+            .expect("http client config is valid");
+
+        Self {
+            client,
+            config: config.clone(),
+            fetched: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Sats per unit of every unit with a known rate
+    pub fn rates(&self) -> Vec<FiatRate> {
+        let mut rates: BTreeMap<String, f64> = self
+            .config
+            .sat_per_unit
+            .iter()
+            .map(|(unit, rate)| (unit.to_lowercase(), *rate))
+            .collect();
+        for (unit, rate) in self.fetched.read().expect("rates lock poisoned").iter() {
+            rates.insert(unit.clone(), *rate);
+        }
+
+        rates
+            .into_iter()
+            .filter(|(_, rate)| rate.is_finite() && *rate > 0.0)
+            .map(|(unit, approx_sat)| FiatRate { unit, approx_sat })
+            .collect()
+    }
+
+    /// `amount` in every unit with a known rate
+    pub fn convert(&self, amount: Amount) -> Vec<FiatAmount> {
+        let sat = u64::from(amount);
+        self.rates()
+            .into_iter()
+            .map(|rate| FiatAmount {
+                amount: (sat as f64 / rate.approx_sat).ceil() as u64,
+                unit: rate.unit,
+                approx_sat: sat,
+            })
+            .collect()
+    }
+
+    async fn fetch(&self, url: &str) -> anyhow::Result<()> {
+        let rates: HashMap<String, f64> = self
+            .client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let rates = rates
+            .into_iter()
+            .map(|(unit, rate)| (unit.to_lowercase(), rate))
+            .collect();
+        *self.fetched.write().expect("rates lock poisoned") = rates;
+        Ok(())
+    }
+}
+
+/// Fetch rates from the configured provider until `cancel` is triggered
+pub async fn refresh_rates(rates: Arc<ExchangeRates>, cancel: CancellationToken) {
+    let Some(url) = rates.config.provider_url.clone() else {
+        return;
+    };
+    let interval = Duration::from_secs(rates.config.refresh_secs);
+
+    loop {
+        match rates.fetch(&url).await {
+            Ok(()) => tracing::debug!("Fetched exchange rates from {}", url),
+            Err(e) => tracing::warn!("Could not fetch exchange rates from {}: {}", url, e),
+        }
+
+        tokio::select! {
+            _ = cancel.cancelled() => return,
+            _ = tokio::time::sleep(interval) => {}
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::time::Instant;
+
+use axum::body::{Body, to_bytes};
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde_json::Value;
+
+use crate::gateway_server::{ErrorKind, ErrorResponse};
+
+/// Fields whose values are never logged, they carry ecash or payment secrets
+const REDACTED_FIELDS: &[&str] = &["tokens", "change", "payment_proof", "preimage", "preimages"];
+
+/// Log every request with its status and latency
+///
+/// Requests are logged at debug level. At trace level request bodies are
+/// logged as well, with tokens and preimages redacted.
+pub async fn log_requests(
+    State(max_body_bytes): State<usize>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let started = Instant::now();
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+
+    let request = if tracing::enabled!(tracing::Level::TRACE) {
+        let (parts, body) = request.into_parts();
+        let bytes = match to_bytes(body, max_body_bytes).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                tracing::debug!(
+                    "{} {} rejected, body could not be read: {}",
+                    method,
+                    path,
+                    e
+                );
+                return ErrorResponse::new(ErrorKind::TooLarge, "Request body too large")
+                    .with_details(e.to_string())
+                    .into_response();
+            }
+        };
+        if !bytes.is_empty() {
+            tracing::trace!("{} {} body: {}", method, path, redact_body(&bytes));
+        }
+        Request::from_parts(parts, Body::from(bytes))
+    } else {
+        request
+    };
+
+    let response = next.run(request).await;
+    tracing::debug!(
+        "{} {} {} in {:?}",
+        method,
+        path,
+        response.status().as_u16(),
+        started.elapsed()
+    );
+
+    response
+}
+
+/// A request body as it is logged, with tokens and preimages replaced
+///
+/// Bodies that aren't JSON are never logged, since they can't be redacted.
+pub fn redact_body(body: &[u8]) -> String {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(mut value) => {
+            redact(&mut value);
+            value.to_string()
+        }
+        Err(_) => format!("<{} bytes, not JSON>", body.len()),
+    }
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                if REDACTED_FIELDS.contains(&name.as_str()) {
+                    *field = Value::String("[redacted]".to_string());
+                } else {
+                    redact(field);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
@@ -0,0 +1,965 @@
+//! Test helpers for running the gateway without a lightning node
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::sha256::Hash as Sha256Hash;
+use bitcoin::hex::DisplayHex;
+use bitcoin::secp256k1::{self, Message, Secp256k1};
+use cdk::Bolt11Invoice;
+use cdk::amount::Amount;
+use cdk::cdk_database::{self, WalletDatabase};
+use cdk::cdk_payment::{
+    self, Bolt11OutgoingPaymentOptions, CreateIncomingPaymentResponse, IncomingPaymentOptions,
+    MakePaymentResponse, MintPayment, OutgoingPaymentOptions, PaymentIdentifier,
+    PaymentQuoteResponse, WaitPaymentResponse,
+};
+use cdk::dhke::{blind_message, construct_proofs, sign_message, verify_message};
+use cdk::lightning_invoice::{Currency, InvoiceBuilder, PaymentSecret};
+use cdk::mint_url::MintUrl;
+use cdk::nuts::nut00::token::TokenV3;
+use cdk::nuts::{
+    BlindSignature, BlindedMessage, Conditions, CurrencyUnit, Id, Keys, MeltQuoteState,
+    Nut10Secret, Proof, Proofs, PublicKey, SecretKey, SpendingConditions, State as ProofState,
+    Token,
+};
+use cdk::secret::Secret;
+use cdk::util::unix_time;
+use cdk::wallet::{MultiMintWallet, WalletBuilder};
+use futures::Stream;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::task::JoinHandle;
+use tracing_subscriber::fmt::MakeWriter;
+use uuid::Uuid;
+
+use crate::config::{DatabaseBackend, MintEntry, Settings};
+use crate::gateway_server::{self, CdkGateway, ServerHandle};
+use crate::lnurl::LnurlResolver;
+use crate::metrics::Metrics;
+use crate::node::NodeHealth;
+use crate::payment_store::PaymentStore;
+
+/// Result a [`MockNode`] gives for a payment
+#[derive(Debug, Clone)]
+pub enum MockOutcome {
+    /// Pay the invoice, revealing `preimage` and charging `fee` on top of the invoice amount
+    Success { preimage: String, fee: Amount },
+    /// Pay the invoice without the node returning the preimage
+    Unconfirmed { fee: Amount },
+    /// Fail the payment with the given error message
+    Failure(String),
+}
+
+/// Scriptable payment node for tests
+///
+/// Payments take the next queued outcome, or the default outcome once the
+/// queue is empty.
+pub struct MockNode {
+    default: MockOutcome,
+    queued: Mutex<VecDeque<MockOutcome>>,
+    delay: Duration,
+    fee_estimate: Option<Amount>,
+    payments: Mutex<Vec<OutgoingPaymentOptions>>,
+    /// Preimage reported when payments are looked up
+    lookup_preimage: Mutex<Option<String>>,
+    settings_requests: AtomicUsize,
+}
+
+impl MockNode {
+    /// Create a node that pays every invoice with `preimage`
+    pub fn succeeding(preimage: impl Into<String>) -> Self {
+        Self::new(MockOutcome::Success {
+            preimage: preimage.into(),
+            fee: Amount::ZERO,
+        })
+    }
+
+    /// Create a node that fails every payment with `message`
+    pub fn failing(message: impl Into<String>) -> Self {
+        Self::new(MockOutcome::Failure(message.into()))
+    }
+
+    /// Create a node giving `outcome` for every payment
+    pub fn new(outcome: MockOutcome) -> Self {
+        Self {
+            default: outcome,
+            queued: Mutex::new(VecDeque::new()),
+            delay: Duration::ZERO,
+            fee_estimate: None,
+            payments: Mutex::new(Vec::new()),
+            lookup_preimage: Mutex::new(None),
+            settings_requests: AtomicUsize::new(0),
+        }
+    }
+
+    /// Give `outcome` for the next payment before falling back to the default
+    ///
+    /// Outcomes are used in the order they are queued.
+    pub fn then(self, outcome: MockOutcome) -> Self {
+        self.queued
+            .lock()
+            .expect("mock node lock poisoned")
+            .push_back(outcome);
+        self
+    }
+
+    /// Wait `delay` before completing each payment
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Quote `fee` as the routing fee of every payment, instead of being unable to estimate
+    pub fn with_fee_estimate(mut self, fee: Amount) -> Self {
+        self.fee_estimate = Some(fee);
+        self
+    }
+
+    /// Number of times the node's settings have been asked for, as health
+    /// checks and pings do
+    pub fn settings_requests(&self) -> usize {
+        self.settings_requests.load(Ordering::SeqCst)
+    }
+
+    /// Payments the node has been asked to make
+    pub fn payments(&self) -> Vec<OutgoingPaymentOptions> {
+        self.payments
+            .lock()
+            .expect("mock node lock poisoned")
+            .clone()
+    }
+
+    /// Report payments as paid with `preimage` when they are looked up later,
+    /// instead of being unable to look them up
+    pub fn report_preimage(&self, preimage: impl Into<String>) {
+        *self
+            .lookup_preimage
+            .lock()
+            .expect("mock node lock poisoned") = Some(preimage.into());
+    }
+
+    fn next_outcome(&self) -> MockOutcome {
+        self.queued
+            .lock()
+            .expect("mock node lock poisoned")
+            .pop_front()
+            .unwrap_or_else(|| self.default.clone())
+    }
+}
+
+/// Amount a payment sends, the partial amount when melt options give one
+fn payment_amount(options: &Bolt11OutgoingPaymentOptions) -> Amount {
+    let msat = match &options.melt_options {
+        Some(melt_options) => u64::from(melt_options.amount_msat()),
+        None => options.bolt11.amount_milli_satoshis().unwrap_or_default(),
+    };
+    Amount::from(msat / 1_000)
+}
+
+#[async_trait]
+impl MintPayment for MockNode {
+    type Err = cdk_payment::Error;
+
+    async fn get_settings(&self) -> Result<serde_json::Value, Self::Err> {
+        self.settings_requests.fetch_add(1, Ordering::SeqCst);
+        Ok(serde_json::json!({}))
+    }
+
+    async fn create_incoming_payment_request(
+        &self,
+        _unit: &CurrencyUnit,
+        _options: IncomingPaymentOptions,
+    ) -> Result<CreateIncomingPaymentResponse, Self::Err> {
+        Err(cdk_payment::Error::UnsupportedPaymentOption)
+    }
+
+    async fn get_payment_quote(
+        &self,
+        unit: &CurrencyUnit,
+        options: OutgoingPaymentOptions,
+    ) -> Result<PaymentQuoteResponse, Self::Err> {
+        let (Some(fee), OutgoingPaymentOptions::Bolt11(options)) = (self.fee_estimate, options)
+        else {
+            return Err(cdk_payment::Error::UnsupportedPaymentOption);
+        };
+
+        Ok(PaymentQuoteResponse {
+            request_lookup_id: PaymentIdentifier::PaymentHash(
+                *options.bolt11.payment_hash().as_ref(),
+            ),
+            amount: payment_amount(&options),
+            fee,
+            unit: unit.clone(),
+            state: MeltQuoteState::Unpaid,
+        })
+    }
+
+    async fn make_payment(
+        &self,
+        unit: &CurrencyUnit,
+        options: OutgoingPaymentOptions,
+    ) -> Result<MakePaymentResponse, Self::Err> {
+        self.payments
+            .lock()
+            .expect("mock node lock poisoned")
+            .push(options.clone());
+
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+
+        let OutgoingPaymentOptions::Bolt11(options) = options else {
+            return Err(cdk_payment::Error::UnsupportedPaymentOption);
+        };
+
+        match self.next_outcome() {
+            MockOutcome::Success { preimage, fee } => {
+                let amount = payment_amount(&options);
+
+                Ok(MakePaymentResponse {
+                    payment_lookup_id: PaymentIdentifier::PaymentHash(
+                        *options.bolt11.payment_hash().as_ref(),
+                    ),
+                    payment_proof: Some(preimage),
+                    status: MeltQuoteState::Paid,
+                    total_spent: amount + fee,
+                    unit: unit.clone(),
+                })
+            }
+            MockOutcome::Unconfirmed { fee } => {
+                let amount = payment_amount(&options);
+
+                Ok(MakePaymentResponse {
+                    payment_lookup_id: PaymentIdentifier::PaymentHash(
+                        *options.bolt11.payment_hash().as_ref(),
+                    ),
+                    payment_proof: None,
+                    status: MeltQuoteState::Paid,
+                    total_spent: amount + fee,
+                    unit: unit.clone(),
+                })
+            }
+            MockOutcome::Failure(message) => Err(cdk_payment::Error::Custom(message)),
+        }
+    }
+
+    async fn wait_any_incoming_payment(
+        &self,
+    ) -> Result<Pin<Box<dyn Stream<Item = WaitPaymentResponse> + Send>>, Self::Err> {
+        Ok(Box::pin(futures::stream::empty()))
+    }
+
+    fn is_wait_invoice_active(&self) -> bool {
+        false
+    }
+
+    fn cancel_wait_invoice(&self) {}
+
+    async fn check_incoming_payment_status(
+        &self,
+        _payment_identifier: &PaymentIdentifier,
+    ) -> Result<Vec<WaitPaymentResponse>, Self::Err> {
+        Ok(Vec::new())
+    }
+
+    async fn check_outgoing_payment(
+        &self,
+        payment_identifier: &PaymentIdentifier,
+    ) -> Result<MakePaymentResponse, Self::Err> {
+        let preimage = self
+            .lookup_preimage
+            .lock()
+            .expect("mock node lock poisoned")
+            .clone()
+            .ok_or(cdk_payment::Error::UnsupportedPaymentOption)?;
+
+        Ok(MakePaymentResponse {
+            payment_lookup_id: payment_identifier.clone(),
+            payment_proof: Some(preimage),
+            status: MeltQuoteState::Paid,
+            total_spent: Amount::ZERO,
+            unit: CurrencyUnit::Sat,
+        })
+    }
+}
+
+/// Number of denominations the mock mint has keys for, 1 sat up to 2^31 sat
+const MOCK_MINT_MAX_ORDER: u32 = 32;
+
+/// Error code cashu mints return for proofs that are already spent
+const TOKEN_ALREADY_SPENT_CODE: u64 = 11001;
+
+struct MockMintState {
+    keyset_id: Id,
+    keys: Keys,
+    secret_keys: BTreeMap<Amount, SecretKey>,
+    spent: Mutex<HashSet<PublicKey>>,
+    pending: Mutex<HashSet<PublicKey>>,
+    failing_swaps: AtomicUsize,
+    /// Swaps made before the failing ones start
+    swaps_before_failing: AtomicUsize,
+    failing_info: AtomicUsize,
+    info_requests: AtomicUsize,
+    swap_requests: AtomicUsize,
+    /// Inputs of the swaps the mint has made
+    swapped_inputs: Mutex<Proofs>,
+}
+
+impl MockMintState {
+    fn sign(&self, amount: Amount, blinded_message: &PublicKey) -> Option<BlindSignature> {
+        let secret_key = self.secret_keys.get(&amount)?;
+        let signature = sign_message(secret_key, blinded_message).ok()?;
+        BlindSignature::new(
+            amount,
+            signature,
+            self.keyset_id,
+            blinded_message,
+            secret_key.clone(),
+        )
+        .ok()
+    }
+
+    fn verify(&self, proof: &Proof) -> bool {
+        proof.keyset_id == self.keyset_id
+            && self
+                .secret_keys
+                .get(&proof.amount)
+                .is_some_and(|secret_key| {
+                    verify_message(secret_key, proof.c, proof.secret.as_bytes()).is_ok()
+                })
+    }
+}
+
+/// In-process cashu mint for tests, with a single sat keyset
+///
+/// Swaps check that the inputs were signed by the mint and are unspent, then
+/// mark them spent. Spending conditions are not enforced.
+pub struct MockMint {
+    url: MintUrl,
+    state: Arc<MockMintState>,
+    task: JoinHandle<()>,
+}
+
+impl MockMint {
+    /// Start a mint serving on a free local port
+    pub async fn start() -> anyhow::Result<Self> {
+        let mut secret_keys = BTreeMap::new();
+        let mut public_keys = serde_json::Map::new();
+        for order in 0..MOCK_MINT_MAX_ORDER {
+            let amount = Amount::from(1u64 << order);
+            let secret_key = SecretKey::generate();
+            public_keys.insert(
+                amount.to_string(),
+                Value::String(secret_key.public_key().to_string()),
+            );
+            secret_keys.insert(amount, secret_key);
+        }
+        let keys: Keys = serde_json::from_value(Value::Object(public_keys))?;
+
+        let state = Arc::new(MockMintState {
+            keyset_id: Id::from(&keys),
+            keys,
+            secret_keys,
+            spent: Mutex::new(HashSet::new()),
+            pending: Mutex::new(HashSet::new()),
+            failing_swaps: AtomicUsize::new(0),
+            swaps_before_failing: AtomicUsize::new(0),
+            failing_info: AtomicUsize::new(0),
+            info_requests: AtomicUsize::new(0),
+            swap_requests: AtomicUsize::new(0),
+            swapped_inputs: Mutex::default(),
+        });
+
+        let router = Router::new()
+            .route("/v1/info", get(mock_mint_info))
+            .route("/v1/keys", get(mock_mint_keys))
+            .route("/v1/keys/{id}", get(mock_mint_keys))
+            .route("/v1/keysets", get(mock_mint_keysets))
+            .route("/v1/swap", post(mock_mint_swap))
+            .route("/v1/checkstate", post(mock_mint_checkstate))
+            .with_state(state.clone());
+
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let url = MintUrl::from_str(&format!("http://{}", listener.local_addr()?))?;
+        let task = tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, router).await {
+                tracing::error!("Mock mint stopped: {}", e);
+            }
+        });
+
+        Ok(Self { url, state, task })
+    }
+
+    pub fn url(&self) -> &MintUrl {
+        &self.url
+    }
+
+    pub fn keyset_id(&self) -> Id {
+        self.state.keyset_id
+    }
+
+    /// Stop serving, so the mint can no longer be reached
+    pub fn stop(&self) {
+        self.task.abort();
+    }
+
+    /// Fail the next `count` swaps
+    pub fn fail_swaps(&self, count: usize) {
+        self.fail_swaps_after(0, count);
+    }
+
+    /// Make `successful` swaps, then fail the `count` after them
+    pub fn fail_swaps_after(&self, successful: usize, count: usize) {
+        self.state
+            .swaps_before_failing
+            .store(successful, Ordering::SeqCst);
+        self.state.failing_swaps.store(count, Ordering::SeqCst);
+    }
+
+    /// Fail the next `count` requests for the mint's info
+    pub fn fail_info(&self, count: usize) {
+        self.state.failing_info.store(count, Ordering::SeqCst);
+    }
+
+    /// Number of requests for the mint's info it has answered or failed
+    pub fn info_requests(&self) -> usize {
+        self.state.info_requests.load(Ordering::SeqCst)
+    }
+
+    /// Number of swaps the mint has been asked to make
+    pub fn swap_requests(&self) -> usize {
+        self.state.swap_requests.load(Ordering::SeqCst)
+    }
+
+    /// Proofs spent by the swaps the mint has made, with their witnesses
+    pub fn swapped_proofs(&self) -> Proofs {
+        self.state
+            .swapped_inputs
+            .lock()
+            .expect("mock mint lock poisoned")
+            .clone()
+    }
+
+    /// Report `proofs` as pending until [`MockMint::settle`] is called
+    pub fn set_pending(&self, proofs: &Proofs) {
+        let mut pending = self.state.pending.lock().expect("mock mint lock poisoned");
+        pending.extend(proofs.iter().filter_map(|proof| proof.y().ok()));
+    }
+
+    /// Stop reporting any proofs as pending
+    pub fn settle(&self) {
+        self.state
+            .pending
+            .lock()
+            .expect("mock mint lock poisoned")
+            .clear();
+    }
+
+    /// Mark `proofs` as spent, as if they had been swapped elsewhere
+    pub fn spend(&self, proofs: &Proofs) {
+        let mut spent = self.state.spent.lock().expect("mock mint lock poisoned");
+        spent.extend(proofs.iter().filter_map(|proof| proof.y().ok()));
+    }
+
+    /// Issue proofs worth `amount`, locked with `conditions` when given
+    pub fn issue(&self, amount: u64, conditions: Option<&SpendingConditions>) -> Proofs {
+        self.issue_with(amount, || match conditions {
+            Some(conditions) => Secret::try_from(Nut10Secret::from(conditions.clone()))
+                .expect("spending conditions encode as a secret"),
+            None => Secret::generate(),
+        })
+    }
+
+    /// Issue proofs worth `amount`, each with a secret made by `secret`
+    pub fn issue_with(&self, amount: u64, mut secret: impl FnMut() -> Secret) -> Proofs {
+        let mut signatures = Vec::new();
+        let mut blinding_factors = Vec::new();
+        let mut secrets = Vec::new();
+        for amount in Amount::from(amount).split() {
+            let secret = secret();
+            let (blinded_message, blinding_factor) =
+                blind_message(secret.as_bytes(), None).expect("secrets can be blinded");
+            signatures.push(
+                self.state
+                    .sign(amount, &blinded_message)
+                    .expect("the mint has keys for every denomination"),
+            );
+            blinding_factors.push(blinding_factor);
+            secrets.push(secret);
+        }
+
+        construct_proofs(signatures, blinding_factors, secrets, &self.state.keys)
+            .expect("the mint's signatures unblind")
+    }
+
+    /// Encode `proofs` as a V4 token from this mint
+    pub fn token(&self, proofs: Proofs) -> String {
+        Token::new(self.url.clone(), proofs, None, CurrencyUnit::Sat).to_string()
+    }
+
+    /// Encode `proofs` as a V3 token from this mint
+    pub fn token_v3(&self, proofs: Proofs) -> String {
+        Token::TokenV3(
+            TokenV3::new(self.url.clone(), proofs, None, Some(CurrencyUnit::Sat))
+                .expect("proofs encode as a V3 token"),
+        )
+        .to_string()
+    }
+
+    /// A token worth `amount` locked to the payment hash `hash`, reclaimable after an hour
+    pub fn htlc_token(&self, hash: Sha256Hash, amount: u64) -> String {
+        let conditions = htlc_conditions(hash, Some(unix_time() + 3600));
+        self.token(self.issue(amount, Some(&conditions)))
+    }
+}
+
+impl Drop for MockMint {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Conditions locking a token to the preimage of `hash`, reclaimable from `locktime`
+pub fn htlc_conditions(hash: Sha256Hash, locktime: Option<u64>) -> SpendingConditions {
+    SpendingConditions::HTLCConditions {
+        data: hash,
+        conditions: locktime.map(|locktime| Conditions {
+            locktime: Some(locktime),
+            ..Default::default()
+        }),
+    }
+}
+
+/// Conditions locking a token to `pubkey`
+pub fn p2pk_conditions(pubkey: PublicKey) -> SpendingConditions {
+    SpendingConditions::P2PKConditions {
+        data: pubkey,
+        conditions: None,
+    }
+}
+
+/// Run the gateway's check of the change returned for a payment, with
+/// amounts the payment flow itself can't produce
+pub fn check_change(
+    metrics: &Metrics,
+    received: Amount,
+    change_due: Amount,
+    accounted: Amount,
+) -> bool {
+    gateway_server::check_change(
+        metrics,
+        Uuid::new_v4(),
+        received,
+        change_due,
+        change_due,
+        accounted,
+    )
+}
+
+fn mock_mint_error(code: u64, detail: &str) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({ "code": code, "detail": detail })),
+    )
+        .into_response()
+}
+
+async fn mock_mint_info(State(state): State<Arc<MockMintState>>) -> Response {
+    state.info_requests.fetch_add(1, Ordering::SeqCst);
+    let failing = state
+        .failing_info
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+            count.checked_sub(1)
+        })
+        .is_ok();
+    if failing {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+
+    Json(json!({
+        "name": "Mock mint",
+        "nuts": {
+            "4": { "methods": [], "disabled": true },
+            "5": { "methods": [], "disabled": true },
+            "7": { "supported": true },
+            "12": { "supported": true }
+        }
+    }))
+    .into_response()
+}
+
+async fn mock_mint_keys(State(state): State<Arc<MockMintState>>) -> Json<Value> {
+    Json(json!({
+        "keysets": [{
+            "id": state.keyset_id,
+            "unit": CurrencyUnit::Sat,
+            "keys": state.keys
+        }]
+    }))
+}
+
+async fn mock_mint_keysets(State(state): State<Arc<MockMintState>>) -> Json<Value> {
+    Json(json!({
+        "keysets": [{
+            "id": state.keyset_id,
+            "unit": CurrencyUnit::Sat,
+            "active": true,
+            "input_fee_ppk": 0
+        }]
+    }))
+}
+
+#[derive(Deserialize)]
+struct MockSwapRequest {
+    inputs: Proofs,
+    outputs: Vec<BlindedMessage>,
+}
+
+async fn mock_mint_swap(
+    State(state): State<Arc<MockMintState>>,
+    Json(request): Json<MockSwapRequest>,
+) -> Response {
+    state.swap_requests.fetch_add(1, Ordering::SeqCst);
+    let skipped = state
+        .swaps_before_failing
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+            count.checked_sub(1)
+        })
+        .is_ok();
+    let failing = !skipped
+        && state
+            .failing_swaps
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok();
+    if failing {
+        return mock_mint_error(0, "Swap failed");
+    }
+
+    if !request.inputs.iter().all(|proof| state.verify(proof)) {
+        return mock_mint_error(0, "Invalid proof");
+    }
+    let input_amount: u64 = request.inputs.iter().map(|p| u64::from(p.amount)).sum();
+    let output_amount: u64 = request.outputs.iter().map(|o| u64::from(o.amount)).sum();
+    if input_amount != output_amount {
+        return mock_mint_error(0, "Inputs and outputs are not balanced");
+    }
+
+    let ys: Vec<PublicKey> = request
+        .inputs
+        .iter()
+        .filter_map(|proof| proof.y().ok())
+        .collect();
+    {
+        let mut spent = state.spent.lock().expect("mock mint lock poisoned");
+        let pending = state.pending.lock().expect("mock mint lock poisoned");
+        if ys.iter().any(|y| spent.contains(y) || pending.contains(y)) {
+            return mock_mint_error(TOKEN_ALREADY_SPENT_CODE, "Token already spent");
+        }
+        spent.extend(ys);
+    }
+    state
+        .swapped_inputs
+        .lock()
+        .expect("mock mint lock poisoned")
+        .extend(request.inputs.iter().cloned());
+
+    let signatures: Option<Vec<BlindSignature>> = request
+        .outputs
+        .iter()
+        .map(|output| state.sign(output.amount, &output.blinded_secret))
+        .collect();
+    match signatures {
+        Some(signatures) => Json(json!({ "signatures": signatures })).into_response(),
+        None => mock_mint_error(0, "Unsupported output amount"),
+    }
+}
+
+#[derive(Deserialize)]
+struct MockCheckStateRequest {
+    #[serde(rename = "Ys")]
+    ys: Vec<PublicKey>,
+}
+
+async fn mock_mint_checkstate(
+    State(state): State<Arc<MockMintState>>,
+    Json(request): Json<MockCheckStateRequest>,
+) -> Json<Value> {
+    let spent = state.spent.lock().expect("mock mint lock poisoned");
+    let pending = state.pending.lock().expect("mock mint lock poisoned");
+    let states: Vec<Value> = request
+        .ys
+        .iter()
+        .map(|y| {
+            let proof_state = if spent.contains(y) {
+                ProofState::Spent
+            } else if pending.contains(y) {
+                ProofState::Pending
+            } else {
+                ProofState::Unspent
+            };
+            json!({ "Y": y, "state": proof_state, "witness": null })
+        })
+        .collect();
+
+    Json(json!({ "states": states }))
+}
+
+/// Key of the node the test invoices are issued by
+const TEST_NODE_KEY: [u8; 32] = [0x42; 32];
+
+/// An invoice whose preimage is known, so tokens locked to it can be claimed
+pub struct TestInvoice {
+    pub invoice: Bolt11Invoice,
+    /// Hex encoded preimage of the invoice's payment hash
+    pub preimage: String,
+}
+
+impl TestInvoice {
+    /// An invoice for `amount_sat`, or without an amount
+    pub fn new(amount_sat: Option<u64>) -> Self {
+        Self::from_node(&TEST_NODE_KEY, amount_sat)
+    }
+
+    /// An invoice issued by the node with secret key `node_key`
+    pub fn from_node(node_key: &[u8; 32], amount_sat: Option<u64>) -> Self {
+        let secp = Secp256k1::new();
+        let node_key = secp256k1::SecretKey::from_slice(node_key).expect("test node key is valid");
+
+        let mut preimage = [0u8; 32];
+        preimage[..16].copy_from_slice(Uuid::new_v4().as_bytes());
+        preimage[16..].copy_from_slice(Uuid::new_v4().as_bytes());
+
+        let builder = InvoiceBuilder::new(Currency::Bitcoin)
+            .description("cdk-gateway test".to_string())
+            .payment_hash(Sha256Hash::hash(&preimage))
+            .payment_secret(PaymentSecret([0x11; 32]))
+            .current_timestamp()
+            .min_final_cltv_expiry_delta(144);
+        let sign = |hash: &Message| secp.sign_ecdsa_recoverable(hash, &node_key);
+        let invoice = match amount_sat {
+            Some(amount_sat) => builder
+                .amount_milli_satoshis(amount_sat * 1_000)
+                .build_signed(sign),
+            None => builder.build_signed(sign),
+        }
+        .expect("test invoice is valid");
+
+        Self {
+            invoice,
+            preimage: preimage.to_lower_hex_string(),
+        }
+    }
+
+    pub fn payment_hash(&self) -> Sha256Hash {
+        *self.invoice.payment_hash()
+    }
+}
+
+/// Mnemonic of the gateway wallets started by [`TestGateway`]
+pub const TEST_MNEMONIC: &str =
+    "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+/// A gateway serving on a free local port, paying with a [`MockNode`]
+pub struct TestGateway {
+    pub gateway: CdkGateway,
+    pub node: Arc<MockNode>,
+    pub client: reqwest::Client,
+    url: String,
+    server: ServerHandle,
+}
+
+impl TestGateway {
+    /// Settings for a gateway with wallets at `mints`, storing everything in memory
+    pub fn settings(mints: &[&MockMint]) -> Settings {
+        let mut settings = Settings::default();
+        settings.wallet.mnemonic_seed = TEST_MNEMONIC.to_string();
+        settings.wallet.mint_urls = mints
+            .iter()
+            .map(|mint| MintEntry::from(mint.url().to_string().as_str()))
+            .collect();
+        settings.database.backend = DatabaseBackend::Memory;
+        settings.server.port = 0;
+        settings.server.expose_error_details = true;
+        // Invoices are paid with exactly their amount unless a test sets a fee reserve
+        settings.payment.fee_reserve_ppm = 0;
+        settings.payment.fee_reserve_min_sat = 0;
+        settings
+    }
+
+    /// Start a gateway with default settings for `mints`
+    pub async fn start(node: MockNode, mints: &[&MockMint]) -> anyhow::Result<Self> {
+        Self::start_with(node, Self::settings(mints)).await
+    }
+
+    /// Start a gateway with `settings`, listening on the configured address and port
+    pub async fn start_with(node: MockNode, settings: Settings) -> anyhow::Result<Self> {
+        let localstore = Arc::new(cdk_sqlite::wallet::memory::empty().await?);
+        Self::start_with_localstore(node, settings, localstore).await
+    }
+
+    /// Start a gateway with `settings`, keeping its wallets in `localstore`
+    pub async fn start_with_localstore(
+        node: MockNode,
+        settings: Settings,
+        localstore: Arc<dyn WalletDatabase<Err = cdk_database::Error> + Send + Sync>,
+    ) -> anyhow::Result<Self> {
+        Self::launch(node, settings, localstore, None).await
+    }
+
+    /// Start a gateway with `settings`, resolving payment destinations with `lnurl`
+    pub async fn start_with_lnurl(
+        node: MockNode,
+        settings: Settings,
+        lnurl: LnurlResolver,
+    ) -> anyhow::Result<Self> {
+        let localstore = Arc::new(cdk_sqlite::wallet::memory::empty().await?);
+        Self::launch(node, settings, localstore, Some(lnurl)).await
+    }
+
+    async fn launch(
+        node: MockNode,
+        settings: Settings,
+        localstore: Arc<dyn WalletDatabase<Err = cdk_database::Error> + Send + Sync>,
+        lnurl: Option<LnurlResolver>,
+    ) -> anyhow::Result<Self> {
+        let node = Arc::new(node);
+        let mnemonic = bip39::Mnemonic::from_str(&settings.wallet.mnemonic()?)?;
+        let seed = mnemonic.to_seed_normalized("");
+
+        let mut wallets = Vec::new();
+        let mut mints = Vec::new();
+        for entry in settings.wallet.mint_urls.iter() {
+            let mint_url = MintUrl::from_str(entry.url())?;
+            let wallet = WalletBuilder::new()
+                .mint_url(mint_url.clone())
+                .unit(CurrencyUnit::Sat)
+                .localstore(localstore.clone())
+                .seed(&seed)
+                .build()?;
+            wallets.push(wallet);
+            mints.push(mint_url);
+        }
+        let wallets = MultiMintWallet::new(localstore, Arc::new(seed), wallets);
+
+        let bind_address = SocketAddr::new(
+            IpAddr::from_str(&settings.server.listen_addr)?,
+            settings.server.port,
+        );
+        let scheme = if settings.server.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        let gateway = CdkGateway::new(
+            node.clone(),
+            Arc::new(NodeHealth::new()),
+            wallets,
+            Arc::new(PaymentStore::in_memory()?),
+            settings,
+        );
+        let gateway = match lnurl {
+            Some(lnurl) => gateway.with_lnurl_resolver(lnurl),
+            None => gateway,
+        };
+        let server = gateway.start_server(bind_address, mints).await?;
+        let url = format!("{}://{}", scheme, server.local_addr());
+
+        Ok(Self {
+            gateway,
+            node,
+            client: reqwest::Client::new(),
+            url,
+            server,
+        })
+    }
+
+    /// Address the gateway is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.server.local_addr()
+    }
+
+    /// URL of `path` on the gateway
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.url, path)
+    }
+
+    pub async fn get(&self, path: &str) -> reqwest::Response {
+        self.client
+            .get(self.url(path))
+            .send()
+            .await
+            .expect("gateway is reachable")
+    }
+
+    pub async fn post(&self, path: &str, body: &Value) -> reqwest::Response {
+        self.client
+            .post(self.url(path))
+            .json(body)
+            .send()
+            .await
+            .expect("gateway is reachable")
+    }
+
+    /// Stop the server, waiting for requests in flight
+    pub async fn stop(self) -> anyhow::Result<()> {
+        self.server.stop().await
+    }
+}
+
+/// Log output captured in memory, for asserting on what was logged
+#[derive(Clone, Default)]
+pub struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl LogBuffer {
+    /// Everything logged so far
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().expect("log buffer lock poisoned")).into_owned()
+    }
+}
+
+impl io::Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .lock()
+            .expect("log buffer lock poisoned")
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogBuffer {
+    type Writer = LogBuffer;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+/// Body of a `/payment` request paying `invoice` with `tokens`
+pub fn melt_request(invoice: &TestInvoice, tokens: &[String]) -> Value {
+    json!({
+        "method": "bolt11",
+        "request": invoice.invoice.to_string(),
+        "tokens": tokens,
+    })
+}
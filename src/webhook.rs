@@ -0,0 +1,167 @@
+use std::time::Duration;
+
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::sha256::Hash as Sha256Hash;
+use bitcoin::hashes::{Hash, HashEngine};
+use cdk::amount::Amount;
+use cdk::util::unix_time;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::WebhookConfig;
+use crate::gateway_server::{ErrorKind, ErrorResponse};
+use crate::payment_store::PaymentRecord;
+
+/// Header carrying the HMAC-SHA256 signature of the event body
+pub const SIGNATURE_HEADER: &str = "X-Gateway-Signature";
+
+/// Delay before retrying a failed delivery, doubled on each attempt
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PaymentEventKind {
+    #[serde(rename = "payment.succeeded")]
+    Succeeded,
+    #[serde(rename = "payment.failed")]
+    Failed,
+}
+
+/// Outcome of a payment request, posted to the configured webhook
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaymentEvent {
+    #[serde(rename = "type")]
+    pub kind: PaymentEventKind,
+    pub request_id: Uuid,
+    /// Amount paid over lightning, when known
+    pub amount: Option<Amount>,
+    /// Routing fee, for successful payments
+    pub fee: Option<Amount>,
+    pub payment_hash: Option<String>,
+    /// Why the payment failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<ErrorKind>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    pub timestamp: u64,
+}
+
+impl PaymentEvent {
+    pub fn succeeded(record: &PaymentRecord) -> Self {
+        Self {
+            kind: PaymentEventKind::Succeeded,
+            request_id: record.request_id,
+            amount: Some(record.amount),
+            fee: Some(record.fee),
+            payment_hash: Some(record.payment_hash.clone()),
+            error_kind: None,
+            message: None,
+            timestamp: unix_time(),
+        }
+    }
+
+    pub fn failed(
+        request_id: Uuid,
+        amount: Option<Amount>,
+        payment_hash: Option<String>,
+        error: &ErrorResponse,
+    ) -> Self {
+        Self {
+            kind: PaymentEventKind::Failed,
+            request_id,
+            amount,
+            fee: None,
+            payment_hash,
+            error_kind: Some(error.error_kind),
+            message: Some(error.message.clone()),
+            timestamp: unix_time(),
+        }
+    }
+}
+
+/// Posts payment events to the configured webhook
+pub struct WebhookNotifier {
+    client: reqwest::Client,
+    config: WebhookConfig,
+}
+
+impl WebhookNotifier {
+    pub fn new(config: &WebhookConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .build()
+            .expect("http client config is valid");
+
+        Self {
+            client,
+            config: config.clone(),
+        }
+    }
+
+    /// Deliver `event` in the background, retrying with backoff
+    ///
+    /// Delivery never delays or fails the payment it reports on.
+    pub fn notify(&self, event: PaymentEvent) {
+        let client = self.client.clone();
+        let config = self.config.clone();
+        tokio::spawn(async move { deliver(&client, &config, &event).await });
+    }
+}
+
+async fn deliver(client: &reqwest::Client, config: &WebhookConfig, event: &PaymentEvent) {
+    let body = serde_json::to_vec(event).expect("payment events serialize");
+    let signature = config
+        .secret
+        .as_deref()
+        .map(|secret| sign(secret.as_bytes(), &body));
+
+    let mut backoff = RETRY_BACKOFF;
+    for attempt in 0..=config.max_retries {
+        let mut request = client
+            .post(&config.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.clone());
+        if let Some(signature) = &signature {
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+
+        match request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+        {
+            Ok(_) => {
+                tracing::debug!(
+                    "Delivered {:?} event for request {}",
+                    event.kind,
+                    event.request_id
+                );
+                return;
+            }
+            Err(e) if attempt < config.max_retries => {
+                tracing::warn!(
+                    "Webhook delivery for request {} failed, retrying in {:?}: {}",
+                    event.request_id,
+                    backoff,
+                    e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => {
+                tracing::error!(
+                    "Giving up on webhook delivery for request {} after {} attempts: {}",
+                    event.request_id,
+                    attempt + 1,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// `sha256=` followed by the hex HMAC-SHA256 of `body` keyed with `secret`
+fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut engine = HmacEngine::<Sha256Hash>::new(secret);
+    engine.input(body);
+    format!("sha256={}", Hmac::<Sha256Hash>::from_engine(engine))
+}
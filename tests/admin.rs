@@ -0,0 +1,205 @@
+//! Managing the gateway at runtime through the admin endpoints
+
+use std::str::FromStr;
+
+use cdk::amount::Amount;
+use cdk::mint_url::MintUrl;
+use cdk::nuts::nut18::PaymentRequestBuilder;
+use cdk::nuts::{CurrencyUnit, Token};
+use cdk_gateway::admin::{MintEarnings, WithdrawResponse};
+use cdk_gateway::gateway_server::{ErrorResponse, InfoResponse, MintMetadata};
+use cdk_gateway::testing::{MockMint, MockNode, TestGateway, TestInvoice, melt_request};
+use reqwest::{StatusCode, Url};
+use serde_json::json;
+
+const API_KEY: &str = "test-api-key";
+
+async fn gateway_with_api_key(node: MockNode, mint: &MockMint) -> TestGateway {
+    let mut settings = TestGateway::settings(&[mint]);
+    settings.auth.api_keys = vec![API_KEY.to_string()];
+    TestGateway::start_with(node, settings).await.unwrap()
+}
+
+/// Url of the admin endpoint of `mint_url`, with the mint URL as one path segment
+fn admin_mint_url(gateway: &TestGateway, mint_url: &MintUrl) -> Url {
+    let mut url = Url::parse(&gateway.url("/admin/mints")).unwrap();
+    url.path_segments_mut().unwrap().push(&mint_url.to_string());
+    url
+}
+
+async fn supported_mints(gateway: &TestGateway) -> (Vec<MintUrl>, Vec<MintUrl>) {
+    let mints: Vec<MintMetadata> = gateway.get("/mints").await.json().await.unwrap();
+    let info: InfoResponse = gateway.get("/info").await.json().await.unwrap();
+    (mints.into_iter().map(|mint| mint.url).collect(), info.mints)
+}
+
+#[tokio::test]
+async fn adds_and_removes_mints() {
+    let mint = MockMint::start().await.unwrap();
+    let added = MockMint::start().await.unwrap();
+    let gateway = gateway_with_api_key(MockNode::failing("unused"), &mint).await;
+
+    let response = gateway
+        .client
+        .post(gateway.url("/admin/mints"))
+        .bearer_auth(API_KEY)
+        .json(&json!({ "mint_url": added.url() }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let (mints, info_mints) = supported_mints(&gateway).await;
+    assert_eq!(mints, [mint.url().clone(), added.url().clone()]);
+    assert_eq!(info_mints, mints);
+
+    let response = gateway
+        .client
+        .delete(admin_mint_url(&gateway, added.url()))
+        .bearer_auth(API_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let (mints, info_mints) = supported_mints(&gateway).await;
+    assert_eq!(mints, [mint.url().clone()]);
+    assert_eq!(info_mints, mints);
+
+    // The gateway always keeps one mint
+    let response = gateway
+        .client
+        .delete(admin_mint_url(&gateway, mint.url()))
+        .bearer_auth(API_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // Without the API key nothing changes
+    let response = gateway
+        .post("/admin/mints", &json!({ "mint_url": added.url() }))
+        .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(supported_mints(&gateway).await.0, [mint.url().clone()]);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn withdraws_earned_fees() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = gateway_with_api_key(MockNode::succeeding(&invoice.preimage), &mint).await;
+
+    let token = mint.htlc_token(invoice.payment_hash(), 110);
+    let response = gateway
+        .client
+        .post(gateway.url("/payment"))
+        .bearer_auth(API_KEY)
+        .json(&melt_request(&invoice, &[token]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // There is no service fee, so part of the ecash the payment left in the
+    // gateway's wallet is recorded as earned
+    gateway
+        .gateway
+        .payment_store()
+        .add_earnings(&[(mint.url().clone(), Amount::from(5))])
+        .unwrap();
+
+    let earnings: Vec<MintEarnings> = gateway
+        .client
+        .get(gateway.url("/admin/earnings"))
+        .bearer_auth(API_KEY)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(earnings.len(), 1);
+    assert_eq!(&earnings[0].mint_url, mint.url());
+    assert_eq!(earnings[0].amount, Amount::from(5));
+
+    let withdraw = json!({ "mint_url": mint.url(), "amount": 5 });
+    let response = gateway
+        .client
+        .post(gateway.url("/admin/withdraw"))
+        .bearer_auth(API_KEY)
+        .json(&withdraw)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let withdrawn: WithdrawResponse = response.json().await.unwrap();
+    let token = Token::from_str(&withdrawn.token).unwrap();
+    assert_eq!(token.value().unwrap(), Amount::from(5));
+    assert_eq!(&token.mint_url().unwrap(), mint.url());
+
+    let earnings: Vec<MintEarnings> = gateway
+        .client
+        .get(gateway.url("/admin/earnings"))
+        .bearer_auth(API_KEY)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(earnings.iter().all(|earned| earned.amount == Amount::ZERO));
+
+    // Earnings can only be withdrawn once
+    let response = gateway
+        .client
+        .post(gateway.url("/admin/withdraw"))
+        .bearer_auth(API_KEY)
+        .json(&withdraw)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Insufficient earnings");
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn rejects_withdrawing_to_a_payment_request_for_another_amount() {
+    let mint = MockMint::start().await.unwrap();
+    let gateway = gateway_with_api_key(MockNode::failing("unused"), &mint).await;
+    gateway
+        .gateway
+        .payment_store()
+        .add_earnings(&[(mint.url().clone(), Amount::from(5))])
+        .unwrap();
+
+    let payment_request = PaymentRequestBuilder::default()
+        .unit(CurrencyUnit::Sat)
+        .amount(7)
+        .build();
+    let withdraw = json!({
+        "mint_url": mint.url(),
+        "amount": 5,
+        "destination": { "payment_request": payment_request.to_string() },
+    });
+    let response = gateway
+        .client
+        .post(gateway.url("/admin/withdraw"))
+        .bearer_auth(API_KEY)
+        .json(&withdraw)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Invalid payment request");
+
+    // Nothing was taken from the earnings
+    let earnings = gateway.gateway.payment_store().earnings().unwrap();
+    assert_eq!(earnings, [(mint.url().clone(), Amount::from(5))]);
+
+    gateway.stop().await.unwrap();
+}
@@ -0,0 +1,117 @@
+//! API key authentication and rate limiting of the protected routes
+
+use cdk_gateway::config::RateLimitConfig;
+use cdk_gateway::testing::{MockMint, MockNode, TestGateway};
+use reqwest::{StatusCode, header};
+
+const API_KEY: &str = "test-api-key";
+
+async fn gateway_with_api_key(mint: &MockMint) -> TestGateway {
+    let mut settings = TestGateway::settings(&[mint]);
+    settings.auth.api_keys = vec![API_KEY.to_string()];
+    TestGateway::start_with(MockNode::failing("unused"), settings)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn accepts_a_configured_api_key() {
+    let mint = MockMint::start().await.unwrap();
+    let gateway = gateway_with_api_key(&mint).await;
+
+    let bearer = gateway
+        .client
+        .get(gateway.url("/balances"))
+        .bearer_auth(API_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(bearer.status(), StatusCode::OK);
+
+    let api_key_header = gateway
+        .client
+        .get(gateway.url("/balances"))
+        .header("x-api-key", API_KEY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(api_key_header.status(), StatusCode::OK);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn rejects_missing_and_unknown_api_keys() {
+    let mint = MockMint::start().await.unwrap();
+    let gateway = gateway_with_api_key(&mint).await;
+
+    assert_eq!(
+        gateway.get("/balances").await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    let unknown = gateway
+        .client
+        .get(gateway.url("/balances"))
+        .header(header::AUTHORIZATION, "Bearer not-the-key")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(unknown.status(), StatusCode::UNAUTHORIZED);
+
+    // Keys only match in full
+    let extended = format!("{}-extra", API_KEY);
+    for partial in [&API_KEY[..4], extended.as_str()] {
+        let response = gateway
+            .client
+            .get(gateway.url("/balances"))
+            .bearer_auth(partial)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    // Public routes stay open
+    assert_eq!(gateway.get("/mints").await.status(), StatusCode::OK);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn lets_every_request_through_without_api_keys() {
+    let mint = MockMint::start().await.unwrap();
+    let gateway = TestGateway::start(MockNode::failing("unused"), &[&mint])
+        .await
+        .unwrap();
+
+    assert_eq!(gateway.get("/balances").await.status(), StatusCode::OK);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn rate_limits_requests_beyond_the_burst() {
+    let mint = MockMint::start().await.unwrap();
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.rate_limit = Some(RateLimitConfig {
+        requests_per_minute: 1,
+        burst: 2,
+    });
+    let gateway = TestGateway::start_with(MockNode::failing("unused"), settings)
+        .await
+        .unwrap();
+
+    for _ in 0..2 {
+        assert_eq!(gateway.get("/balances").await.status(), StatusCode::OK);
+    }
+    let limited = gateway.get("/balances").await;
+    assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = limited.headers()[header::RETRY_AFTER]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 0);
+
+    gateway.stop().await.unwrap();
+}
@@ -0,0 +1,196 @@
+//! Loading and validating the gateway's configuration
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use cdk_gateway::config::{MNEMONIC_ENV_VAR, MintEntry, Settings, WalletConfig};
+use cdk_gateway::testing::{LogBuffer, TEST_MNEMONIC};
+use uuid::Uuid;
+
+/// A new empty directory for a test to write config files to
+fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cdk-gateway-config-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn reads_the_mnemonic_from_a_file() {
+    let dir = temp_dir();
+    let mnemonic_file = dir.join("mnemonic");
+    std::fs::write(&mnemonic_file, format!("  {}\n", TEST_MNEMONIC)).unwrap();
+
+    let mut wallet = WalletConfig {
+        mnemonic_file: Some(mnemonic_file),
+        ..Default::default()
+    };
+    assert_eq!(wallet.mnemonic().unwrap(), TEST_MNEMONIC);
+
+    // Only one source of the mnemonic may be configured
+    wallet.mnemonic_seed = TEST_MNEMONIC.to_string();
+    assert!(wallet.mnemonic().is_err());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn rejects_missing_and_empty_mnemonic_files() {
+    let dir = temp_dir();
+    let empty_file = dir.join("empty");
+    std::fs::write(&empty_file, "\n").unwrap();
+
+    for mnemonic_file in [dir.join("missing"), empty_file] {
+        let wallet = WalletConfig {
+            mnemonic_file: Some(mnemonic_file),
+            ..Default::default()
+        };
+        assert!(wallet.mnemonic().is_err());
+    }
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// Settings that pass validation
+fn valid_settings() -> Settings {
+    let mut settings = Settings::default();
+    settings.wallet.mnemonic_seed = TEST_MNEMONIC.to_string();
+    settings
+}
+
+/// The validation error for `settings`, which must be invalid
+fn validation_error(settings: &Settings) -> String {
+    settings.validate().unwrap_err().to_string()
+}
+
+#[test]
+fn accepts_valid_settings() {
+    valid_settings().validate().unwrap();
+}
+
+#[test]
+fn rejects_invalid_settings() {
+    let mut settings = valid_settings();
+    settings.wallet.mnemonic_seed = "not a mnemonic".to_string();
+    assert!(validation_error(&settings).contains("not a valid BIP39 phrase"));
+
+    let mut settings = valid_settings();
+    settings.wallet.mint_urls.clear();
+    assert!(validation_error(&settings).contains("wallet.mint_urls is empty"));
+
+    let mut settings = valid_settings();
+    settings.wallet.mint_urls = vec![MintEntry::WithLimits {
+        url: "https://mint.example.com".to_string(),
+        min_sat: Some(100),
+        max_sat: Some(10),
+    }];
+    assert!(validation_error(&settings).contains("min_sat 100 is above max_sat 10"));
+
+    let mut settings = valid_settings();
+    settings.server.listen_addr = "localhost".to_string();
+    assert!(validation_error(&settings).contains("invalid server.listen_addr"));
+
+    let mut settings = valid_settings();
+    settings.payment.p2pk_secret_key = Some("not hex".to_string());
+    assert!(validation_error(&settings).contains("invalid payment.p2pk_secret_key"));
+}
+
+#[test]
+fn lists_every_problem_found() {
+    let mut settings = valid_settings();
+    settings.server.port = 0;
+    settings.server.listen_addr = "localhost".to_string();
+    settings.wallet.mint_urls.clear();
+
+    let error = validation_error(&settings);
+    assert!(error.starts_with("Invalid configuration:"));
+    assert!(error.contains("server.port must not be 0"));
+    assert!(error.contains("invalid server.listen_addr"));
+    assert!(error.contains("wallet.mint_urls is empty"));
+}
+
+/// Held by tests that set environment variables read when loading settings
+static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+#[test]
+fn the_mnemonic_can_be_given_only_in_the_environment() {
+    let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let dir = temp_dir();
+    std::fs::write(dir.join("config.toml"), "[server]\nport = 3338\n").unwrap();
+
+    // SAFETY: tests that read the environment hold ENV_LOCK
+    unsafe { std::env::set_var(MNEMONIC_ENV_VAR, TEST_MNEMONIC) };
+    let settings = Settings::with_work_dir(dir.to_str());
+    unsafe { std::env::remove_var(MNEMONIC_ENV_VAR) };
+
+    let settings = settings.unwrap();
+    assert_eq!(settings.wallet.mnemonic().unwrap(), TEST_MNEMONIC);
+    assert_eq!(settings.server.port, 3338);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn warns_about_a_mnemonic_in_a_world_readable_file() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let dir = temp_dir();
+    let config_path = dir.join("config.toml");
+    std::fs::write(
+        &config_path,
+        format!("[wallet]\nmnemonic_seed = \"{}\"\n", TEST_MNEMONIC),
+    )
+    .unwrap();
+
+    let load_logs = |mode| {
+        std::fs::set_permissions(&config_path, std::fs::Permissions::from_mode(mode)).unwrap();
+        let logs = LogBuffer::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(logs.clone())
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            Settings::with_work_dir(dir.to_str()).unwrap()
+        });
+        logs.contents()
+    };
+
+    assert!(load_logs(0o644).contains("is readable by every user"));
+    assert!(!load_logs(0o600).contains("is readable by every user"));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn the_local_override_takes_precedence_over_the_config_file() {
+    let _env = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let dir = temp_dir();
+    std::fs::write(
+        dir.join("config.toml"),
+        "[server]\nlisten_addr = \"0.0.0.0\"\nport = 3338\n",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("config.local.toml"),
+        format!(
+            "[server]\nport = 4000\n\n[wallet]\nmnemonic_seed = \"{}\"\n",
+            TEST_MNEMONIC
+        ),
+    )
+    .unwrap();
+
+    let settings = Settings::with_work_dir(dir.to_str()).unwrap();
+    assert_eq!(settings.server.port, 4000);
+    assert_eq!(settings.server.listen_addr, "0.0.0.0");
+    assert_eq!(settings.wallet.mnemonic().unwrap(), TEST_MNEMONIC);
+
+    // A config file given explicitly is overridden by the file next to it
+    let config_path = dir.join("gateway.toml");
+    std::fs::write(&config_path, "[server]\nport = 5000\n").unwrap();
+    std::fs::write(dir.join("gateway.local.toml"), "[server]\nport = 6000\n").unwrap();
+    let settings = Settings::load(None, Some(&config_path)).unwrap();
+    assert_eq!(settings.server.port, 6000);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
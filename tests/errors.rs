@@ -0,0 +1,174 @@
+//! How errors are reported to clients
+
+use axum::response::IntoResponse;
+use cdk_gateway::gateway_server::{ErrorKind, ErrorResponse};
+use cdk_gateway::testing::{MockMint, MockNode, TestGateway, TestInvoice, melt_request};
+use reqwest::StatusCode;
+
+#[test]
+fn the_status_follows_the_kind_whatever_the_message() {
+    let kinds = [
+        (ErrorKind::InvalidRequest, StatusCode::BAD_REQUEST),
+        (ErrorKind::InvalidInvoice, StatusCode::BAD_REQUEST),
+        (ErrorKind::UnsupportedMethod, StatusCode::BAD_REQUEST),
+        (ErrorKind::UnsupportedMint, StatusCode::BAD_REQUEST),
+        (ErrorKind::NoTokens, StatusCode::BAD_REQUEST),
+        (ErrorKind::TokenVerificationFailed, StatusCode::BAD_REQUEST),
+        (ErrorKind::Unauthorized, StatusCode::UNAUTHORIZED),
+        (ErrorKind::InsufficientFunds, StatusCode::PAYMENT_REQUIRED),
+        (ErrorKind::NotFound, StatusCode::NOT_FOUND),
+        (ErrorKind::RequestInProgress, StatusCode::CONFLICT),
+        (ErrorKind::TooLarge, StatusCode::PAYLOAD_TOO_LARGE),
+        (
+            ErrorKind::IdempotencyKeyReused,
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ),
+        (ErrorKind::RateLimited, StatusCode::TOO_MANY_REQUESTS),
+        (ErrorKind::PaymentFailed, StatusCode::INTERNAL_SERVER_ERROR),
+        (ErrorKind::Internal, StatusCode::INTERNAL_SERVER_ERROR),
+        (ErrorKind::DestinationUnresolved, StatusCode::BAD_GATEWAY),
+        (ErrorKind::MintUnreachable, StatusCode::SERVICE_UNAVAILABLE),
+        (ErrorKind::Unavailable, StatusCode::SERVICE_UNAVAILABLE),
+        (ErrorKind::Timeout, StatusCode::GATEWAY_TIMEOUT),
+    ];
+
+    for (kind, status) in kinds {
+        // Messages that used to decide the status must not change it
+        for message in [
+            "Insufficient funds",
+            "Token verification failed",
+            "Anything",
+        ] {
+            let response = ErrorResponse::new(kind, message).into_response();
+            assert_eq!(response.status(), status, "{:?} with {:?}", kind, message);
+        }
+    }
+}
+
+#[tokio::test]
+async fn details_are_only_sent_when_exposed() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut request = melt_request(&invoice, &[]);
+    request["method"] = "bolt12".into();
+
+    for expose_error_details in [false, true] {
+        let mut settings = TestGateway::settings(&[&mint]);
+        settings.server.expose_error_details = expose_error_details;
+        let gateway = TestGateway::start_with(MockNode::failing("unused"), settings)
+            .await
+            .unwrap();
+
+        let response = gateway.post("/payment", &request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error: ErrorResponse = response.json().await.unwrap();
+        assert_eq!(error.message, "Payment method not supported");
+        assert_eq!(error.details.is_some(), expose_error_details);
+
+        gateway.stop().await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn each_failure_reports_its_kind() {
+    let mint = MockMint::start().await.unwrap();
+    let unsupported = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::failing("no route"), &[&mint])
+        .await
+        .unwrap();
+    let hash = invoice.payment_hash();
+
+    let mut not_an_invoice = melt_request(&invoice, &[mint.htlc_token(hash, 100)]);
+    not_an_invoice["request"] = "lnbc-not-an-invoice".into();
+    let scenarios = [
+        (
+            melt_request(&invoice, &[mint.htlc_token(hash, 60)]),
+            ErrorKind::InsufficientFunds,
+        ),
+        (not_an_invoice, ErrorKind::InvalidInvoice),
+        (
+            melt_request(&invoice, &[unsupported.htlc_token(hash, 100)]),
+            ErrorKind::UnsupportedMint,
+        ),
+        (
+            melt_request(
+                &invoice,
+                &[mint.htlc_token(TestInvoice::new(Some(1)).payment_hash(), 100)],
+            ),
+            ErrorKind::TokenVerificationFailed,
+        ),
+        (melt_request(&invoice, &[]), ErrorKind::NoTokens),
+        (
+            melt_request(&invoice, &[mint.htlc_token(hash, 100)]),
+            ErrorKind::PaymentFailed,
+        ),
+    ];
+
+    for (request, kind) in scenarios {
+        let response = gateway.post("/payment", &request).await;
+        assert_eq!(response.status().as_u16(), kind.status(), "{:?}", kind);
+        let error: ErrorResponse = response.json().await.unwrap();
+        assert_eq!(error.error_kind, kind);
+    }
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn a_rate_limited_processor_asks_the_client_to_retry_later() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+
+    // The processor's own hint is passed on, the configured delay used otherwise
+    for (message, retry_after) in [
+        (
+            "status: ResourceExhausted, message: \"retry after 12 seconds\"",
+            12,
+        ),
+        ("Too many requests", 7),
+    ] {
+        let mut settings = TestGateway::settings(&[&mint]);
+        settings.payment.processor_retry_after_secs = 7;
+        let gateway = TestGateway::start_with(MockNode::failing(message), settings)
+            .await
+            .unwrap();
+
+        let token = mint.htlc_token(invoice.payment_hash(), 110);
+        let response = gateway
+            .post("/payment", &melt_request(&invoice, &[token]))
+            .await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let header: u64 = response.headers()[reqwest::header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(header, retry_after);
+        let error: ErrorResponse = response.json().await.unwrap();
+        assert_eq!(error.error_kind, ErrorKind::RateLimited);
+
+        gateway.stop().await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn retry_after_is_sent_without_error_details() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.server.expose_error_details = false;
+    let node = MockNode::failing("status: ResourceExhausted, message: \"retry after 12 seconds\"");
+    let gateway = TestGateway::start_with(node, settings).await.unwrap();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()[reqwest::header::RETRY_AFTER], "12");
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.details, None);
+
+    gateway.stop().await.unwrap();
+}
@@ -0,0 +1,161 @@
+//! Limits on the size of payment requests
+
+use cdk_gateway::config::MintEntry;
+use cdk_gateway::gateway_server::ErrorResponse;
+use cdk_gateway::testing::{MockMint, MockNode, TestGateway, TestInvoice, melt_request};
+use reqwest::StatusCode;
+
+#[tokio::test]
+async fn rejects_requests_beyond_the_token_and_proof_limits() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.max_tokens_per_request = 2;
+    settings.payment.max_proofs_per_request = 4;
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+
+    // 64 + 32 + 4 and 1, four proofs in two tokens
+    let tokens = [
+        mint.htlc_token(invoice.payment_hash(), 100),
+        mint.htlc_token(invoice.payment_hash(), 1),
+    ];
+    let response = gateway
+        .post("/payment/dry-run", &melt_request(&invoice, &tokens))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let tokens = [
+        mint.htlc_token(invoice.payment_hash(), 98),
+        mint.htlc_token(invoice.payment_hash(), 1),
+        mint.htlc_token(invoice.payment_hash(), 1),
+    ];
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &tokens))
+        .await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Too many tokens");
+
+    // 64 + 32 + 4 and 2 + 1, five proofs in two tokens
+    let tokens = [
+        mint.htlc_token(invoice.payment_hash(), 100),
+        mint.htlc_token(invoice.payment_hash(), 3),
+    ];
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &tokens))
+        .await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Too many proofs");
+
+    assert!(gateway.node.payments().is_empty());
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn rejects_payment_bodies_beyond_the_size_limit() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.server.max_body_bytes = 4096;
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+
+    let oversized = "a".repeat(8192);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[oversized]))
+        .await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(gateway.node.payments().is_empty());
+
+    let token = mint.htlc_token(invoice.payment_hash(), 110);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn enforces_the_limits_of_each_mint() {
+    let trusted = MockMint::start().await.unwrap();
+    let untrusted = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&trusted, &untrusted]);
+    settings.wallet.mint_urls = vec![
+        MintEntry::WithLimits {
+            url: trusted.url().to_string(),
+            min_sat: Some(10),
+            max_sat: Some(1000),
+        },
+        MintEntry::WithLimits {
+            url: untrusted.url().to_string(),
+            min_sat: None,
+            max_sat: Some(50),
+        },
+    ];
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+
+    let dry_run = async |tokens: &[String]| {
+        gateway
+            .post("/payment/dry-run", &melt_request(&invoice, tokens))
+            .await
+    };
+    let rejected = async |tokens: &[String]| {
+        let response = dry_run(tokens).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error: ErrorResponse = response.json().await.unwrap();
+        assert_eq!(error.message, "Amount outside mint limits");
+    };
+
+    let hash = invoice.payment_hash();
+    let response = dry_run(&[trusted.htlc_token(hash, 100)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    rejected(&[untrusted.htlc_token(hash, 100)]).await;
+
+    let response = dry_run(&[trusted.htlc_token(hash, 50), untrusted.htlc_token(hash, 50)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    rejected(&[trusted.htlc_token(hash, 5), untrusted.htlc_token(hash, 95)]).await;
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn payments_up_to_the_cap_are_made() {
+    let mint = MockMint::start().await.unwrap();
+    let at_cap = TestInvoice::new(Some(100));
+    let over_cap = TestInvoice::new(Some(101));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.max_single_payment_sat = Some(100);
+    let gateway = TestGateway::start_with(MockNode::succeeding(&at_cap.preimage), settings)
+        .await
+        .unwrap();
+
+    let token = mint.htlc_token(over_cap.payment_hash(), 101);
+    let response = gateway
+        .post("/payment", &melt_request(&over_cap, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Amount above the maximum payment");
+    assert_eq!(
+        error.details.unwrap(),
+        "At most 100 sat can be paid in a single request, got 101 sat"
+    );
+    assert!(gateway.node.payments().is_empty());
+
+    let token = mint.htlc_token(at_cap.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&at_cap, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(gateway.node.payments().len(), 1);
+
+    gateway.stop().await.unwrap();
+}
@@ -0,0 +1,318 @@
+//! Paying lightning addresses and LNURLs, resolved against a local LNURL-pay server
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use axum::extract::{Query, State};
+use axum::routing::get;
+use axum::{Json, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use bitcoin::bech32;
+use cdk::cdk_payment::OutgoingPaymentOptions;
+use cdk_gateway::config::Settings;
+use cdk_gateway::gateway_server::{ErrorResponse, MeltResponse};
+use cdk_gateway::lnurl::LnurlResolver;
+use cdk_gateway::testing::{MockMint, MockNode, TestGateway, TestInvoice};
+use reqwest::StatusCode;
+use serde_json::{Value, json};
+
+fn fixtures() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+#[derive(Clone)]
+struct StubState {
+    base_url: String,
+    invoice: String,
+    max_sendable_msat: u64,
+    requested_msat: Arc<Mutex<Vec<u64>>>,
+}
+
+/// LNURL-pay server for `alice`, answering every callback with one invoice
+struct LnurlStub {
+    base_url: String,
+    requested_msat: Arc<Mutex<Vec<u64>>>,
+    handle: axum_server::Handle,
+}
+
+impl LnurlStub {
+    async fn start(invoice: &TestInvoice, max_sendable_msat: u64) -> Self {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let tls = RustlsConfig::from_pem_file(
+            fixtures().join("tls-cert.pem"),
+            fixtures().join("tls-key.pem"),
+        )
+        .await
+        .unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state = StubState {
+            base_url: format!("https://localhost:{}", port),
+            invoice: invoice.invoice.to_string(),
+            max_sendable_msat,
+            requested_msat: Arc::default(),
+        };
+
+        let app = Router::new()
+            .route("/.well-known/lnurlp/alice", get(pay_params))
+            .route("/callback", get(callback))
+            .with_state(state.clone());
+        let handle = axum_server::Handle::new();
+        let server =
+            axum_server::from_tcp_rustls(listener.into_std().unwrap(), tls).handle(handle.clone());
+        tokio::spawn(server.serve(app.into_make_service()));
+
+        Self {
+            base_url: state.base_url,
+            requested_msat: state.requested_msat,
+            handle,
+        }
+    }
+
+    fn address(&self) -> String {
+        format!("alice@{}", self.base_url.trim_start_matches("https://"))
+    }
+
+    /// Bech32 LNURL of the pay endpoint
+    fn lnurl(&self) -> String {
+        let url = format!("{}/.well-known/lnurlp/alice", self.base_url);
+        bech32::encode::<bech32::Bech32>(bech32::Hrp::parse("lnurl").unwrap(), url.as_bytes())
+            .unwrap()
+    }
+
+    /// Amounts the gateway asked the callback for invoices of
+    fn requested_msat(&self) -> Vec<u64> {
+        self.requested_msat.lock().unwrap().clone()
+    }
+
+    fn stop(&self) {
+        self.handle.shutdown();
+    }
+}
+
+async fn pay_params(State(state): State<StubState>) -> Json<Value> {
+    Json(json!({
+        "callback": format!("{}/callback", state.base_url),
+        "minSendable": 1_000,
+        "maxSendable": state.max_sendable_msat,
+        "metadata": "[[\"text/plain\",\"alice\"]]",
+        "tag": "payRequest",
+    }))
+}
+
+async fn callback(
+    State(state): State<StubState>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Json<Value> {
+    let amount = query["amount"].parse().unwrap();
+    state.requested_msat.lock().unwrap().push(amount);
+    Json(json!({ "pr": state.invoice, "routes": [] }))
+}
+
+/// Gateway resolving destinations with a resolver that trusts the fixture CA
+async fn gateway_with_lnurl(node: MockNode, mint: &MockMint) -> TestGateway {
+    gateway_with_lnurl_settings(node, TestGateway::settings(&[mint])).await
+}
+
+async fn gateway_with_lnurl_settings(node: MockNode, mut settings: Settings) -> TestGateway {
+    settings.lnurl.enabled = true;
+    let ca = std::fs::read(fixtures().join("ca-cert.pem")).unwrap();
+    let resolver = LnurlResolver::for_local_servers(
+        &settings.lnurl,
+        reqwest::Certificate::from_pem(&ca).unwrap(),
+    );
+    TestGateway::start_with_lnurl(node, settings, resolver)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn pays_a_lightning_address() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let stub = LnurlStub::start(&invoice, 1_000_000).await;
+    let gateway = gateway_with_lnurl(MockNode::succeeding(&invoice.preimage), &mint).await;
+
+    let request = json!({
+        "method": "ln_address",
+        "request": stub.address(),
+        "amount": 100,
+        "tokens": [mint.htlc_token(invoice.payment_hash(), 100)],
+    });
+    let response = gateway.post("/payment", &request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let melt: MeltResponse = response.json().await.unwrap();
+    assert_eq!(melt.payment_proof, invoice.preimage);
+
+    assert_eq!(stub.requested_msat(), [100_000]);
+    let payments = gateway.node.payments();
+    let OutgoingPaymentOptions::Bolt11(payment) = &payments[0] else {
+        panic!("expected a bolt11 payment");
+    };
+    assert_eq!(payment.bolt11, invoice.invoice);
+
+    gateway.stop().await.unwrap();
+    stub.stop();
+}
+
+#[tokio::test]
+async fn lightning_addresses_are_not_resolved_when_disabled() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let stub = LnurlStub::start(&invoice, 1_000_000).await;
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&mint])
+        .await
+        .unwrap();
+
+    let request = json!({
+        "method": "ln_address",
+        "request": stub.address(),
+        "amount": 100,
+        "tokens": [mint.htlc_token(invoice.payment_hash(), 100)],
+    });
+    let response = gateway.post("/payment", &request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Payment method not supported");
+
+    assert!(stub.requested_msat().is_empty());
+    assert!(gateway.node.payments().is_empty());
+    gateway.stop().await.unwrap();
+    stub.stop();
+}
+
+#[tokio::test]
+async fn pays_an_lnurl_within_the_sendable_range() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let stub = LnurlStub::start(&invoice, 200_000).await;
+    let gateway = gateway_with_lnurl(MockNode::succeeding(&invoice.preimage), &mint).await;
+
+    let response = gateway
+        .post(
+            "/payment",
+            &json!({
+                "method": "lnurl",
+                "request": stub.lnurl(),
+                "amount": 100,
+                "tokens": [mint.htlc_token(invoice.payment_hash(), 100)],
+            }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let melt: MeltResponse = response.json().await.unwrap();
+    assert_eq!(melt.payment_proof, invoice.preimage);
+    assert_eq!(stub.requested_msat(), [100_000]);
+
+    // More than the recipient accepts is refused before asking for an invoice
+    let response = gateway
+        .post(
+            "/payment",
+            &json!({
+                "method": "lnurl",
+                "request": stub.lnurl(),
+                "amount": 300,
+                "tokens": [mint.htlc_token(invoice.payment_hash(), 300)],
+            }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Failed to resolve payment destination");
+    assert_eq!(
+        error.details.unwrap(),
+        "Recipient accepts between 1 and 200 sat"
+    );
+
+    // An amount too large to convert to msat is out of range as well
+    let response = gateway
+        .post(
+            "/payment",
+            &json!({
+                "method": "lnurl",
+                "request": stub.lnurl(),
+                "amount": u64::MAX,
+                "tokens": [mint.htlc_token(invoice.payment_hash(), 300)],
+            }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(
+        error.details.unwrap(),
+        "Recipient accepts between 1 and 200 sat"
+    );
+
+    assert_eq!(stub.requested_msat(), [100_000]);
+    assert_eq!(gateway.node.payments().len(), 1);
+    gateway.stop().await.unwrap();
+    stub.stop();
+}
+
+#[tokio::test]
+async fn paid_invoices_are_not_reused() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let stub = LnurlStub::start(&invoice, 1_000_000).await;
+    let gateway = gateway_with_lnurl(MockNode::succeeding(&invoice.preimage), &mint).await;
+
+    let request = |tokens: Vec<String>| {
+        json!({
+            "method": "ln_address",
+            "request": stub.address(),
+            "amount": 100,
+            "tokens": tokens,
+        })
+    };
+
+    // A request asking for ecash and its retry share one invoice
+    let response = gateway.post("/payment", &request(Vec::new())).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = gateway
+        .post(
+            "/payment",
+            &request(vec![mint.htlc_token(invoice.payment_hash(), 100)]),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(stub.requested_msat(), [100_000]);
+
+    // Once paid, the next payment to the same address gets a new invoice
+    let response = gateway.post("/payment", &request(Vec::new())).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(stub.requested_msat(), [100_000, 100_000]);
+
+    gateway.stop().await.unwrap();
+    stub.stop();
+}
+
+#[tokio::test]
+async fn amounts_above_the_cap_are_rejected_before_resolving() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let stub = LnurlStub::start(&invoice, 1_000_000).await;
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.max_single_payment_sat = Some(50);
+    let gateway =
+        gateway_with_lnurl_settings(MockNode::succeeding(&invoice.preimage), settings).await;
+
+    for amount in [100, u64::MAX] {
+        let request = json!({
+            "method": "lnurl",
+            "request": stub.lnurl(),
+            "amount": amount,
+            "tokens": [mint.htlc_token(invoice.payment_hash(), 100)],
+        });
+        let response = gateway.post("/payment", &request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error: ErrorResponse = response.json().await.unwrap();
+        assert_eq!(error.message, "Amount above the maximum payment");
+    }
+
+    assert!(stub.requested_msat().is_empty());
+    assert!(gateway.node.payments().is_empty());
+    gateway.stop().await.unwrap();
+    stub.stop();
+}
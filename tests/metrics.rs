@@ -0,0 +1,204 @@
+//! Metrics recorded for payments and served on `/metrics`
+
+use cdk::amount::Amount;
+use cdk::nuts::SecretKey;
+use cdk_gateway::gateway_server::MeltResponse;
+use cdk_gateway::testing::{
+    MockMint, MockNode, MockOutcome, TestGateway, TestInvoice, check_change, htlc_conditions,
+    melt_request,
+};
+use reqwest::StatusCode;
+
+#[tokio::test]
+async fn labels_metrics_by_mint_and_destination() {
+    let first = MockMint::start().await.unwrap();
+    let second = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&first, &second]);
+    settings.metrics.enabled = true;
+    settings.metrics.destination_labels = true;
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+
+    let tokens = [
+        first.htlc_token(invoice.payment_hash(), 60),
+        second.htlc_token(invoice.payment_hash(), 50),
+    ];
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &tokens))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let metrics = gateway.get("/metrics").await.text().await.unwrap();
+    for (mint, received) in [(&first, 60), (&second, 50)] {
+        let mint_url = mint.url().to_string();
+        assert!(metrics.contains(&format!(
+            "gateway_receives_total{{mint_url=\"{}\",outcome=\"success\"}} 1",
+            mint_url
+        )));
+        assert!(metrics.contains(&format!(
+            "gateway_received_sats_total{{mint_url=\"{}\"}} {}",
+            mint_url, received
+        )));
+    }
+    assert!(metrics.contains(&format!(
+        "gateway_destination_payments_total{{destination=\"{}\",outcome=\"success\"}} 1",
+        invoice.invoice.get_payee_pub_key()
+    )));
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn counts_successful_payments() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.metrics.enabled = true;
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+
+    let metrics = gateway.get("/metrics").await.text().await.unwrap();
+    assert!(metrics.contains("gateway_payments_total 0"));
+
+    let token = mint.htlc_token(invoice.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let metrics = gateway.get("/metrics").await.text().await.unwrap();
+    assert!(metrics.contains("gateway_payments_total 1"));
+    assert!(metrics.contains("gateway_payments_failed_total 0"));
+    assert!(metrics.contains("gateway_paid_sats_total 100"));
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn metrics_are_only_served_when_enabled() {
+    let mint = MockMint::start().await.unwrap();
+    let gateway = TestGateway::start(MockNode::failing("unused"), &[&mint])
+        .await
+        .unwrap();
+
+    assert_eq!(
+        gateway.get("/metrics").await.status(),
+        StatusCode::NOT_FOUND
+    );
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn counts_change_that_does_not_add_up() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.metrics.enabled = true;
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 112);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let metrics = gateway.get("/metrics").await.text().await.unwrap();
+    assert!(metrics.contains("gateway_change_anomalies_total 0"));
+
+    let recorded = gateway.gateway.metrics();
+    let (received, change) = (Amount::from(112), Amount::from(12));
+    assert!(check_change(recorded, received, change, change));
+    // More change than was received
+    assert!(!check_change(
+        recorded,
+        received,
+        Amount::from(224),
+        Amount::from(224)
+    ));
+    // Change tokens worth more than the change due
+    assert!(!check_change(recorded, received, change, Amount::from(24)));
+
+    let metrics = gateway.get("/metrics").await.text().await.unwrap();
+    assert!(metrics.contains("gateway_change_anomalies_total 2"));
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn counts_sats_spent_beyond_the_tokens_received() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    // The node charges a fee the tokens don't cover
+    let node = MockNode::new(MockOutcome::Success {
+        preimage: invoice.preimage.clone(),
+        fee: Amount::from(5),
+    });
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.metrics.enabled = true;
+    let gateway = TestGateway::start_with(node, settings).await.unwrap();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let melt: MeltResponse = response.json().await.unwrap();
+    assert_eq!(melt.fee_paid_sat, 5);
+    assert_eq!(melt.change_sat, 0);
+    assert!(melt.change.is_empty());
+
+    let metrics = gateway.get("/metrics").await.text().await.unwrap();
+    assert!(metrics.contains("gateway_overpaid_sats_total 5"));
+    assert!(metrics.contains("gateway_change_anomalies_total 0"));
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn counts_dleq_failures_by_mint() {
+    let flaky = MockMint::start().await.unwrap();
+    let healthy = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&flaky, &healthy]);
+    settings.metrics.enabled = true;
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+
+    let mut proofs = flaky.issue(100, Some(&htlc_conditions(invoice.payment_hash(), None)));
+    for proof in proofs.iter_mut() {
+        if let Some(dleq) = proof.dleq.as_mut() {
+            dleq.r = SecretKey::generate();
+        }
+    }
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[flaky.token(proofs)]))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let token = healthy.htlc_token(invoice.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let metrics = gateway.get("/metrics").await.text().await.unwrap();
+    let dleq_count = |mint: &MockMint, outcome: &str, count: u64| {
+        format!(
+            "gateway_dleq_verifications_total{{mint_url=\"{}\",outcome=\"{}\"}} {}",
+            mint.url(),
+            outcome,
+            count
+        )
+    };
+    assert!(metrics.contains(&dleq_count(&flaky, "failure", 1)));
+    assert!(metrics.contains(&dleq_count(&healthy, "success", 1)));
+    assert!(!metrics.contains(&dleq_count(&healthy, "failure", 1)));
+
+    gateway.stop().await.unwrap();
+}
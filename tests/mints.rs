@@ -0,0 +1,167 @@
+//! Fetching and caching the metadata of the supported mints
+
+use std::time::{Duration, Instant};
+
+use cdk::mint_url::MintUrl;
+use cdk::nuts::CurrencyUnit;
+use cdk::wallet::types::WalletKey;
+use cdk_gateway::gateway_server::{ErrorKind, ErrorResponse, MintMetadata};
+use cdk_gateway::testing::{MockMint, MockNode, TestGateway, TestInvoice, melt_request};
+use reqwest::StatusCode;
+
+#[tokio::test]
+async fn retries_a_mint_that_failed_the_first_fetch() {
+    let mint = MockMint::start().await.unwrap();
+    mint.fail_info(1);
+    let gateway = TestGateway::start(MockNode::failing("unused"), &[&mint])
+        .await
+        .unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !gateway.gateway.mint_info().has_reachable_mint() {
+        assert!(Instant::now() < deadline, "mint info was never fetched");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(mint.info_requests() >= 2);
+
+    let response = gateway.get("/mints").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let mints: Vec<MintMetadata> = response.json().await.unwrap();
+    assert!(mints[0].reachable);
+
+    gateway.stop().await.unwrap();
+}
+
+/// Wait for the gateway's first fetch of the mint's info to succeed
+async fn wait_for_mint_info(gateway: &TestGateway) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !gateway.gateway.mint_info().has_reachable_mint() {
+        assert!(Instant::now() < deadline, "mint info was never fetched");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[tokio::test]
+async fn lists_the_metadata_of_reachable_mints() {
+    let mint = MockMint::start().await.unwrap();
+    let gateway = TestGateway::start(MockNode::failing("unused"), &[&mint])
+        .await
+        .unwrap();
+    wait_for_mint_info(&gateway).await;
+
+    let response = gateway.get("/mints").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let mints: Vec<MintMetadata> = response.json().await.unwrap();
+    assert_eq!(mints.len(), 1);
+    assert_eq!(&mints[0].url, mint.url());
+    assert_eq!(mints[0].name.as_deref(), Some("Mock mint"));
+    assert_eq!(mints[0].units, [CurrencyUnit::Sat]);
+    assert!(mints[0].reachable);
+    assert_eq!(mints[0].fee_ppk, Some(0));
+
+    let response = gateway.get("/mints?plain=true").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let urls: Vec<MintUrl> = response.json().await.unwrap();
+    assert_eq!(urls, [mint.url().clone()]);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn serves_mint_info_from_the_cache() {
+    let mint = MockMint::start().await.unwrap();
+    let gateway = TestGateway::start(MockNode::failing("unused"), &[&mint])
+        .await
+        .unwrap();
+    wait_for_mint_info(&gateway).await;
+
+    let fetched = mint.info_requests();
+    for _ in 0..2 {
+        let response = gateway.get("/mints").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let mints: Vec<MintMetadata> = response.json().await.unwrap();
+        assert!(mints[0].reachable);
+    }
+    assert_eq!(mint.info_requests(), fetched);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn denylisted_mints_are_rejected_though_they_have_a_wallet() {
+    let allowed = MockMint::start().await.unwrap();
+    let denied = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&allowed, &denied]);
+    settings.payment.mint_denylist = vec![denied.url().to_string()];
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+    assert!(
+        gateway
+            .gateway
+            .wallets()
+            .get_wallet(&WalletKey::new(denied.url().clone(), CurrencyUnit::Sat))
+            .await
+            .is_some()
+    );
+
+    let urls: Vec<MintUrl> = gateway.get("/mints?plain=true").await.json().await.unwrap();
+    assert_eq!(urls, [allowed.url().clone()]);
+
+    let token = denied.htlc_token(invoice.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.error_kind, ErrorKind::UnsupportedMint);
+    assert_eq!(error.message, "Mint not accepted");
+    assert!(gateway.node.payments().is_empty());
+
+    let token = allowed.htlc_token(invoice.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn mints_without_a_wallet_are_not_offered() {
+    let mint = MockMint::start().await.unwrap();
+    let orphan = MockMint::start().await.unwrap();
+    let gateway = TestGateway::start(MockNode::failing("unused"), &[&mint])
+        .await
+        .unwrap();
+
+    // As if the orphan's wallet had failed to build at startup
+    let server = gateway
+        .gateway
+        .start_server(
+            "127.0.0.1:0".parse().unwrap(),
+            vec![mint.url().clone(), orphan.url().clone()],
+        )
+        .await
+        .unwrap();
+    let response = gateway
+        .client
+        .get(format!("http://{}/mints?plain=true", server.local_addr()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let mints: Vec<MintUrl> = response.json().await.unwrap();
+    assert_eq!(mints, [mint.url().clone()]);
+    server.stop().await.unwrap();
+
+    // Without a wallet for any of them there is nothing to serve
+    let started = gateway
+        .gateway
+        .start_server("127.0.0.1:0".parse().unwrap(), vec![orphan.url().clone()])
+        .await;
+    assert!(started.is_err());
+
+    gateway.stop().await.unwrap();
+}
@@ -0,0 +1,301 @@
+//! Connecting to payment processors over gRPC, with a mock node behind them
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use cdk::amount::Amount;
+use cdk::cdk_payment::{Bolt11OutgoingPaymentOptions, MintPayment, OutgoingPaymentOptions};
+use cdk::nuts::CurrencyUnit;
+use cdk_gateway::config::GrpcProcessor;
+use cdk_gateway::node::{FailoverBackend, FailoverNode, NodeHealth, ReconnectingNode};
+use cdk_gateway::testing::{MockNode, MockOutcome, TestInvoice};
+use cdk_payment_processor::PaymentProcessorServer;
+use tokio_util::sync::CancellationToken;
+
+/// A port nothing is listening on
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Serve `node` as a payment processor on `port`
+async fn start_processor(node: Arc<MockNode>, port: u16) -> PaymentProcessorServer {
+    let mut server = PaymentProcessorServer::new(node, "127.0.0.1", port).unwrap();
+    server.start(None).await.unwrap();
+    server
+}
+
+fn processor_config(port: u16) -> GrpcProcessor {
+    GrpcProcessor {
+        addr: "http://127.0.0.1".to_string(),
+        port,
+        ..Default::default()
+    }
+}
+
+/// Wait up to `timeout` for `condition` to hold
+async fn eventually<F, Fut>(timeout: Duration, condition: F) -> bool
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    tokio::time::timeout(timeout, async {
+        while !condition().await {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .is_ok()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reconnects_after_the_processor_restarts() {
+    let port = free_port();
+    let mock = Arc::new(MockNode::failing("unused"));
+    let server = start_processor(mock.clone(), port).await;
+
+    let health = Arc::new(NodeHealth::new());
+    let node = ReconnectingNode::connect(
+        processor_config(port),
+        health.clone(),
+        CancellationToken::new(),
+    )
+    .await;
+    node.probe().await.unwrap();
+    assert!(health.is_connected());
+
+    // The connection drops with the processor
+    server.stop().await.unwrap();
+    assert!(node.get_settings().await.is_err());
+    assert!(!health.is_connected());
+
+    let server = start_processor(mock, port).await;
+    assert!(eventually(Duration::from_secs(20), || async { health.is_connected() }).await);
+    node.get_settings().await.unwrap();
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn startup_probe_fails_for_an_unreachable_processor() {
+    let port = free_port();
+    let health = Arc::new(NodeHealth::new());
+    let node = ReconnectingNode::connect(
+        processor_config(port),
+        health.clone(),
+        CancellationToken::new(),
+    )
+    .await;
+
+    let error = node.probe().await.unwrap_err().to_string();
+    assert!(error.contains(&format!("127.0.0.1:{}", port)));
+    assert!(error.contains("is not reachable"));
+    assert!(!health.is_connected());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn connects_once_a_processor_down_at_startup_comes_up() {
+    let port = free_port();
+    let health = Arc::new(NodeHealth::new());
+    let node = ReconnectingNode::connect(
+        processor_config(port),
+        health.clone(),
+        CancellationToken::new(),
+    )
+    .await;
+    assert!(node.get_settings().await.is_err());
+    assert!(!health.is_connected());
+
+    let server = start_processor(Arc::new(MockNode::failing("unused")), port).await;
+    assert!(eventually(Duration::from_secs(20), || async { health.is_connected() }).await);
+    node.get_settings().await.unwrap();
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pings_an_idle_processor_at_the_configured_interval() {
+    let pinged_port = free_port();
+    let pinged = Arc::new(MockNode::failing("unused"));
+    let pinged_server = start_processor(pinged.clone(), pinged_port).await;
+    let quiet_port = free_port();
+    let quiet = Arc::new(MockNode::failing("unused"));
+    let quiet_server = start_processor(quiet.clone(), quiet_port).await;
+
+    let config = GrpcProcessor {
+        ping_interval_secs: 1,
+        ..processor_config(pinged_port)
+    };
+    let node = ReconnectingNode::connect(
+        config,
+        Arc::new(NodeHealth::new()),
+        CancellationToken::new(),
+    )
+    .await;
+    node.probe().await.unwrap();
+    let config = GrpcProcessor {
+        ping_interval_secs: 0,
+        ..processor_config(quiet_port)
+    };
+    let quiet_node = ReconnectingNode::connect(
+        config,
+        Arc::new(NodeHealth::new()),
+        CancellationToken::new(),
+    )
+    .await;
+    quiet_node.probe().await.unwrap();
+
+    let probed = pinged.settings_requests();
+    assert!(
+        eventually(Duration::from_secs(10), || async {
+            pinged.settings_requests() >= probed + 2
+        })
+        .await
+    );
+    assert_eq!(quiet.settings_requests(), 1);
+
+    pinged_server.stop().await.unwrap();
+    quiet_server.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stops_pinging_once_cancelled() {
+    let port = free_port();
+    let mock = Arc::new(MockNode::failing("unused"));
+    let server = start_processor(mock.clone(), port).await;
+
+    let config = GrpcProcessor {
+        ping_interval_secs: 1,
+        ..processor_config(port)
+    };
+    let cancel = CancellationToken::new();
+    let node = ReconnectingNode::connect(config, Arc::new(NodeHealth::new()), cancel.clone()).await;
+    node.probe().await.unwrap();
+    let probed = mock.settings_requests();
+    assert!(
+        eventually(Duration::from_secs(10), || async {
+            mock.settings_requests() > probed
+        })
+        .await
+    );
+
+    cancel.cancel();
+    // Give a ping already under way time to finish
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let pinged = mock.settings_requests();
+    tokio::time::sleep(Duration::from_millis(2_500)).await;
+    assert_eq!(mock.settings_requests(), pinged);
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fails_over_to_the_next_processor_when_the_primary_is_down() {
+    let invoice = TestInvoice::new(Some(100));
+    let primary_port = free_port();
+    let primary_health = Arc::new(NodeHealth::new());
+    let primary = ReconnectingNode::connect(
+        processor_config(primary_port),
+        primary_health.clone(),
+        CancellationToken::new(),
+    )
+    .await;
+    // Nothing listens on the primary's port
+    assert!(primary.probe().await.is_err());
+    assert!(!primary_health.is_connected());
+
+    let secondary_port = free_port();
+    let secondary_mock = Arc::new(MockNode::succeeding(&invoice.preimage));
+    let server = start_processor(secondary_mock.clone(), secondary_port).await;
+    let secondary_health = Arc::new(NodeHealth::new());
+    let secondary = ReconnectingNode::connect(
+        processor_config(secondary_port),
+        secondary_health.clone(),
+        CancellationToken::new(),
+    )
+    .await;
+    secondary.probe().await.unwrap();
+
+    let node = FailoverNode::new(vec![
+        FailoverBackend {
+            name: "primary".to_string(),
+            node: primary,
+            health: primary_health,
+        },
+        FailoverBackend {
+            name: "secondary".to_string(),
+            node: secondary,
+            health: secondary_health,
+        },
+    ])
+    .unwrap();
+    assert!(node.health().is_connected());
+
+    let options = OutgoingPaymentOptions::Bolt11(Box::new(Bolt11OutgoingPaymentOptions {
+        bolt11: invoice.invoice.clone(),
+        max_fee_amount: None,
+        timeout_secs: None,
+        melt_options: None,
+    }));
+    let response = node
+        .make_payment(&CurrencyUnit::Sat, options)
+        .await
+        .unwrap();
+    assert_eq!(response.payment_proof, Some(invoice.preimage.clone()));
+    assert_eq!(secondary_mock.payments().len(), 1);
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn forgets_payments_once_their_outcome_is_final() {
+    let paid = TestInvoice::new(Some(100));
+    let unconfirmed = TestInvoice::new(Some(100));
+    let mock = Arc::new(
+        MockNode::succeeding(&paid.preimage).then(MockOutcome::Unconfirmed { fee: Amount::ZERO }),
+    );
+    let node = FailoverNode::new(vec![FailoverBackend {
+        name: "only".to_string(),
+        node: mock.clone(),
+        health: Arc::new(NodeHealth::new()),
+    }])
+    .unwrap();
+    let pay = |invoice: &TestInvoice| {
+        OutgoingPaymentOptions::Bolt11(Box::new(Bolt11OutgoingPaymentOptions {
+            bolt11: invoice.invoice.clone(),
+            max_fee_amount: None,
+            timeout_secs: None,
+            melt_options: None,
+        }))
+    };
+
+    // Sent without a preimage, so it is kept to be checked later
+    let response = node
+        .make_payment(&CurrencyUnit::Sat, pay(&unconfirmed))
+        .await
+        .unwrap();
+    assert_eq!(response.payment_proof, None);
+    assert_eq!(node.tracked_payments(), 1);
+
+    node.make_payment(&CurrencyUnit::Sat, pay(&paid))
+        .await
+        .unwrap();
+    assert_eq!(node.tracked_payments(), 1);
+
+    mock.report_preimage(&unconfirmed.preimage);
+    let checked = node
+        .check_outgoing_payment(&response.payment_lookup_id)
+        .await
+        .unwrap();
+    assert_eq!(checked.payment_proof, Some(unconfirmed.preimage.clone()));
+    assert_eq!(node.tracked_payments(), 0);
+}
+
+#[test]
+fn a_failover_node_needs_a_processor() {
+    assert!(FailoverNode::new(Vec::new()).is_err());
+}
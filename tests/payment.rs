@@ -0,0 +1,1440 @@
+//! End-to-end payments through the gateway, with a mock mint and lightning node
+
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use cdk::amount::Amount;
+use cdk::cdk_payment::OutgoingPaymentOptions;
+use cdk::nuts::nut18::PaymentRequest;
+use cdk::nuts::{MeltOptions, SecretKey, Token};
+use cdk::util::unix_time;
+use cdk_gateway::gateway_server::{
+    BatchMeltRequest, BatchMeltResponse, BatchPayment, BatchPaymentStatus, DryRunResponse,
+    ErrorKind, ErrorResponse, InfoResponse, MeltResponse, PaymentMethod, REQUEST_ID_HEADER,
+};
+use cdk_gateway::idempotency::IDEMPOTENCY_KEY_HEADER;
+use cdk_gateway::testing::{
+    MockMint, MockNode, MockOutcome, TestGateway, TestInvoice, htlc_conditions, melt_request,
+    p2pk_conditions,
+};
+use reqwest::StatusCode;
+use serde_json::{Value, json};
+use uuid::Uuid;
+
+#[tokio::test]
+async fn pays_an_invoice_with_htlc_tokens() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let node = MockNode::new(MockOutcome::Success {
+        preimage: invoice.preimage.clone(),
+        fee: Amount::from(1),
+    });
+    let gateway = TestGateway::start(node, &[&mint]).await.unwrap();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 110);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let melt: MeltResponse = response.json().await.unwrap();
+    assert_eq!(melt.payment_proof, invoice.preimage);
+    assert_eq!(melt.payment_hash, invoice.payment_hash().to_string());
+    assert_eq!(melt.amount_paid_sat, 100);
+    assert_eq!(melt.fee_paid_sat, 1);
+    assert_eq!(melt.change_sat, 9);
+    assert!(!melt.change.is_empty());
+
+    let payments = gateway.node.payments();
+    assert_eq!(payments.len(), 1);
+    let OutgoingPaymentOptions::Bolt11(payment) = &payments[0] else {
+        panic!("expected a bolt11 payment");
+    };
+    assert_eq!(payment.bolt11, invoice.invoice);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn breakdown_matches_the_amount_requested() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.include_breakdown = true;
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 60);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+
+    let payment_request = response.headers()["x-cashu"].to_str().unwrap();
+    let payment_request = PaymentRequest::from_str(payment_request).unwrap();
+    assert_eq!(payment_request.amount, Some(Amount::from(100)));
+
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(
+        error.breakdown.unwrap(),
+        "Invoice: 100 sat, routing reserve: 0 sat, gateway fee: 0 sat, total: 100 sat"
+    );
+    assert!(gateway.node.payments().is_empty());
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn pending_proofs_delay_the_payment_until_settled() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.check_proofs_before_payment = true;
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+
+    let conditions = htlc_conditions(invoice.payment_hash(), Some(unix_time() + 3600));
+    let proofs = mint.issue(110, Some(&conditions));
+    mint.set_pending(&proofs);
+    let request = melt_request(&invoice, &[mint.token(proofs)]);
+
+    let settle = async {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(gateway.node.payments().is_empty());
+        mint.settle();
+    };
+    let (response, ()) = tokio::join!(gateway.post("/payment", &request), settle);
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let melt: MeltResponse = response.json().await.unwrap();
+    assert_eq!(melt.change_sat, 10);
+    assert_eq!(gateway.node.payments().len(), 1);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn slow_payments_time_out() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let node = MockNode::succeeding(&invoice.preimage).with_delay(Duration::from_secs(30));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.server.request_timeout_secs = 1;
+    settings.server.shutdown_drain_timeout_secs = 0;
+    let gateway = TestGateway::start_with(node, settings).await.unwrap();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 110);
+    let started = Instant::now();
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    assert!(started.elapsed() < Duration::from_secs(10));
+
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Payment request timed out");
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn responses_carry_a_request_id() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&mint])
+        .await
+        .unwrap();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 100);
+    let paid = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    let rejected = gateway
+        .post(
+            "/payment",
+            &json!({ "method": "bolt11", "request": "not an invoice", "tokens": [] }),
+        )
+        .await;
+    assert_eq!(paid.status(), StatusCode::OK);
+    assert_eq!(rejected.status(), StatusCode::BAD_REQUEST);
+
+    let request_ids: Vec<Uuid> = [&paid, &rejected]
+        .iter()
+        .map(|response| {
+            let request_id = response.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+            Uuid::parse_str(request_id).unwrap()
+        })
+        .collect();
+    assert_ne!(request_ids[0], request_ids[1]);
+
+    gateway.stop().await.unwrap();
+}
+
+async fn post_with_idempotency_key(
+    gateway: &TestGateway,
+    key: &str,
+    body: &Value,
+) -> reqwest::Response {
+    gateway
+        .client
+        .post(gateway.url("/payment"))
+        .header(IDEMPOTENCY_KEY_HEADER, key)
+        .json(body)
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn repeated_idempotency_keys_replay_the_payment() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&mint])
+        .await
+        .unwrap();
+
+    let request = melt_request(&invoice, &[mint.htlc_token(invoice.payment_hash(), 110)]);
+    let first = post_with_idempotency_key(&gateway, "payment-1", &request).await;
+    assert_eq!(first.status(), StatusCode::OK);
+    let first: MeltResponse = first.json().await.unwrap();
+
+    let retried = post_with_idempotency_key(&gateway, "payment-1", &request).await;
+    assert_eq!(retried.status(), StatusCode::OK);
+    let retried: MeltResponse = retried.json().await.unwrap();
+
+    assert_eq!(retried.payment_proof, first.payment_proof);
+    assert_eq!(retried.change, first.change);
+    assert_eq!(gateway.node.payments().len(), 1);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn requests_asking_for_more_ecash_are_not_replayed() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&mint])
+        .await
+        .unwrap();
+
+    let request = melt_request(&invoice, &[mint.htlc_token(invoice.payment_hash(), 60)]);
+    let response = post_with_idempotency_key(&gateway, "payment-1", &request).await;
+    assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+
+    // The retry with enough ecash runs instead of getting the 402 again
+    let request = melt_request(&invoice, &[mint.htlc_token(invoice.payment_hash(), 100)]);
+    let response = post_with_idempotency_key(&gateway, "payment-1", &request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(gateway.node.payments().len(), 1);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn distinct_idempotency_keys_pay_independently() {
+    let mint = MockMint::start().await.unwrap();
+    let first = TestInvoice::new(Some(100));
+    let second = TestInvoice::new(Some(50));
+    // The first payment takes the queued outcome, the second the default
+    let node = MockNode::succeeding(&second.preimage).then(MockOutcome::Success {
+        preimage: first.preimage.clone(),
+        fee: Amount::ZERO,
+    });
+    let gateway = TestGateway::start(node, &[&mint]).await.unwrap();
+
+    let request = melt_request(&first, &[mint.htlc_token(first.payment_hash(), 100)]);
+    let response = post_with_idempotency_key(&gateway, "payment-1", &request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let request = melt_request(&second, &[mint.htlc_token(second.payment_hash(), 50)]);
+    let response = post_with_idempotency_key(&gateway, "payment-2", &request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let melt: MeltResponse = response.json().await.unwrap();
+    assert_eq!(melt.payment_proof, second.preimage);
+
+    assert_eq!(gateway.node.payments().len(), 2);
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn idempotency_keys_reused_with_another_request_are_rejected() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&mint])
+        .await
+        .unwrap();
+
+    let request = melt_request(&invoice, &[mint.htlc_token(invoice.payment_hash(), 100)]);
+    let response = post_with_idempotency_key(&gateway, "payment-1", &request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let other = TestInvoice::new(Some(50));
+    let request = melt_request(&other, &[mint.htlc_token(other.payment_hash(), 50)]);
+    let response = post_with_idempotency_key(&gateway, "payment-1", &request).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.error_kind, ErrorKind::IdempotencyKeyReused);
+
+    assert_eq!(gateway.node.payments().len(), 1);
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn idempotency_keys_are_scoped_to_the_api_key() {
+    let mint = MockMint::start().await.unwrap();
+    let first = TestInvoice::new(Some(100));
+    let second = TestInvoice::new(Some(50));
+    let node = MockNode::succeeding(&second.preimage).then(MockOutcome::Success {
+        preimage: first.preimage.clone(),
+        fee: Amount::ZERO,
+    });
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.auth.api_keys = vec!["client-a".to_string(), "client-b".to_string()];
+    let gateway = TestGateway::start_with(node, settings).await.unwrap();
+
+    // Both clients use the same key, neither sees the other's response
+    for (api_key, invoice, amount) in [("client-a", &first, 100), ("client-b", &second, 50)] {
+        let request = melt_request(invoice, &[mint.htlc_token(invoice.payment_hash(), amount)]);
+        let response = gateway
+            .client
+            .post(gateway.url("/payment"))
+            .bearer_auth(api_key)
+            .header(IDEMPOTENCY_KEY_HEADER, "payment-1")
+            .json(&request)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let melt: MeltResponse = response.json().await.unwrap();
+        assert_eq!(melt.payment_proof, invoice.preimage);
+    }
+
+    assert_eq!(gateway.node.payments().len(), 2);
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn expired_idempotency_keys_run_again() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let node =
+        MockNode::succeeding(&invoice.preimage).then(MockOutcome::Failure("no route".to_string()));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.idempotency_ttl_secs = 1;
+    let gateway = TestGateway::start_with(node, settings).await.unwrap();
+
+    let request = melt_request(&invoice, &[mint.htlc_token(invoice.payment_hash(), 100)]);
+    let response = post_with_idempotency_key(&gateway, "payment-1", &request).await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    // The failure is replayed until it expires
+    let response = post_with_idempotency_key(&gateway, "payment-1", &request).await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(gateway.node.payments().len(), 1);
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let response = post_with_idempotency_key(&gateway, "payment-1", &request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(gateway.node.payments().len(), 2);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn retries_transient_node_failures() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let transient = MockOutcome::Failure("temporary channel failure".to_string());
+    let node = MockNode::succeeding(&invoice.preimage)
+        .then(transient.clone())
+        .then(transient);
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.max_retries = 2;
+    settings.payment.backoff_ms = 10;
+    let gateway = TestGateway::start_with(node, settings).await.unwrap();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(gateway.node.payments().len(), 3);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn does_not_retry_beyond_the_limit() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.max_retries = 1;
+    settings.payment.backoff_ms = 10;
+    let gateway = TestGateway::start_with(MockNode::failing("no route"), settings)
+        .await
+        .unwrap();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(gateway.node.payments().len(), 2);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn pays_with_several_tokens_from_the_same_mint() {
+    let first = MockMint::start().await.unwrap();
+    let second = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&first, &second])
+        .await
+        .unwrap();
+
+    let tokens = [
+        first.htlc_token(invoice.payment_hash(), 40),
+        first.htlc_token(invoice.payment_hash(), 30),
+        second.htlc_token(invoice.payment_hash(), 20),
+        first.htlc_token(invoice.payment_hash(), 20),
+    ];
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &tokens))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let melt: MeltResponse = response.json().await.unwrap();
+    assert_eq!(melt.payment_proof, invoice.preimage);
+    assert_eq!(melt.amount_paid_sat, 100);
+    assert_eq!(melt.fee_paid_sat, 0);
+    assert_eq!(melt.change_sat, 10);
+    assert_eq!(gateway.node.payments().len(), 1);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn insufficient_funds_send_the_payment_request_in_header_and_body() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&mint])
+        .await
+        .unwrap();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 60);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+    let header = response.headers()["x-cashu"].to_str().unwrap().to_string();
+
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.error_kind, ErrorKind::InsufficientFunds);
+    assert_eq!(error.payment_request.unwrap(), header);
+    let payment_request = PaymentRequest::from_str(&header).unwrap();
+    assert_eq!(payment_request.amount.unwrap(), Amount::from(100));
+
+    // Requests that can't be fixed by paying more don't ask for payment
+    let other_hash = TestInvoice::new(Some(1)).payment_hash();
+    let token = mint.htlc_token(other_hash, 100);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert!(response.headers().get("x-cashu").is_none());
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert!(error.payment_request.is_none());
+
+    assert!(gateway.node.payments().is_empty());
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn streams_the_stages_of_a_payment() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&mint])
+        .await
+        .unwrap();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 110);
+    let response = gateway
+        .post("/payment/stream", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.text().await.unwrap();
+
+    let mut events = Vec::new();
+    for message in body.split("\n\n").filter(|message| !message.is_empty()) {
+        let mut event = None;
+        let mut data = None;
+        for line in message.lines() {
+            if let Some(name) = line.strip_prefix("event:") {
+                event = Some(name.trim().to_string());
+            } else if let Some(value) = line.strip_prefix("data:") {
+                data = Some(value.trim().to_string());
+            }
+        }
+        // Keep-alive comments carry neither
+        if let (Some(event), Some(data)) = (event, data) {
+            events.push((event, data));
+        }
+    }
+
+    let stages: Vec<&str> = events
+        .iter()
+        .filter(|(event, _)| event == "stage")
+        .map(|(_, data)| data.as_str())
+        .collect();
+    assert_eq!(
+        stages,
+        [
+            "\"verifying_tokens\"",
+            "\"paying_invoice\"",
+            "\"receiving_tokens\"",
+            "\"returning_change\""
+        ]
+    );
+
+    let (event, data) = events.last().unwrap();
+    assert_eq!(event, "complete");
+    let melt: MeltResponse = serde_json::from_str(data).unwrap();
+    assert_eq!(melt.payment_proof, invoice.preimage);
+    assert_eq!(melt.change_sat, 10);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn passes_multi_path_options_to_the_node() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&mint])
+        .await
+        .unwrap();
+
+    let melt_options = MeltOptions::new_mpp(40_000u64);
+    let mut request = melt_request(&invoice, &[mint.htlc_token(invoice.payment_hash(), 40)]);
+    request["melt_options"] = serde_json::to_value(melt_options).unwrap();
+    let response = gateway.post("/payment", &request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let melt: MeltResponse = response.json().await.unwrap();
+    assert_eq!(melt.amount_paid_sat, 40);
+
+    let payments = gateway.node.payments();
+    let OutgoingPaymentOptions::Bolt11(payment) = &payments[0] else {
+        panic!("expected a bolt11 payment");
+    };
+    assert_eq!(payment.melt_options, Some(melt_options));
+
+    // A part can't be more than the whole invoice
+    let mut request = melt_request(&invoice, &[mint.htlc_token(invoice.payment_hash(), 150)]);
+    request["melt_options"] = serde_json::to_value(MeltOptions::new_mpp(150_000u64)).unwrap();
+    let response = gateway.post("/payment", &request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Invalid partial payment amount");
+    assert_eq!(gateway.node.payments().len(), 1);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn returns_the_payment_proof_when_change_fails() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&mint])
+        .await
+        .unwrap();
+
+    // The tokens are claimed as 64 + 32 + 16, so the change of 12 needs a
+    // swap, and every swap after the claim fails
+    mint.fail_swaps_after(1, usize::MAX);
+    let token = mint.htlc_token(invoice.payment_hash(), 112);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let melt: MeltResponse = response.json().await.unwrap();
+    assert_eq!(melt.payment_proof, invoice.preimage);
+    assert!(melt.change.is_empty());
+    assert_eq!(melt.change_sat, 0);
+    assert!(melt.change_error.is_some());
+
+    let owed = gateway.gateway.payment_store().owed_change().unwrap();
+    assert_eq!(owed.len(), 1);
+    assert_eq!(&owed[0].mint_url, mint.url());
+    assert_eq!(owed[0].amount, Amount::from(12));
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn returns_change_in_the_fewest_proofs() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.minimize_change_proofs = true;
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 123);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let melt: MeltResponse = response.json().await.unwrap();
+    assert_eq!(melt.change_sat, 23);
+    let mut denominations: Vec<Amount> = melt
+        .change
+        .iter()
+        .flat_map(|change| Token::from_str(change).unwrap().proofs())
+        .map(|proof| proof.amount)
+        .collect();
+    denominations.sort_by(|a, b| b.cmp(a));
+    assert_eq!(denominations, [16u64, 4, 2, 1].map(Amount::from).to_vec());
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn dry_runs_verify_without_paying() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&mint])
+        .await
+        .unwrap();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 110);
+    let response = gateway
+        .post(
+            "/payment/dry-run",
+            &melt_request(&invoice, &[token.clone()]),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let dry_run: DryRunResponse = response.json().await.unwrap();
+    assert_eq!(dry_run.would_pay.amount, Amount::from(100));
+    assert_eq!(dry_run.would_pay.provided, Amount::from(110));
+    assert_eq!(dry_run.would_pay.change, Amount::from(10));
+    assert_eq!(dry_run.would_pay.mints, [mint.url().clone()]);
+
+    assert!(gateway.node.payments().is_empty());
+    assert_eq!(mint.swap_requests(), 0);
+
+    // The tokens are still good for the real payment
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(gateway.node.payments().len(), 1);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn pays_on_the_versioned_and_unversioned_routes() {
+    let mint = MockMint::start().await.unwrap();
+    let first = TestInvoice::new(Some(100));
+    let second = TestInvoice::new(Some(100));
+    let node = MockNode::succeeding(&second.preimage).then(MockOutcome::Success {
+        preimage: first.preimage.clone(),
+        fee: Amount::ZERO,
+    });
+    let gateway = TestGateway::start(node, &[&mint]).await.unwrap();
+
+    for (path, invoice) in [("/payment", &first), ("/v1/payment", &second)] {
+        let token = mint.htlc_token(invoice.payment_hash(), 100);
+        let response = gateway.post(path, &melt_request(invoice, &[token])).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", path);
+        let melt: MeltResponse = response.json().await.unwrap();
+        assert_eq!(melt.payment_proof, invoice.preimage);
+    }
+    assert_eq!(gateway.node.payments().len(), 2);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn rejects_methods_that_are_not_accepted() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.accepted_methods = vec![PaymentMethod::LnAddress];
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+
+    let info: InfoResponse = gateway.get("/info").await.json().await.unwrap();
+    assert_eq!(info.accepted_methods, [PaymentMethod::LnAddress]);
+
+    let token = mint.htlc_token(invoice.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.error_kind, ErrorKind::UnsupportedMethod);
+    assert!(gateway.node.payments().is_empty());
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn passes_the_payment_timeout_to_the_node() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.payment_timeout_secs = Some(45);
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let payments = gateway.node.payments();
+    let OutgoingPaymentOptions::Bolt11(payment) = &payments[0] else {
+        panic!("expected a bolt11 payment");
+    };
+    assert_eq!(payment.timeout_secs, Some(45));
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn claims_payments_sent_without_a_preimage_once_the_node_reports_it() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.metrics.enabled = true;
+    let node = MockNode::new(MockOutcome::Unconfirmed { fee: Amount::ZERO });
+    let gateway = TestGateway::start_with(node, settings).await.unwrap();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 110);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Payment sent but no preimage returned");
+
+    let metrics = gateway.get("/metrics").await.text().await.unwrap();
+    assert!(metrics.contains("gateway_missing_preimages_total 1"));
+    let store = gateway.gateway.payment_store();
+    let unclaimed = store.unclaimed_payments().unwrap();
+    assert_eq!(unclaimed.len(), 1);
+    assert_eq!(
+        unclaimed[0].payment_hash,
+        invoice.payment_hash().to_string()
+    );
+
+    // Nothing can be claimed until the node knows the preimage
+    assert!(
+        gateway
+            .gateway
+            .reconcile_unclaimed_payments()
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    gateway.node.report_preimage(&invoice.preimage);
+    let claimed = gateway
+        .gateway
+        .reconcile_unclaimed_payments()
+        .await
+        .unwrap();
+    assert_eq!(claimed, [unclaimed[0].request_id]);
+    assert!(store.unclaimed_payments().unwrap().is_empty());
+
+    // The client already had an error, so its change is owed
+    let owed = store.owed_change().unwrap();
+    assert_eq!(owed.len(), 1);
+    assert_eq!(owed[0].amount, Amount::from(10));
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn rejects_a_preimage_that_does_not_match_the_invoice() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let wrong = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&wrong.preimage), &[&mint])
+        .await
+        .unwrap();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(
+        error.message,
+        "Node returned a preimage that does not match the payment hash"
+    );
+
+    // The tokens were never claimed with the wrong preimage
+    assert_eq!(mint.swap_requests(), 0);
+    let unclaimed = gateway
+        .gateway
+        .payment_store()
+        .unclaimed_payments()
+        .unwrap();
+    assert_eq!(unclaimed.len(), 1);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn records_tokens_the_mint_fails_to_receive_after_paying() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&mint])
+        .await
+        .unwrap();
+
+    mint.fail_swaps(1);
+    let token = mint.htlc_token(invoice.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Failed to process token receive");
+    assert_eq!(gateway.node.payments().len(), 1);
+
+    let store = gateway.gateway.payment_store();
+    let unclaimed = store.unclaimed_payments().unwrap();
+    assert_eq!(unclaimed.len(), 1);
+    assert_eq!(unclaimed[0].tokens.len(), 1);
+
+    // Once the mint accepts the tokens again they are claimed
+    gateway.node.report_preimage(&invoice.preimage);
+    let claimed = gateway
+        .gateway
+        .reconcile_unclaimed_payments()
+        .await
+        .unwrap();
+    assert_eq!(claimed, [unclaimed[0].request_id]);
+    assert!(store.unclaimed_payments().unwrap().is_empty());
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn failed_claims_are_left_for_the_next_reconciliation() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.metrics.enabled = true;
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+
+    // The receive while paying and the first reconciliation both fail
+    mint.fail_swaps(2);
+    let token = mint.htlc_token(invoice.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    gateway.node.report_preimage(&invoice.preimage);
+
+    let store = gateway.gateway.payment_store();
+    assert!(
+        gateway
+            .gateway
+            .reconcile_unclaimed_payments()
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    assert_eq!(store.unclaimed_payments().unwrap().len(), 1);
+    let metrics = gateway.get("/metrics").await.text().await.unwrap();
+    assert!(metrics.contains("gateway_reconcile_failures_total 1"));
+
+    let claimed = gateway
+        .gateway
+        .reconcile_unclaimed_payments()
+        .await
+        .unwrap();
+    assert_eq!(claimed.len(), 1);
+    assert!(store.unclaimed_payments().unwrap().is_empty());
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn rejects_payments_beyond_the_concurrency_limit() {
+    let mint = MockMint::start().await.unwrap();
+    let first = TestInvoice::new(Some(100));
+    let second = TestInvoice::new(Some(100));
+    let node = MockNode::succeeding(&first.preimage).with_delay(Duration::from_secs(2));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.max_concurrent_payments = 1;
+    let gateway = TestGateway::start_with(node, settings).await.unwrap();
+
+    let in_flight = tokio::spawn(
+        gateway
+            .client
+            .post(gateway.url("/payment"))
+            .json(&melt_request(
+                &first,
+                &[mint.htlc_token(first.payment_hash(), 100)],
+            ))
+            .send(),
+    );
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while gateway.node.payments().is_empty() {
+        assert!(Instant::now() < deadline, "the first payment never started");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let token = mint.htlc_token(second.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&second, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(
+        response
+            .headers()
+            .contains_key(reqwest::header::RETRY_AFTER)
+    );
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Gateway is busy");
+
+    let response = in_flight.await.unwrap().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(gateway.node.payments().len(), 1);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn pays_part_of_an_amountless_invoice() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(None);
+    let settings = TestGateway::settings(&[&mint]);
+    // Without a reserve for the routing fee the whole token total is paid
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+
+    let mut request = melt_request(&invoice, &[mint.htlc_token(invoice.payment_hash(), 60)]);
+    request["amount"] = json!(100);
+    let response = gateway.post("/payment", &request).await;
+    assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+    assert!(gateway.node.payments().is_empty());
+
+    let mut request = melt_request(&invoice, &[mint.htlc_token(invoice.payment_hash(), 60)]);
+    request["amount"] = json!(100);
+    request["allow_partial"] = json!(true);
+    let response = gateway.post("/payment", &request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let melt: MeltResponse = response.json().await.unwrap();
+    assert_eq!(melt.amount_paid_sat, 60);
+    assert_eq!(melt.change_sat, 0);
+
+    let payments = gateway.node.payments();
+    assert_eq!(payments.len(), 1);
+    let OutgoingPaymentOptions::Bolt11(payment) = &payments[0] else {
+        panic!("expected a bolt11 payment");
+    };
+    assert_eq!(
+        payment.melt_options,
+        Some(MeltOptions::new_amountless(60_000u64))
+    );
+    assert_eq!(payment.max_fee_amount, Some(Amount::ZERO));
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn retained_change_stays_in_the_gateway_wallet() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.retain_change = true;
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 112);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let melt: MeltResponse = response.json().await.unwrap();
+    assert!(melt.change.is_empty());
+    assert_eq!(melt.change_sat, 0);
+    assert_eq!(melt.retained_change_sat, Some(12));
+
+    let wallets = gateway.gateway.wallets().get_wallets().await;
+    assert_eq!(wallets.len(), 1);
+    let balance = wallets[0].total_balance().await.unwrap();
+    assert_eq!(balance, Amount::from(112));
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn claims_p2pk_tokens_with_the_gateway_key() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway_key = SecretKey::generate();
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.p2pk_secret_key = Some(gateway_key.to_secret_hex());
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+
+    // Tokens locked to any other key can't be claimed by the gateway
+    let conditions = p2pk_conditions(SecretKey::generate().public_key());
+    let token = mint.token(mint.issue(100, Some(&conditions)));
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Token verification failed");
+    assert!(gateway.node.payments().is_empty());
+
+    let conditions = p2pk_conditions(gateway_key.public_key());
+    let token = mint.token(mint.issue(100, Some(&conditions)));
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let melt: MeltResponse = response.json().await.unwrap();
+    assert_eq!(melt.payment_proof, invoice.preimage);
+
+    let claimed = mint.swapped_proofs();
+    assert!(!claimed.is_empty());
+    for proof in &claimed {
+        proof.verify_p2pk().unwrap();
+    }
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn an_empty_token_list_asks_for_payment() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&mint])
+        .await
+        .unwrap();
+
+    let response = gateway.post("/payment", &melt_request(&invoice, &[])).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let header = response.headers()["x-cashu"].to_str().unwrap().to_string();
+
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.error_kind, ErrorKind::NoTokens);
+    assert_eq!(error.message, "No tokens provided");
+    assert_eq!(error.payment_request.unwrap(), header);
+    let payment_request = PaymentRequest::from_str(&header).unwrap();
+    assert_eq!(payment_request.amount.unwrap(), Amount::from(100));
+
+    assert!(gateway.node.payments().is_empty());
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn a_requested_amount_must_agree_with_the_invoice() {
+    let mint = MockMint::start().await.unwrap();
+    let with_amount = TestInvoice::new(Some(100));
+    let invoice_only = TestInvoice::new(Some(100));
+    let node = MockNode::succeeding(&invoice_only.preimage).then(MockOutcome::Success {
+        preimage: with_amount.preimage.clone(),
+        fee: Amount::ZERO,
+    });
+    let gateway = TestGateway::start(node, &[&mint]).await.unwrap();
+
+    let token = mint.htlc_token(with_amount.payment_hash(), 100);
+    let mut request = melt_request(&with_amount, &[token]);
+    request["amount"] = json!(90);
+    let response = gateway.post("/payment", &request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Amount does not match the invoice");
+    assert!(gateway.node.payments().is_empty());
+
+    let token = mint.htlc_token(with_amount.payment_hash(), 100);
+    let mut request = melt_request(&with_amount, &[token]);
+    request["amount"] = json!(100);
+    let response = gateway.post("/payment", &request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let melt: MeltResponse = response.json().await.unwrap();
+    assert_eq!(melt.amount_paid_sat, 100);
+
+    let token = mint.htlc_token(invoice_only.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice_only, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let melt: MeltResponse = response.json().await.unwrap();
+    assert_eq!(melt.amount_paid_sat, 100);
+
+    assert_eq!(gateway.node.payments().len(), 2);
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn splits_change_across_mints_when_the_first_cannot_cover_it() {
+    let first = MockMint::start().await.unwrap();
+    let second = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(90));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&first, &second])
+        .await
+        .unwrap();
+
+    // The first mint only holds the 10 it was paid, short of the 20 change
+    let tokens = [
+        first.htlc_token(invoice.payment_hash(), 10),
+        second.htlc_token(invoice.payment_hash(), 100),
+    ];
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &tokens))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let melt: MeltResponse = response.json().await.unwrap();
+    assert_eq!(melt.change_sat, 20);
+    assert!(melt.change_error.is_none());
+
+    let change: Vec<Token> = melt
+        .change
+        .iter()
+        .map(|change| Token::from_str(change).unwrap())
+        .collect();
+    assert_eq!(change.len(), 2);
+    assert_eq!(&change[0].mint_url().unwrap(), first.url());
+    assert_eq!(change[0].value().unwrap(), Amount::from(10));
+    assert_eq!(&change[1].mint_url().unwrap(), second.url());
+    assert_eq!(change[1].value().unwrap(), Amount::from(10));
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn the_response_breakdown_adds_up_to_the_tokens() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let node = MockNode::new(MockOutcome::Success {
+        preimage: invoice.preimage.clone(),
+        fee: Amount::from(3),
+    });
+    let gateway = TestGateway::start(node, &[&mint]).await.unwrap();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 123);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let melt: MeltResponse = response.json().await.unwrap();
+    assert_eq!(melt.amount_paid_sat, 100);
+    assert_eq!(melt.fee_paid_sat, 3);
+    assert_eq!(melt.change_sat, 20);
+    assert_eq!(
+        melt.amount_paid_sat + melt.fee_paid_sat + melt.change_sat,
+        123
+    );
+
+    let change_value: u64 = melt
+        .change
+        .iter()
+        .map(|change| u64::from(Token::from_str(change).unwrap().value().unwrap()))
+        .sum();
+    assert_eq!(change_value, melt.change_sat);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn does_not_claim_tokens_whose_locktime_passed_during_a_slow_payment() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.min_locktime_secs = 5;
+    settings.payment.claim_locktime_margin_secs = 5;
+    let node = MockNode::succeeding(&invoice.preimage).with_delay(Duration::from_secs(3));
+    let gateway = TestGateway::start_with(node, settings).await.unwrap();
+
+    // Long enough when the payment starts, too close once it has been made
+    let conditions = htlc_conditions(invoice.payment_hash(), Some(unix_time() + 7));
+    let token = mint.token(mint.issue(100, Some(&conditions)));
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(
+        error.message,
+        "Token lock time too close to claim the tokens"
+    );
+
+    assert_eq!(gateway.node.payments().len(), 1);
+    assert_eq!(mint.swap_requests(), 0);
+    let unclaimed = gateway
+        .gateway
+        .payment_store()
+        .unclaimed_payments()
+        .unwrap();
+    assert_eq!(unclaimed.len(), 1);
+    assert_eq!(
+        unclaimed[0].payment_hash,
+        invoice.payment_hash().to_string()
+    );
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn pays_several_invoices_from_one_set_of_tokens() {
+    let mint = MockMint::start().await.unwrap();
+    let first = TestInvoice::new(Some(100));
+    let second = TestInvoice::new(Some(50));
+    let gateway_key = SecretKey::generate();
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.p2pk_secret_key = Some(gateway_key.to_secret_hex());
+    settings.payment.fee_reserve_min_sat = 2;
+    let node = MockNode::succeeding(&second.preimage).then(MockOutcome::Success {
+        preimage: first.preimage.clone(),
+        fee: Amount::ZERO,
+    });
+    let gateway = TestGateway::start_with(node, settings).await.unwrap();
+
+    // 150 sat of payments and 2 sat of fee reserve for each, from 180 sat of tokens
+    let conditions = p2pk_conditions(gateway_key.public_key());
+    let tokens = [
+        mint.token(mint.issue(100, Some(&conditions))),
+        mint.token(mint.issue(80, Some(&conditions))),
+    ];
+    let request = BatchMeltRequest {
+        payments: vec![
+            BatchPayment {
+                method: PaymentMethod::Bolt11,
+                request: first.invoice.to_string(),
+                amount: None,
+            },
+            BatchPayment {
+                method: PaymentMethod::Bolt11,
+                request: second.invoice.to_string(),
+                amount: None,
+            },
+        ],
+        tokens: tokens.to_vec(),
+    };
+    let response = gateway
+        .post("/payment/batch", &serde_json::to_value(&request).unwrap())
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let batch: BatchMeltResponse = response.json().await.unwrap();
+
+    assert_eq!(batch.payments.len(), 2);
+    for (payment, invoice) in batch.payments.iter().zip([&first, &second]) {
+        assert_eq!(payment.status, BatchPaymentStatus::Paid);
+        assert_eq!(payment.payment_hash, invoice.payment_hash().to_string());
+        assert_eq!(payment.payment_proof.as_ref(), Some(&invoice.preimage));
+    }
+    assert_eq!(batch.payments[0].amount_paid_sat, 100);
+    assert_eq!(batch.payments[1].amount_paid_sat, 50);
+
+    // Whatever neither payment spent comes back in one lot of change
+    assert_eq!(batch.change_sat, 30);
+    let change: u64 = batch
+        .change
+        .iter()
+        .map(|token| u64::from(Token::from_str(token).unwrap().value().unwrap()))
+        .sum();
+    assert_eq!(change, 30);
+    assert_eq!(gateway.node.payments().len(), 2);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn batch_payments_with_an_unknown_outcome_are_settled_later() {
+    let mint = MockMint::start().await.unwrap();
+    let first = TestInvoice::new(Some(100));
+    let second = TestInvoice::new(Some(50));
+    let gateway_key = SecretKey::generate();
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.p2pk_secret_key = Some(gateway_key.to_secret_hex());
+    // The second payment fails and the node can't tell whether it was sent
+    let node = MockNode::failing("connection reset").then(MockOutcome::Success {
+        preimage: first.preimage.clone(),
+        fee: Amount::ZERO,
+    });
+    let gateway = TestGateway::start_with(node, settings).await.unwrap();
+
+    let conditions = p2pk_conditions(gateway_key.public_key());
+    let request = BatchMeltRequest {
+        payments: [&first, &second]
+            .iter()
+            .map(|invoice| BatchPayment {
+                method: PaymentMethod::Bolt11,
+                request: invoice.invoice.to_string(),
+                amount: None,
+            })
+            .collect(),
+        tokens: vec![mint.token(mint.issue(180, Some(&conditions)))],
+    };
+    let response = gateway
+        .post("/payment/batch", &serde_json::to_value(&request).unwrap())
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let batch: BatchMeltResponse = response.json().await.unwrap();
+    assert_eq!(batch.payments[0].status, BatchPaymentStatus::Paid);
+    assert_eq!(batch.payments[1].status, BatchPaymentStatus::Pending);
+
+    let store = gateway.gateway.payment_store();
+    let pending = store.pending_payments().unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].payment_id, batch.payments[1].request_id);
+
+    // Nothing is settled until the node reports the outcome
+    assert!(
+        gateway
+            .gateway
+            .settle_pending_payments()
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    gateway.node.report_preimage(&second.preimage);
+    let settled = gateway.gateway.settle_pending_payments().await.unwrap();
+    assert_eq!(settled, [batch.payments[1].request_id]);
+    assert!(store.pending_payments().unwrap().is_empty());
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn a_requested_max_fee_is_held_to_the_ceiling() {
+    let mint = MockMint::start().await.unwrap();
+    let cheap = TestInvoice::new(Some(100));
+    let dear = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.max_fee_ceiling_sat = Some(8);
+    let node = MockNode::succeeding(&dear.preimage).then(MockOutcome::Success {
+        preimage: cheap.preimage.clone(),
+        fee: Amount::ZERO,
+    });
+    let gateway = TestGateway::start_with(node, settings).await.unwrap();
+
+    // The tokens cover the invoice and the most the fee is allowed to be
+    for (invoice, max_fee, provided) in [(&cheap, 5, 105), (&dear, 20, 108)] {
+        let mut request = melt_request(
+            invoice,
+            &[mint.htlc_token(invoice.payment_hash(), provided)],
+        );
+        request["max_fee_sat"] = json!(max_fee);
+        let response = gateway.post("/payment", &request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let max_fees: Vec<Option<Amount>> = gateway
+        .node
+        .payments()
+        .iter()
+        .map(|payment| {
+            let OutgoingPaymentOptions::Bolt11(payment) = payment else {
+                panic!("expected a bolt11 payment");
+            };
+            payment.max_fee_amount
+        })
+        .collect();
+    assert_eq!(max_fees, [Some(Amount::from(5)), Some(Amount::from(8))]);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn tokens_cover_the_fee_reserve_without_a_max_fee() {
+    let mint = MockMint::start().await.unwrap();
+    let cheap = TestInvoice::new(Some(100));
+    let dear = TestInvoice::new(Some(1_000));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.fee_reserve_ppm = 10_000;
+    settings.payment.fee_reserve_min_sat = 2;
+    settings.payment.max_fee_ceiling_sat = Some(5);
+    let node = MockNode::succeeding(&dear.preimage).then(MockOutcome::Success {
+        preimage: cheap.preimage.clone(),
+        fee: Amount::ZERO,
+    });
+    let gateway = TestGateway::start_with(node, settings).await.unwrap();
+
+    let token = mint.htlc_token(cheap.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&cheap, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.details.unwrap(), "Required: 102, provided: 100");
+    assert!(gateway.node.payments().is_empty());
+
+    // The reserve of 10 sat on the larger invoice is held to the ceiling
+    for (invoice, provided) in [(&cheap, 102), (&dear, 1_005)] {
+        let token = mint.htlc_token(invoice.payment_hash(), provided);
+        let response = gateway
+            .post("/payment", &melt_request(invoice, &[token]))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let max_fees: Vec<Option<Amount>> = gateway
+        .node
+        .payments()
+        .iter()
+        .map(|payment| {
+            let OutgoingPaymentOptions::Bolt11(payment) = payment else {
+                panic!("expected a bolt11 payment");
+            };
+            payment.max_fee_amount
+        })
+        .collect();
+    assert_eq!(max_fees, [Some(Amount::from(2)), Some(Amount::from(5))]);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn responses_name_the_hash_of_the_invoice_paid() {
+    let mint = MockMint::start().await.unwrap();
+    let first = TestInvoice::new(Some(100));
+    let second = TestInvoice::new(None);
+    let node = MockNode::succeeding(&second.preimage).then(MockOutcome::Success {
+        preimage: first.preimage.clone(),
+        fee: Amount::ZERO,
+    });
+    let gateway = TestGateway::start(node, &[&mint]).await.unwrap();
+
+    let request = melt_request(&first, &[mint.htlc_token(first.payment_hash(), 110)]);
+    let mut amountless = melt_request(&second, &[mint.htlc_token(second.payment_hash(), 60)]);
+    amountless["amount"] = json!(50);
+
+    for (invoice, request) in [(&first, request), (&second, amountless)] {
+        let response = gateway.post("/payment", &request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let melt: MeltResponse = response.json().await.unwrap();
+        assert_eq!(
+            melt.payment_hash,
+            invoice.invoice.payment_hash().to_string()
+        );
+    }
+
+    gateway.stop().await.unwrap();
+}
@@ -0,0 +1,296 @@
+//! Quoting the cost of a payment, and paying on the quoted terms
+
+use std::time::Duration;
+
+use cdk::amount::Amount;
+use cdk::cdk_payment::OutgoingPaymentOptions;
+use cdk_gateway::gateway_server::{ErrorResponse, FeeSource, InfoResponse, QuoteResponse};
+use cdk_gateway::quote::{QuoteState, ReservedQuote};
+use cdk_gateway::rates::{FiatAmount, FiatRate};
+use cdk_gateway::testing::{
+    MockMint, MockNode, MockOutcome, TestGateway, TestInvoice, melt_request,
+};
+use reqwest::StatusCode;
+use serde_json::json;
+use uuid::Uuid;
+
+async fn quote(gateway: &TestGateway, invoice: &TestInvoice) -> QuoteResponse {
+    let response = gateway
+        .post(
+            "/quote",
+            &json!({ "method": "bolt11", "request": invoice.invoice.to_string() }),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+#[tokio::test]
+async fn quotes_the_fee_the_node_estimates() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(1_000));
+    let node = MockNode::succeeding(&invoice.preimage).with_fee_estimate(Amount::from(7));
+    let gateway = TestGateway::start(node, &[&mint]).await.unwrap();
+
+    let quote = quote(&gateway, &invoice).await;
+    assert_eq!(quote.amount, Amount::from(1_000));
+    assert_eq!(quote.fee, Amount::from(7));
+    assert_eq!(quote.total, Amount::from(1_007));
+    assert_eq!(quote.fee_source, FeeSource::Node);
+    assert_eq!(quote.breakdown, None);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn quotes_include_a_breakdown_when_enabled() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(1_000));
+    let node = MockNode::succeeding(&invoice.preimage).with_fee_estimate(Amount::from(7));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.include_breakdown = true;
+    let gateway = TestGateway::start_with(node, settings).await.unwrap();
+
+    let quote = quote(&gateway, &invoice).await;
+    assert_eq!(
+        quote.breakdown.unwrap(),
+        "Invoice: 1000 sat, routing reserve: 7 sat, gateway fee: 0 sat, total: 1007 sat"
+    );
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn quotes_the_reserve_when_the_node_cannot_estimate() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(1_000));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.fee_reserve_ppm = 10_000;
+    settings.payment.fee_reserve_min_sat = 2;
+    let reserve = Amount::from(settings.payment.fee_reserve(1_000));
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+
+    let quote = quote(&gateway, &invoice).await;
+    assert_eq!(quote.fee, reserve);
+    assert_eq!(quote.fee_source, FeeSource::Reserve);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn pays_against_a_reserved_quote() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let node = MockNode::succeeding(&invoice.preimage).with_fee_estimate(Amount::from(5));
+    let gateway = TestGateway::start(node, &[&mint]).await.unwrap();
+
+    let quote = quote(&gateway, &invoice).await;
+    assert_eq!(quote.total, Amount::from(105));
+    let reserved: ReservedQuote = gateway
+        .get(&format!("/quote/{}", quote.quote_id))
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(reserved.state, QuoteState::Reserved);
+    assert_eq!(reserved.expires_at, quote.expires_at);
+
+    // The quoted fee has to be covered on top of the amount
+    let mut request = melt_request(&invoice, &[mint.htlc_token(invoice.payment_hash(), 100)]);
+    request["quote_id"] = json!(quote.quote_id);
+    let response = gateway.post("/payment", &request).await;
+    assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+
+    let mut request = melt_request(&invoice, &[mint.htlc_token(invoice.payment_hash(), 105)]);
+    request["quote_id"] = json!(quote.quote_id);
+    let response = gateway.post("/payment", &request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let payments = gateway.node.payments();
+    assert_eq!(payments.len(), 1);
+    let OutgoingPaymentOptions::Bolt11(payment) = &payments[0] else {
+        panic!("expected a bolt11 payment");
+    };
+    assert_eq!(payment.max_fee_amount, Some(Amount::from(5)));
+
+    let reserved: ReservedQuote = gateway
+        .get(&format!("/quote/{}", quote.quote_id))
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(reserved.state, QuoteState::Paid);
+
+    let mut request = melt_request(&invoice, &[mint.htlc_token(invoice.payment_hash(), 105)]);
+    request["quote_id"] = json!(quote.quote_id);
+    let response = gateway.post("/payment", &request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Quote has already been paid");
+    assert_eq!(gateway.node.payments().len(), 1);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn expired_quotes_can_not_be_paid() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let node = MockNode::succeeding(&invoice.preimage).with_fee_estimate(Amount::from(5));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.quote_ttl_secs = 1;
+    let gateway = TestGateway::start_with(node, settings).await.unwrap();
+
+    let quote = quote(&gateway, &invoice).await;
+    tokio::time::sleep(Duration::from_millis(2_100)).await;
+
+    let reserved: ReservedQuote = gateway
+        .get(&format!("/quote/{}", quote.quote_id))
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(reserved.state, QuoteState::Expired);
+
+    let mut request = melt_request(&invoice, &[mint.htlc_token(invoice.payment_hash(), 105)]);
+    request["quote_id"] = json!(quote.quote_id);
+    let response = gateway.post("/payment", &request).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Quote has expired");
+
+    let response = gateway.get(&format!("/quote/{}", Uuid::new_v4())).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    assert!(gateway.node.payments().is_empty());
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn converts_to_fiat_at_a_fixed_rate() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(1_000));
+    let node = MockNode::succeeding(&invoice.preimage).with_fee_estimate(Amount::from(5));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.rates.sat_per_unit.insert("USD".to_string(), 2.5);
+    let gateway = TestGateway::start_with(node, settings).await.unwrap();
+
+    let info: InfoResponse = gateway.get("/info").await.json().await.unwrap();
+    assert_eq!(
+        info.rates,
+        [FiatRate {
+            unit: "usd".to_string(),
+            approx_sat: 2.5,
+        }]
+    );
+
+    let quote = quote(&gateway, &invoice).await;
+    assert_eq!(
+        quote.fiat_totals,
+        [FiatAmount {
+            unit: "usd".to_string(),
+            amount: 402,
+            approx_sat: 1_005,
+        }]
+    );
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn only_one_payment_is_made_on_a_quote_at_a_time() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let node = MockNode::succeeding(&invoice.preimage)
+        .with_fee_estimate(Amount::from(5))
+        .with_delay(Duration::from_millis(500));
+    let gateway = TestGateway::start(node, &[&mint]).await.unwrap();
+    let quote = quote(&gateway, &invoice).await;
+
+    let request = || {
+        let mut request = melt_request(&invoice, &[mint.htlc_token(invoice.payment_hash(), 105)]);
+        request["quote_id"] = json!(quote.quote_id);
+        request
+    };
+    let (first, second) = (request(), request());
+    let (first, second) = tokio::join!(
+        gateway.post("/payment", &first),
+        gateway.post("/payment", &second)
+    );
+    let mut statuses = [first.status(), second.status()];
+    statuses.sort_by_key(|status| status.as_u16());
+    assert_eq!(statuses, [StatusCode::OK, StatusCode::CONFLICT]);
+    assert_eq!(gateway.node.payments().len(), 1);
+
+    let reserved: ReservedQuote = gateway
+        .get(&format!("/quote/{}", quote.quote_id))
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(reserved.state, QuoteState::Paid);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn a_failed_payment_releases_its_quote() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let node = MockNode::succeeding(&invoice.preimage)
+        .then(MockOutcome::Failure("no route".to_string()))
+        .with_fee_estimate(Amount::from(5));
+    let gateway = TestGateway::start(node, &[&mint]).await.unwrap();
+    let quote = quote(&gateway, &invoice).await;
+
+    let mut request = melt_request(&invoice, &[mint.htlc_token(invoice.payment_hash(), 105)]);
+    request["quote_id"] = json!(quote.quote_id);
+    let response = gateway.post("/payment", &request).await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    let reserved: ReservedQuote = gateway
+        .get(&format!("/quote/{}", quote.quote_id))
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(reserved.state, QuoteState::Reserved);
+
+    // The tokens were never claimed, so the retry can use them
+    let response = gateway.post("/payment", &request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(gateway.node.payments().len(), 2);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn quotes_use_reloaded_fee_settings() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(1_000));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.fee_reserve_ppm = 10_000;
+    settings.payment.fee_reserve_min_sat = 2;
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+    let before = quote(&gateway, &invoice).await;
+    assert_eq!(before.fee_source, FeeSource::Reserve);
+    assert_eq!(before.fee, Amount::from(10));
+
+    let mut settings = (*gateway.gateway.settings()).clone();
+    settings.payment.fee_reserve_min_sat = 50;
+    // Moving the server needs a restart, so it is left as it is
+    settings.server.port = 4000;
+    let ignored = gateway.gateway.reload_settings(&settings);
+    assert_eq!(ignored, ["server"]);
+    assert_eq!(gateway.gateway.settings().server.port, 0);
+
+    let after = quote(&gateway, &invoice).await;
+    assert_eq!(after.fee, Amount::from(50));
+    assert_eq!(after.total, Amount::from(1_050));
+
+    gateway.stop().await.unwrap();
+}
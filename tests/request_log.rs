@@ -0,0 +1,60 @@
+//! Logging of requests, with the secrets they carry redacted
+
+use cdk_gateway::gateway_server::MeltResponse;
+use cdk_gateway::request_log::redact_body;
+use cdk_gateway::testing::{LogBuffer, MockMint, MockNode, TestGateway, TestInvoice, melt_request};
+use reqwest::StatusCode;
+use serde_json::json;
+
+#[tokio::test]
+async fn logged_payments_do_not_contain_tokens_or_preimages() {
+    let logs = LogBuffer::default();
+    // Only the gateway's own logs, the payment runs on this thread so they are all captured
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter("cdk_gateway=trace")
+        .with_writer(logs.clone())
+        .with_ansi(false)
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.server.log_requests = true;
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 110);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token.clone()]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let melt: MeltResponse = response.json().await.unwrap();
+    gateway.stop().await.unwrap();
+
+    let logs = logs.contents();
+    assert!(logs.contains("POST /payment 200"));
+    assert!(logs.contains("POST /payment body:"));
+    assert!(!logs.contains(&token));
+    assert!(!logs.contains(&invoice.preimage));
+    for change in &melt.change {
+        assert!(!logs.contains(change));
+    }
+}
+
+#[test]
+fn redacts_secrets_from_bodies() {
+    let body = json!({
+        "method": "bolt11",
+        "tokens": ["cashuBsecret"],
+        "preimages": ["00ff"],
+        "nested": { "preimage": "00ff" },
+    });
+    let logged = redact_body(body.to_string().as_bytes());
+    assert!(logged.contains("bolt11"));
+    assert!(!logged.contains("cashuBsecret"));
+    assert!(!logged.contains("00ff"));
+
+    assert_eq!(redact_body(b"cashuBsecret"), "<12 bytes, not JSON>");
+}
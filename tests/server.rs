@@ -0,0 +1,339 @@
+//! Starting, serving and stopping the gateway's HTTP server
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use cdk_gateway::config::TlsConfig;
+use cdk_gateway::gateway_server::{MintMetadata, ReadyResponse, VersionResponse};
+use cdk_gateway::openapi::openapi_document;
+use cdk_gateway::testing::{MockMint, MockNode, TestGateway, TestInvoice, melt_request};
+use reqwest::{Method, StatusCode, header};
+
+#[tokio::test]
+async fn serves_mints_until_stopped() {
+    let mint = MockMint::start().await.unwrap();
+    let gateway = TestGateway::start(MockNode::failing("unused"), &[&mint])
+        .await
+        .unwrap();
+    let client = gateway.client.clone();
+    let url = gateway.url("/mints");
+
+    let response = gateway.get("/mints").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let mints: Vec<MintMetadata> = response.json().await.unwrap();
+    assert_eq!(mints.len(), 1);
+    assert_eq!(&mints[0].url, mint.url());
+
+    gateway.stop().await.unwrap();
+    assert!(client.get(url).send().await.is_err());
+}
+
+#[tokio::test]
+async fn binding_a_port_in_use_is_an_error() {
+    let mint = MockMint::start().await.unwrap();
+    let gateway = TestGateway::start(MockNode::failing("unused"), &[&mint])
+        .await
+        .unwrap();
+
+    let second = gateway
+        .gateway
+        .start_server(gateway.local_addr(), vec![mint.url().clone()])
+        .await;
+    assert!(second.is_err());
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn starting_without_mints_is_an_error() {
+    let result = TestGateway::start(MockNode::failing("unused"), &[]).await;
+    let error = result
+        .err()
+        .expect("a gateway without mints does not start");
+    assert!(error.to_string().contains("without any supported mints"));
+}
+
+#[tokio::test]
+async fn stopping_waits_for_payments_in_flight() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let node = MockNode::succeeding(&invoice.preimage).with_delay(Duration::from_secs(2));
+    let gateway = TestGateway::start(node, &[&mint]).await.unwrap();
+    let node = gateway.node.clone();
+    let payments = gateway.gateway.payments().clone();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 110);
+    let payment = gateway
+        .client
+        .post(gateway.url("/payment"))
+        .json(&melt_request(&invoice, &[token]))
+        .send();
+    let stopping = async {
+        while payments.active() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let started = Instant::now();
+        gateway.stop().await.unwrap();
+        started.elapsed()
+    };
+    let (response, stop_duration) = tokio::join!(payment, stopping);
+
+    assert_eq!(response.unwrap().status(), StatusCode::OK);
+    assert!(stop_duration >= Duration::from_secs(1));
+    assert_eq!(node.payments().len(), 1);
+    assert_eq!(payments.active(), 0);
+}
+
+#[tokio::test]
+async fn stopping_gives_up_on_stuck_requests_after_the_timeout() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    // A payment that outlasts both the drain and the shutdown timeout
+    let node = MockNode::succeeding(&invoice.preimage).with_delay(Duration::from_secs(60));
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.server.shutdown_drain_timeout_secs = 1;
+    settings.server.shutdown_timeout_secs = 1;
+    let gateway = TestGateway::start_with(node, settings).await.unwrap();
+    let payments = gateway.gateway.payments().clone();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 110);
+    let payment = tokio::spawn(
+        gateway
+            .client
+            .post(gateway.url("/payment"))
+            .json(&melt_request(&invoice, &[token]))
+            .send(),
+    );
+    while payments.active() == 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let started = Instant::now();
+    gateway.stop().await.unwrap();
+    let stop_duration = started.elapsed();
+    assert!(stop_duration >= Duration::from_secs(2));
+    assert!(stop_duration < Duration::from_secs(10));
+
+    payment.abort();
+}
+
+/// Send a CORS preflight for a payment from `origin`
+async fn preflight(gateway: &TestGateway, origin: &str) -> reqwest::Response {
+    gateway
+        .client
+        .request(Method::OPTIONS, gateway.url("/payment"))
+        .header(header::ORIGIN, origin)
+        .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+        .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "content-type")
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn answers_preflights_from_allowed_origins() {
+    let mint = MockMint::start().await.unwrap();
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.cors.allowed_origins = vec!["https://wallet.example".to_string()];
+    let gateway = TestGateway::start_with(MockNode::failing("unused"), settings)
+        .await
+        .unwrap();
+
+    let allowed = preflight(&gateway, "https://wallet.example").await;
+    assert!(allowed.status().is_success());
+    assert_eq!(
+        allowed.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+        "https://wallet.example"
+    );
+
+    let other = preflight(&gateway, "https://other.example").await;
+    assert!(
+        other
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none()
+    );
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn allows_localhost_origins_by_default() {
+    let mint = MockMint::start().await.unwrap();
+    let gateway = TestGateway::start(MockNode::failing("unused"), &[&mint])
+        .await
+        .unwrap();
+
+    let response = preflight(&gateway, "http://localhost:5173").await;
+    assert_eq!(
+        response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+        "http://localhost:5173"
+    );
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn serves_over_tls() {
+    let mint = MockMint::start().await.unwrap();
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.server.tls = Some(TlsConfig {
+        cert_path: fixtures.join("tls-cert.pem"),
+        key_path: fixtures.join("tls-key.pem"),
+    });
+    let gateway = TestGateway::start_with(MockNode::failing("unused"), settings)
+        .await
+        .unwrap();
+
+    // The fixture certificate is signed by a test CA
+    let ca = std::fs::read(fixtures.join("ca-cert.pem")).unwrap();
+    let client = reqwest::Client::builder()
+        .add_root_certificate(reqwest::Certificate::from_pem(&ca).unwrap())
+        .build()
+        .unwrap();
+    let url = gateway.url("/mints");
+    assert!(url.starts_with("https://"));
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let mints: Vec<MintMetadata> = response.json().await.unwrap();
+    assert_eq!(&mints[0].url, mint.url());
+
+    // Plain HTTP is not served on the TLS port
+    let plain = url.replacen("https://", "http://", 1);
+    assert!(gateway.client.get(plain).send().await.is_err());
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn reports_the_compiled_versions() {
+    let mint = MockMint::start().await.unwrap();
+    let gateway = TestGateway::start(MockNode::failing("unused"), &[&mint])
+        .await
+        .unwrap();
+
+    let response = gateway.get("/version").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let version: VersionResponse = response.json().await.unwrap();
+    assert_eq!(version.gateway_version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(version.cdk_version, env!("CDK_VERSION"));
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn serves_the_openapi_document() {
+    let mint = MockMint::start().await.unwrap();
+    let gateway = TestGateway::start(MockNode::failing("unused"), &[&mint])
+        .await
+        .unwrap();
+
+    let response = gateway.get("/openapi.json").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let document: serde_json::Value = response.json().await.unwrap();
+    assert!(document["openapi"].as_str().unwrap().starts_with("3."));
+    for path in ["/payment", "/mints", "/info"] {
+        assert!(document["paths"][path].is_object(), "{} is missing", path);
+    }
+    assert!(document["paths"]["/payment"]["post"].is_object());
+    assert!(document["components"]["schemas"]["ErrorResponse"].is_object());
+
+    gateway.stop().await.unwrap();
+}
+
+/// Paths of the routes added with `.route(` in `source`
+fn routes(source: &str) -> Vec<&str> {
+    source
+        .split(".route(")
+        .skip(1)
+        .map(|route| route.split('"').nth(1).unwrap())
+        .collect()
+}
+
+#[test]
+fn documents_every_route() {
+    let document = openapi_document();
+    let sources = [
+        include_str!("../src/gateway_server.rs"),
+        include_str!("../src/admin.rs"),
+    ];
+    let routes: Vec<&str> = sources.into_iter().flat_map(routes).collect();
+    assert!(routes.contains(&"/payment"));
+    assert!(routes.contains(&"/admin/reconcile"));
+    for route in routes {
+        assert!(
+            document["paths"][route].is_object(),
+            "{} is not documented",
+            route
+        );
+    }
+}
+
+async fn readiness(gateway: &TestGateway) -> (StatusCode, ReadyResponse) {
+    let response = gateway.get("/ready").await;
+    (response.status(), response.json().await.unwrap())
+}
+
+#[tokio::test]
+async fn becomes_ready_once_mint_info_and_the_node_are_available() {
+    let mint = MockMint::start().await.unwrap();
+    mint.fail_info(1);
+    let gateway = TestGateway::start(MockNode::failing("unused"), &[&mint])
+        .await
+        .unwrap();
+
+    // The first fetch of the mint's info fails, the retry comes after a backoff
+    let (status, ready) = readiness(&gateway).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert!(!ready.ready);
+    assert!(ready.wallets_initialized);
+    assert!(!ready.mint_info_loaded);
+    assert_eq!(gateway.get("/health").await.status(), StatusCode::OK);
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let (status, ready) = readiness(&gateway).await;
+        if status == StatusCode::OK {
+            assert!(ready.ready && ready.mint_info_loaded && ready.node_connected);
+            break;
+        }
+        assert!(Instant::now() < deadline, "the gateway never became ready");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    // Losing the node makes the gateway unready, though it is still alive
+    gateway.gateway.node_health().set_connected(false);
+    let (status, ready) = readiness(&gateway).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert!(!ready.node_connected);
+    assert_eq!(gateway.get("/health").await.status(), StatusCode::OK);
+
+    gateway.gateway.node_health().set_connected(true);
+    assert_eq!(readiness(&gateway).await.0, StatusCode::OK);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn serves_routes_under_the_base_path() {
+    let mint = MockMint::start().await.unwrap();
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.server.base_path = Some("/gateway".to_string());
+    let gateway = TestGateway::start_with(MockNode::failing("unused"), settings)
+        .await
+        .unwrap();
+
+    let response = gateway.get("/gateway/mints").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let mints: Vec<MintMetadata> = response.json().await.unwrap();
+    assert_eq!(&mints[0].url, mint.url());
+    assert_eq!(
+        gateway.get("/gateway/v1/mints").await.status(),
+        StatusCode::OK
+    );
+
+    assert_eq!(gateway.get("/mints").await.status(), StatusCode::NOT_FOUND);
+
+    gateway.stop().await.unwrap();
+}
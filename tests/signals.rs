@@ -0,0 +1,116 @@
+//! Shutting the gateway binary down with signals
+#![cfg(unix)]
+
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::Arc;
+use std::time::Duration;
+
+use cdk_gateway::testing::{MockMint, MockNode, TEST_MNEMONIC};
+use cdk_payment_processor::PaymentProcessorServer;
+use uuid::Uuid;
+
+/// A port nothing is listening on
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Run the gateway binary with `home` as its home directory, serving on `port`
+/// and paying through the processor on `processor_port`
+fn spawn_gateway(home: &Path, mint: &MockMint, processor_port: u16, port: u16) -> Child {
+    let work_dir = home.join(".cdk-gateway");
+    std::fs::create_dir_all(&work_dir).unwrap();
+    let config = format!(
+        r#"
+[grpc_processor]
+addr = "http://127.0.0.1"
+port = {processor_port}
+
+[wallet]
+mnemonic_seed = "{TEST_MNEMONIC}"
+mint_urls = ["{mint}"]
+
+[server]
+listen_addr = "127.0.0.1"
+port = {port}
+"#,
+        mint = mint.url(),
+    );
+    std::fs::write(work_dir.join("config.toml"), config).unwrap();
+
+    Command::new(env!("CARGO_BIN_EXE_cdk_gateway"))
+        .env("HOME", home)
+        .spawn()
+        .unwrap()
+}
+
+/// Wait until the gateway accepts connections on `port`, or has exited
+async fn wait_until_serving(gateway: &mut Child, port: u16) -> bool {
+    for _ in 0..300 {
+        if tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .is_ok()
+        {
+            return true;
+        }
+        if gateway.try_wait().unwrap().is_some() {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    false
+}
+
+/// Wait up to ten seconds for the gateway to exit
+async fn wait_for_exit(gateway: &mut Child) -> Option<ExitStatus> {
+    for _ in 0..100 {
+        if let Some(status) = gateway.try_wait().unwrap() {
+            return Some(status);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    None
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn interrupt_and_terminate_both_shut_down() {
+    let mint = MockMint::start().await.unwrap();
+    let processor_port = free_port();
+    let mut processor = PaymentProcessorServer::new(
+        Arc::new(MockNode::failing("unused")),
+        "127.0.0.1",
+        processor_port,
+    )
+    .unwrap();
+    processor.start(None).await.unwrap();
+
+    for signal in ["-INT", "-TERM"] {
+        let home = std::env::temp_dir().join(format!("cdk-gateway-{}", Uuid::new_v4()));
+        let port = free_port();
+        let mut gateway = spawn_gateway(&home, &mint, processor_port, port);
+        assert!(
+            wait_until_serving(&mut gateway, port).await,
+            "the gateway did not start"
+        );
+
+        let killed = Command::new("kill")
+            .args([signal, &gateway.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(killed.success());
+
+        let status = wait_for_exit(&mut gateway).await;
+        if status.is_none() {
+            gateway.kill().unwrap();
+        }
+        std::fs::remove_dir_all(&home).unwrap();
+        let status = status.expect("the gateway did not stop");
+        assert!(status.success(), "{signal} left the gateway with {status}");
+    }
+
+    processor.stop().await.unwrap();
+}
@@ -0,0 +1,475 @@
+//! Verification of the tokens a payment is made with
+
+use cdk::Amount;
+use cdk::nuts::nut00::token::{TokenV3, TokenV3Token};
+use cdk::nuts::{CurrencyUnit, Kind, Nut10Secret, Proofs, SecretKey, Token};
+use cdk::secret::Secret;
+use cdk::util::unix_time;
+use cdk_gateway::config::TokenVersion;
+use cdk_gateway::gateway_server::{ErrorKind, ErrorResponse};
+use cdk_gateway::testing::{
+    MockMint, MockNode, TestGateway, TestInvoice, htlc_conditions, melt_request,
+};
+use reqwest::StatusCode;
+
+/// `proofs` with their DLEQ proofs stripped, as some wallets encode tokens
+fn without_dleq(mut proofs: Proofs) -> Proofs {
+    for proof in proofs.iter_mut() {
+        proof.dleq = None;
+    }
+    proofs
+}
+
+#[tokio::test]
+async fn dleq_is_only_skipped_for_trusted_mints() {
+    let trusted = MockMint::start().await.unwrap();
+    let untrusted = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let mut settings = TestGateway::settings(&[&trusted, &untrusted]);
+    settings.payment.dleq_trusted_mints = vec![trusted.url().to_string()];
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+
+    let conditions = htlc_conditions(invoice.payment_hash(), Some(unix_time() + 3600));
+    let trusted_token = trusted.token(without_dleq(trusted.issue(100, Some(&conditions))));
+    let response = gateway
+        .post(
+            "/payment/dry-run",
+            &melt_request(&invoice, &[trusted_token]),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let untrusted_token = untrusted.token(without_dleq(untrusted.issue(100, Some(&conditions))));
+    let response = gateway
+        .post(
+            "/payment/dry-run",
+            &melt_request(&invoice, &[untrusted_token]),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(
+        error.message,
+        "Token missing DLEQ proof; a DLEQ-carrying token is required"
+    );
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn expired_tokens_are_rejected_apart_from_short_locktimes() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&mint])
+        .await
+        .unwrap();
+
+    let now = unix_time();
+    let expired = mint.token(mint.issue_with(100, || {
+        let tags = vec![
+            vec!["expiry".to_string(), (now - 60).to_string()],
+            vec!["locktime".to_string(), (now + 3600).to_string()],
+        ];
+        Secret::try_from(Nut10Secret::new(
+            Kind::HTLC,
+            invoice.payment_hash().to_string(),
+            Some(tags),
+        ))
+        .unwrap()
+    }));
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[expired]))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Token has expired");
+
+    let conditions = htlc_conditions(invoice.payment_hash(), Some(now + 60));
+    let short_locktime = mint.token(mint.issue(100, Some(&conditions)));
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[short_locktime]))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Token lock time is not long enough");
+
+    assert!(gateway.node.payments().is_empty());
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn reports_the_first_invalid_token_across_mints() {
+    let first = MockMint::start().await.unwrap();
+    let second = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&first, &second])
+        .await
+        .unwrap();
+
+    let valid = vec![
+        first.htlc_token(invoice.payment_hash(), 30),
+        second.htlc_token(invoice.payment_hash(), 30),
+        first.htlc_token(invoice.payment_hash(), 20),
+        second.htlc_token(invoice.payment_hash(), 20),
+    ];
+    let response = gateway
+        .post("/payment/dry-run", &melt_request(&invoice, &valid))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let conditions = htlc_conditions(invoice.payment_hash(), Some(unix_time() + 3600));
+    let missing_dleq = first.token(without_dleq(first.issue(50, Some(&conditions))));
+    let wrong_hash = second.htlc_token(TestInvoice::new(Some(1)).payment_hash(), 50);
+
+    let tokens = [valid[0].clone(), wrong_hash.clone(), missing_dleq.clone()];
+    let response = gateway
+        .post("/payment/dry-run", &melt_request(&invoice, &tokens))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Token hash does not match payment hash");
+
+    let tokens = [valid[0].clone(), missing_dleq, wrong_hash];
+    let response = gateway
+        .post("/payment/dry-run", &melt_request(&invoice, &tokens))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(
+        error.message,
+        "Token missing DLEQ proof; a DLEQ-carrying token is required"
+    );
+
+    assert!(gateway.node.payments().is_empty());
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn missing_and_invalid_dleq_proofs_are_told_apart() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&mint])
+        .await
+        .unwrap();
+    let conditions = htlc_conditions(invoice.payment_hash(), Some(unix_time() + 3600));
+
+    let missing = mint.token(without_dleq(mint.issue(100, Some(&conditions))));
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[missing]))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(
+        error.message,
+        "Token missing DLEQ proof; a DLEQ-carrying token is required"
+    );
+
+    let mut proofs = mint.issue(100, Some(&conditions));
+    for proof in proofs.iter_mut() {
+        if let Some(dleq) = proof.dleq.as_mut() {
+            dleq.r = SecretKey::generate();
+        }
+    }
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[mint.token(proofs)]))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Token verification failed");
+    assert!(
+        error
+            .details
+            .unwrap()
+            .starts_with("DLEQ verification error")
+    );
+
+    assert!(gateway.node.payments().is_empty());
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn accepts_the_configured_token_versions() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let conditions = htlc_conditions(invoice.payment_hash(), Some(unix_time() + 3600));
+    let v3 = mint.token_v3(mint.issue(100, Some(&conditions)));
+    let v4 = mint.token(mint.issue(100, Some(&conditions)));
+    assert!(v3.starts_with("cashuA"));
+    assert!(v4.starts_with("cashuB"));
+
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&mint])
+        .await
+        .unwrap();
+    for token in [&v3, &v4] {
+        let response = gateway
+            .post(
+                "/payment/dry-run",
+                &melt_request(&invoice, &[token.clone()]),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    gateway.stop().await.unwrap();
+
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.accepted_token_versions = vec![TokenVersion::V4];
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+    let response = gateway
+        .post("/payment/dry-run", &melt_request(&invoice, &[v3]))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Unsupported token version");
+
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[v4]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn rejects_tokens_that_fail_to_parse() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&mint])
+        .await
+        .unwrap();
+
+    let token = mint.htlc_token(invoice.payment_hash(), 100);
+    let response = gateway
+        .post(
+            "/payment",
+            &melt_request(&invoice, &[token, "cashuBnotatoken".to_string()]),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Invalid token");
+
+    assert!(gateway.node.payments().is_empty());
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn rejects_tokens_whose_value_overflows() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&mint])
+        .await
+        .unwrap();
+
+    let conditions = htlc_conditions(invoice.payment_hash(), Some(unix_time() + 3600));
+    let mut proofs = mint.issue(3, Some(&conditions));
+    for proof in proofs.iter_mut() {
+        proof.amount = Amount::from(u64::MAX);
+    }
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[mint.token(proofs)]))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.error_kind, ErrorKind::InvalidRequest);
+    assert_eq!(error.message, "Invalid token amount");
+
+    assert!(gateway.node.payments().is_empty());
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn malformed_spending_conditions_are_a_bad_request() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&mint])
+        .await
+        .unwrap();
+
+    let malformed = [
+        Nut10Secret::new(Kind::HTLC, "not-a-payment-hash", None::<Vec<Vec<String>>>),
+        Nut10Secret::new(
+            Kind::HTLC,
+            invoice.payment_hash().to_string(),
+            Some(vec![vec!["locktime".to_string(), "soon".to_string()]]),
+        ),
+    ];
+    for secret in malformed {
+        let token = mint.token(mint.issue_with(100, || Secret::try_from(secret.clone()).unwrap()));
+        let response = gateway
+            .post("/payment", &melt_request(&invoice, &[token]))
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error: ErrorResponse = response.json().await.unwrap();
+        assert_eq!(error.message, "Invalid spending conditions");
+    }
+
+    assert!(gateway.node.payments().is_empty());
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn an_unreachable_mint_is_retryable_unlike_an_invalid_dleq_proof() {
+    let down = MockMint::start().await.unwrap();
+    let up = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let conditions = htlc_conditions(invoice.payment_hash(), Some(unix_time() + 3600));
+    let unverifiable = down.token(down.issue(100, Some(&conditions)));
+    // Stopped before the gateway has fetched anything, so the mint's keys
+    // have to be fetched to check the token
+    down.stop();
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&down, &up])
+        .await
+        .unwrap();
+
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[unverifiable]))
+        .await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.error_kind, ErrorKind::MintUnreachable);
+    assert_eq!(
+        error.message,
+        "Mint could not be reached to verify the token, try again later"
+    );
+
+    let mut proofs = up.issue(100, Some(&conditions));
+    for proof in proofs.iter_mut() {
+        if let Some(dleq) = proof.dleq.as_mut() {
+            dleq.r = SecretKey::generate();
+        }
+    }
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[up.token(proofs)]))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.error_kind, ErrorKind::TokenVerificationFailed);
+
+    assert!(gateway.node.payments().is_empty());
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn tokens_without_a_single_mint_are_a_bad_request() {
+    let first = MockMint::start().await.unwrap();
+    let second = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&first, &second])
+        .await
+        .unwrap();
+
+    let conditions = htlc_conditions(invoice.payment_hash(), Some(unix_time() + 3600));
+    let mintless = TokenV3 {
+        token: Vec::new(),
+        memo: None,
+        unit: Some(CurrencyUnit::Sat),
+    };
+    let mixed = TokenV3 {
+        token: vec![
+            TokenV3Token::new(first.url().clone(), first.issue(50, Some(&conditions))),
+            TokenV3Token::new(second.url().clone(), second.issue(50, Some(&conditions))),
+        ],
+        memo: None,
+        unit: Some(CurrencyUnit::Sat),
+    };
+
+    for token in [mintless, mixed] {
+        let token = Token::TokenV3(token).to_string();
+        let response = gateway
+            .post("/payment", &melt_request(&invoice, &[token]))
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error: ErrorResponse = response.json().await.unwrap();
+        assert_eq!(error.message, "Token has no mint URL");
+    }
+
+    assert!(gateway.node.payments().is_empty());
+    assert_eq!(first.swap_requests() + second.swap_requests(), 0);
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn htlc_tokens_without_a_locktime_are_only_accepted_when_not_required() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let conditions = htlc_conditions(invoice.payment_hash(), None);
+
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&mint])
+        .await
+        .unwrap();
+    let token = mint.token(mint.issue(100, Some(&conditions)));
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    gateway.stop().await.unwrap();
+
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.require_locktime = true;
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+    let token = mint.token(mint.issue(100, Some(&conditions)));
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.error_kind, ErrorKind::TokenVerificationFailed);
+    assert_eq!(error.message, "Token has no lock time");
+    assert!(gateway.node.payments().is_empty());
+
+    // A token with a locktime is still accepted
+    let token = mint.htlc_token(invoice.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn tokens_locked_years_out_are_rejected_when_capped() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let conditions = htlc_conditions(
+        invoice.payment_hash(),
+        Some(unix_time() + 5 * 365 * 24 * 3600),
+    );
+
+    let mut settings = TestGateway::settings(&[&mint]);
+    settings.payment.max_locktime_secs = Some(7 * 24 * 3600);
+    let gateway = TestGateway::start_with(MockNode::succeeding(&invoice.preimage), settings)
+        .await
+        .unwrap();
+    let token = mint.token(mint.issue(100, Some(&conditions)));
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error: ErrorResponse = response.json().await.unwrap();
+    assert_eq!(error.message, "Token lock time is too far in the future");
+    assert!(gateway.node.payments().is_empty());
+
+    // An hour out is within the cap
+    let token = mint.htlc_token(invoice.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    gateway.stop().await.unwrap();
+
+    // Without a cap any locktime far enough out is accepted
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&mint])
+        .await
+        .unwrap();
+    let token = mint.token(mint.issue(100, Some(&conditions)));
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    gateway.stop().await.unwrap();
+}
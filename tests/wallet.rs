@@ -0,0 +1,93 @@
+//! The gateway's wallets, their balances and the payments made through them
+
+use cdk::amount::Amount;
+use cdk_gateway::gateway_server::{REQUEST_ID_HEADER, WalletBalance};
+use cdk_gateway::payment_store::PaymentRecord;
+use cdk_gateway::testing::{
+    MockMint, MockNode, MockOutcome, TestGateway, TestInvoice, melt_request,
+};
+use reqwest::StatusCode;
+use uuid::Uuid;
+
+#[tokio::test]
+async fn reports_the_balance_of_every_wallet() {
+    let first = MockMint::start().await.unwrap();
+    let second = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let gateway = TestGateway::start(MockNode::succeeding(&invoice.preimage), &[&first, &second])
+        .await
+        .unwrap();
+
+    let token = first.htlc_token(invoice.payment_hash(), 110);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = gateway.get("/balances").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let balances: Vec<WalletBalance> = response.json().await.unwrap();
+    assert_eq!(balances.len(), 2);
+    let balance = |mint: &MockMint| {
+        balances
+            .iter()
+            .find(|balance| &balance.mint_url == mint.url())
+            .map(|balance| balance.balance)
+    };
+    // The first wallet keeps what was paid on lightning, having returned the change
+    assert_eq!(balance(&first), Some(Amount::from(100)));
+    assert_eq!(balance(&second), Some(Amount::ZERO));
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn lists_completed_payments() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let node = MockNode::new(MockOutcome::Success {
+        preimage: invoice.preimage.clone(),
+        fee: Amount::from(2),
+    });
+    let gateway = TestGateway::start(node, &[&mint]).await.unwrap();
+
+    assert!(
+        gateway
+            .get("/payments")
+            .await
+            .json::<Vec<PaymentRecord>>()
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    let token = mint.htlc_token(invoice.payment_hash(), 110);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let request_id: Uuid = response.headers()[REQUEST_ID_HEADER]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    let payments: Vec<PaymentRecord> = gateway.get("/payments").await.json().await.unwrap();
+    assert_eq!(payments.len(), 1);
+    let payment = &payments[0];
+    assert_eq!(payment.request_id, request_id);
+    assert_eq!(payment.payment_hash, invoice.payment_hash().to_string());
+    assert_eq!(payment.amount, Amount::from(100));
+    assert_eq!(payment.fee, Amount::from(2));
+    assert_eq!(payment.change, Amount::from(8));
+
+    let past_the_end: Vec<PaymentRecord> = gateway
+        .get("/payments?offset=1")
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert!(past_the_end.is_empty());
+
+    gateway.stop().await.unwrap();
+}
@@ -0,0 +1,161 @@
+//! Payment events posted to the configured webhook
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::post;
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::sha256::Hash as Sha256Hash;
+use bitcoin::hashes::{Hash, HashEngine};
+use cdk::amount::Amount;
+use cdk_gateway::config::WebhookConfig;
+use cdk_gateway::gateway_server::{ErrorKind, REQUEST_ID_HEADER};
+use cdk_gateway::testing::{MockMint, MockNode, TestGateway, TestInvoice, melt_request};
+use cdk_gateway::webhook::{PaymentEvent, PaymentEventKind, SIGNATURE_HEADER};
+use tokio::sync::mpsc;
+
+const SECRET: &str = "webhook-secret";
+
+/// A delivered event, with the signature it was sent under
+struct Delivery {
+    body: Bytes,
+    signature: Option<String>,
+}
+
+#[derive(Clone)]
+struct ReceiverState {
+    /// Deliveries still to be refused before accepting them
+    refusals: Arc<AtomicUsize>,
+    attempts: Arc<AtomicUsize>,
+    tx: mpsc::UnboundedSender<Delivery>,
+}
+
+/// Local webhook receiver, refusing the first `refusals` deliveries
+async fn start_receiver(
+    refusals: usize,
+) -> (String, Arc<AtomicUsize>, mpsc::UnboundedReceiver<Delivery>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let state = ReceiverState {
+        refusals: Arc::new(AtomicUsize::new(refusals)),
+        attempts: Arc::default(),
+        tx,
+    };
+    let attempts = state.attempts.clone();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/events", listener.local_addr().unwrap());
+    let app = Router::new()
+        .route("/events", post(receive))
+        .with_state(state);
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    (url, attempts, rx)
+}
+
+async fn receive(
+    State(state): State<ReceiverState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    state.attempts.fetch_add(1, Ordering::SeqCst);
+    let refused = state
+        .refusals
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+            count.checked_sub(1)
+        })
+        .is_ok();
+    if refused {
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+
+    let signature = headers
+        .get(SIGNATURE_HEADER)
+        .map(|value| value.to_str().unwrap().to_string());
+    state.tx.send(Delivery { body, signature }).unwrap();
+    StatusCode::OK
+}
+
+fn expected_signature(body: &[u8]) -> String {
+    let mut engine = HmacEngine::<Sha256Hash>::new(SECRET.as_bytes());
+    engine.input(body);
+    format!("sha256={}", Hmac::<Sha256Hash>::from_engine(engine))
+}
+
+async fn next_event(rx: &mut mpsc::UnboundedReceiver<Delivery>) -> PaymentEvent {
+    let delivery = tokio::time::timeout(Duration::from_secs(10), rx.recv())
+        .await
+        .expect("the event was never delivered")
+        .unwrap();
+    assert_eq!(
+        delivery.signature.as_deref(),
+        Some(expected_signature(&delivery.body).as_str())
+    );
+    serde_json::from_slice(&delivery.body).unwrap()
+}
+
+async fn gateway_with_webhook(node: MockNode, mint: &MockMint, url: String) -> TestGateway {
+    let mut settings = TestGateway::settings(&[mint]);
+    settings.webhooks = Some(WebhookConfig {
+        url,
+        secret: Some(SECRET.to_string()),
+        ..Default::default()
+    });
+    TestGateway::start_with(node, settings).await.unwrap()
+}
+
+#[tokio::test]
+async fn posts_signed_events_for_successful_payments() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    // The first delivery is refused, so the event only arrives on a retry
+    let (url, attempts, mut events) = start_receiver(1).await;
+    let gateway = gateway_with_webhook(MockNode::succeeding(&invoice.preimage), &mint, url).await;
+
+    let token = mint.htlc_token(invoice.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let request_id = response.headers()[REQUEST_ID_HEADER]
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let event = next_event(&mut events).await;
+    assert_eq!(event.kind, PaymentEventKind::Succeeded);
+    assert_eq!(event.request_id.to_string(), request_id);
+    assert_eq!(event.amount, Some(Amount::from(100)));
+    assert_eq!(event.fee, Some(Amount::ZERO));
+    assert_eq!(event.payment_hash, Some(invoice.payment_hash().to_string()));
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+    gateway.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn posts_signed_events_for_failed_payments() {
+    let mint = MockMint::start().await.unwrap();
+    let invoice = TestInvoice::new(Some(100));
+    let (url, _, mut events) = start_receiver(0).await;
+    let gateway = gateway_with_webhook(MockNode::failing("no route"), &mint, url).await;
+
+    let token = mint.htlc_token(invoice.payment_hash(), 100);
+    let response = gateway
+        .post("/payment", &melt_request(&invoice, &[token]))
+        .await;
+    assert!(!response.status().is_success());
+
+    let event = next_event(&mut events).await;
+    assert_eq!(event.kind, PaymentEventKind::Failed);
+    assert_eq!(event.amount, Some(Amount::from(100)));
+    assert_eq!(event.fee, None);
+    assert_eq!(event.payment_hash, Some(invoice.payment_hash().to_string()));
+    assert_eq!(event.error_kind, Some(ErrorKind::PaymentFailed));
+
+    gateway.stop().await.unwrap();
+}